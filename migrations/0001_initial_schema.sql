@@ -0,0 +1,30 @@
+-- Baseline schema: users, their login credentials, issued tokens and the
+-- transfer log.
+CREATE TYPE role AS ENUM ('user', 'admin');
+CREATE TABLE userlogin (
+    id VARCHAR(255) PRIMARY KEY,
+    full_name VARCHAR(255) NOT NULL,
+    email VARCHAR(255) NOT NULL UNIQUE,
+    password VARCHAR(255) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL
+);
+CREATE TABLE users (
+    id VARCHAR(255) PRIMARY KEY REFERENCES userlogin (id),
+    full_name VARCHAR(255) NOT NULL,
+    role role NOT NULL DEFAULT 'user',
+    email VARCHAR(255) NOT NULL UNIQUE REFERENCES userlogin (email),
+    balance FLOAT8 NOT NULL DEFAULT 0
+);
+CREATE TABLE authorise (
+    id VARCHAR(255) PRIMARY KEY REFERENCES users (id),
+    email VARCHAR(255) NOT NULL REFERENCES users (email),
+    token VARCHAR(255) NOT NULL
+);
+CREATE TABLE transactions (
+    id VARCHAR(255) PRIMARY KEY,
+    from_email VARCHAR(255) NOT NULL REFERENCES users (email),
+    to_email VARCHAR(255) NOT NULL REFERENCES users (email),
+    amount FLOAT8 NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,7 @@
+-- Store balances and transfer amounts as exact integer minor units (cents)
+-- instead of float8.
+ALTER TABLE users
+    ALTER COLUMN balance TYPE BIGINT USING round(balance * 100)::BIGINT;
+
+ALTER TABLE transactions
+    ALTER COLUMN amount TYPE BIGINT USING round(amount * 100)::BIGINT;
@@ -0,0 +1,12 @@
+-- Idempotency-Key records for POST /transaction. A row without a response is
+-- still being processed.
+CREATE TABLE idempotency_keys (
+    email VARCHAR(255) NOT NULL REFERENCES users (email),
+    idempotency_key VARCHAR(255) NOT NULL,
+    request_fingerprint VARCHAR(64) NOT NULL,
+    response_status SMALLINT,
+    response_body TEXT,
+    created_at TIMESTAMPTZ NOT NULL,
+    completed_at TIMESTAMPTZ,
+    PRIMARY KEY (email, idempotency_key)
+);
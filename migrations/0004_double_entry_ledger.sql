@@ -0,0 +1,56 @@
+-- Double-entry ledger underneath users.balance. Every movement of money is a
+-- journal entry whose postings sum to zero; users.balance is a cache of the
+-- postings on the user's ledger account.
+CREATE TABLE ledger_accounts (
+    id VARCHAR(255) PRIMARY KEY,
+    user_id VARCHAR(255) UNIQUE REFERENCES users (id),
+    name VARCHAR(255) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE journal_entries (
+    id VARCHAR(255) PRIMARY KEY,
+    transaction_id VARCHAR(255) REFERENCES transactions (id),
+    description VARCHAR(255) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE postings (
+    id BIGSERIAL PRIMARY KEY,
+    entry_id VARCHAR(255) NOT NULL REFERENCES journal_entries (id),
+    account_id VARCHAR(255) NOT NULL REFERENCES ledger_accounts (id),
+    amount BIGINT NOT NULL
+);
+
+CREATE INDEX postings_account_id_idx ON postings (account_id);
+
+-- Checked at commit, so all postings of an entry can be inserted first.
+CREATE FUNCTION check_journal_entry_balanced() RETURNS trigger AS $$
+BEGIN
+    IF (SELECT COALESCE(SUM(amount), 0) FROM postings WHERE entry_id = NEW.entry_id) <> 0 THEN
+        RAISE EXCEPTION 'journal entry % is not balanced', NEW.entry_id;
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE CONSTRAINT TRIGGER postings_balanced
+    AFTER INSERT OR UPDATE ON postings
+    DEFERRABLE INITIALLY DEFERRED
+    FOR EACH ROW EXECUTE FUNCTION check_journal_entry_balanced();
+
+-- Funds initial balances; its balance is minus the money held by all users.
+INSERT INTO ledger_accounts (id, user_id, name, created_at)
+VALUES ('equity', NULL, 'system equity/suspense', now());
+
+-- Existing users start from an opening balance equal to their current balance.
+INSERT INTO ledger_accounts (id, user_id, name, created_at)
+SELECT id, id, email, now() FROM users;
+
+INSERT INTO journal_entries (id, transaction_id, description, created_at)
+SELECT 'opening-' || id, NULL, 'opening balance', now() FROM users WHERE balance <> 0;
+
+INSERT INTO postings (entry_id, account_id, amount)
+SELECT 'opening-' || id, id, balance FROM users WHERE balance <> 0
+UNION ALL
+SELECT 'opening-' || id, 'equity', -balance FROM users WHERE balance <> 0;
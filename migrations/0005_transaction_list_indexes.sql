@@ -0,0 +1,3 @@
+-- Keyset pagination of a user's transactions on (created_at, id).
+CREATE INDEX transactions_from_email_created_at_idx ON transactions (from_email, created_at, id);
+CREATE INDEX transactions_to_email_created_at_idx ON transactions (to_email, created_at, id);
@@ -0,0 +1,9 @@
+-- Refunds are transactions in the opposite direction that point back at the
+-- transfer they return money for.
+ALTER TABLE transactions
+    ADD COLUMN kind VARCHAR(32) NOT NULL DEFAULT 'transfer',
+    ADD COLUMN reverses_id VARCHAR(255) REFERENCES transactions (id),
+    ADD CONSTRAINT transactions_kind_check CHECK (kind IN ('transfer', 'refund')),
+    ADD CONSTRAINT transactions_refund_link_check CHECK ((kind = 'refund') = (reverses_id IS NOT NULL));
+
+CREATE INDEX transactions_reverses_id_idx ON transactions (reverses_id);
@@ -0,0 +1,15 @@
+-- Every transfer and refund moves through pending -> completed or
+-- pending -> failed; a completed transfer becomes reversed once it has been
+-- refunded in full. created_at is the time the transaction became pending.
+-- Every transaction recorded so far moved its money, hence the default.
+ALTER TABLE transactions
+    ADD COLUMN status VARCHAR(32) NOT NULL DEFAULT 'completed',
+    ADD COLUMN completed_at TIMESTAMPTZ,
+    ADD COLUMN failed_at TIMESTAMPTZ,
+    ADD COLUMN reversed_at TIMESTAMPTZ,
+    ADD COLUMN failure_reason VARCHAR(255),
+    ADD CONSTRAINT transactions_status_check CHECK (status IN ('pending', 'completed', 'failed', 'reversed'));
+
+UPDATE transactions SET completed_at = created_at;
+
+CREATE INDEX transactions_status_idx ON transactions (status);
@@ -0,0 +1,10 @@
+-- Optional details a client sends with a transfer to match it against its own
+-- records. Lengths are enforced by the service as well.
+ALTER TABLE transactions
+    ADD COLUMN memo VARCHAR(280),
+    ADD COLUMN reference VARCHAR(128),
+    ADD COLUMN metadata JSONB,
+    ADD CONSTRAINT transactions_metadata_object_check CHECK (metadata IS NULL OR jsonb_typeof(metadata) = 'object');
+
+CREATE INDEX transactions_reference_idx ON transactions (reference) WHERE reference IS NOT NULL;
+CREATE INDEX transactions_metadata_idx ON transactions USING GIN (metadata jsonb_path_ops);
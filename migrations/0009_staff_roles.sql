@@ -0,0 +1,4 @@
+-- Staff roles next to the existing user and admin: support handles customer
+-- accounts, auditors only read.
+ALTER TYPE role ADD VALUE IF NOT EXISTS 'support';
+ALTER TYPE role ADD VALUE IF NOT EXISTS 'auditor';
@@ -0,0 +1,17 @@
+-- Accounts can be frozen by an admin. Every admin action is written to
+-- admin_actions together with the admin who performed it.
+ALTER TABLE users
+    ADD COLUMN status VARCHAR(32) NOT NULL DEFAULT 'active',
+    ADD CONSTRAINT users_status_check CHECK (status IN ('active', 'frozen'));
+
+CREATE TABLE admin_actions (
+    id VARCHAR(255) PRIMARY KEY,
+    admin_email VARCHAR(255) NOT NULL REFERENCES users (email),
+    action VARCHAR(64) NOT NULL,
+    target_user_id VARCHAR(255) REFERENCES users (id),
+    details JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX admin_actions_admin_email_idx ON admin_actions (admin_email, created_at);
+CREATE INDEX admin_actions_target_user_id_idx ON admin_actions (target_user_id, created_at);
@@ -0,0 +1,4 @@
+-- Closed accounts can no longer log in, send or receive money. Closing is final.
+ALTER TABLE users
+    DROP CONSTRAINT users_status_check,
+    ADD CONSTRAINT users_status_check CHECK (status IN ('active', 'frozen', 'closed'));
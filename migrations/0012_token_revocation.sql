@@ -0,0 +1,12 @@
+-- One row per issued token instead of one per user, so a user can be logged in
+-- more than once and each token can be revoked on its own.
+ALTER TABLE authorise DROP CONSTRAINT authorise_pkey;
+ALTER TABLE authorise RENAME COLUMN id TO user_id;
+ALTER TABLE authorise
+    ALTER COLUMN token TYPE TEXT,
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN expires_at TIMESTAMPTZ,
+    ADD COLUMN revoked_at TIMESTAMPTZ,
+    ADD PRIMARY KEY (token);
+
+CREATE INDEX authorise_email_idx ON authorise (email);
@@ -0,0 +1,21 @@
+-- Long-lived refresh tokens, stored as SHA-256 hashes. Every login starts a
+-- token family; each refresh uses up one refresh token and issues the next one
+-- in the same family. Access tokens record the family they were issued in so a
+-- whole family can be revoked at once.
+CREATE TABLE refresh_tokens (
+    id VARCHAR(255) PRIMARY KEY,
+    family_id VARCHAR(255) NOT NULL,
+    user_id VARCHAR(255) NOT NULL REFERENCES users (id),
+    email VARCHAR(255) NOT NULL REFERENCES users (email),
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX refresh_tokens_family_id_idx ON refresh_tokens (family_id);
+CREATE INDEX refresh_tokens_email_idx ON refresh_tokens (email);
+
+ALTER TABLE authorise ADD COLUMN family_id VARCHAR(255);
+CREATE INDEX authorise_family_id_idx ON authorise (family_id);
@@ -0,0 +1,39 @@
+-- One row per login. A session is the token family started by that login:
+-- every access token and refresh token of the session carries its id in
+-- family_id.
+CREATE TABLE sessions (
+    id VARCHAR(255) PRIMARY KEY,
+    user_id VARCHAR(255) NOT NULL REFERENCES users (id),
+    email VARCHAR(255) NOT NULL REFERENCES users (email),
+    user_agent VARCHAR(512),
+    ip VARCHAR(64),
+    created_at TIMESTAMPTZ NOT NULL,
+    last_seen TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX sessions_email_idx ON sessions (email, created_at);
+
+-- tokens issued before refresh tokens existed become a session of their own
+UPDATE authorise SET family_id = md5(token) WHERE family_id IS NULL;
+
+INSERT INTO sessions (id, user_id, email, created_at, last_seen, revoked_at)
+SELECT
+    family_id,
+    MIN(user_id),
+    MIN(email),
+    MIN(created_at),
+    MAX(created_at),
+    CASE WHEN BOOL_AND(revoked_at IS NOT NULL) THEN MAX(revoked_at) END
+FROM (
+    SELECT family_id, user_id, email, created_at, revoked_at FROM authorise
+    UNION ALL
+    SELECT family_id, user_id, email, created_at, revoked_at FROM refresh_tokens
+) AS tokens
+GROUP BY family_id;
+
+ALTER TABLE authorise
+    ALTER COLUMN family_id SET NOT NULL,
+    ADD CONSTRAINT authorise_family_id_fkey FOREIGN KEY (family_id) REFERENCES sessions (id);
+ALTER TABLE refresh_tokens
+    ADD CONSTRAINT refresh_tokens_family_id_fkey FOREIGN KEY (family_id) REFERENCES sessions (id);
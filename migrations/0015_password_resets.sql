@@ -0,0 +1,12 @@
+-- One-time password reset tokens, stored as SHA-256 hashes.
+CREATE TABLE password_resets (
+    id VARCHAR(255) PRIMARY KEY,
+    user_id VARCHAR(255) NOT NULL REFERENCES users (id),
+    email VARCHAR(255) NOT NULL REFERENCES users (email),
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ
+);
+
+CREATE INDEX password_resets_user_id_idx ON password_resets (user_id);
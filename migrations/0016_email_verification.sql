@@ -0,0 +1,8 @@
+-- Users must verify their email before they can send money. Accounts that
+-- existed before verification was required count as verified.
+ALTER TABLE users ADD COLUMN email_verified_at TIMESTAMPTZ;
+
+UPDATE users
+SET email_verified_at = userlogin.created_at
+FROM userlogin
+WHERE userlogin.id = users.id;
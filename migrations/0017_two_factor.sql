@@ -0,0 +1,36 @@
+-- TOTP (RFC 6238) secrets. A secret is pending until the user confirms it with
+-- a code from their authenticator app, and only confirmed secrets are asked for.
+CREATE TABLE totp_secrets (
+    user_id VARCHAR(255) PRIMARY KEY REFERENCES users (id),
+    email VARCHAR(255) NOT NULL REFERENCES users (email),
+    secret VARCHAR(64) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    confirmed_at TIMESTAMPTZ,
+    -- time step of the last code accepted, so that no code works twice
+    last_used_step BIGINT
+);
+
+-- One-time recovery codes for users who lost their authenticator, stored as
+-- SHA-256 hashes.
+CREATE TABLE recovery_codes (
+    id VARCHAR(255) PRIMARY KEY,
+    user_id VARCHAR(255) NOT NULL REFERENCES users (id),
+    code_hash VARCHAR(64) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ
+);
+
+CREATE INDEX recovery_codes_user_id_idx ON recovery_codes (user_id);
+
+-- Challenges handed out by the first step of a login with two-factor
+-- authentication, stored as SHA-256 hashes.
+CREATE TABLE login_challenges (
+    id VARCHAR(255) PRIMARY KEY,
+    user_id VARCHAR(255) NOT NULL REFERENCES users (id),
+    email VARCHAR(255) NOT NULL REFERENCES users (email),
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    used_at TIMESTAMPTZ
+);
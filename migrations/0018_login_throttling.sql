@@ -0,0 +1,21 @@
+-- Failed logins, counted per email and per client IP. The count starts over
+-- once a subject has had no failure for as long as a lockout lasts.
+CREATE TABLE login_failures (
+    scope VARCHAR(16) NOT NULL CHECK (scope IN ('email', 'ip')),
+    subject VARCHAR(255) NOT NULL,
+    failures INTEGER NOT NULL,
+    last_failure_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (scope, subject)
+);
+
+-- Security events that are not caused by an admin, such as login lockouts.
+CREATE TABLE security_events (
+    id VARCHAR(255) PRIMARY KEY,
+    kind VARCHAR(64) NOT NULL,
+    email VARCHAR(255),
+    ip VARCHAR(64),
+    details JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX security_events_email_idx ON security_events (email, created_at);
@@ -0,0 +1,7 @@
+-- Token buckets of the rate limiter, used when RATE_LIMIT_STORE=postgres so
+-- that every instance of the service counts against the same limits.
+CREATE TABLE rate_limit_buckets (
+    key VARCHAR(512) PRIMARY KEY,
+    tokens DOUBLE PRECISION NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL
+);
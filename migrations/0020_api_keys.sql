@@ -0,0 +1,17 @@
+-- Keys users create for their own integrations, stored as SHA-256 hashes.
+-- prefix is the start of the key, listed so users can tell their keys apart.
+CREATE TABLE api_keys (
+    id VARCHAR(255) PRIMARY KEY,
+    user_id VARCHAR(255) NOT NULL REFERENCES users (id),
+    email VARCHAR(255) NOT NULL REFERENCES users (email),
+    name VARCHAR(100) NOT NULL,
+    prefix VARCHAR(32) NOT NULL,
+    key_hash VARCHAR(64) NOT NULL UNIQUE,
+    scopes TEXT[] NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    expires_at TIMESTAMPTZ,
+    last_used_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX api_keys_email_idx ON api_keys (email, created_at);
@@ -0,0 +1,64 @@
+-- Apps registered by users to act on behalf of other users, or of the user who
+-- registered them with the client-credentials grant. Public clients, such as
+-- mobile apps, cannot keep a secret and have none.
+CREATE TABLE oauth_clients (
+    id VARCHAR(255) PRIMARY KEY,
+    owner_id VARCHAR(255) NOT NULL REFERENCES users (id),
+    owner_email VARCHAR(255) NOT NULL REFERENCES users (email),
+    name VARCHAR(100) NOT NULL,
+    secret_hash VARCHAR(64),
+    redirect_uris TEXT[] NOT NULL,
+    scopes TEXT[] NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX oauth_clients_owner_email_idx ON oauth_clients (owner_email, created_at);
+
+-- The scopes a user has allowed a client. Approving more scopes later adds to
+-- them.
+CREATE TABLE oauth_consents (
+    user_id VARCHAR(255) NOT NULL REFERENCES users (id),
+    client_id VARCHAR(255) NOT NULL REFERENCES oauth_clients (id),
+    email VARCHAR(255) NOT NULL REFERENCES users (email),
+    scopes TEXT[] NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ,
+    PRIMARY KEY (user_id, client_id)
+);
+
+-- One-time codes of the authorization-code grant, stored as SHA-256 hashes.
+-- code_challenge is the PKCE S256 challenge the code is redeemed against.
+CREATE TABLE oauth_authorization_codes (
+    id VARCHAR(255) PRIMARY KEY,
+    code_hash VARCHAR(64) NOT NULL UNIQUE,
+    client_id VARCHAR(255) NOT NULL REFERENCES oauth_clients (id),
+    user_id VARCHAR(255) NOT NULL REFERENCES users (id),
+    email VARCHAR(255) NOT NULL REFERENCES users (email),
+    redirect_uri TEXT NOT NULL,
+    scopes TEXT[] NOT NULL,
+    code_challenge VARCHAR(128) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ
+);
+
+-- Access tokens issued to clients, stored as SHA-256 hashes. A token is only
+-- accepted while its row exists and is not revoked.
+CREATE TABLE oauth_tokens (
+    id VARCHAR(255) PRIMARY KEY,
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    client_id VARCHAR(255) NOT NULL REFERENCES oauth_clients (id),
+    user_id VARCHAR(255) NOT NULL REFERENCES users (id),
+    email VARCHAR(255) NOT NULL REFERENCES users (email),
+    scopes TEXT[] NOT NULL,
+    grant_type VARCHAR(32) NOT NULL,
+    authorization_code_id VARCHAR(255) REFERENCES oauth_authorization_codes (id),
+    created_at TIMESTAMPTZ NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX oauth_tokens_client_id_idx ON oauth_tokens (client_id, user_id);
+CREATE INDEX oauth_tokens_authorization_code_id_idx ON oauth_tokens (authorization_code_id);
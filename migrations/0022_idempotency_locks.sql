@@ -0,0 +1,6 @@
+-- When the request holding an idempotency key last claimed it. A claim that
+-- has not finished within its lease is taken over by the next retry, so a
+-- request that crashed does not leave its key stuck.
+ALTER TABLE idempotency_keys ADD COLUMN locked_at TIMESTAMPTZ;
+UPDATE idempotency_keys SET locked_at = created_at;
+ALTER TABLE idempotency_keys ALTER COLUMN locked_at SET NOT NULL;
@@ -0,0 +1,6 @@
+-- The policy each bucket fills up with, so that buckets which are full again
+-- can be pruned. Existing buckets are dropped; a new bucket starts full, so
+-- that only gives their clients a fresh start.
+DELETE FROM rate_limit_buckets;
+ALTER TABLE rate_limit_buckets ADD COLUMN capacity INTEGER NOT NULL;
+ALTER TABLE rate_limit_buckets ADD COLUMN refill_per_sec DOUBLE PRECISION NOT NULL;
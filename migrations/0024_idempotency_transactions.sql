@@ -0,0 +1,4 @@
+-- The transfer a claimed idempotency key created. It is written in the same
+-- database transaction as the pending transfer, so a retry that takes over the
+-- key can tell whether the money already moved instead of moving it again.
+ALTER TABLE idempotency_keys ADD COLUMN transaction_id VARCHAR(255) REFERENCES transactions (id);
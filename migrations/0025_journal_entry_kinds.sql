@@ -0,0 +1,16 @@
+-- What each journal entry records, so reconciliation does not have to go by
+-- its free-text description: 'funding' sets an account's starting balance,
+-- 'transaction' moves the money of a transfer or refund and 'correction' is
+-- posted by the reconciliation fix.
+ALTER TABLE journal_entries ADD COLUMN kind VARCHAR(32);
+
+UPDATE journal_entries SET kind = CASE
+    WHEN transaction_id IS NOT NULL THEN 'transaction'
+    WHEN description IN ('initial funding', 'opening balance') THEN 'funding'
+    ELSE 'correction'
+END;
+
+ALTER TABLE journal_entries
+    ALTER COLUMN kind SET NOT NULL,
+    ADD CONSTRAINT journal_entries_kind_check CHECK (kind IN ('funding', 'transaction', 'correction')),
+    ADD CONSTRAINT journal_entries_transaction_link_check CHECK ((kind = 'transaction') = (transaction_id IS NOT NULL));
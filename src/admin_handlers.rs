@@ -0,0 +1,224 @@
+use crate::config::db::get_conn;
+use crate::errors::Errors;
+use crate::utils::{
+    admin_controller::{
+        get_user_account, list_user_transactions, record_admin_action, search_users,
+        set_account_status, set_user_role, unlock_login,
+    },
+    reconciliation::reconcile,
+    user_structs::{
+        AccountStatus, AccountStatusRequest, ChangeRoleRequest, ListTransactionsQuery,
+        UserSearchQuery,
+    },
+};
+use axum::Extension;
+use axum::{
+    extract::{Json, Path, Query},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use tracing::{error, info, warn};
+
+/// Longest reason accepted when freezing or unfreezing an account.
+const MAX_REASON_CHARS: usize = 255;
+
+fn admin_error(e: Errors, action: &str) -> (StatusCode, Json<serde_json::Value>) {
+    match e {
+        Errors::UserDoesNotExist => {
+            let error_json = serde_json::json!({
+                "error": "User not found",
+            });
+            (StatusCode::NOT_FOUND, Json(error_json))
+        }
+        Errors::AccountClosed => {
+            let error_json = serde_json::json!({
+                "error": "Account is closed",
+            });
+            (StatusCode::CONFLICT, Json(error_json))
+        }
+        Errors::InvalidCursor => {
+            let error_json = serde_json::json!({
+                "error": "Invalid cursor",
+            });
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Errors::InvalidTransactionDetails(reason) => {
+            let error_json = serde_json::json!({
+                "error": reason,
+            });
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        e => {
+            let error_json = serde_json::json!({
+                "error": e.to_string(),
+            });
+            error!("error occurred while {}: {}", action, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
+        }
+    }
+}
+
+pub async fn search_users_handler(
+    Extension(admin_email): Extension<String>,
+    Query(filter): Query<UserSearchQuery>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match search_users(pool, admin_email.as_str(), &filter).await {
+        Ok(page) => {
+            info!("admin: {} searched users", admin_email);
+            (StatusCode::OK, Json(serde_json::json!(page)))
+        }
+        Err(e) => admin_error(e, "searching users"),
+    }
+}
+
+pub async fn get_user_handler(
+    Extension(admin_email): Extension<String>,
+    Path(user_id): Path<String>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match get_user_account(pool, admin_email.as_str(), user_id.as_str()).await {
+        Ok(account) => {
+            info!("admin: {} viewed user {}", admin_email, user_id);
+            (StatusCode::OK, Json(serde_json::json!(account)))
+        }
+        Err(e) => admin_error(e, "fetching user"),
+    }
+}
+
+pub async fn list_user_transactions_handler(
+    Extension(admin_email): Extension<String>,
+    Path(user_id): Path<String>,
+    Query(filter): Query<ListTransactionsQuery>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match list_user_transactions(pool, admin_email.as_str(), user_id.as_str(), &filter).await {
+        Ok(page) => {
+            let transactions_json = serde_json::json!({
+                "transactions": page.transactions,
+                "next_cursor": page.next_cursor,
+            });
+            info!(
+                "admin: {} listed transactions of user {}",
+                admin_email, user_id
+            );
+            (StatusCode::OK, Json(transactions_json))
+        }
+        Err(e) => admin_error(e, "listing user transactions"),
+    }
+}
+
+async fn change_account_status(
+    admin_email: String,
+    user_id: String,
+    status: AccountStatus,
+    payload: AccountStatusRequest,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let reason = payload.reason.trim();
+    if reason.is_empty() || reason.chars().count() > MAX_REASON_CHARS {
+        let error_json = serde_json::json!({
+            "error": format!("reason must be between 1 and {} characters", MAX_REASON_CHARS),
+        });
+        return (StatusCode::BAD_REQUEST, Json(error_json));
+    }
+    let pool = get_conn().await;
+    match set_account_status(pool, admin_email.as_str(), user_id.as_str(), status, reason).await {
+        Ok(account) => {
+            warn!(
+                "admin: {} set account {} to {}: {}",
+                admin_email,
+                account.email,
+                status.as_str(),
+                reason
+            );
+            (StatusCode::OK, Json(serde_json::json!(account)))
+        }
+        Err(e) => admin_error(e, "changing account status"),
+    }
+}
+
+pub async fn freeze_user_handler(
+    Extension(admin_email): Extension<String>,
+    Path(user_id): Path<String>,
+    Json(payload): Json<AccountStatusRequest>,
+) -> impl IntoResponse {
+    change_account_status(admin_email, user_id, AccountStatus::Frozen, payload).await
+}
+
+pub async fn unfreeze_user_handler(
+    Extension(admin_email): Extension<String>,
+    Path(user_id): Path<String>,
+    Json(payload): Json<AccountStatusRequest>,
+) -> impl IntoResponse {
+    change_account_status(admin_email, user_id, AccountStatus::Active, payload).await
+}
+
+pub async fn close_user_handler(
+    Extension(admin_email): Extension<String>,
+    Path(user_id): Path<String>,
+    Json(payload): Json<AccountStatusRequest>,
+) -> impl IntoResponse {
+    change_account_status(admin_email, user_id, AccountStatus::Closed, payload).await
+}
+
+/// Clears the failed logins of a user, ending a lockout.
+pub async fn unlock_user_handler(
+    Extension(admin_email): Extension<String>,
+    Path(user_id): Path<String>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match unlock_login(pool, admin_email.as_str(), user_id.as_str()).await {
+        Ok(account) => (StatusCode::OK, Json(serde_json::json!(account))),
+        Err(e) => admin_error(e, "unlocking logins"),
+    }
+}
+
+pub async fn change_role_handler(
+    Extension(admin_email): Extension<String>,
+    Path(user_id): Path<String>,
+    Json(payload): Json<ChangeRoleRequest>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match set_user_role(pool, admin_email.as_str(), user_id.as_str(), payload.role).await {
+        Ok(account) => {
+            info!(
+                "admin: {} changed the role of {} to {}",
+                admin_email,
+                account.email,
+                account.role.as_str()
+            );
+            (StatusCode::OK, Json(serde_json::json!(account)))
+        }
+        Err(Errors::Forbidden) => {
+            let error_json = serde_json::json!({
+                "error": "Admins cannot change their own role",
+            });
+            (StatusCode::FORBIDDEN, Json(error_json))
+        }
+        Err(e) => admin_error(e, "changing user role"),
+    }
+}
+
+pub async fn reconciliation_handler(Extension(user_email): Extension<String>) -> impl IntoResponse {
+    let pool = get_conn().await;
+    let drifts = match reconcile(pool).await {
+        Ok(drifts) => drifts,
+        Err(e) => return admin_error(e, "reconciling accounts"),
+    };
+    let details = serde_json::json!({
+        "drifted_accounts": drifts.len(),
+    });
+    if let Err(e) = record_admin_action(pool, user_email.as_str(), "reconcile", None, details).await
+    {
+        return admin_error(e, "recording reconciliation");
+    }
+    info!(
+        "user: {} ran reconciliation, {} accounts drifted",
+        user_email,
+        drifts.len()
+    );
+    let drifts_json = serde_json::json!({
+        "drifts": drifts,
+    });
+    (StatusCode::OK, Json(drifts_json))
+}
@@ -0,0 +1,113 @@
+use std::process::ExitCode;
+use tracing_subscriber::EnvFilter;
+use transaction_service::config::db::get_conn;
+use transaction_service::config::rate_limit::PostgresStore;
+use transaction_service::reconciliation::{
+    fail_stale_transactions, fix_account, pending_timeout, reconcile,
+};
+
+const USAGE: &str =
+    "usage: admin reconcile [--fix] | admin prune-rate-limits | admin fail-stale-transactions";
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    dotenv::dotenv().ok();
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new("warn"))
+        .init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["reconcile"] => run_reconcile(false).await,
+        ["reconcile", "--fix"] => run_reconcile(true).await,
+        ["prune-rate-limits"] => run_prune_rate_limits().await,
+        ["fail-stale-transactions"] => run_fail_stale_transactions().await,
+        _ => {
+            eprintln!("{}", USAGE);
+            ExitCode::from(2)
+        }
+    }
+}
+
+/// Prints every drifting account and, with `--fix`, writes correcting entries.
+/// Exits with 1 when drift is left behind.
+async fn run_reconcile(fix: bool) -> ExitCode {
+    let pool = get_conn().await;
+    let drifts = match reconcile(pool).await {
+        Ok(drifts) => drifts,
+        Err(err) => {
+            eprintln!("reconciliation failed: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    if drifts.is_empty() {
+        println!("all accounts reconcile");
+        return ExitCode::SUCCESS;
+    }
+
+    let mut unresolved = 0;
+    for drift in &drifts {
+        println!(
+            "{}\texpected {}\tcached {}\tledger {}\tfirst divergent transaction {}",
+            drift.email,
+            drift.expected,
+            drift.cached,
+            drift.ledger,
+            drift
+                .first_divergent_transaction
+                .as_deref()
+                .unwrap_or("none")
+        );
+        if !fix {
+            unresolved += 1;
+            continue;
+        }
+        match fix_account(pool, &drift.user_id).await {
+            Ok(Some(_)) => println!("\tcorrected"),
+            Ok(None) => println!("\tno longer drifting"),
+            Err(err) => {
+                eprintln!("\tunable to correct {}: {}", drift.email, err);
+                unresolved += 1;
+            }
+        }
+    }
+    println!("{} drifting account(s)", drifts.len());
+    if unresolved > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/// Deletes the rate limit buckets in Postgres that are full again.
+async fn run_prune_rate_limits() -> ExitCode {
+    match PostgresStore.prune().await {
+        Ok(pruned) => {
+            println!("pruned {} rate limit bucket(s)", pruned);
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("pruning rate limit buckets failed: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Marks transactions that have been pending for too long as failed.
+async fn run_fail_stale_transactions() -> ExitCode {
+    match fail_stale_transactions(get_conn().await, pending_timeout()).await {
+        Ok(failed) => {
+            println!("marked {} stale transaction(s) as failed", failed);
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("failing stale transactions failed: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
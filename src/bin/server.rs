@@ -1,20 +1,38 @@
 use std::net::SocketAddr;
+use std::process::ExitCode;
 use tracing_subscriber::EnvFilter;
+use transaction_service::config::db::get_conn;
+use transaction_service::config::jwt_keys::init_jwt_keys;
+use transaction_service::reconciliation::{fail_stale_transactions, pending_timeout};
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     dotenv::dotenv().ok();
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::new("info"))
         .pretty()
         .init();
     let server_addr = SocketAddr::from(([127, 0, 0, 1], 3042));
+    if let Err(err) = init_jwt_keys() {
+        tracing::error!("unable to load JWT keys: {}", err);
+        return ExitCode::FAILURE;
+    }
+    // transfers a crashed instance recorded but never settled
+    if let Err(err) = fail_stale_transactions(get_conn().await, pending_timeout()).await {
+        tracing::error!("unable to fail stale transactions: {:?}", err);
+    }
 
     println!("Server started on {}", server_addr);
     let listener = tokio::net::TcpListener::bind(server_addr).await.unwrap();
-    let server = axum::serve(listener, transaction_service::trnx_service());
+    // the peer address is recorded with every session
+    let server = axum::serve(
+        listener,
+        transaction_service::trnx_service().into_make_service_with_connect_info::<SocketAddr>(),
+    );
 
     if let Err(err) = server.await {
         tracing::error!("server error: {:?}", err);
+        return ExitCode::FAILURE;
     }
+    ExitCode::SUCCESS
 }
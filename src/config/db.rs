@@ -20,18 +20,16 @@ pub async fn connect_db() -> Result<Pool<sqlx::Postgres>, sqlx::Error> {
 #[instrument]
 async fn init_conn() -> PgPool {
     let db_pool = connect_db();
-    let db_conn_info;
     match db_pool.await {
         Ok(value) => {
             info!("Db Connection Successful");
-            db_conn_info = value;
+            value
         }
         Err(err) => {
             error!("error {}", err);
             panic!("Database connection was not established");
         }
     }
-    db_conn_info
 }
 pub async fn get_conn() -> &'static PgPool {
     CONN.get_or_init(init_conn).await
@@ -0,0 +1,304 @@
+//! The keys tokens are signed and verified with.
+//!
+//! By default tokens are signed with HS256 and the shared secret in `JWT_KEY`.
+//! Setting `JWT_SIGNING_KEY_FILE` switches to asymmetric keys read from PEM
+//! files:
+//!
+//! - `JWT_SIGNING_KEY_FILE`: the private key new tokens are signed with, an RSA
+//!   key for RS256 or an Ed25519 key for EdDSA.
+//! - `JWT_SIGNING_KEY_ID`: the `kid` put in the header of new tokens.
+//! - `JWT_VERIFICATION_KEYS`: the public keys tokens are accepted from, as a
+//!   comma separated list of `kid=path/to/public.pem`. It has to include the
+//!   signing key.
+//!
+//! To rotate keys without downtime, add the public key of the new key to
+//! `JWT_VERIFICATION_KEYS` first, then make it the signing key, and drop the
+//! old one once the tokens it signed have expired.
+
+use crate::errors::Errors;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::prelude::*;
+use dotenv::dotenv;
+use jsonwebtoken::{
+    decode, decode_header, encode,
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation,
+};
+use pkcs1::RsaPublicKey;
+use serde::{de::DeserializeOwned, Serialize};
+use spki::{ObjectIdentifier, SubjectPublicKeyInfoRef};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::sync::OnceLock;
+use tracing::{error, info, instrument};
+
+static KEYS: OnceLock<JwtKeys> = OnceLock::new();
+
+/// The key types of RSA (RFC 8017) and Ed25519 (RFC 8410) keys in a
+/// `PUBLIC KEY` PEM.
+const RSA_ENCRYPTION_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.1");
+const ED25519_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.101.112");
+
+struct VerificationKey {
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+/// The key new tokens are signed with and the keys tokens are accepted from.
+pub struct JwtKeys {
+    algorithm: Algorithm,
+    /// `None` for the shared secret, which has no id.
+    signing_kid: Option<String>,
+    signing_key: EncodingKey,
+    verification_keys: HashMap<String, VerificationKey>,
+    /// Used when the shared secret is in use, as tokens signed with it carry no
+    /// `kid`.
+    secret_key: Option<DecodingKey>,
+    jwks: JwkSet,
+}
+
+impl JwtKeys {
+    /// Signs and verifies with HS256 and `secret`. The key set is empty, as the
+    /// secret cannot be published.
+    pub fn from_secret(secret: &str) -> Self {
+        JwtKeys {
+            algorithm: Algorithm::HS256,
+            signing_kid: None,
+            signing_key: EncodingKey::from_secret(secret.as_bytes()),
+            verification_keys: HashMap::new(),
+            secret_key: Some(DecodingKey::from_secret(secret.as_bytes())),
+            jwks: JwkSet { keys: Vec::new() },
+        }
+    }
+
+    /// Signs with the private key in PEM `signing_key` under `signing_kid`, and
+    /// verifies with the public keys in the PEM files of `verification_keys`,
+    /// which are `(kid, path)` pairs. The algorithm of each key follows from its
+    /// type.
+    pub fn from_pem_files(
+        signing_kid: &str,
+        signing_key: &str,
+        verification_keys: &[(String, String)],
+    ) -> Result<Self, Errors> {
+        let mut keys = HashMap::new();
+        let mut jwks = JwkSet { keys: Vec::new() };
+        for (kid, path) in verification_keys {
+            if keys.contains_key(kid) {
+                let err = Errors::InvalidJwtKey(format!("kid {} is used twice", kid));
+                return Err(err);
+            }
+            let pem = read_pem(path)?;
+            let (algorithm, jwk) = public_jwk(kid, &pem).ok_or_else(|| {
+                Errors::InvalidJwtKey(format!("{} is not an RSA or Ed25519 public key", path))
+            })?;
+            let key = DecodingKey::from_jwk(&jwk)
+                .map_err(|err| Errors::InvalidJwtKey(format!("{}: {}", path, err)))?;
+            keys.insert(kid.clone(), VerificationKey { algorithm, key });
+            jwks.keys.push(jwk);
+        }
+        let algorithm = match keys.get(signing_kid) {
+            Some(key) => key.algorithm,
+            None => {
+                let err = Errors::InvalidJwtKey(format!(
+                    "signing key {} has no verification key",
+                    signing_kid
+                ));
+                return Err(err);
+            }
+        };
+        let pem = read_pem(signing_key)?;
+        let encoding_key = match algorithm {
+            Algorithm::EdDSA => EncodingKey::from_ed_pem(&pem),
+            _ => EncodingKey::from_rsa_pem(&pem),
+        }
+        .map_err(|err| Errors::InvalidJwtKey(format!("{}: {}", signing_key, err)))?;
+        let keys = JwtKeys {
+            algorithm,
+            signing_kid: Some(signing_kid.to_string()),
+            signing_key: encoding_key,
+            verification_keys: keys,
+            secret_key: None,
+            jwks,
+        };
+        // a signing key that does not belong to its public key would only show
+        // once every token is rejected
+        let probe = serde_json::json!({ "exp": Utc::now().timestamp() + 60 });
+        let token = keys.sign(&probe)?;
+        if keys.verify::<serde_json::Value>(&token).is_err() {
+            let err = Errors::InvalidJwtKey(format!(
+                "{} does not match verification key {}",
+                signing_key, signing_kid
+            ));
+            return Err(err);
+        }
+        Ok(keys)
+    }
+
+    /// The algorithm new tokens are signed with.
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// The public keys tokens are accepted from, as served on
+    /// `/.well-known/jwks.json`.
+    pub fn jwks(&self) -> &JwkSet {
+        &self.jwks
+    }
+
+    /// Signs `claims` with the signing key and puts its `kid` in the header.
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, Errors> {
+        let mut header = Header::new(self.algorithm);
+        header.kid = self.signing_kid.clone();
+        encode(&header, claims, &self.signing_key).map_err(|_| Errors::InternalServerError)
+    }
+
+    /// Verifies `token` with the key its `kid` names and returns its claims.
+    /// Tokens without a `kid`, with an unknown one or with an algorithm other
+    /// than that of their key are rejected.
+    pub fn verify<T: DeserializeOwned>(&self, token: &str) -> Result<TokenData<T>, Errors> {
+        let header = decode_header(token).map_err(|_| Errors::InternalServerError)?;
+        let (algorithm, key) = match (&self.secret_key, header.kid.as_deref()) {
+            (Some(secret_key), _) => (Algorithm::HS256, secret_key),
+            (None, Some(kid)) => match self.verification_keys.get(kid) {
+                Some(key) => (key.algorithm, &key.key),
+                None => return Err(Errors::InternalServerError),
+            },
+            (None, None) => return Err(Errors::InternalServerError),
+        };
+        decode::<T>(token, key, &Validation::new(algorithm))
+            .map_err(|_| Errors::InternalServerError)
+    }
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>, Errors> {
+    fs::read(path).map_err(|err| Errors::InvalidJwtKey(format!("unable to read {}: {}", path, err)))
+}
+
+/// Builds the JWK of the public key in PEM `pem`, which is either a
+/// `PUBLIC KEY` holding an RSA or Ed25519 key or an `RSA PUBLIC KEY`, and
+/// returns it with the algorithm tokens signed by the key use.
+fn public_jwk(kid: &str, pem: &[u8]) -> Option<(Algorithm, Jwk)> {
+    let pem = pem::parse(pem).ok()?;
+    let (algorithm, parameters) = match pem.tag() {
+        "RSA PUBLIC KEY" => (Algorithm::RS256, rsa_parameters(pem.contents())?),
+        "PUBLIC KEY" => {
+            let info = SubjectPublicKeyInfoRef::try_from(pem.contents()).ok()?;
+            let key = info.subject_public_key.as_bytes()?;
+            if info.algorithm.oid == RSA_ENCRYPTION_OID {
+                (Algorithm::RS256, rsa_parameters(key)?)
+            } else if info.algorithm.oid == ED25519_OID {
+                let parameters = AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                    key_type: OctetKeyPairType::OctetKeyPair,
+                    curve: EllipticCurve::Ed25519,
+                    x: URL_SAFE_NO_PAD.encode(key),
+                });
+                (Algorithm::EdDSA, parameters)
+            } else {
+                return None;
+            }
+        }
+        _ => return None,
+    };
+    let key_algorithm = match algorithm {
+        Algorithm::EdDSA => KeyAlgorithm::EdDSA,
+        _ => KeyAlgorithm::RS256,
+    };
+    let jwk = Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: Some(key_algorithm),
+            key_id: Some(kid.to_string()),
+            ..Default::default()
+        },
+        algorithm: parameters,
+    };
+    Some((algorithm, jwk))
+}
+
+/// The modulus and public exponent of the PKCS#1 `RSAPublicKey` in `der`,
+/// without the zero bytes that keep them positive, as JWKs expect them.
+fn rsa_parameters(der: &[u8]) -> Option<AlgorithmParameters> {
+    let key = RsaPublicKey::try_from(der).ok()?;
+    Some(AlgorithmParameters::RSA(RSAKeyParameters {
+        key_type: RSAKeyType::RSA,
+        n: URL_SAFE_NO_PAD.encode(key.modulus.as_bytes()),
+        e: URL_SAFE_NO_PAD.encode(key.public_exponent.as_bytes()),
+    }))
+}
+
+/// Parses `JWT_VERIFICATION_KEYS`, a comma separated list of `kid=path`, into
+/// `(kid, path)` pairs.
+pub fn verification_key_files(list: &str) -> Result<Vec<(String, String)>, Errors> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.split_once('=') {
+            Some((kid, path)) if !kid.trim().is_empty() && !path.trim().is_empty() => {
+                Ok((kid.trim().to_string(), path.trim().to_string()))
+            }
+            _ => {
+                let err = Errors::InvalidJwtKey(format!(
+                    "JWT_VERIFICATION_KEYS entry {} is not kid=path",
+                    entry
+                ));
+                Err(err)
+            }
+        })
+        .collect()
+}
+
+/// Reads the keys from the environment, as described at the top of this module.
+pub fn load_jwt_keys() -> Result<JwtKeys, Errors> {
+    dotenv().ok();
+    let signing_key = match env::var("JWT_SIGNING_KEY_FILE") {
+        Ok(path) => path,
+        Err(_) => {
+            let secret = env::var("JWT_KEY")
+                .map_err(|_| Errors::InvalidJwtKey("JWT_KEY must be set".to_string()))?;
+            return Ok(JwtKeys::from_secret(&secret));
+        }
+    };
+    let signing_kid = env::var("JWT_SIGNING_KEY_ID")
+        .map_err(|_| Errors::InvalidJwtKey("JWT_SIGNING_KEY_ID must be set".to_string()))?;
+    let list = env::var("JWT_VERIFICATION_KEYS")
+        .map_err(|_| Errors::InvalidJwtKey("JWT_VERIFICATION_KEYS must be set".to_string()))?;
+    let verification_keys = verification_key_files(&list)?;
+    let keys = JwtKeys::from_pem_files(&signing_kid, &signing_key, &verification_keys)?;
+    info!(
+        "Signing tokens with {:?} key {}, accepting {} keys",
+        keys.algorithm,
+        signing_kid,
+        verification_keys.len()
+    );
+    Ok(keys)
+}
+
+/// Loads the keys ahead of the first request, so the server can refuse to start
+/// with a bad configuration instead of failing its requests.
+pub fn init_jwt_keys() -> Result<(), Errors> {
+    if KEYS.get().is_none() {
+        let keys = load_jwt_keys()?;
+        // a concurrent first request may have loaded them in the meantime
+        let _ = KEYS.set(keys);
+    }
+    Ok(())
+}
+
+#[instrument]
+fn init_keys() -> JwtKeys {
+    match load_jwt_keys() {
+        Ok(keys) => keys,
+        Err(err) => {
+            error!("error {}", err);
+            panic!("JWT keys could not be loaded");
+        }
+    }
+}
+
+pub fn get_jwt_keys() -> &'static JwtKeys {
+    KEYS.get_or_init(init_keys)
+}
@@ -0,0 +1,111 @@
+use crate::errors::Errors;
+use dotenv::dotenv;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use tracing::{error, info};
+
+static MAILER: OnceLock<Box<dyn Mailer>> = OnceLock::new();
+
+/// An email to one recipient.
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Sends the emails of the service. Set `MAIL_DIR` to use a [`FileMailer`];
+/// otherwise emails are printed by a [`StdoutMailer`].
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: &Email) -> Result<(), Errors>;
+}
+
+/// Prints every email to stdout, for local development.
+pub struct StdoutMailer;
+
+impl Mailer for StdoutMailer {
+    fn send(&self, email: &Email) -> Result<(), Errors> {
+        println!(
+            "To: {}\nSubject: {}\n\n{}\n",
+            email.to, email.subject, email.body
+        );
+        Ok(())
+    }
+}
+
+/// Appends every email to a file named after its recipient in `dir`, so tests
+/// and local tools can read what was sent.
+pub struct FileMailer {
+    pub dir: PathBuf,
+}
+
+impl FileMailer {
+    /// The file the emails to `to` are appended to.
+    pub fn mailbox(&self, to: &str) -> PathBuf {
+        let name = to
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || "@.-_+".contains(c) {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        self.dir.join(format!("{name}.txt"))
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, email: &Email) -> Result<(), Errors> {
+        let written = fs::create_dir_all(&self.dir).and_then(|_| {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.mailbox(&email.to))?;
+            writeln!(
+                file,
+                "To: {}\nSubject: {}\n\n{}\n",
+                email.to, email.subject, email.body
+            )
+        });
+        match written {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                error!("Unable to write email to {}: {:?}", email.to, err);
+                Err(Errors::InternalServerError)
+            }
+        }
+    }
+}
+
+fn init_mailer() -> Box<dyn Mailer> {
+    dotenv().ok();
+    match env::var("MAIL_DIR") {
+        Ok(dir) => {
+            info!("Writing emails to {}", dir);
+            Box::new(FileMailer {
+                dir: PathBuf::from(dir),
+            })
+        }
+        Err(_) => Box::new(StdoutMailer),
+    }
+}
+
+pub fn get_mailer() -> &'static dyn Mailer {
+    MAILER.get_or_init(init_mailer).as_ref()
+}
+
+/// Sends `email` with the configured mailer. Mailers write files or talk to a
+/// server, so they run on the blocking thread pool.
+pub async fn send_email(email: Email) -> Result<(), Errors> {
+    match tokio::task::spawn_blocking(move || get_mailer().send(&email)).await {
+        Ok(sent) => sent,
+        Err(err) => {
+            error!("Unable to run the mailer: {:?}", err);
+            Err(Errors::InternalServerError)
+        }
+    }
+}
@@ -1 +1,4 @@
-pub mod db;
+pub mod db;
+pub mod jwt_keys;
+pub mod mailer;
+pub mod rate_limit;
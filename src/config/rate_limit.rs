@@ -0,0 +1,322 @@
+use crate::config::db::get_conn;
+use crate::errors::Errors;
+use chrono::prelude::*;
+use dotenv::dotenv;
+use sqlx::Row;
+use std::collections::HashMap;
+use std::env;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Mutex, OnceLock};
+use tracing::{error, info};
+
+static STORE: OnceLock<Box<dyn RateLimitStore>> = OnceLock::new();
+
+/// Buckets the [`MemoryStore`] keeps by default. When there are more, it
+/// forgets the ones that are full again, which are the same as new ones, and
+/// then the ones that were used longest ago.
+const MAX_MEMORY_BUCKETS: usize = 10_000;
+
+/// Requests per minute of each route group when its
+/// `RATE_LIMIT_<GROUP>_PER_MINUTE` is not set.
+const DEFAULT_AUTH_PER_MINUTE: u32 = 10;
+const DEFAULT_MONEY_PER_MINUTE: u32 = 30;
+const DEFAULT_READ_PER_MINUTE: u32 = 120;
+
+/// Routes that share a rate limit. Every client has a bucket of its own for
+/// each group.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RouteGroup {
+    /// Logging in, registering and managing credentials.
+    Auth,
+    /// Transfers and refunds.
+    Money,
+    /// Balances, transactions and sessions.
+    Read,
+}
+
+impl RouteGroup {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RouteGroup::Auth => "auth",
+            RouteGroup::Money => "money",
+            RouteGroup::Read => "read",
+        }
+    }
+
+    pub fn policy(self) -> RateLimitPolicy {
+        dotenv().ok();
+        let (var, default) = match self {
+            RouteGroup::Auth => ("RATE_LIMIT_AUTH_PER_MINUTE", DEFAULT_AUTH_PER_MINUTE),
+            RouteGroup::Money => ("RATE_LIMIT_MONEY_PER_MINUTE", DEFAULT_MONEY_PER_MINUTE),
+            RouteGroup::Read => ("RATE_LIMIT_READ_PER_MINUTE", DEFAULT_READ_PER_MINUTE),
+        };
+        let per_minute = env::var(var)
+            .ok()
+            .and_then(|value| value.parse::<u32>().ok())
+            .unwrap_or(default);
+        RateLimitPolicy::per_minute(per_minute)
+    }
+}
+
+/// A token bucket that holds up to `capacity` requests and gets
+/// `refill_per_sec` of them back every second.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimitPolicy {
+    pub capacity: u32,
+    pub refill_per_sec: f64,
+}
+
+impl RateLimitPolicy {
+    /// A bucket of `per_minute` requests that refills in a minute.
+    pub fn per_minute(per_minute: u32) -> Self {
+        RateLimitPolicy {
+            capacity: per_minute,
+            refill_per_sec: per_minute as f64 / 60.0,
+        }
+    }
+
+    /// The tokens a bucket that held `tokens` at `updated_at` holds at `now`.
+    fn refill(&self, tokens: f64, updated_at: DateTime<Utc>, now: DateTime<Utc>) -> f64 {
+        let elapsed = (now - updated_at).num_milliseconds().max(0) as f64 / 1000.0;
+        (tokens + elapsed * self.refill_per_sec).min(self.capacity as f64)
+    }
+
+    /// Takes a token from a bucket that holds `tokens`, and returns what is left
+    /// in it and the decision.
+    fn take(&self, tokens: f64) -> (f64, RateLimitDecision) {
+        let allowed = tokens >= 1.0;
+        let left = if allowed { tokens - 1.0 } else { tokens };
+        let seconds_until = |target: f64| -> u64 {
+            if left >= target || self.refill_per_sec <= 0.0 {
+                0
+            } else {
+                ((target - left) / self.refill_per_sec).ceil() as u64
+            }
+        };
+        let decision = RateLimitDecision {
+            allowed,
+            limit: self.capacity,
+            remaining: left.floor() as u32,
+            retry_after_secs: if allowed { 0 } else { seconds_until(1.0) },
+            reset_secs: seconds_until(self.capacity as f64),
+        };
+        (left, decision)
+    }
+}
+
+/// Whether a request may go ahead, and what to tell the client about its
+/// bucket.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Seconds until the next request is let through, when this one is not.
+    pub retry_after_secs: u64,
+    /// Seconds until the bucket is full again.
+    pub reset_secs: u64,
+}
+
+pub type TakeFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<RateLimitDecision, Errors>> + Send + 'a>>;
+
+/// Keeps the token buckets of the rate limiter. Set `RATE_LIMIT_STORE=postgres`
+/// to share the buckets between instances of the service with a
+/// [`PostgresStore`]; otherwise each instance keeps its own in a
+/// [`MemoryStore`].
+pub trait RateLimitStore: Send + Sync {
+    /// Takes one token from bucket `key`, creating it full if it does not exist.
+    fn take<'a>(&'a self, key: &'a str, policy: RateLimitPolicy) -> TakeFuture<'a>;
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: DateTime<Utc>,
+    /// The policy of the route group the bucket belongs to.
+    policy: RateLimitPolicy,
+}
+
+impl Bucket {
+    fn is_full(&self, now: DateTime<Utc>) -> bool {
+        self.policy.refill(self.tokens, self.updated_at, now) >= self.policy.capacity as f64
+    }
+}
+
+/// Keeps the buckets in the memory of this instance.
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+    max_buckets: usize,
+}
+
+impl MemoryStore {
+    /// A store that keeps at most `max_buckets` buckets.
+    pub fn with_max_buckets(max_buckets: usize) -> Self {
+        MemoryStore {
+            buckets: Mutex::new(HashMap::new()),
+            max_buckets,
+        }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        MemoryStore::with_max_buckets(MAX_MEMORY_BUCKETS)
+    }
+}
+
+/// Makes room for one more bucket in `buckets`, which holds at most
+/// `max_buckets`.
+fn evict(buckets: &mut HashMap<String, Bucket>, max_buckets: usize, now: DateTime<Utc>) {
+    if buckets.len() < max_buckets {
+        return;
+    }
+    buckets.retain(|_, bucket| !bucket.is_full(now));
+    let excess = (buckets.len() + 1).saturating_sub(max_buckets);
+    if excess == 0 {
+        return;
+    }
+    let mut oldest = buckets
+        .iter()
+        .map(|(key, bucket)| (bucket.updated_at, key.clone()))
+        .collect::<Vec<_>>();
+    oldest.sort_unstable();
+    for (_, key) in oldest.into_iter().take(excess) {
+        buckets.remove(&key);
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn take<'a>(&'a self, key: &'a str, policy: RateLimitPolicy) -> TakeFuture<'a> {
+        Box::pin(async move {
+            let now = Utc::now();
+            let mut buckets = self
+                .buckets
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if !buckets.contains_key(key) {
+                evict(&mut buckets, self.max_buckets, now);
+            }
+            let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+                tokens: policy.capacity as f64,
+                updated_at: now,
+                policy,
+            });
+            let tokens = policy.refill(bucket.tokens, bucket.updated_at, now);
+            let (left, decision) = policy.take(tokens);
+            bucket.tokens = left;
+            bucket.updated_at = now;
+            bucket.policy = policy;
+            Ok(decision)
+        })
+    }
+}
+
+/// Keeps the buckets in the `rate_limit_buckets` table, so that every instance
+/// of the service counts against the same limits.
+pub struct PostgresStore;
+
+impl PostgresStore {
+    /// Deletes the buckets that are full again, which are the same as new ones,
+    /// and returns how many there were. Run by `admin prune-rate-limits`.
+    pub async fn prune(&self) -> Result<u64, Errors> {
+        let pool = get_conn().await;
+        let query = sqlx::query("DELETE FROM rate_limit_buckets WHERE tokens + EXTRACT(EPOCH FROM ($1 - updated_at))::DOUBLE PRECISION * refill_per_sec >= capacity")
+            .bind(Utc::now())
+            .execute(pool)
+            .await;
+        match query {
+            Ok(result) => Ok(result.rows_affected()),
+            Err(err) => {
+                error!("Unable to prune rate_limit_buckets table{:?}", err);
+                Err(Errors::DatabaseError(err))
+            }
+        }
+    }
+}
+
+impl RateLimitStore for PostgresStore {
+    fn take<'a>(&'a self, key: &'a str, policy: RateLimitPolicy) -> TakeFuture<'a> {
+        Box::pin(async move {
+            let pool = get_conn().await;
+            let mut trnx = match pool.begin().await {
+                Ok(trnx) => trnx,
+                Err(err) => {
+                    error!("Unable to start transaction{:?}", err);
+                    let err = Errors::DatabaseError(err);
+                    return Err(err);
+                }
+            };
+            let now = Utc::now();
+            let query1 = sqlx::query("INSERT INTO rate_limit_buckets (key, tokens, updated_at, capacity, refill_per_sec) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (key) DO NOTHING")
+                .bind(key)
+                .bind(policy.capacity as f64)
+                .bind(now)
+                .bind(policy.capacity as i32)
+                .bind(policy.refill_per_sec)
+                .execute(&mut *trnx)
+                .await;
+            if let Err(err) = query1 {
+                error!("Unable to insert into rate_limit_buckets table{:?}", err);
+                let err = Errors::DatabaseError(err);
+                return Err(err);
+            }
+            let query2 = sqlx::query(
+                "SELECT tokens, updated_at FROM rate_limit_buckets WHERE key = $1 FOR UPDATE",
+            )
+            .bind(key)
+            .fetch_one(&mut *trnx)
+            .await;
+            let row = match query2 {
+                Ok(row) => row,
+                Err(err) => {
+                    error!("Unable to get rate limit bucket {}: {:?}", key, err);
+                    let err = Errors::DatabaseError(err);
+                    return Err(err);
+                }
+            };
+            let tokens = policy.refill(
+                row.get::<f64, &str>("tokens"),
+                row.get::<DateTime<Utc>, &str>("updated_at"),
+                now,
+            );
+            let (left, decision) = policy.take(tokens);
+            let query3 = sqlx::query(
+                "UPDATE rate_limit_buckets SET tokens = $2, updated_at = $3, capacity = $4, refill_per_sec = $5 WHERE key = $1",
+            )
+            .bind(key)
+            .bind(left)
+            .bind(now)
+            .bind(policy.capacity as i32)
+            .bind(policy.refill_per_sec)
+            .execute(&mut *trnx)
+            .await;
+            if let Err(err) = query3 {
+                error!("Unable to update rate limit bucket {}: {:?}", key, err);
+                let err = Errors::DatabaseError(err);
+                return Err(err);
+            }
+            if let Err(err) = trnx.commit().await {
+                error!("Unable to commit rate limit bucket{:?}", err);
+                let err = Errors::DatabaseError(err);
+                return Err(err);
+            }
+            Ok(decision)
+        })
+    }
+}
+
+fn init_store() -> Box<dyn RateLimitStore> {
+    dotenv().ok();
+    match env::var("RATE_LIMIT_STORE").as_deref() {
+        Ok("postgres") => {
+            info!("Keeping rate limits in Postgres");
+            Box::new(PostgresStore)
+        }
+        _ => Box::new(MemoryStore::default()),
+    }
+}
+
+pub fn get_rate_limit_store() -> &'static dyn RateLimitStore {
+    STORE.get_or_init(init_store).as_ref()
+}
@@ -18,4 +18,86 @@ pub enum Errors {
     UserDoesNotExist,
     #[error("Unable to create transaction")]
     TransactionError,
+    #[error("transaction was abandoned before it settled")]
+    TransactionAbandoned,
+    #[error("idempotency key was taken over by another request")]
+    IdempotencyKeyLost,
+    #[error("invalid amount")]
+    InvalidAmount,
+    #[error("amount out of range")]
+    MoneyOverflow,
+    #[error("journal entry is not balanced")]
+    UnbalancedEntry,
+    #[error("invalid cursor")]
+    InvalidCursor,
+    #[error("Transaction not found")]
+    TransactionNotFound,
+    #[error("Refund exceeds the amount left to refund")]
+    RefundExceedsOriginal,
+    #[error("Transaction cannot be refunded")]
+    NotRefundable,
+    #[error("Transaction has not completed")]
+    TransactionNotCompleted,
+    #[error("Forbidden")]
+    Forbidden,
+    #[error("{0}")]
+    InvalidTransactionDetails(String),
+    #[error("account is frozen")]
+    AccountFrozen,
+    #[error("account is closed")]
+    AccountClosed,
+    #[error("recipient account is frozen")]
+    RecipientFrozen,
+    #[error("recipient account is closed")]
+    RecipientClosed,
+    #[error("invalid refresh token")]
+    InvalidRefreshToken,
+    #[error("Session not found")]
+    SessionNotFound,
+    #[error("{0}")]
+    WeakPassword(String),
+    #[error("new password must differ from the current one")]
+    PasswordReused,
+    #[error("invalid or expired reset token")]
+    InvalidResetToken,
+    #[error("invalid email address")]
+    InvalidEmail,
+    #[error("invalid or expired verification token")]
+    InvalidVerificationToken,
+    #[error("email is not verified")]
+    EmailNotVerified,
+    #[error("email is already verified")]
+    EmailAlreadyVerified,
+    #[error("two-factor authentication is already enabled")]
+    TwoFactorAlreadyEnabled,
+    #[error("two-factor authentication is not enabled")]
+    TwoFactorNotEnabled,
+    #[error("invalid authentication code")]
+    InvalidTotpCode,
+    #[error("an authentication code is required")]
+    TotpRequired,
+    #[error("invalid or expired login challenge")]
+    InvalidLoginChallenge,
+    #[error("invalid JWT key: {0}")]
+    InvalidJwtKey(String),
+    #[error("{0}")]
+    InvalidApiKeyDetails(String),
+    #[error("API key not found")]
+    ApiKeyNotFound,
+    #[error("{0}")]
+    InvalidOAuthRequest(String),
+    #[error("client authentication failed")]
+    InvalidOAuthClient,
+    #[error("invalid, expired or used authorization grant")]
+    InvalidOAuthGrant,
+    #[error("the client cannot use this grant type")]
+    UnauthorizedOAuthClient,
+    #[error("unsupported grant type")]
+    UnsupportedGrantType,
+    #[error("invalid scope")]
+    InvalidOAuthScope,
+    #[error("OAuth client not found")]
+    OAuthClientNotFound,
+    #[error("consent not found")]
+    OAuthConsentNotFound,
 }
@@ -1,22 +1,52 @@
-use super::service::authorize_user;
+use super::service::{authorize_user, AuthToken};
 use crate::config::db::get_conn;
+use crate::config::jwt_keys::get_jwt_keys;
+use crate::config::rate_limit::{get_rate_limit_store, RouteGroup};
 use crate::errors::Errors;
 use crate::utils::{
+    api_key_controller::{
+        authorize_api_key, create_api_key, list_api_keys, revoke_api_key, API_KEY_PREFIX,
+    },
+    idempotency::{
+        begin_idempotent_request, complete_idempotent_request, fingerprint, key_ttl, lock_lease,
+        release_idempotent_request, IdempotencyClaim, IdempotencyStatus,
+    },
+    money::Money,
+    password_controller::{forgot_password, reset_password},
+    token_controller::{delete_session, list_sessions, logout_user, refresh_tokens},
+    two_factor_controller::{
+        complete_login_challenge, confirm_totp, disable_totp, enroll_totp,
+        regenerate_recovery_codes, require_transfer_code,
+    },
     user_controller::{
-        create_transaction, get_user_balance, list_transactions, login_user, register_user,
-        update_user,
+        change_password, create_transaction, get_transaction, get_user_balance, get_user_role,
+        list_transactions, login_user, refund_transaction, register_user, update_user,
+    },
+    user_structs::{
+        ApiScope, ChangePasswordRequest, ClientDetails, CreateApiKeyRequest, ForgotPasswordRequest,
+        ListTransactionsQuery, LoginOutcome, LoginRequest, LogoutQuery, ModifyUser,
+        RefreshTokenRequest, RefundRequest, RegisterRequest, ResetPasswordRequest, Role,
+        TotpCodeRequest, TransactionRequest, TwoFactorLoginRequest, User, UserAuth,
+        VerifyEmailRequest,
     },
-    user_structs::{LoginRequest, ModifyUser, RegisterRequest, TransactionRequest, UserAuth},
+    verification_controller::{resend_verification_email, verify_email},
 };
 use axum::Extension;
 use axum::{
-    extract::{Json, Request},
-    http::StatusCode,
+    extract::{ConnectInfo, Json, Path, Query, Request, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
     middleware::Next,
     response::{IntoResponse, Response},
 };
+use std::net::SocketAddr;
 use tracing::{error, info, instrument, warn};
 
+/// How long clients may cache the key set. Keys are added to it well before
+/// they sign anything, so a cached copy is never missing a signing key for long.
+const JWKS_MAX_AGE_SECS: u32 = 5 * 60;
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+const TOTP_CODE_HEADER: &str = "X-TOTP-Code";
+
 /// axum handler for any request that fails to match the router routes.
 /// This implementation returns HTTP status code Not Found (404).
 #[instrument]
@@ -28,7 +58,25 @@ pub async fn fallback_handler(uri: axum::http::Uri) -> impl axum::response::Into
     )
 }
 
+/// Serves the public keys tokens are signed with, so that other services can
+/// verify tokens without calling `/authorise`.
+pub async fn jwks_handler() -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        [(
+            axum::http::header::CACHE_CONTROL,
+            format!("public, max-age={}", JWKS_MAX_AGE_SECS),
+        )],
+        Json(get_jwt_keys().jwks().clone()),
+    )
+}
+
+/// Lets in requests with a bearer token from `/login`, or with an API key or
+/// OAuth client token when the route group's state names a scope it has. Keys
+/// and client tokens without that scope get 403 Forbidden; with `None`, the
+/// routes are only open to tokens from `/login`.
 pub async fn authorization_middleware(
+    State(scope): State<Option<ApiScope>>,
     mut req: Request,
     next: Next,
 ) -> Result<Response, StatusCode> {
@@ -45,25 +93,171 @@ pub async fn authorization_middleware(
     };
     let mut header = auth_header.split_whitespace();
     let (_bearer, token) = (header.next(), header.next());
-    let extracted_token;
-    match token {
-        Some(newtoken) => {
-            extracted_token = newtoken;
-        }
+    let extracted_token = match token {
+        Some(newtoken) => newtoken,
         None => {
             warn!("Invalid token");
             return Err(StatusCode::UNAUTHORIZED);
         }
+    };
+
+    let extracted_token = extracted_token.to_string();
+
+    if extracted_token.starts_with(API_KEY_PREFIX) {
+        let pool = get_conn().await;
+        let key = match authorize_api_key(pool, &extracted_token).await {
+            Some(key) => key,
+            None => {
+                warn!("Unauthorized");
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+        };
+        match scope {
+            Some(scope) if key.scopes.contains(&scope) => {}
+            _ => {
+                warn!(
+                    "API key {} of {} is not allowed on {}",
+                    key.id,
+                    key.email,
+                    req.uri().path()
+                );
+                return Err(StatusCode::FORBIDDEN);
+            }
+        }
+        req.extensions_mut().insert(key.email);
+        // keys never carry the staff role of their owner
+        req.extensions_mut().insert(Role::User);
+        return Ok(next.run(req).await);
     }
 
-    if let Some(user_email) = authorize_user(extracted_token).await {
-        req.extensions_mut().insert(user_email);
+    if let Some(claims) = authorize_user(&extracted_token).await {
+        // tokens of OAuth clients are held to their scopes like API keys
+        if let Some(scopes) = claims.client_scopes() {
+            if !scope.is_some_and(|scope| scopes.contains(&scope)) {
+                warn!(
+                    "OAuth client {} of {} is not allowed on {}",
+                    claims.client_id.unwrap_or_default(),
+                    claims.email,
+                    req.uri().path()
+                );
+                return Err(StatusCode::FORBIDDEN);
+            }
+            req.extensions_mut().insert(claims.email);
+            // nor do they carry the staff role of the user
+            req.extensions_mut().insert(Role::User);
+            return Ok(next.run(req).await);
+        }
+        req.extensions_mut().insert(claims.email);
+        req.extensions_mut().insert(claims.role);
+        req.extensions_mut().insert(AuthToken(extracted_token));
         Ok(next.run(req).await)
     } else {
         warn!("Unauthorized");
         Err(StatusCode::UNAUTHORIZED)
     }
 }
+
+/// Limits how often each client can call the routes of `group`. Clients are
+/// told by the email of their token when `authorization_middleware` runs
+/// before this, and by their IP address otherwise.
+///
+/// Every response carries `RateLimit-Limit`, `RateLimit-Remaining` and
+/// `RateLimit-Reset` headers; a request over the limit gets a 429 with
+/// `Retry-After` instead of reaching the handler.
+pub async fn rate_limit_middleware(
+    State(group): State<RouteGroup>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let client = match req.extensions().get::<String>() {
+        Some(email) => format!("user:{}", email),
+        None => match connect_info {
+            Some(ConnectInfo(addr)) => format!("ip:{}", addr.ip()),
+            None => "ip:unknown".to_string(),
+        },
+    };
+    let key = format!("{}:{}", group.as_str(), client);
+    let policy = group.policy();
+    let decision = match get_rate_limit_store().take(&key, policy).await {
+        Ok(decision) => decision,
+        Err(e) => {
+            // an outage of a shared store should not take the service down with it
+            error!("error occurred while rate limiting {}: {}", key, e);
+            return next.run(req).await;
+        }
+    };
+
+    let mut response = if decision.allowed {
+        next.run(req).await
+    } else {
+        warn!("{} is over the {} rate limit", client, group.as_str());
+        let error_json = serde_json::json!({
+            "error": "Too many requests",
+        });
+        let mut response = (StatusCode::TOO_MANY_REQUESTS, Json(error_json)).into_response();
+        response.headers_mut().insert(
+            axum::http::header::RETRY_AFTER,
+            HeaderValue::from(decision.retry_after_secs),
+        );
+        response
+    };
+    let headers = response.headers_mut();
+    headers.insert("RateLimit-Limit", HeaderValue::from(decision.limit));
+    headers.insert("RateLimit-Remaining", HeaderValue::from(decision.remaining));
+    headers.insert("RateLimit-Reset", HeaderValue::from(decision.reset_secs));
+    response
+}
+
+/// Restricts a route to the roles in its state, answering 403 Forbidden to
+/// anyone else. Must be layered inside `authorization_middleware`, which
+/// provides the caller's email and the role from their token.
+///
+/// The role is checked against the database as well, so a user whose role was
+/// taken away cannot keep using staff routes with a token issued before.
+pub async fn role_middleware(
+    State(roles): State<&'static [Role]>,
+    req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let token_role = req.extensions().get::<Role>().copied();
+    let user_email = req.extensions().get::<String>().cloned();
+    let (token_role, user_email) = match (token_role, user_email) {
+        (Some(role), Some(email)) => (role, email),
+        _ => {
+            error!("role_middleware used without authorization_middleware");
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+    };
+    if !roles.contains(&token_role) {
+        warn!(
+            "role {} is not allowed on {}",
+            token_role.as_str(),
+            req.uri().path()
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let pool = get_conn().await;
+    match get_user_role(pool, user_email.as_str()).await {
+        Ok(role) if roles.contains(&role) => Ok(next.run(req).await),
+        Ok(role) => {
+            warn!(
+                "user: {} has role {} now, not {}",
+                user_email,
+                role.as_str(),
+                token_role.as_str()
+            );
+            Err(StatusCode::FORBIDDEN)
+        }
+        Err(Errors::UserDoesNotExist) => Err(StatusCode::UNAUTHORIZED),
+        Err(e) => {
+            error!("error occurred while checking role: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
 pub async fn authorise_check(req: Request) -> impl IntoResponse {
     let user_email = req.extensions().get::<String>().unwrap();
     let user_json = serde_json::json!({
@@ -75,9 +269,9 @@ pub async fn authorise_check(req: Request) -> impl IntoResponse {
 pub async fn register_handler(Json(payload): Json<RegisterRequest>) -> impl IntoResponse {
     // Implement your user registration logic here
     let pool = get_conn().await;
-    let mut initial_balance = 0.0;
+    let mut initial_balance = Money::ZERO;
     if let Some(balance) = payload.balance {
-        if balance < 0.0 {
+        if balance.is_negative() {
             let error_json = serde_json::json!({
                 "error": "Balance cannot be negative",
             });
@@ -90,7 +284,7 @@ pub async fn register_handler(Json(payload): Json<RegisterRequest>) -> impl Into
         initial_balance = balance;
     }
     match register_user(
-        &pool,
+        pool,
         &payload.fullname,
         &payload.email,
         &payload.password,
@@ -106,6 +300,12 @@ pub async fn register_handler(Json(payload): Json<RegisterRequest>) -> impl Into
             info!("user: {} registered successfully", user.email);
             (StatusCode::CREATED, Json(user_json))
         }
+        Err(Errors::InvalidEmail) => {
+            let error_json = serde_json::json!({
+                "error": "Invalid email address",
+            });
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
         Err(Errors::DuplicateUserEmail) => {
             let error_json = serde_json::json!({
                 "error": "Email is already taken",
@@ -126,20 +326,45 @@ pub async fn register_handler(Json(payload): Json<RegisterRequest>) -> impl Into
     }
 }
 
-pub async fn login_handler(Json(payload): Json<LoginRequest>) -> impl IntoResponse {
+/// Longest user agent kept for a session.
+const MAX_USER_AGENT_CHARS: usize = 512;
+
+/// Describes the device a request came from, for the session it starts.
+fn client_details(
+    headers: &HeaderMap,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+) -> ClientDetails {
+    let user_agent = headers
+        .get(axum::http::header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.chars().take(MAX_USER_AGENT_CHARS).collect());
+    ClientDetails {
+        user_agent,
+        ip: connect_info.map(|ConnectInfo(addr)| addr.ip().to_string()),
+    }
+}
+
+pub async fn login_handler(
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Json(payload): Json<LoginRequest>,
+) -> impl IntoResponse {
     // Implement your user registration logic here
     let pool = get_conn().await;
-    match login_user(&pool, &payload.email, &payload.password).await {
-        Ok(user) => {
-            // let verified_token = decode_token(token.unwrap().as_str());
-            let user_json = serde_json::json!({
-                "fullname": user.fullname,
-                "email": user.email,
-                "token" : user.token,
-                "balance": user.balance,
-            });
+    let client = client_details(&headers, connect_info);
+    match login_user(pool, &payload.email, &payload.password, &client).await {
+        Ok(LoginOutcome::LoggedIn(user)) => {
             info!("user: {} logged in successfully", user.email);
-            (StatusCode::CREATED, Json(user_json))
+            (StatusCode::CREATED, Json(logged_in_json(&user)))
+        }
+        Ok(LoginOutcome::TwoFactorRequired(challenge)) => {
+            let challenge_json = serde_json::json!({
+                "email": challenge.email,
+                "two_factor_required": true,
+                "challenge_token": challenge.challenge_token,
+                "challenge_expires_at": challenge.expires_at,
+            });
+            (StatusCode::OK, Json(challenge_json))
         }
         Err(Errors::WrongCredentials) => {
             let error_json = serde_json::json!({
@@ -151,6 +376,12 @@ pub async fn login_handler(Json(payload): Json<LoginRequest>) -> impl IntoRespon
             );
             (StatusCode::BAD_REQUEST, Json(error_json))
         }
+        Err(Errors::AccountClosed) => {
+            let error_json = serde_json::json!({
+                "error": "Account is closed",
+            });
+            (StatusCode::FORBIDDEN, Json(error_json))
+        }
         Err(e) => {
             let error_json = serde_json::json!({
                 "error": e.to_string(),
@@ -161,135 +392,1116 @@ pub async fn login_handler(Json(payload): Json<LoginRequest>) -> impl IntoRespon
     }
 }
 
-pub async fn user_balance_handler(Json(payload): Json<UserAuth>) -> impl IntoResponse {
+/// The response to a completed login.
+fn logged_in_json(user: &User) -> serde_json::Value {
+    serde_json::json!({
+        "fullname": user.fullname,
+        "email": user.email,
+        "token" : user.token,
+        "token_expires_at": user.token_expires_at,
+        "refresh_token": user.refresh_token,
+        "balance": user.balance,
+        "role": user.role,
+        "email_verified": user.email_verified,
+    })
+}
+
+/// Second step of a login with two-factor authentication. Takes the challenge
+/// token from `POST /login` and a code from the user's authenticator app or a
+/// recovery code.
+pub async fn login_two_factor_handler(
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Json(payload): Json<TwoFactorLoginRequest>,
+) -> impl IntoResponse {
     let pool = get_conn().await;
-    let user_email = payload.email.clone();
+    let client = client_details(&headers, connect_info);
+    match complete_login_challenge(pool, &payload.challenge_token, &payload.code, &client).await {
+        Ok(user) => {
+            info!("user: {} logged in successfully with 2FA", user.email);
+            (StatusCode::CREATED, Json(logged_in_json(&user)))
+        }
+        Err(Errors::InvalidLoginChallenge) => {
+            let error_json = serde_json::json!({
+                "error": "Invalid or expired login challenge",
+            });
+            (StatusCode::UNAUTHORIZED, Json(error_json))
+        }
+        Err(Errors::InvalidTotpCode) => {
+            let error_json = serde_json::json!({
+                "error": "Invalid authentication code",
+            });
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Err(Errors::AccountClosed) => {
+            let error_json = serde_json::json!({
+                "error": "Account is closed",
+            });
+            (StatusCode::FORBIDDEN, Json(error_json))
+        }
+        Err(e) => {
+            let error_json = serde_json::json!({
+                "error": e.to_string(),
+            });
+            error!("error occurred while completing 2FA login: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
+        }
+    }
+}
 
-    match get_user_balance(pool, user_email.as_str()).await {
-        Ok(balance) => {
-            let balance_json = serde_json::json!({
-                "balance": balance,
-                "email": user_email.as_str(),
+/// Exchanges a refresh token for a new access token and refresh token. The
+/// refresh token in the request cannot be used again.
+pub async fn refresh_token_handler(Json(payload): Json<RefreshTokenRequest>) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match refresh_tokens(pool, &payload.refresh_token).await {
+        Ok(tokens) => {
+            let token_json = serde_json::json!({
+                "token": tokens.token,
+                "token_expires_at": tokens.token_expires_at,
+                "refresh_token": tokens.refresh_token,
             });
-            info!("user: {} checked balance successfully", user_email);
-            (StatusCode::OK, Json(balance_json))
+            (StatusCode::OK, Json(token_json))
+        }
+        Err(Errors::InvalidRefreshToken) => {
+            let error_json = serde_json::json!({
+                "error": "Invalid refresh token",
+            });
+            (StatusCode::UNAUTHORIZED, Json(error_json))
+        }
+        Err(Errors::AccountClosed) => {
+            let error_json = serde_json::json!({
+                "error": "Account is closed",
+            });
+            (StatusCode::FORBIDDEN, Json(error_json))
         }
         Err(e) => {
             let error_json = serde_json::json!({
                 "error": e.to_string(),
             });
-            error!("error occurred while checking balance: {}", e);
+            error!("error occurred while refreshing token: {}", e);
             (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
         }
     }
 }
 
-pub async fn create_transaction_handler(
+/// Ends the session the request was made with, or every session of the user
+/// with `?all=true`.
+pub async fn logout_handler(
     Extension(user_email): Extension<String>,
-    Json(payload): Json<TransactionRequest>,
+    Extension(AuthToken(token)): Extension<AuthToken>,
+    Query(query): Query<LogoutQuery>,
 ) -> impl IntoResponse {
     let pool = get_conn().await;
-    let from_email = payload.from_email.clone();
-    let to_email = payload.to_email.clone();
-    let amount = payload.amount;
-
-    if amount < 0.0 {
-        let error_json = serde_json::json!({
-            "error": "Amount cannot be negative",
-        });
-        warn!(
-            "user: {} initiated transaction amount {} with negative amount",
-            from_email, amount
-        );
-        return (StatusCode::BAD_REQUEST, Json(error_json));
+    match logout_user(pool, user_email.as_str(), token.as_str(), query.all).await {
+        Ok(revoked) => {
+            let logout_json = serde_json::json!({
+                "email": user_email,
+                "revoked_sessions": revoked,
+            });
+            info!("user: {} logged out successfully", user_email);
+            (StatusCode::OK, Json(logout_json))
+        }
+        Err(e) => {
+            let error_json = serde_json::json!({
+                "error": e.to_string(),
+            });
+            error!("error occurred while logging out user: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
+        }
     }
+}
 
-    if from_email == to_email {
-        let error_json = serde_json::json!({
-            "error": "Cannot transfer to self",
-        });
-        warn!("user: {} initiated transaction to self", from_email);
-        return (StatusCode::BAD_REQUEST, Json(error_json));
+pub async fn list_sessions_handler(
+    Extension(user_email): Extension<String>,
+    Extension(AuthToken(token)): Extension<AuthToken>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match list_sessions(pool, user_email.as_str(), token.as_str()).await {
+        Ok(sessions) => {
+            let sessions_json = serde_json::json!({
+                "sessions": sessions,
+            });
+            info!("user: {} listed sessions successfully", user_email);
+            (StatusCode::OK, Json(sessions_json))
+        }
+        Err(e) => {
+            let error_json = serde_json::json!({
+                "error": e.to_string(),
+            });
+            error!("error occurred while listing sessions: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
+        }
     }
+}
 
-    if user_email != from_email {
-        let error_json = serde_json::json!({
-            "error": "Unauthorized",
-        });
-        warn!(
-            "user: {} attempted to initiate transaction for another user: {}",
-            user_email, from_email
-        );
-        return (StatusCode::UNAUTHORIZED, Json(error_json));
+/// Ends one of the user's sessions, which may be the current one.
+pub async fn delete_session_handler(
+    Extension(user_email): Extension<String>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match delete_session(pool, user_email.as_str(), id.as_str()).await {
+        Ok(()) => {
+            let session_json = serde_json::json!({
+                "id": id,
+                "revoked": true,
+            });
+            (StatusCode::OK, Json(session_json))
+        }
+        Err(Errors::SessionNotFound) => {
+            let error_json = serde_json::json!({
+                "error": "Session not found",
+            });
+            (StatusCode::NOT_FOUND, Json(error_json))
+        }
+        Err(e) => {
+            let error_json = serde_json::json!({
+                "error": e.to_string(),
+            });
+            error!("error occurred while ending session: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
+        }
     }
+}
 
-    match create_transaction(pool, from_email.as_str(), to_email.as_str(), amount).await {
-        Ok(transaction) => {
-            let transaction_json = serde_json::json!({
-                "from_email": transaction.from_email,
-                "to_email": transaction.to_email,
-                "amount": transaction.amount,
+/// Creates an API key. The key is only in this response; afterwards it is
+/// listed by its prefix.
+pub async fn create_api_key_handler(
+    Extension(user_email): Extension<String>,
+    Json(payload): Json<CreateApiKeyRequest>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match create_api_key(pool, user_email.as_str(), payload).await {
+        Ok((key, api_key)) => {
+            let key_json = serde_json::json!({
+                "id": api_key.id,
+                "key": key,
+                "name": api_key.name,
+                "prefix": api_key.prefix,
+                "scopes": api_key.scopes,
+                "created_at": api_key.created_at,
+                "expires_at": api_key.expires_at,
             });
-            info!(
-                "user: {} initiated transaction to user: {} with amount {}",
-                from_email, to_email, amount
-            );
-            (StatusCode::CREATED, Json(transaction_json))
+            (StatusCode::CREATED, Json(key_json))
         }
-        Err(Errors::InsufficientBalance) => {
+        Err(Errors::InvalidApiKeyDetails(message)) => {
             let error_json = serde_json::json!({
-                "error": "Insufficient balance",
+                "error": message,
             });
-            warn!(
-                "user: {} attempted to initiate transaction with insufficient balance",
-                from_email
-            );
             (StatusCode::BAD_REQUEST, Json(error_json))
         }
-        Err(Errors::UserDoesNotExist) => {
+        Err(e) => {
             let error_json = serde_json::json!({
-                "error": "User does not exist",
+                "error": e.to_string(),
             });
-            warn!(
-                "user: {} attempted to initiate transaction to non-existent user: {}",
-                from_email, to_email
-            );
-            (StatusCode::BAD_REQUEST, Json(error_json))
+            error!("error occurred while creating API key: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
         }
-        Err(Errors::TransactionError) => {
+    }
+}
+
+pub async fn list_api_keys_handler(Extension(user_email): Extension<String>) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match list_api_keys(pool, user_email.as_str()).await {
+        Ok(api_keys) => {
+            let keys_json = serde_json::json!({
+                "api_keys": api_keys,
+            });
+            (StatusCode::OK, Json(keys_json))
+        }
+        Err(e) => {
             let error_json = serde_json::json!({
-                "error": "Transaction error",
+                "error": e.to_string(),
             });
-            error!("error occurred while creating transaction");
-            (StatusCode::BAD_REQUEST, Json(error_json))
+            error!("error occurred while listing API keys: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
+        }
+    }
+}
+
+pub async fn revoke_api_key_handler(
+    Extension(user_email): Extension<String>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match revoke_api_key(pool, user_email.as_str(), id.as_str()).await {
+        Ok(()) => {
+            let key_json = serde_json::json!({
+                "id": id,
+                "revoked": true,
+            });
+            (StatusCode::OK, Json(key_json))
+        }
+        Err(Errors::ApiKeyNotFound) => {
+            let error_json = serde_json::json!({
+                "error": "API key not found",
+            });
+            (StatusCode::NOT_FOUND, Json(error_json))
         }
         Err(e) => {
             let error_json = serde_json::json!({
                 "error": e.to_string(),
             });
-            error!("error occurred while creating transaction: {}", e);
+            error!("error occurred while revoking API key: {}", e);
             (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
         }
     }
 }
 
-pub async fn list_transaction_handler(
+/// Returns the balance of the user of the token or API key. The body used to
+/// name the user; a body naming anyone else is refused.
+pub async fn user_balance_handler(
     Extension(user_email): Extension<String>,
+    payload: Option<Json<UserAuth>>,
 ) -> impl IntoResponse {
     let pool = get_conn().await;
-    match list_transactions(pool, user_email.as_str()).await {
-        Ok(transactions) => {
-            let transactions_json = serde_json::json!({
-                "transactions": transactions,
+    if let Some(Json(payload)) = payload {
+        if payload.email != user_email {
+            let error_json = serde_json::json!({
+                "error": "Forbidden",
             });
-            info!("user: {} listed transactions successfully", user_email);
-            (StatusCode::OK, Json(transactions_json))
+            warn!(
+                "user: {} attempted to check the balance of another user: {}",
+                user_email, payload.email
+            );
+            return (StatusCode::FORBIDDEN, Json(error_json));
+        }
+    }
+
+    match get_user_balance(pool, user_email.as_str()).await {
+        Ok(balance) => {
+            let balance_json = serde_json::json!({
+                "balance": balance,
+                "email": user_email.as_str(),
+            });
+            info!("user: {} checked balance successfully", user_email);
+            (StatusCode::OK, Json(balance_json))
         }
         Err(e) => {
             let error_json = serde_json::json!({
                 "error": e.to_string(),
             });
-            error!("error occurred while listing transactions: {}", e);
+            error!("error occurred while checking balance: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
+        }
+    }
+}
+
+/// Creates a transfer. When the request carries an `Idempotency-Key` header the
+/// response is stored under that key, and retries with the same key and body get
+/// the stored response back instead of creating a second transfer.
+pub async fn create_transaction_handler(
+    Extension(user_email): Extension<String>,
+    headers: HeaderMap,
+    Json(payload): Json<TransactionRequest>,
+) -> Response {
+    let key = match headers.get(IDEMPOTENCY_KEY_HEADER) {
+        None => {
+            if let Err(response) = check_transfer_code(&user_email, &payload, &headers).await {
+                return response.into_response();
+            }
+            return transfer(user_email, payload, None).await.into_response();
+        }
+        Some(value) => match value.to_str() {
+            Ok(key) if !key.is_empty() && key.len() <= 255 => key.to_string(),
+            _ => {
+                let error_json = serde_json::json!({
+                    "error": "Invalid Idempotency-Key",
+                });
+                warn!("user: {} sent an invalid idempotency key", user_email);
+                return (StatusCode::BAD_REQUEST, Json(error_json)).into_response();
+            }
+        },
+    };
+
+    let pool = get_conn().await;
+    let request_fingerprint = fingerprint(&serde_json::to_vec(&payload).unwrap_or_default());
+    let claimed_at = match begin_idempotent_request(
+        pool,
+        user_email.as_str(),
+        key.as_str(),
+        request_fingerprint.as_str(),
+        key_ttl(),
+        lock_lease(),
+    )
+    .await
+    {
+        Ok(IdempotencyStatus::New { claimed_at }) => claimed_at,
+        Ok(IdempotencyStatus::Settled {
+            claimed_at,
+            transaction_id,
+        }) => {
+            let claim = IdempotencyClaim {
+                email: user_email.as_str(),
+                key: key.as_str(),
+                claimed_at,
+            };
+            info!(
+                "user: {} retried transaction {} with idempotency key {}",
+                user_email, transaction_id, key
+            );
+            let (status, Json(body)) = settled_transfer(&user_email, &transaction_id).await;
+            store_idempotent_response(&claim, status, &body).await;
+            return (status, Json(body)).into_response();
+        }
+        Ok(IdempotencyStatus::Replay { status, body }) => {
+            info!(
+                "user: {} replayed transaction with idempotency key {}",
+                user_email, key
+            );
+            let status = StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            return (
+                status,
+                [(axum::http::header::CONTENT_TYPE, "application/json")],
+                body,
+            )
+                .into_response();
+        }
+        Ok(IdempotencyStatus::InFlight) => {
+            let error_json = serde_json::json!({
+                "error": "A request with this Idempotency-Key is still being processed",
+            });
+            warn!(
+                "user: {} sent a duplicate in-flight request with idempotency key {}",
+                user_email, key
+            );
+            return (StatusCode::CONFLICT, Json(error_json)).into_response();
+        }
+        Ok(IdempotencyStatus::Mismatch) => {
+            let error_json = serde_json::json!({
+                "error": "Idempotency-Key was already used with a different request",
+            });
+            return (StatusCode::UNPROCESSABLE_ENTITY, Json(error_json)).into_response();
+        }
+        Err(e) => {
+            let error_json = serde_json::json!({
+                "error": e.to_string(),
+            });
+            error!("error occurred while checking idempotency key: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json)).into_response();
+        }
+    };
+    let claim = IdempotencyClaim {
+        email: user_email.as_str(),
+        key: key.as_str(),
+        claimed_at,
+    };
+
+    // a request turned away for its 2FA code is not stored, so the client can
+    // retry it with the same key and a new code
+    if let Err(response) = check_transfer_code(&user_email, &payload, &headers).await {
+        if let Err(e) = release_idempotent_request(pool, &claim).await {
+            error!(
+                "error occurred while releasing idempotency key {}: {}",
+                key, e
+            );
+        }
+        return response.into_response();
+    }
+    let (status, Json(body)) = transfer(user_email.clone(), payload, Some(&claim)).await;
+    store_idempotent_response(&claim, status, &body).await;
+    (status, Json(body)).into_response()
+}
+
+/// Stores the response to a request made under an idempotency key. Server
+/// errors are not stored, so the client can retry them with the same key.
+async fn store_idempotent_response(
+    claim: &IdempotencyClaim<'_>,
+    status: StatusCode,
+    body: &serde_json::Value,
+) {
+    let pool = get_conn().await;
+    let stored = if status.is_server_error() {
+        release_idempotent_request(pool, claim).await
+    } else {
+        complete_idempotent_request(pool, claim, status.as_u16(), body.to_string().as_str()).await
+    };
+    match stored {
+        Ok(()) => {}
+        // the retry that took over the key answers for it
+        Err(Errors::IdempotencyKeyLost) => {
+            warn!(
+                "idempotency key {} of user {} was taken over before its response was stored",
+                claim.key, claim.email
+            );
+        }
+        Err(e) => {
+            error!(
+                "error occurred while storing response for idempotency key {}: {}",
+                claim.key, e
+            );
+        }
+    }
+}
+
+/// The response to a retry whose idempotency key already made transfer `id`,
+/// the same one the transfer got when it was made.
+async fn settled_transfer(user_email: &str, id: &str) -> (StatusCode, Json<serde_json::Value>) {
+    let pool = get_conn().await;
+    match get_transaction(pool, id, user_email, false).await {
+        Ok(transaction) => {
+            let transaction_json = serde_json::json!(transaction);
+            (StatusCode::CREATED, Json(transaction_json))
+        }
+        Err(e) => {
+            let error_json = serde_json::json!({
+                "error": e.to_string(),
+            });
+            error!("error occurred while getting transaction {}: {}", id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
+        }
+    }
+}
+
+/// Turns away a transfer that needs a fresh 2FA code and came without a valid
+/// one in the `X-TOTP-Code` header.
+async fn check_transfer_code(
+    user_email: &str,
+    payload: &TransactionRequest,
+    headers: &HeaderMap,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let pool = get_conn().await;
+    let code = headers
+        .get(TOTP_CODE_HEADER)
+        .and_then(|value| value.to_str().ok());
+    match require_transfer_code(pool, user_email, payload.amount, code).await {
+        Ok(()) => Ok(()),
+        Err(Errors::TotpRequired) => {
+            let error_json = serde_json::json!({
+                "error": "A code from your authenticator app is required for this amount",
+            });
+            Err((StatusCode::FORBIDDEN, Json(error_json)))
+        }
+        Err(Errors::InvalidTotpCode) => {
+            let error_json = serde_json::json!({
+                "error": "Invalid authentication code",
+            });
+            Err((StatusCode::FORBIDDEN, Json(error_json)))
+        }
+        Err(e) => {
+            let error_json = serde_json::json!({
+                "error": e.to_string(),
+            });
+            error!("error occurred while checking 2FA code: {}", e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, Json(error_json)))
+        }
+    }
+}
+
+async fn transfer(
+    user_email: String,
+    payload: TransactionRequest,
+    claim: Option<&IdempotencyClaim<'_>>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let pool = get_conn().await;
+    let from_email = payload.from_email.clone();
+    let to_email = payload.to_email.clone();
+    let amount = payload.amount;
+
+    if amount.is_negative() {
+        let error_json = serde_json::json!({
+            "error": "Amount cannot be negative",
+        });
+        warn!(
+            "user: {} initiated transaction amount {} with negative amount",
+            from_email, amount
+        );
+        return (StatusCode::BAD_REQUEST, Json(error_json));
+    }
+
+    if from_email == to_email {
+        let error_json = serde_json::json!({
+            "error": "Cannot transfer to self",
+        });
+        warn!("user: {} initiated transaction to self", from_email);
+        return (StatusCode::BAD_REQUEST, Json(error_json));
+    }
+
+    if user_email != from_email {
+        let error_json = serde_json::json!({
+            "error": "Unauthorized",
+        });
+        warn!(
+            "user: {} attempted to initiate transaction for another user: {}",
+            user_email, from_email
+        );
+        return (StatusCode::UNAUTHORIZED, Json(error_json));
+    }
+
+    match create_transaction(
+        pool,
+        from_email.as_str(),
+        to_email.as_str(),
+        amount,
+        &payload.details,
+        claim,
+    )
+    .await
+    {
+        Ok(transaction) => {
+            let transaction_json = serde_json::json!(transaction);
+            info!(
+                "user: {} initiated transaction to user: {} with amount {}",
+                from_email, to_email, amount
+            );
+            (StatusCode::CREATED, Json(transaction_json))
+        }
+        Err(Errors::InsufficientBalance) => {
+            let error_json = serde_json::json!({
+                "error": "Insufficient balance",
+            });
+            warn!(
+                "user: {} attempted to initiate transaction with insufficient balance",
+                from_email
+            );
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Err(Errors::UserDoesNotExist) => {
+            let error_json = serde_json::json!({
+                "error": "User does not exist",
+            });
+            warn!(
+                "user: {} attempted to initiate transaction to non-existent user: {}",
+                from_email, to_email
+            );
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Err(Errors::MoneyOverflow) => {
+            let error_json = serde_json::json!({
+                "error": "Amount out of range",
+            });
+            warn!(
+                "user: {} attempted a transaction to user: {} that overflows a balance",
+                from_email, to_email
+            );
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Err(Errors::AccountFrozen) | Err(Errors::AccountClosed) => {
+            let error_json = serde_json::json!({
+                "error": "Your account cannot send money",
+            });
+            (StatusCode::FORBIDDEN, Json(error_json))
+        }
+        Err(Errors::EmailNotVerified) => {
+            let error_json = serde_json::json!({
+                "error": "Verify your email before sending money",
+            });
+            (StatusCode::FORBIDDEN, Json(error_json))
+        }
+        Err(Errors::RecipientFrozen) => {
+            let error_json = serde_json::json!({
+                "error": "Recipient account is frozen",
+            });
+            (StatusCode::UNPROCESSABLE_ENTITY, Json(error_json))
+        }
+        Err(Errors::RecipientClosed) => {
+            let error_json = serde_json::json!({
+                "error": "Recipient account is closed",
+            });
+            (StatusCode::UNPROCESSABLE_ENTITY, Json(error_json))
+        }
+        Err(Errors::InvalidTransactionDetails(reason)) => {
+            let error_json = serde_json::json!({
+                "error": reason,
+            });
+            warn!(
+                "user: {} sent invalid transaction details: {}",
+                from_email, reason
+            );
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Err(Errors::IdempotencyKeyLost) => {
+            let error_json = serde_json::json!({
+                "error": "A request with this Idempotency-Key is still being processed",
+            });
+            (StatusCode::CONFLICT, Json(error_json))
+        }
+        Err(Errors::TransactionError) => {
+            let error_json = serde_json::json!({
+                "error": "Transaction error",
+            });
+            error!("error occurred while creating transaction");
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Err(e) => {
+            let error_json = serde_json::json!({
+                "error": e.to_string(),
+            });
+            error!("error occurred while creating transaction: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
+        }
+    }
+}
+
+pub async fn refund_transaction_handler(
+    Extension(user_email): Extension<String>,
+    auth_token: Option<Extension<AuthToken>>,
+    Path(id): Path<String>,
+    Json(payload): Json<RefundRequest>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    // only tokens from /login carry an AuthToken
+    let staff_view = auth_token.is_some();
+    match refund_transaction(
+        pool,
+        id.as_str(),
+        user_email.as_str(),
+        payload.amount,
+        staff_view,
+    )
+    .await
+    {
+        Ok(refund) => {
+            let refund_json = serde_json::json!(refund);
+            info!(
+                "user: {} refunded {} of transaction {}",
+                user_email, refund.amount, id
+            );
+            (StatusCode::CREATED, Json(refund_json))
+        }
+        Err(Errors::TransactionNotFound) => {
+            let error_json = serde_json::json!({
+                "error": "Transaction not found",
+            });
+            (StatusCode::NOT_FOUND, Json(error_json))
+        }
+        Err(Errors::Forbidden) => {
+            let error_json = serde_json::json!({
+                "error": "Only the receiver can refund a transaction",
+            });
+            (StatusCode::FORBIDDEN, Json(error_json))
+        }
+        Err(Errors::NotRefundable) => {
+            let error_json = serde_json::json!({
+                "error": "Refunds cannot be refunded",
+            });
+            (StatusCode::UNPROCESSABLE_ENTITY, Json(error_json))
+        }
+        Err(Errors::TransactionNotCompleted) => {
+            let error_json = serde_json::json!({
+                "error": "Only completed transfers can be refunded",
+            });
+            (StatusCode::UNPROCESSABLE_ENTITY, Json(error_json))
+        }
+        Err(Errors::InvalidAmount) => {
+            let error_json = serde_json::json!({
+                "error": "Refund amount must be positive",
+            });
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Err(Errors::RefundExceedsOriginal) => {
+            let error_json = serde_json::json!({
+                "error": "Refund exceeds the amount left to refund",
+            });
+            warn!(
+                "user: {} attempted to over-refund transaction {}",
+                user_email, id
+            );
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Err(Errors::AccountFrozen) | Err(Errors::AccountClosed) => {
+            let error_json = serde_json::json!({
+                "error": "The receiving account cannot send money",
+            });
+            (StatusCode::FORBIDDEN, Json(error_json))
+        }
+        Err(Errors::RecipientFrozen) => {
+            let error_json = serde_json::json!({
+                "error": "Sender account is frozen",
+            });
+            (StatusCode::UNPROCESSABLE_ENTITY, Json(error_json))
+        }
+        Err(Errors::RecipientClosed) => {
+            let error_json = serde_json::json!({
+                "error": "Sender account is closed",
+            });
+            (StatusCode::UNPROCESSABLE_ENTITY, Json(error_json))
+        }
+        Err(Errors::InsufficientBalance) => {
+            let error_json = serde_json::json!({
+                "error": "Insufficient balance",
+            });
+            warn!(
+                "user: {} attempted to refund transaction {} with insufficient balance",
+                user_email, id
+            );
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Err(e) => {
+            let error_json = serde_json::json!({
+                "error": e.to_string(),
+            });
+            error!("error occurred while refunding transaction: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
+        }
+    }
+}
+
+pub async fn list_transaction_handler(
+    Extension(user_email): Extension<String>,
+    Query(filter): Query<ListTransactionsQuery>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match list_transactions(pool, user_email.as_str(), &filter).await {
+        Ok(page) => {
+            let transactions_json = serde_json::json!({
+                "transactions": page.transactions,
+                "next_cursor": page.next_cursor,
+            });
+            info!("user: {} listed transactions successfully", user_email);
+            (StatusCode::OK, Json(transactions_json))
+        }
+        Err(Errors::InvalidCursor) => {
+            let error_json = serde_json::json!({
+                "error": "Invalid cursor",
+            });
+            warn!(
+                "user: {} listed transactions with an invalid cursor",
+                user_email
+            );
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Err(Errors::InvalidTransactionDetails(reason)) => {
+            let error_json = serde_json::json!({
+                "error": reason,
+            });
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Err(e) => {
+            let error_json = serde_json::json!({
+                "error": e.to_string(),
+            });
+            error!("error occurred while listing transactions: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
+        }
+    }
+}
+
+pub async fn get_transaction_handler(
+    Extension(user_email): Extension<String>,
+    auth_token: Option<Extension<AuthToken>>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    // only tokens from /login carry an AuthToken
+    let staff_view = auth_token.is_some();
+    match get_transaction(pool, id.as_str(), user_email.as_str(), staff_view).await {
+        Ok(transaction) => {
+            let transaction_json = serde_json::json!(transaction);
+            info!("user: {} fetched transaction {}", user_email, id);
+            (StatusCode::OK, Json(transaction_json))
+        }
+        Err(Errors::TransactionNotFound) => {
+            let error_json = serde_json::json!({
+                "error": "Transaction not found",
+            });
+            (StatusCode::NOT_FOUND, Json(error_json))
+        }
+        Err(e) => {
+            let error_json = serde_json::json!({
+                "error": e.to_string(),
+            });
+            error!("error occurred while fetching transaction: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
+        }
+    }
+}
+
+pub async fn change_password_handler(
+    Extension(user_email): Extension<String>,
+    Extension(AuthToken(token)): Extension<AuthToken>,
+    Json(payload): Json<ChangePasswordRequest>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match change_password(
+        pool,
+        user_email.as_str(),
+        token.as_str(),
+        &payload.current_password,
+        &payload.new_password,
+    )
+    .await
+    {
+        Ok(revoked) => {
+            let password_json = serde_json::json!({
+                "email": user_email,
+                "revoked_sessions": revoked,
+            });
+            info!("user: {} changed their password successfully", user_email);
+            (StatusCode::OK, Json(password_json))
+        }
+        Err(Errors::WrongCredentials) => {
+            let error_json = serde_json::json!({
+                "error": "Current password is wrong",
+            });
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Err(Errors::PasswordReused) => {
+            let error_json = serde_json::json!({
+                "error": "New password must differ from the current one",
+            });
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Err(Errors::WeakPassword(reason)) => {
+            let error_json = serde_json::json!({
+                "error": reason,
+            });
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Err(e) => {
+            let error_json = serde_json::json!({
+                "error": e.to_string(),
+            });
+            error!("error occurred while changing password: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
+        }
+    }
+}
+
+pub async fn verify_email_handler(Json(payload): Json<VerifyEmailRequest>) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match verify_email(pool, &payload.token).await {
+        Ok(email) => {
+            let verified_json = serde_json::json!({
+                "email": email,
+                "email_verified": true,
+            });
+            (StatusCode::OK, Json(verified_json))
+        }
+        Err(Errors::InvalidVerificationToken) => {
+            let error_json = serde_json::json!({
+                "error": "Invalid or expired verification token",
+            });
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Err(e) => {
+            let error_json = serde_json::json!({
+                "error": e.to_string(),
+            });
+            error!("error occurred while verifying email: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
+        }
+    }
+}
+
+/// The link in the verification email, `GET /verify-email?token=...`.
+pub async fn verify_email_link_handler(
+    Query(payload): Query<VerifyEmailRequest>,
+) -> impl IntoResponse {
+    verify_email_handler(Json(payload)).await
+}
+
+pub async fn resend_verification_handler(
+    Extension(user_email): Extension<String>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match resend_verification_email(pool, user_email.as_str()).await {
+        Ok(()) => {
+            let resend_json = serde_json::json!({
+                "email": user_email,
+                "message": "Verification email sent",
+            });
+            (StatusCode::ACCEPTED, Json(resend_json))
+        }
+        Err(Errors::EmailAlreadyVerified) => {
+            let error_json = serde_json::json!({
+                "error": "Email is already verified",
+            });
+            (StatusCode::CONFLICT, Json(error_json))
+        }
+        Err(e) => {
+            let error_json = serde_json::json!({
+                "error": e.to_string(),
+            });
+            error!("error occurred while resending verification email: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
+        }
+    }
+}
+
+/// Starts 2FA enrollment with a new TOTP secret for the user's authenticator
+/// app. 2FA is on once the secret is confirmed with `POST /2fa/confirm`.
+pub async fn enroll_totp_handler(Extension(user_email): Extension<String>) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match enroll_totp(pool, user_email.as_str()).await {
+        Ok(enrollment) => (StatusCode::CREATED, Json(serde_json::json!(enrollment))),
+        Err(Errors::TwoFactorAlreadyEnabled) => {
+            let error_json = serde_json::json!({
+                "error": "Two-factor authentication is already enabled",
+            });
+            (StatusCode::CONFLICT, Json(error_json))
+        }
+        Err(e) => {
+            let error_json = serde_json::json!({
+                "error": e.to_string(),
+            });
+            error!("error occurred while enrolling in 2FA: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
+        }
+    }
+}
+
+pub async fn confirm_totp_handler(
+    Extension(user_email): Extension<String>,
+    Json(payload): Json<TotpCodeRequest>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match confirm_totp(pool, user_email.as_str(), &payload.code).await {
+        Ok(recovery_codes) => {
+            let confirm_json = serde_json::json!({
+                "email": user_email,
+                "two_factor_enabled": true,
+                "recovery_codes": recovery_codes,
+            });
+            (StatusCode::OK, Json(confirm_json))
+        }
+        Err(Errors::InvalidTotpCode) => {
+            let error_json = serde_json::json!({
+                "error": "Invalid authentication code",
+            });
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Err(Errors::TwoFactorNotEnabled) => {
+            let error_json = serde_json::json!({
+                "error": "Start enrollment with POST /2fa/enroll first",
+            });
+            (StatusCode::CONFLICT, Json(error_json))
+        }
+        Err(Errors::TwoFactorAlreadyEnabled) => {
+            let error_json = serde_json::json!({
+                "error": "Two-factor authentication is already enabled",
+            });
+            (StatusCode::CONFLICT, Json(error_json))
+        }
+        Err(e) => {
+            let error_json = serde_json::json!({
+                "error": e.to_string(),
+            });
+            error!("error occurred while confirming 2FA: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
+        }
+    }
+}
+
+pub async fn disable_totp_handler(
+    Extension(user_email): Extension<String>,
+    Json(payload): Json<TotpCodeRequest>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match disable_totp(pool, user_email.as_str(), &payload.code).await {
+        Ok(()) => {
+            let disable_json = serde_json::json!({
+                "email": user_email,
+                "two_factor_enabled": false,
+            });
+            (StatusCode::OK, Json(disable_json))
+        }
+        Err(Errors::InvalidTotpCode) => {
+            let error_json = serde_json::json!({
+                "error": "Invalid authentication code",
+            });
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Err(Errors::TwoFactorNotEnabled) => {
+            let error_json = serde_json::json!({
+                "error": "Two-factor authentication is not enabled",
+            });
+            (StatusCode::CONFLICT, Json(error_json))
+        }
+        Err(e) => {
+            let error_json = serde_json::json!({
+                "error": e.to_string(),
+            });
+            error!("error occurred while disabling 2FA: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
+        }
+    }
+}
+
+pub async fn recovery_codes_handler(
+    Extension(user_email): Extension<String>,
+    Json(payload): Json<TotpCodeRequest>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match regenerate_recovery_codes(pool, user_email.as_str(), &payload.code).await {
+        Ok(recovery_codes) => {
+            let codes_json = serde_json::json!({
+                "email": user_email,
+                "recovery_codes": recovery_codes,
+            });
+            (StatusCode::OK, Json(codes_json))
+        }
+        Err(Errors::InvalidTotpCode) => {
+            let error_json = serde_json::json!({
+                "error": "Invalid authentication code",
+            });
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Err(Errors::TwoFactorNotEnabled) => {
+            let error_json = serde_json::json!({
+                "error": "Two-factor authentication is not enabled",
+            });
+            (StatusCode::CONFLICT, Json(error_json))
+        }
+        Err(e) => {
+            let error_json = serde_json::json!({
+                "error": e.to_string(),
+            });
+            error!("error occurred while creating recovery codes: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
+        }
+    }
+}
+
+/// Emails a reset token if the email belongs to a user. The response is the
+/// same either way, so it cannot be used to find out who has an account.
+pub async fn forgot_password_handler(
+    Json(payload): Json<ForgotPasswordRequest>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    // answered before the work is done, as a known email takes longer than an
+    // unknown one
+    tokio::spawn(async move {
+        if let Err(e) = forgot_password(pool, &payload.email).await {
+            error!("error occurred while sending password reset: {}", e);
+        }
+    });
+    let forgot_json = serde_json::json!({
+        "message": "If the email belongs to an account, a password reset token has been sent to it",
+    });
+    (StatusCode::ACCEPTED, Json(forgot_json))
+}
+
+pub async fn reset_password_handler(
+    Json(payload): Json<ResetPasswordRequest>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match reset_password(pool, &payload.token, &payload.new_password).await {
+        Ok((email, revoked)) => {
+            let reset_json = serde_json::json!({
+                "email": email,
+                "revoked_sessions": revoked,
+            });
+            info!("user: {} reset their password successfully", email);
+            (StatusCode::OK, Json(reset_json))
+        }
+        Err(Errors::InvalidResetToken) => {
+            let error_json = serde_json::json!({
+                "error": "Invalid or expired reset token",
+            });
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Err(Errors::WeakPassword(reason)) => {
+            let error_json = serde_json::json!({
+                "error": reason,
+            });
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Err(e) => {
+            let error_json = serde_json::json!({
+                "error": e.to_string(),
+            });
+            error!("error occurred while resetting password: {}", e);
             (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
         }
     }
@@ -1,43 +1,175 @@
+use admin_handlers::{
+    change_role_handler, close_user_handler, freeze_user_handler, get_user_handler,
+    list_user_transactions_handler, reconciliation_handler, search_users_handler,
+    unfreeze_user_handler, unlock_user_handler,
+};
 use axum::{
-    routing::{get, post, put},
+    routing::{delete, get, post, put},
     Router,
 };
+use config::rate_limit::RouteGroup;
 use handlers::{
-    authorise_check, authorization_middleware, create_transaction_handler, fallback_handler,
-    list_transaction_handler, login_handler, modify_user_handler, register_handler,
-    user_balance_handler,
+    authorise_check, authorization_middleware, change_password_handler, confirm_totp_handler,
+    create_api_key_handler, create_transaction_handler, delete_session_handler,
+    disable_totp_handler, enroll_totp_handler, fallback_handler, forgot_password_handler,
+    get_transaction_handler, jwks_handler, list_api_keys_handler, list_sessions_handler,
+    list_transaction_handler, login_handler, login_two_factor_handler, logout_handler,
+    modify_user_handler, rate_limit_middleware, recovery_codes_handler, refresh_token_handler,
+    refund_transaction_handler, register_handler, resend_verification_handler,
+    reset_password_handler, revoke_api_key_handler, role_middleware, user_balance_handler,
+    verify_email_handler, verify_email_link_handler,
+};
+use oauth_handlers::{
+    authorization_prompt_handler, authorize_handler, list_clients_handler, list_consents_handler,
+    register_client_handler, revoke_client_handler, revoke_consent_handler, token_handler,
 };
-mod errors;
+use utils::user_structs::{ApiScope, Role};
+mod admin_handlers;
 mod handlers;
+mod oauth_handlers;
 mod service;
 mod utils;
 
 pub mod config;
+pub mod errors;
+pub use utils::{money::Money, reconciliation, totp};
+
+/// Roles that can look up users and their transactions.
+const STAFF_ROLES: &[Role] = &[Role::Admin, Role::Support, Role::Auditor];
+/// Roles that can change other users' accounts.
+const ADMIN_ROLES: &[Role] = &[Role::Admin];
+/// Roles allowed to read the reconciliation report.
+const RECONCILIATION_ROLES: &[Role] = &[Role::Admin, Role::Auditor];
+/// Routes that API keys and OAuth clients cannot call, whatever their scopes.
+const TOKENS_ONLY: Option<ApiScope> = None;
+
+/// Staff-only routes, mounted under `/admin`. Every group of routes is
+/// restricted with `role_middleware` to the roles that need it.
+fn admin_router() -> Router {
+    let staff = Router::new()
+        .route("/users", get(search_users_handler))
+        .route("/users/:id", get(get_user_handler))
+        .route(
+            "/users/:id/transactions",
+            get(list_user_transactions_handler),
+        )
+        .route_layer(axum::middleware::from_fn_with_state(
+            STAFF_ROLES,
+            role_middleware,
+        ));
+    let admins = Router::new()
+        .route("/users/:id/freeze", post(freeze_user_handler))
+        .route("/users/:id/unfreeze", post(unfreeze_user_handler))
+        .route("/users/:id/close", post(close_user_handler))
+        .route("/users/:id/unlock", post(unlock_user_handler))
+        .route("/users/:id/role", put(change_role_handler))
+        .route_layer(axum::middleware::from_fn_with_state(
+            ADMIN_ROLES,
+            role_middleware,
+        ));
+    let reconciliation = Router::new()
+        .route("/reconciliation", get(reconciliation_handler))
+        .route_layer(axum::middleware::from_fn_with_state(
+            RECONCILIATION_ROLES,
+            role_middleware,
+        ));
+    staff
+        .merge(admins)
+        .merge(reconciliation)
+        .route_layer(axum::middleware::from_fn_with_state(
+            TOKENS_ONLY,
+            authorization_middleware,
+        ))
+}
+
+/// Layers `authorization_middleware` around `routes` and the rate limit of
+/// `group`, which then counts requests per user. API keys and OAuth client
+/// tokens are let in when they have `scope`.
+fn authorized(routes: Router, group: RouteGroup, scope: Option<ApiScope>) -> Router {
+    routes
+        .route_layer(axum::middleware::from_fn_with_state(
+            group,
+            rate_limit_middleware,
+        ))
+        .route_layer(axum::middleware::from_fn_with_state(
+            scope,
+            authorization_middleware,
+        ))
+}
+
 pub fn trnx_service() -> Router {
-    Router::new()
+    // routes that let anyone in, limited per IP address
+    let access = Router::new()
         .route("/register", post(register_handler))
         .route("/login", post(login_handler))
+        .route("/login/2fa", post(login_two_factor_handler))
+        .route("/token/refresh", post(refresh_token_handler))
         .route(
-            "/user",
-            put(modify_user_handler).layer(axum::middleware::from_fn(authorization_middleware)),
-        )
-        .route(
-            "/authorise",
-            post(authorise_check).layer(axum::middleware::from_fn(authorization_middleware)),
+            "/verify-email",
+            get(verify_email_link_handler).post(verify_email_handler),
         )
+        .route("/password/forgot", post(forgot_password_handler))
+        .route("/password/reset", post(reset_password_handler))
+        .route("/oauth/token", post(token_handler))
+        .route_layer(axum::middleware::from_fn_with_state(
+            RouteGroup::Auth,
+            rate_limit_middleware,
+        ));
+    let account = Router::new()
+        .route("/verify-email/resend", post(resend_verification_handler))
+        .route("/logout", post(logout_handler))
+        .route("/user", put(modify_user_handler))
+        .route("/user/password", put(change_password_handler))
+        .route("/2fa/enroll", post(enroll_totp_handler))
+        .route("/2fa/confirm", post(confirm_totp_handler))
+        .route("/2fa/disable", post(disable_totp_handler))
+        .route("/2fa/recovery-codes", post(recovery_codes_handler))
+        .route("/sessions/:id", delete(delete_session_handler))
         .route(
-            "/balance",
-            get(user_balance_handler).layer(axum::middleware::from_fn(authorization_middleware)),
+            "/api-keys",
+            get(list_api_keys_handler).post(create_api_key_handler),
         )
+        .route("/api-keys/:id", delete(revoke_api_key_handler))
         .route(
-            "/transaction",
-            post(create_transaction_handler)
-                .layer(axum::middleware::from_fn(authorization_middleware)),
+            "/oauth/clients",
+            get(list_clients_handler).post(register_client_handler),
         )
+        .route("/oauth/clients/:id", delete(revoke_client_handler))
         .route(
-            "/transaction",
-            get(list_transaction_handler)
-                .layer(axum::middleware::from_fn(authorization_middleware)),
+            "/oauth/authorize",
+            get(authorization_prompt_handler).post(authorize_handler),
         )
+        .route("/oauth/consents", get(list_consents_handler))
+        .route("/oauth/consents/:client_id", delete(revoke_consent_handler));
+    let money = Router::new()
+        .route("/transaction", post(create_transaction_handler))
+        .route("/transaction/:id/refund", post(refund_transaction_handler));
+    let balance = Router::new().route("/balance", get(user_balance_handler));
+    let transactions = Router::new()
+        .route("/transaction", get(list_transaction_handler))
+        .route("/transaction/:id", get(get_transaction_handler));
+    let reads = Router::new()
+        .route("/authorise", post(authorise_check))
+        .route("/sessions", get(list_sessions_handler));
+    access
+        .merge(authorized(account, RouteGroup::Auth, TOKENS_ONLY))
+        .merge(authorized(
+            money,
+            RouteGroup::Money,
+            Some(ApiScope::TransactionsWrite),
+        ))
+        .merge(authorized(
+            balance,
+            RouteGroup::Read,
+            Some(ApiScope::BalanceRead),
+        ))
+        .merge(authorized(
+            transactions,
+            RouteGroup::Read,
+            Some(ApiScope::TransactionsRead),
+        ))
+        .merge(authorized(reads, RouteGroup::Read, TOKENS_ONLY))
+        .route("/.well-known/jwks.json", get(jwks_handler))
+        .nest("/admin", admin_router())
         .fallback(fallback_handler)
 }
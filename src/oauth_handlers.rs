@@ -0,0 +1,285 @@
+use crate::config::db::get_conn;
+use crate::errors::Errors;
+use crate::utils::{
+    oauth_controller::{
+        authenticate_client, authorization_prompt, authorize, client_credentials_token,
+        exchange_code, list_clients, list_consents, register_client, revoke_client, revoke_consent,
+        ClientToken, AUTHORIZATION_CODE_GRANT, CLIENT_CREDENTIALS_GRANT,
+    },
+    user_structs::{
+        AuthorizationDecision, AuthorizationRequest, RegisterClientRequest, TokenRequest,
+    },
+};
+use axum::Extension;
+use axum::{
+    extract::{Form, Json, Path, Query},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::prelude::*;
+use sqlx::PgPool;
+use tracing::{error, info};
+
+fn oauth_error(e: Errors, action: &str) -> (StatusCode, Json<serde_json::Value>) {
+    match e {
+        Errors::InvalidOAuthRequest(reason) => {
+            let error_json = serde_json::json!({
+                "error": reason,
+            });
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Errors::InvalidOAuthScope => {
+            let error_json = serde_json::json!({
+                "error": "scope is not allowed for the client",
+            });
+            (StatusCode::BAD_REQUEST, Json(error_json))
+        }
+        Errors::OAuthClientNotFound => {
+            let error_json = serde_json::json!({
+                "error": "OAuth client not found",
+            });
+            (StatusCode::NOT_FOUND, Json(error_json))
+        }
+        Errors::OAuthConsentNotFound => {
+            let error_json = serde_json::json!({
+                "error": "Consent not found",
+            });
+            (StatusCode::NOT_FOUND, Json(error_json))
+        }
+        e => {
+            let error_json = serde_json::json!({
+                "error": e.to_string(),
+            });
+            error!("error occurred while {}: {}", action, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(error_json))
+        }
+    }
+}
+
+/// Registers an OAuth client. Its secret is only in this response.
+pub async fn register_client_handler(
+    Extension(user_email): Extension<String>,
+    Json(payload): Json<RegisterClientRequest>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match register_client(pool, user_email.as_str(), payload).await {
+        Ok((secret, client)) => {
+            let client_json = serde_json::json!({
+                "client_id": client.client_id,
+                "client_secret": secret,
+                "name": client.name,
+                "redirect_uris": client.redirect_uris,
+                "scopes": client.scopes,
+                "public": client.public,
+                "created_at": client.created_at,
+            });
+            (StatusCode::CREATED, Json(client_json))
+        }
+        Err(e) => oauth_error(e, "registering OAuth client"),
+    }
+}
+
+pub async fn list_clients_handler(Extension(user_email): Extension<String>) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match list_clients(pool, user_email.as_str()).await {
+        Ok(clients) => {
+            let clients_json = serde_json::json!({
+                "clients": clients,
+            });
+            (StatusCode::OK, Json(clients_json))
+        }
+        Err(e) => oauth_error(e, "listing OAuth clients"),
+    }
+}
+
+pub async fn revoke_client_handler(
+    Extension(user_email): Extension<String>,
+    Path(client_id): Path<String>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match revoke_client(pool, user_email.as_str(), client_id.as_str()).await {
+        Ok(()) => {
+            let client_json = serde_json::json!({
+                "client_id": client_id,
+                "revoked": true,
+            });
+            (StatusCode::OK, Json(client_json))
+        }
+        Err(e) => oauth_error(e, "revoking OAuth client"),
+    }
+}
+
+/// Tells the app showing the consent screen what the client asks for, and
+/// whether the user already allowed it.
+pub async fn authorization_prompt_handler(
+    Extension(user_email): Extension<String>,
+    Query(request): Query<AuthorizationRequest>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match authorization_prompt(pool, user_email.as_str(), &request).await {
+        Ok(prompt) => (StatusCode::OK, Json(serde_json::json!(prompt))),
+        Err(e) => oauth_error(e, "checking authorization request"),
+    }
+}
+
+/// Records the user's decision and tells the app where to send them next.
+pub async fn authorize_handler(
+    Extension(user_email): Extension<String>,
+    Json(payload): Json<AuthorizationDecision>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match authorize(pool, user_email.as_str(), payload).await {
+        Ok(redirect_to) => {
+            let redirect_json = serde_json::json!({
+                "redirect_to": redirect_to,
+            });
+            (StatusCode::OK, Json(redirect_json))
+        }
+        Err(e) => oauth_error(e, "authorizing OAuth client"),
+    }
+}
+
+pub async fn list_consents_handler(Extension(user_email): Extension<String>) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match list_consents(pool, user_email.as_str()).await {
+        Ok(consents) => {
+            let consents_json = serde_json::json!({
+                "consents": consents,
+            });
+            (StatusCode::OK, Json(consents_json))
+        }
+        Err(e) => oauth_error(e, "listing consents"),
+    }
+}
+
+pub async fn revoke_consent_handler(
+    Extension(user_email): Extension<String>,
+    Path(client_id): Path<String>,
+) -> impl IntoResponse {
+    let pool = get_conn().await;
+    match revoke_consent(pool, user_email.as_str(), client_id.as_str()).await {
+        Ok(revoked_tokens) => {
+            let consent_json = serde_json::json!({
+                "client_id": client_id,
+                "revoked": true,
+                "revoked_tokens": revoked_tokens,
+            });
+            (StatusCode::OK, Json(consent_json))
+        }
+        Err(e) => oauth_error(e, "revoking consent"),
+    }
+}
+
+/// The client id and secret of a token request, from HTTP Basic
+/// authentication or else from the form.
+fn client_of(headers: &HeaderMap, payload: &TokenRequest) -> Option<(String, Option<String>)> {
+    let basic = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Basic "));
+    match basic {
+        Some(credentials) => {
+            let decoded = STANDARD.decode(credentials.trim()).ok()?;
+            let decoded = String::from_utf8(decoded).ok()?;
+            let (client_id, secret) = decoded.split_once(':')?;
+            Some((client_id.to_string(), Some(secret.to_string())))
+        }
+        None => Some((payload.client_id.clone()?, payload.client_secret.clone())),
+    }
+}
+
+async fn issue_token(
+    pool: &PgPool,
+    headers: &HeaderMap,
+    payload: TokenRequest,
+) -> Result<ClientToken, Errors> {
+    let grant_type = payload.grant_type.as_str();
+    if grant_type != AUTHORIZATION_CODE_GRANT && grant_type != CLIENT_CREDENTIALS_GRANT {
+        let err = Errors::UnsupportedGrantType;
+        return Err(err);
+    }
+    let (client_id, secret) = match client_of(headers, &payload) {
+        Some(client) => client,
+        None => {
+            let err = Errors::InvalidOAuthClient;
+            return Err(err);
+        }
+    };
+    let client = authenticate_client(pool, &client_id, secret.as_deref()).await?;
+    if grant_type == CLIENT_CREDENTIALS_GRANT {
+        return client_credentials_token(pool, &client, payload.scope.as_deref()).await;
+    }
+    match (&payload.code, &payload.redirect_uri, &payload.code_verifier) {
+        (Some(code), Some(redirect_uri), Some(code_verifier)) => {
+            exchange_code(pool, &client, code, redirect_uri, code_verifier).await
+        }
+        _ => {
+            let err = Errors::InvalidOAuthRequest(
+                "code, redirect_uri and code_verifier are required".to_string(),
+            );
+            Err(err)
+        }
+    }
+}
+
+/// The token endpoint. Answers and errors follow RFC 6749, section 5.
+pub async fn token_handler(headers: HeaderMap, Form(payload): Form<TokenRequest>) -> Response {
+    let pool = get_conn().await;
+    let no_store = [
+        (axum::http::header::CACHE_CONTROL, "no-store"),
+        (axum::http::header::PRAGMA, "no-cache"),
+    ];
+    let (status, error, description) = match issue_token(pool, &headers, payload).await {
+        Ok(token) => {
+            let token_json = serde_json::json!({
+                "access_token": token.token,
+                "token_type": "Bearer",
+                "expires_in": (token.expires_at - Utc::now()).num_seconds().max(0),
+                "scope": token
+                    .scopes
+                    .iter()
+                    .map(|scope| scope.as_str())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            });
+            return (StatusCode::OK, no_store, Json(token_json)).into_response();
+        }
+        Err(Errors::InvalidOAuthRequest(reason)) => {
+            (StatusCode::BAD_REQUEST, "invalid_request", reason)
+        }
+        Err(e @ Errors::InvalidOAuthClient) => {
+            (StatusCode::UNAUTHORIZED, "invalid_client", e.to_string())
+        }
+        Err(e @ Errors::InvalidOAuthGrant) => {
+            (StatusCode::BAD_REQUEST, "invalid_grant", e.to_string())
+        }
+        Err(e @ Errors::UnauthorizedOAuthClient) => (
+            StatusCode::BAD_REQUEST,
+            "unauthorized_client",
+            e.to_string(),
+        ),
+        Err(e @ Errors::UnsupportedGrantType) => (
+            StatusCode::BAD_REQUEST,
+            "unsupported_grant_type",
+            e.to_string(),
+        ),
+        Err(e @ Errors::InvalidOAuthScope) => {
+            (StatusCode::BAD_REQUEST, "invalid_scope", e.to_string())
+        }
+        Err(e) => {
+            error!("error occurred while issuing OAuth token: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "server_error",
+                e.to_string(),
+            )
+        }
+    };
+    info!("OAuth token request failed: {}", error);
+    let error_json = serde_json::json!({
+        "error": error,
+        "error_description": description,
+    });
+    (status, no_store, Json(error_json)).into_response()
+}
@@ -1,63 +1,214 @@
-use crate::{config::db::get_conn, errors::Errors};
+use crate::{
+    config::{db::get_conn, jwt_keys::get_jwt_keys},
+    errors::Errors,
+    utils::{
+        oauth_controller::oauth_token_active,
+        user_structs::{AccountStatus, ApiScope, Role},
+    },
+};
 use chrono::{prelude::*, Duration};
 use dotenv::dotenv;
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, TokenData, Validation};
+use jsonwebtoken::TokenData;
 use serde::{Deserialize, Serialize};
+use sqlx::Row;
 use std::env;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+/// How long an access token is valid when `ACCESS_TOKEN_TTL_SECS` is not set.
+/// Clients renew it with their refresh token.
+const DEFAULT_ACCESS_TOKEN_TTL_SECS: i64 = 15 * 60;
 
 #[derive(Serialize, Deserialize)]
 pub struct Claims {
     pub exp: usize,
     pub iat: usize,
     pub email: String,
+    /// Tokens issued before roles were added carry none and are treated as `user`.
+    #[serde(default)]
+    pub role: Role,
+    /// Makes every token unique, even two issued to the same user in the same
+    /// second.
+    #[serde(default)]
+    pub jti: String,
+    /// The OAuth client a token was issued to. Tokens from `/login` have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    /// Space separated scopes of a token issued to an OAuth client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+}
+
+impl Claims {
+    /// The scopes of a token issued to an OAuth client, or `None` for tokens
+    /// from `/login`, which are not limited to scopes.
+    pub fn client_scopes(&self) -> Option<Vec<ApiScope>> {
+        self.client_id.as_ref()?;
+        let scope = self.scope.as_deref().unwrap_or_default();
+        Some(
+            scope
+                .split_whitespace()
+                .filter_map(ApiScope::from_db)
+                .collect(),
+        )
+    }
 }
 
-pub fn encode_token(payload: String) -> Result<String, Errors> {
+/// The bearer token of the current request, added to the request extensions by
+/// `authorization_middleware` so the token can be revoked on logout.
+#[derive(Clone)]
+pub struct AuthToken(pub String);
+
+pub fn access_token_ttl() -> Duration {
     dotenv().ok();
-    let secret = env::var("JWT_KEY").expect("SECRET must be set");
+    let secs = env::var("ACCESS_TOKEN_TTL_SECS")
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_ACCESS_TOKEN_TTL_SECS);
+    Duration::seconds(secs)
+}
+
+/// Returns a new token for `payload` and the time it expires.
+pub fn encode_token(payload: String, role: Role) -> Result<(String, DateTime<Utc>), Errors> {
+    encode_claims(payload, role, None)
+}
+
+/// Returns a new token for `payload` that OAuth client `client_id` can use
+/// within `scopes`, and the time it expires. Client tokens never carry a staff
+/// role, whoever the user is.
+pub fn encode_client_token(
+    payload: String,
+    client_id: &str,
+    scopes: &[ApiScope],
+) -> Result<(String, DateTime<Utc>), Errors> {
+    encode_claims(payload, Role::User, Some((client_id, scopes)))
+}
+
+fn encode_claims(
+    payload: String,
+    role: Role,
+    client: Option<(&str, &[ApiScope])>,
+) -> Result<(String, DateTime<Utc>), Errors> {
     let time_now = Utc::now();
-    let expire = Duration::hours(24);
-    let exp: usize = (time_now + expire).timestamp() as usize;
+    let expire = access_token_ttl();
+    let expires_at = time_now + expire;
+    let exp: usize = expires_at.timestamp() as usize;
     let iat: usize = time_now.timestamp() as usize;
     let user_claim = Claims {
         exp,
         iat,
         email: payload,
+        role,
+        jti: Uuid::new_v4().as_simple().to_string(),
+        client_id: client.map(|(client_id, _)| client_id.to_string()),
+        scope: client.map(|(_, scopes)| {
+            scopes
+                .iter()
+                .map(|scope| scope.as_str())
+                .collect::<Vec<_>>()
+                .join(" ")
+        }),
     };
-    encode(
-        &Header::default(),
-        &user_claim,
-        &EncodingKey::from_secret(secret.as_ref()),
-    )
-    .map_err(|_| Errors::InternalServerError)
+    let token = get_jwt_keys().sign(&user_claim)?;
+    Ok((token, expires_at))
 }
 
 pub fn decode_token(token: &str) -> Result<TokenData<Claims>, Errors> {
+    get_jwt_keys().verify(token)
+}
+
+/// How long an email verification link works when
+/// `EMAIL_VERIFICATION_TTL_SECS` is not set.
+const DEFAULT_VERIFICATION_TTL_SECS: i64 = 24 * 60 * 60;
+
+const VERIFY_EMAIL_PURPOSE: &str = "verify_email";
+
+/// Claims of the token in an email verification link. It has no `iat` and a
+/// `purpose`, so it cannot pass for an access token or the other way round.
+#[derive(Serialize, Deserialize)]
+pub struct VerificationClaims {
+    pub exp: usize,
+    /// Id of the user the email belongs to.
+    pub sub: String,
+    pub email: String,
+    pub purpose: String,
+}
+
+pub fn verification_token_ttl() -> Duration {
     dotenv().ok();
-    let secret = env::var("JWT_KEY").expect("SECRET must be set");
-    let result: Result<TokenData<Claims>, Errors> = decode(
-        &token,
-        &DecodingKey::from_secret(secret.as_ref()),
-        &Validation::default(),
-    )
-    .map_err(|_| Errors::InternalServerError);
-    result
+    let secs = env::var("EMAIL_VERIFICATION_TTL_SECS")
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_VERIFICATION_TTL_SECS);
+    Duration::seconds(secs)
 }
 
-pub async fn authorize_user(token: &str) -> Option<String> {
+pub fn encode_verification_token(user_id: &str, email: &str) -> Result<String, Errors> {
+    let claims = VerificationClaims {
+        exp: (Utc::now() + verification_token_ttl()).timestamp() as usize,
+        sub: user_id.to_string(),
+        email: email.to_string(),
+        purpose: VERIFY_EMAIL_PURPOSE.to_string(),
+    };
+    get_jwt_keys().sign(&claims)
+}
+
+pub fn decode_verification_token(token: &str) -> Result<VerificationClaims, Errors> {
+    let token_data = get_jwt_keys()
+        .verify::<VerificationClaims>(token)
+        .map_err(|_| Errors::InvalidVerificationToken)?;
+    if token_data.claims.purpose != VERIFY_EMAIL_PURPOSE {
+        let err = Errors::InvalidVerificationToken;
+        return Err(err);
+    }
+    Ok(token_data.claims)
+}
+
+/// Returns the claims of `token` if it is a valid JWT that was issued at login
+/// or to an OAuth client, has not been revoked and belongs to an account that
+/// is not closed, and updates the last_seen time of the token's session.
+pub async fn authorize_user(token: &str) -> Option<Claims> {
     match decode_token(token) {
         Ok(token_data) => {
             let pool = get_conn().await;
-            let query = sqlx::query("SELECT * FROM authorise WHERE token = $1")
-                .bind(&token)
-                .fetch_optional(pool)
-                .await;
-            if query.is_ok() {
-                let email = token_data.claims.email.clone();
-                Some(email)
-            } else {
-                None
+            if token_data.claims.client_id.is_some() {
+                return oauth_token_active(pool, token)
+                    .await
+                    .then_some(token_data.claims);
+            }
+            let query = sqlx::query(
+                "SELECT users.status, authorise.family_id FROM authorise JOIN users ON users.id = authorise.user_id WHERE authorise.token = $1 AND authorise.revoked_at IS NULL",
+            )
+            .bind(token)
+            .fetch_optional(pool)
+            .await;
+            let row = match query {
+                Ok(Some(row))
+                    if AccountStatus::from_db(row.get::<&str, &str>("status"))
+                        != AccountStatus::Closed =>
+                {
+                    row
+                }
+                Ok(_) => return None,
+                Err(err) => {
+                    error!("Unable to look up token{:?}", err);
+                    return None;
+                }
+            };
+            // last_seen only needs to be roughly right, so it is written at
+            // most once a minute
+            let now = Utc::now();
+            let query2 =
+                sqlx::query("UPDATE sessions SET last_seen = $2 WHERE id = $1 AND last_seen < $3")
+                    .bind(row.get::<&str, &str>("family_id"))
+                    .bind(now)
+                    .bind(now - Duration::minutes(1))
+                    .execute(pool)
+                    .await;
+            if let Err(err) = query2 {
+                warn!("Unable to update session{:?}", err);
             }
+            Some(token_data.claims)
         }
         Err(_) => None,
     }
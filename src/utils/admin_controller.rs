@@ -0,0 +1,359 @@
+use crate::errors::Errors;
+use chrono::prelude::*;
+use sqlx::postgres::PgRow;
+use sqlx::{PgExecutor, PgPool, Postgres, QueryBuilder, Row};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use super::login_throttle::clear_login_failures;
+use super::money::Money;
+use super::user_controller::{
+    contains_pattern, list_transactions, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE,
+};
+use super::user_structs::{
+    AccountStatus, ListTransactionsQuery, Role, TransactionPage, UserAccount, UserPage,
+    UserSearchQuery,
+};
+
+/// Columns read into a [`UserAccount`] by [`account_from_row`].
+const USER_COLUMNS: &str = "id, full_name, email, role::text AS role, status, balance";
+
+fn account_from_row(row: &PgRow) -> UserAccount {
+    UserAccount {
+        id: row.get::<String, &str>("id"),
+        fullname: row.get::<String, &str>("full_name"),
+        email: row.get::<String, &str>("email"),
+        role: Role::from_db(row.get::<&str, &str>("role")),
+        status: AccountStatus::from_db(row.get::<&str, &str>("status")),
+        balance: row.get::<Money, &str>("balance"),
+    }
+}
+
+/// Writes one row to `admin_actions`. Changes record the action in the same
+/// database transaction, so a change is never applied without its record.
+pub async fn record_admin_action<'e, E: PgExecutor<'e>>(
+    executor: E,
+    admin_email: &str,
+    action: &str,
+    target_user_id: Option<&str>,
+    details: serde_json::Value,
+) -> Result<(), Errors> {
+    let query = sqlx::query(
+        "INSERT INTO admin_actions (id, admin_email, action, target_user_id, details, created_at) VALUES ($1, $2, $3, $4, $5, $6)",
+    )
+    .bind(Uuid::new_v4().as_simple().to_string())
+    .bind(admin_email)
+    .bind(action)
+    .bind(target_user_id)
+    .bind(details)
+    .bind(Utc::now())
+    .execute(executor)
+    .await;
+    match query {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            error!(
+                "Unable to record admin action {} by {}: {:?}",
+                action, admin_email, err
+            );
+            Err(Errors::DatabaseError(err))
+        }
+    }
+}
+
+/// Returns one page of users matching `filter`, ordered by email.
+pub async fn search_users(
+    pool: &PgPool,
+    admin_email: &str,
+    filter: &UserSearchQuery,
+) -> Result<UserPage, Errors> {
+    let limit = filter
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let cursor = match &filter.cursor {
+        Some(cursor) => {
+            let decoded = hex::decode(cursor).map_err(|_| Errors::InvalidCursor)?;
+            Some(String::from_utf8(decoded).map_err(|_| Errors::InvalidCursor)?)
+        }
+        None => None,
+    };
+
+    let mut query =
+        QueryBuilder::<Postgres>::new(format!("SELECT {USER_COLUMNS} FROM users WHERE TRUE"));
+    if let Some(text) = &filter.q {
+        let pattern = contains_pattern(text);
+        query
+            .push(" AND (email ILIKE ")
+            .push_bind(pattern.clone())
+            .push(" OR full_name ILIKE ")
+            .push_bind(pattern)
+            .push(")");
+    }
+    if let Some(role) = filter.role {
+        query
+            .push(" AND role = ")
+            .push_bind(role.as_str())
+            .push("::role");
+    }
+    if let Some(status) = filter.status {
+        query.push(" AND status = ").push_bind(status.as_str());
+    }
+    if let Some(email) = cursor {
+        query.push(" AND email > ").push_bind(email);
+    }
+    query
+        .push(" ORDER BY email LIMIT ")
+        // one extra row tells whether there is a next page
+        .push_bind(limit + 1);
+
+    let rows = match query.build().fetch_all(pool).await {
+        Ok(rows) => rows,
+        Err(err) => {
+            error!("Unable to search users{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let mut users = rows.iter().map(account_from_row).collect::<Vec<_>>();
+    let mut next_cursor = None;
+    if users.len() as i64 > limit {
+        users.truncate(limit as usize);
+        next_cursor = users.last().map(|last| hex::encode(&last.email));
+    }
+
+    let details = serde_json::to_value(filter).unwrap_or_default();
+    record_admin_action(pool, admin_email, "search_users", None, details).await?;
+    Ok(UserPage { users, next_cursor })
+}
+
+async fn find_account<'e, E: PgExecutor<'e>>(
+    executor: E,
+    user_id: &str,
+    lock: bool,
+) -> Result<UserAccount, Errors> {
+    let lock = if lock { " FOR NO KEY UPDATE" } else { "" };
+    let query = sqlx::query(&format!(
+        "SELECT {USER_COLUMNS} FROM users WHERE id = $1{lock}"
+    ))
+    .bind(user_id)
+    .fetch_optional(executor)
+    .await;
+    match query {
+        Ok(Some(row)) => Ok(account_from_row(&row)),
+        Ok(None) => {
+            let err = Errors::UserDoesNotExist;
+            Err(err)
+        }
+        Err(err) => {
+            error!("Unable to get user {}: {:?}", user_id, err);
+            let err = Errors::DatabaseError(err);
+            Err(err)
+        }
+    }
+}
+
+/// Fetches any user's account, including their balance.
+pub async fn get_user_account(
+    pool: &PgPool,
+    admin_email: &str,
+    user_id: &str,
+) -> Result<UserAccount, Errors> {
+    let account = find_account(pool, user_id, false).await?;
+    record_admin_action(
+        pool,
+        admin_email,
+        "view_user",
+        Some(user_id),
+        serde_json::json!({}),
+    )
+    .await?;
+    Ok(account)
+}
+
+/// Lists any user's transactions with the same filters and paging as
+/// `GET /transaction`.
+pub async fn list_user_transactions(
+    pool: &PgPool,
+    admin_email: &str,
+    user_id: &str,
+    filter: &ListTransactionsQuery,
+) -> Result<TransactionPage, Errors> {
+    let account = find_account(pool, user_id, false).await?;
+    let page = list_transactions(pool, &account.email, filter).await?;
+    record_admin_action(
+        pool,
+        admin_email,
+        "list_user_transactions",
+        Some(user_id),
+        serde_json::json!({}),
+    )
+    .await?;
+    Ok(page)
+}
+
+/// Freezes, unfreezes or closes an account and records why. A closed account
+/// stays closed.
+pub async fn set_account_status(
+    pool: &PgPool,
+    admin_email: &str,
+    user_id: &str,
+    status: AccountStatus,
+    reason: &str,
+) -> Result<UserAccount, Errors> {
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let mut account = find_account(&mut *trnx, user_id, true).await?;
+    let previous = account.status;
+    if previous == AccountStatus::Closed {
+        warn!(
+            "admin {} attempted to change the status of closed account {}",
+            admin_email, account.email
+        );
+        let err = Errors::AccountClosed;
+        return Err(err);
+    }
+
+    let query = sqlx::query("UPDATE users SET status = $2 WHERE id = $1")
+        .bind(user_id)
+        .bind(status.as_str())
+        .execute(&mut *trnx)
+        .await;
+    if let Err(err) = query {
+        error!("Unable to set status of user {}: {:?}", user_id, err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    let action = match status {
+        AccountStatus::Frozen => "freeze_account",
+        AccountStatus::Active => "unfreeze_account",
+        AccountStatus::Closed => "close_account",
+    };
+    let details = serde_json::json!({
+        "from": previous,
+        "to": status,
+        "reason": reason,
+    });
+    record_admin_action(&mut *trnx, admin_email, action, Some(user_id), details).await?;
+
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit account status change{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    info!(
+        "admin {} set account {} to {}",
+        admin_email,
+        account.email,
+        status.as_str()
+    );
+    account.status = status;
+    Ok(account)
+}
+
+/// Changes a user's role. Admins cannot change their own role, so the last
+/// admin cannot lock everyone out by accident.
+pub async fn set_user_role(
+    pool: &PgPool,
+    admin_email: &str,
+    user_id: &str,
+    role: Role,
+) -> Result<UserAccount, Errors> {
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let mut account = find_account(&mut *trnx, user_id, true).await?;
+    if account.email == admin_email {
+        warn!("admin {} attempted to change their own role", admin_email);
+        let err = Errors::Forbidden;
+        return Err(err);
+    }
+    let previous = account.role;
+
+    let query = sqlx::query("UPDATE users SET role = $2::role WHERE id = $1")
+        .bind(user_id)
+        .bind(role.as_str())
+        .execute(&mut *trnx)
+        .await;
+    if let Err(err) = query {
+        error!("Unable to set role of user {}: {:?}", user_id, err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    let details = serde_json::json!({
+        "from": previous,
+        "to": role,
+    });
+    record_admin_action(
+        &mut *trnx,
+        admin_email,
+        "change_role",
+        Some(user_id),
+        details,
+    )
+    .await?;
+
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit role change{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    info!(
+        "admin {} changed the role of {} from {} to {}",
+        admin_email,
+        account.email,
+        previous.as_str(),
+        role.as_str()
+    );
+    account.role = role;
+    Ok(account)
+}
+
+/// Lets a user whose email was locked out by failed logins log in again
+/// straight away.
+pub async fn unlock_login(
+    pool: &PgPool,
+    admin_email: &str,
+    user_id: &str,
+) -> Result<UserAccount, Errors> {
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let account = find_account(&mut *trnx, user_id, false).await?;
+    let cleared = clear_login_failures(&mut *trnx, &account.email).await?;
+    let details = serde_json::json!({
+        "had_failures": cleared > 0,
+    });
+    record_admin_action(
+        &mut *trnx,
+        admin_email,
+        "unlock_login",
+        Some(user_id),
+        details,
+    )
+    .await?;
+
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit login unlock{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    info!("admin {} unlocked logins of {}", admin_email, account.email);
+    Ok(account)
+}
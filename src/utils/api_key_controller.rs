@@ -0,0 +1,243 @@
+use crate::errors::Errors;
+use chrono::{prelude::*, Duration};
+use sqlx::{PgExecutor, PgPool, Row};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use super::token_controller::{hash_token, new_secret_token};
+use super::user_structs::{AccountStatus, ApiKey, ApiScope, CreateApiKeyRequest};
+
+/// Every API key starts with this, which is how `authorization_middleware`
+/// tells keys from JWTs.
+pub const API_KEY_PREFIX: &str = "trnx_";
+/// Characters of the key after [`API_KEY_PREFIX`] that are listed with it.
+const LISTED_KEY_CHARS: usize = 8;
+const MAX_NAME_LEN: usize = 100;
+
+/// The owner of an API key and what the key may be used for.
+pub struct AuthorizedKey {
+    pub id: String,
+    pub email: String,
+    pub scopes: Vec<ApiScope>,
+}
+
+fn api_key_from_row(row: &sqlx::postgres::PgRow) -> ApiKey {
+    ApiKey {
+        id: row.get::<String, &str>("id"),
+        name: row.get::<String, &str>("name"),
+        prefix: row.get::<String, &str>("prefix"),
+        scopes: row
+            .get::<Vec<String>, &str>("scopes")
+            .iter()
+            .filter_map(|scope| ApiScope::from_db(scope))
+            .collect(),
+        created_at: row.get::<DateTime<Utc>, &str>("created_at"),
+        expires_at: row.get::<Option<DateTime<Utc>>, &str>("expires_at"),
+        last_used_at: row.get::<Option<DateTime<Utc>>, &str>("last_used_at"),
+    }
+}
+
+/// Creates an API key for `email` and returns the key, which is not stored and
+/// cannot be shown again, together with what is stored about it.
+pub async fn create_api_key(
+    pool: &PgPool,
+    email: &str,
+    request: CreateApiKeyRequest,
+) -> Result<(String, ApiKey), Errors> {
+    let name = request.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        let err = Errors::InvalidApiKeyDetails(format!(
+            "name must be between 1 and {} characters",
+            MAX_NAME_LEN
+        ));
+        return Err(err);
+    }
+    let mut scopes = Vec::new();
+    for scope in request.scopes {
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+    if scopes.is_empty() {
+        let err = Errors::InvalidApiKeyDetails("at least one scope is required".to_string());
+        return Err(err);
+    }
+    let now = Utc::now();
+    if request
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= now)
+    {
+        let err = Errors::InvalidApiKeyDetails("expires_at must be in the future".to_string());
+        return Err(err);
+    }
+
+    let query1 = sqlx::query("SELECT id FROM users WHERE email = $1")
+        .bind(email)
+        .fetch_optional(pool)
+        .await;
+    let user_id = match query1 {
+        Ok(Some(row)) => row.get::<String, &str>("id"),
+        Ok(None) => {
+            let err = Errors::UserDoesNotExist;
+            return Err(err);
+        }
+        Err(err) => {
+            error!("Unable to find user {}: {:?}", email, err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let key = format!("{}{}", API_KEY_PREFIX, new_secret_token());
+    let api_key = ApiKey {
+        id: Uuid::new_v4().as_simple().to_string(),
+        name: name.to_string(),
+        prefix: key[..API_KEY_PREFIX.len() + LISTED_KEY_CHARS].to_string(),
+        scopes,
+        created_at: now,
+        expires_at: request.expires_at,
+        last_used_at: None,
+    };
+    let scope_names: Vec<&str> = api_key.scopes.iter().map(|scope| scope.as_str()).collect();
+    let query2 = sqlx::query("INSERT INTO api_keys (id, user_id, email, name, prefix, key_hash, scopes, created_at, expires_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)")
+        .bind(&api_key.id)
+        .bind(&user_id)
+        .bind(email)
+        .bind(&api_key.name)
+        .bind(&api_key.prefix)
+        .bind(hash_token(&key))
+        .bind(&scope_names)
+        .bind(now)
+        .bind(api_key.expires_at)
+        .execute(pool)
+        .await;
+    if let Err(err) = query2 {
+        error!("Unable to insert into api_keys table{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    info!(
+        "User: {} created API key {} with scopes {}",
+        email,
+        api_key.id,
+        scope_names.join(" ")
+    );
+    Ok((key, api_key))
+}
+
+/// Lists the API keys of `email` that can still be used, newest first.
+pub async fn list_api_keys(pool: &PgPool, email: &str) -> Result<Vec<ApiKey>, Errors> {
+    let query = sqlx::query("SELECT id, name, prefix, scopes, created_at, expires_at, last_used_at FROM api_keys WHERE email = $1 AND revoked_at IS NULL AND (expires_at IS NULL OR expires_at > $2) ORDER BY created_at DESC, id")
+        .bind(email)
+        .bind(Utc::now())
+        .fetch_all(pool)
+        .await;
+    match query {
+        Ok(rows) => Ok(rows.iter().map(api_key_from_row).collect()),
+        Err(err) => {
+            error!("Unable to list API keys of {}: {:?}", email, err);
+            let err = Errors::DatabaseError(err);
+            Err(err)
+        }
+    }
+}
+
+/// Revokes one API key of `email`. Keys of other users and keys that were
+/// already revoked are not found.
+pub async fn revoke_api_key(pool: &PgPool, email: &str, key_id: &str) -> Result<(), Errors> {
+    let query = sqlx::query(
+        "UPDATE api_keys SET revoked_at = $3 WHERE id = $1 AND email = $2 AND revoked_at IS NULL",
+    )
+    .bind(key_id)
+    .bind(email)
+    .bind(Utc::now())
+    .execute(pool)
+    .await;
+    match query {
+        Ok(result) if result.rows_affected() == 0 => {
+            warn!(
+                "user: {} attempted to revoke unknown API key {}",
+                email, key_id
+            );
+            let err = Errors::ApiKeyNotFound;
+            Err(err)
+        }
+        Ok(_) => {
+            info!("User: {} revoked API key {}", email, key_id);
+            Ok(())
+        }
+        Err(err) => {
+            error!("Unable to revoke API key {}: {:?}", key_id, err);
+            let err = Errors::DatabaseError(err);
+            Err(err)
+        }
+    }
+}
+
+/// Revokes every API key of `email`, such as when the password is reset
+/// because the account may have been taken over.
+pub async fn revoke_all_api_keys<'e, E: PgExecutor<'e>>(
+    executor: E,
+    email: &str,
+) -> Result<u64, Errors> {
+    let query =
+        sqlx::query("UPDATE api_keys SET revoked_at = $2 WHERE email = $1 AND revoked_at IS NULL")
+            .bind(email)
+            .bind(Utc::now())
+            .execute(executor)
+            .await;
+    match query {
+        Ok(result) => Ok(result.rows_affected()),
+        Err(err) => {
+            error!("Unable to revoke API keys of {}: {:?}", email, err);
+            Err(Errors::DatabaseError(err))
+        }
+    }
+}
+
+/// Returns the owner and scopes of `key` if it is an API key that has not
+/// expired or been revoked and belongs to an account that is not closed, and
+/// updates the time the key was last used.
+pub async fn authorize_api_key(pool: &PgPool, key: &str) -> Option<AuthorizedKey> {
+    let now = Utc::now();
+    let query = sqlx::query(
+        "SELECT api_keys.id, api_keys.scopes, api_keys.last_used_at, users.email, users.status FROM api_keys JOIN users ON users.id = api_keys.user_id WHERE api_keys.key_hash = $1 AND api_keys.revoked_at IS NULL AND (api_keys.expires_at IS NULL OR api_keys.expires_at > $2)",
+    )
+    .bind(hash_token(key))
+    .bind(now)
+    .fetch_optional(pool)
+    .await;
+    let row = match query {
+        Ok(Some(row))
+            if AccountStatus::from_db(row.get::<&str, &str>("status")) != AccountStatus::Closed =>
+        {
+            row
+        }
+        Ok(_) => return None,
+        Err(err) => {
+            error!("Unable to look up API key{:?}", err);
+            return None;
+        }
+    };
+    let authorized = AuthorizedKey {
+        id: row.get::<String, &str>("id"),
+        email: row.get::<String, &str>("email"),
+        scopes: row
+            .get::<Vec<String>, &str>("scopes")
+            .iter()
+            .filter_map(|scope| ApiScope::from_db(scope))
+            .collect(),
+    };
+    // like the last_seen of sessions, written at most once a minute
+    let last_used_at = row.get::<Option<DateTime<Utc>>, &str>("last_used_at");
+    if last_used_at.is_none_or(|last_used_at| last_used_at < now - Duration::minutes(1)) {
+        let query2 = sqlx::query("UPDATE api_keys SET last_used_at = $2 WHERE id = $1")
+            .bind(&authorized.id)
+            .bind(now)
+            .execute(pool)
+            .await;
+        if let Err(err) = query2 {
+            warn!("Unable to update API key{:?}", err);
+        }
+    }
+    Some(authorized)
+}
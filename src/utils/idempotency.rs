@@ -0,0 +1,314 @@
+use crate::errors::Errors;
+use chrono::{prelude::*, Duration};
+use dotenv::dotenv;
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Postgres, Row};
+use std::env;
+use tracing::{error, warn};
+
+use super::user_structs::TransactionStatus;
+
+/// How long a stored key is honoured when `IDEMPOTENCY_KEY_TTL_SECS` is not set.
+const DEFAULT_KEY_TTL_SECS: i64 = 24 * 60 * 60;
+/// How long a request may hold a key without finishing when
+/// `IDEMPOTENCY_LOCK_LEASE_SECS` is not set.
+const DEFAULT_LOCK_LEASE_SECS: i64 = 30;
+
+/// Outcome of claiming an `Idempotency-Key` for a request.
+pub enum IdempotencyStatus {
+    /// The key is new (or had expired, or its request stopped before moving any
+    /// money) and now belongs to this request, which claimed it at `claimed_at`.
+    New { claimed_at: DateTime<Utc> },
+    /// The request that held the key stopped without storing a response, but its
+    /// transfer `transaction_id` went through. The key now belongs to this
+    /// request, which answers with that transfer instead of making another.
+    Settled {
+        claimed_at: DateTime<Utc>,
+        transaction_id: String,
+    },
+    /// The key was used before for the same request; this is its stored response.
+    Replay { status: u16, body: String },
+    /// The original request with this key has not finished yet.
+    InFlight,
+    /// The key was used before for a different request.
+    Mismatch,
+}
+
+/// A key held by a request. Acting on the key only works while the claim is
+/// still the current one, so a request whose key was taken over cannot move
+/// money or store a response under it.
+pub struct IdempotencyClaim<'a> {
+    pub email: &'a str,
+    pub key: &'a str,
+    pub claimed_at: DateTime<Utc>,
+}
+
+pub fn key_ttl() -> Duration {
+    dotenv().ok();
+    let secs = env::var("IDEMPOTENCY_KEY_TTL_SECS")
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_KEY_TTL_SECS);
+    Duration::seconds(secs)
+}
+
+/// After this long an unfinished claim on a key is taken to be abandoned.
+pub fn lock_lease() -> Duration {
+    dotenv().ok();
+    let secs = env::var("IDEMPOTENCY_LOCK_LEASE_SECS")
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_LOCK_LEASE_SECS);
+    Duration::seconds(secs)
+}
+
+/// Hex encoded SHA-256 of the serialized request.
+pub fn fingerprint(request: &[u8]) -> String {
+    hex::encode(Sha256::digest(request))
+}
+
+/// Claims `key` for `email`, or reports what happened to the earlier request that
+/// used it. Keys older than `ttl` are discarded first, and a claim that has not
+/// finished within `lease` is taken over.
+pub async fn begin_idempotent_request(
+    pool: &PgPool,
+    email: &str,
+    key: &str,
+    fingerprint: &str,
+    ttl: Duration,
+    lease: Duration,
+) -> Result<IdempotencyStatus, Errors> {
+    // Postgres keeps microseconds, and claims are compared with what it stores
+    let now = Utc::now().trunc_subsecs(6);
+    let query1 = sqlx::query(
+        "DELETE FROM idempotency_keys WHERE email = $1 AND idempotency_key = $2 AND created_at < $3",
+    )
+    .bind(email)
+    .bind(key)
+    .bind(now - ttl)
+    .execute(pool)
+    .await;
+    if let Err(err) = query1 {
+        error!("Unable to expire idempotency key{:?}", err);
+        return Err(Errors::DatabaseError(err));
+    }
+
+    let query2 = sqlx::query(
+        "INSERT INTO idempotency_keys (email, idempotency_key, request_fingerprint, created_at, locked_at) VALUES ($1, $2, $3, $4, $4) ON CONFLICT DO NOTHING",
+    )
+    .bind(email)
+    .bind(key)
+    .bind(fingerprint)
+    .bind(now)
+    .execute(pool)
+    .await;
+    match query2 {
+        Ok(result) if result.rows_affected() == 1 => {
+            return Ok(IdempotencyStatus::New { claimed_at: now })
+        }
+        Ok(_) => {}
+        Err(err) => {
+            error!("Unable to insert idempotency key{:?}", err);
+            return Err(Errors::DatabaseError(err));
+        }
+    }
+
+    let query3 = sqlx::query(
+        "SELECT request_fingerprint, response_status, response_body, locked_at, transaction_id FROM idempotency_keys WHERE email = $1 AND idempotency_key = $2",
+    )
+    .bind(email)
+    .bind(key)
+    .fetch_optional(pool)
+    .await;
+    let row = match query3 {
+        Ok(Some(row)) => row,
+        // the earlier request released the key in the meantime
+        Ok(None) => return Ok(IdempotencyStatus::InFlight),
+        Err(err) => {
+            error!("Unable to read idempotency key{:?}", err);
+            return Err(Errors::DatabaseError(err));
+        }
+    };
+    let stored_fingerprint = row.get::<String, &str>("request_fingerprint");
+    let status = row.get::<Option<i16>, &str>("response_status");
+    let body = row.get::<Option<String>, &str>("response_body");
+    let locked_at = row.get::<DateTime<Utc>, &str>("locked_at");
+    let transaction_id = row.get::<Option<String>, &str>("transaction_id");
+    // a different request is a mismatch whether or not the first one finished
+    if stored_fingerprint != fingerprint {
+        warn!(
+            "user {} reused idempotency key {} for another request",
+            email, key
+        );
+        return Ok(IdempotencyStatus::Mismatch);
+    }
+    match (status, body) {
+        (Some(status), Some(body)) => Ok(IdempotencyStatus::Replay {
+            status: status as u16,
+            body,
+        }),
+        _ if locked_at > now - lease => Ok(IdempotencyStatus::InFlight),
+        _ => take_over_idempotent_request(pool, email, key, locked_at, transaction_id, now).await,
+    }
+}
+
+/// Claims a key whose request stopped without finishing. Only one retry wins,
+/// as the claim only moves if nobody else moved it or recorded a transfer under
+/// it since it was read.
+///
+/// What happens next depends on the transfer the earlier request recorded, if
+/// any. One that went through is kept and answered with, one that is still
+/// pending leaves the key in flight until it settles or is failed as stale, and
+/// one that failed moved no money, so the retry makes the transfer again.
+async fn take_over_idempotent_request(
+    pool: &PgPool,
+    email: &str,
+    key: &str,
+    locked_at: DateTime<Utc>,
+    transaction_id: Option<String>,
+    now: DateTime<Utc>,
+) -> Result<IdempotencyStatus, Errors> {
+    let settled_id = match &transaction_id {
+        None => None,
+        Some(id) => {
+            let query1 = sqlx::query("SELECT status FROM transactions WHERE id = $1")
+                .bind(id)
+                .fetch_one(pool)
+                .await;
+            let status = match query1 {
+                Ok(row) => TransactionStatus::from_db(row.get::<&str, &str>("status")),
+                Err(err) => {
+                    error!("Unable to read transaction {}: {:?}", id, err);
+                    return Err(Errors::DatabaseError(err));
+                }
+            };
+            match status {
+                TransactionStatus::Pending => return Ok(IdempotencyStatus::InFlight),
+                TransactionStatus::Failed => None,
+                TransactionStatus::Completed | TransactionStatus::Reversed => Some(id.clone()),
+            }
+        }
+    };
+
+    let query2 = sqlx::query(
+        "UPDATE idempotency_keys SET locked_at = $4, transaction_id = $6 WHERE email = $1 AND idempotency_key = $2 AND locked_at = $3 AND response_status IS NULL AND transaction_id IS NOT DISTINCT FROM $5::VARCHAR",
+    )
+    .bind(email)
+    .bind(key)
+    .bind(locked_at)
+    .bind(now)
+    .bind(&transaction_id)
+    .bind(&settled_id)
+    .execute(pool)
+    .await;
+    match query2 {
+        Ok(result) if result.rows_affected() == 1 => {
+            warn!(
+                "user {} took over abandoned idempotency key {} locked at {}",
+                email, key, locked_at
+            );
+            match settled_id {
+                Some(transaction_id) => Ok(IdempotencyStatus::Settled {
+                    claimed_at: now,
+                    transaction_id,
+                }),
+                None => Ok(IdempotencyStatus::New { claimed_at: now }),
+            }
+        }
+        Ok(_) => Ok(IdempotencyStatus::InFlight),
+        Err(err) => {
+            error!("Unable to take over idempotency key{:?}", err);
+            Err(Errors::DatabaseError(err))
+        }
+    }
+}
+
+/// Records `transaction_id` as the transfer made under `claim`, inside the
+/// database transaction that records the transfer, so the two are kept together.
+/// Fails with `IdempotencyKeyLost` if the key was taken over in the meantime.
+pub async fn link_idempotent_transaction(
+    trnx: &mut sqlx::Transaction<'_, Postgres>,
+    claim: &IdempotencyClaim<'_>,
+    transaction_id: &str,
+) -> Result<(), Errors> {
+    let query = sqlx::query(
+        "UPDATE idempotency_keys SET transaction_id = $4 WHERE email = $1 AND idempotency_key = $2 AND locked_at = $3 AND response_status IS NULL",
+    )
+    .bind(claim.email)
+    .bind(claim.key)
+    .bind(claim.claimed_at)
+    .bind(transaction_id)
+    .execute(&mut *trnx)
+    .await;
+    match query {
+        Ok(result) if result.rows_affected() == 1 => Ok(()),
+        Ok(_) => {
+            warn!(
+                "idempotency key {} of user {} was taken over before its transfer was recorded",
+                claim.key, claim.email
+            );
+            let err = Errors::IdempotencyKeyLost;
+            Err(err)
+        }
+        Err(err) => {
+            error!("Unable to link idempotency key{:?}", err);
+            Err(Errors::DatabaseError(err))
+        }
+    }
+}
+
+/// Stores the response for a key claimed with [`begin_idempotent_request`]. Fails
+/// with `IdempotencyKeyLost` if the key was taken over in the meantime.
+pub async fn complete_idempotent_request(
+    pool: &PgPool,
+    claim: &IdempotencyClaim<'_>,
+    status: u16,
+    body: &str,
+) -> Result<(), Errors> {
+    let query = sqlx::query(
+        "UPDATE idempotency_keys SET response_status = $4, response_body = $5, completed_at = $6 WHERE email = $1 AND idempotency_key = $2 AND locked_at = $3 AND response_status IS NULL",
+    )
+    .bind(claim.email)
+    .bind(claim.key)
+    .bind(claim.claimed_at)
+    .bind(status as i16)
+    .bind(body)
+    .bind(Utc::now())
+    .execute(pool)
+    .await;
+    match query {
+        Ok(result) if result.rows_affected() == 1 => Ok(()),
+        Ok(_) => {
+            let err = Errors::IdempotencyKeyLost;
+            Err(err)
+        }
+        Err(err) => {
+            error!("Unable to store idempotent response{:?}", err);
+            Err(Errors::DatabaseError(err))
+        }
+    }
+}
+
+/// Releases a key claimed with [`begin_idempotent_request`] without storing a
+/// response, so the client can retry with it. A key that was taken over in the
+/// meantime is left to its new holder.
+pub async fn release_idempotent_request(
+    pool: &PgPool,
+    claim: &IdempotencyClaim<'_>,
+) -> Result<(), Errors> {
+    let query = sqlx::query(
+        "DELETE FROM idempotency_keys WHERE email = $1 AND idempotency_key = $2 AND locked_at = $3 AND response_status IS NULL",
+    )
+    .bind(claim.email)
+    .bind(claim.key)
+    .bind(claim.claimed_at)
+    .execute(pool)
+    .await;
+    match query {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            error!("Unable to release idempotency key{:?}", err);
+            Err(Errors::DatabaseError(err))
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use crate::errors::Errors;
+use chrono::prelude::*;
+use sqlx::Postgres;
+use tracing::error;
+use uuid::Uuid;
+
+use super::money::Money;
+
+/// System account that funds initial balances. Its balance is always minus the
+/// money held by all users.
+pub const EQUITY_ACCOUNT_ID: &str = "equity";
+
+/// What a journal entry records. Reconciliation counts funding towards an
+/// account's expected balance and transaction entries through their transaction.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EntryKind {
+    /// Sets an account's starting balance, at registration or when the ledger
+    /// was introduced.
+    Funding,
+    /// Moves the money of a transfer or refund, named by the entry's
+    /// `transaction_id`.
+    Transaction,
+    /// Brings an account back in line with its history after reconciliation.
+    Correction,
+}
+
+impl EntryKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EntryKind::Funding => "funding",
+            EntryKind::Transaction => "transaction",
+            EntryKind::Correction => "correction",
+        }
+    }
+
+    pub fn from_db(kind: &str) -> Self {
+        match kind {
+            "funding" => EntryKind::Funding,
+            "correction" => EntryKind::Correction,
+            _ => EntryKind::Transaction,
+        }
+    }
+}
+
+/// One side of a journal entry. Positive amounts add to the account, negative
+/// amounts take from it.
+pub struct Posting<'a> {
+    pub account_id: &'a str,
+    pub amount: Money,
+}
+
+/// Opens the ledger account for a user. User accounts share the user's id.
+pub async fn open_user_account(
+    trnx: &mut sqlx::Transaction<'_, Postgres>,
+    user_id: &str,
+    email: &str,
+) -> Result<(), Errors> {
+    let query = sqlx::query(
+        "INSERT INTO ledger_accounts (id, user_id, name, created_at) VALUES ($1, $1, $2, $3)",
+    )
+    .bind(user_id)
+    .bind(email)
+    .bind(Utc::now())
+    .execute(&mut *trnx)
+    .await;
+    match query {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            error!("Unable to open ledger account for {}: {:?}", email, err);
+            Err(Errors::DatabaseError(err))
+        }
+    }
+}
+
+/// Writes a balanced journal entry and applies its postings to the cached
+/// `users.balance` of every user account involved.
+///
+/// The caller is expected to hold row locks on the affected `users` rows and to
+/// have checked any balance rules; this only refuses entries that do not sum to
+/// zero. Returns the id of the new entry.
+pub async fn post_entry(
+    trnx: &mut sqlx::Transaction<'_, Postgres>,
+    kind: EntryKind,
+    transaction_id: Option<&str>,
+    description: &str,
+    postings: &[Posting<'_>],
+) -> Result<String, Errors> {
+    let mut total = Money::ZERO;
+    for posting in postings {
+        total = total.checked_add(posting.amount)?;
+    }
+    if total != Money::ZERO || postings.is_empty() {
+        error!("Refusing unbalanced journal entry: {}", description);
+        return Err(Errors::UnbalancedEntry);
+    }
+
+    let entry_id = Uuid::new_v4().as_simple().to_string();
+    let query1 = sqlx::query(
+        "INSERT INTO journal_entries (id, transaction_id, description, created_at, kind) VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(&entry_id)
+    .bind(transaction_id)
+    .bind(description)
+    .bind(Utc::now())
+    .bind(kind.as_str())
+    .execute(&mut *trnx)
+    .await;
+    if let Err(err) = query1 {
+        error!("Unable to insert journal entry{:?}", err);
+        return Err(Errors::DatabaseError(err));
+    }
+
+    for posting in postings {
+        let query2 =
+            sqlx::query("INSERT INTO postings (entry_id, account_id, amount) VALUES ($1, $2, $3)")
+                .bind(&entry_id)
+                .bind(posting.account_id)
+                .bind(posting.amount)
+                .execute(&mut *trnx)
+                .await;
+        if let Err(err) = query2 {
+            error!("Unable to insert posting{:?}", err);
+            return Err(Errors::DatabaseError(err));
+        }
+
+        // system accounts have no users row, so this is a no-op for them
+        let query3 = sqlx::query("UPDATE users SET balance = balance + $1 WHERE id = $2")
+            .bind(posting.amount)
+            .bind(posting.account_id)
+            .execute(&mut *trnx)
+            .await;
+        if let Err(err) = query3 {
+            error!("Unable to update cached balance{:?}", err);
+            return Err(Errors::DatabaseError(err));
+        }
+    }
+    Ok(entry_id)
+}
@@ -0,0 +1,229 @@
+use crate::errors::Errors;
+use chrono::{prelude::*, Duration};
+use dotenv::dotenv;
+use sqlx::{PgExecutor, PgPool, Row};
+use std::env;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use super::user_structs::ClientDetails;
+
+/// Failed logins of an email that are let through without delay. After that,
+/// every failure doubles the wait before the next attempt, starting at one
+/// second.
+const FREE_FAILURES_PER_EMAIL: i32 = 3;
+/// IP addresses can be shared by many users, so they get more failures.
+const FREE_FAILURES_PER_IP: i32 = 10;
+/// Failures that lock an email out when `LOGIN_MAX_FAILURES` is not set.
+const DEFAULT_MAX_FAILURES_PER_EMAIL: i32 = 10;
+/// Failures that lock an IP address out when `LOGIN_MAX_FAILURES_PER_IP` is
+/// not set.
+const DEFAULT_MAX_FAILURES_PER_IP: i32 = 50;
+/// How long a lockout lasts when `LOGIN_LOCKOUT_SECS` is not set.
+const DEFAULT_LOCKOUT_SECS: i64 = 15 * 60;
+
+/// What failed logins are counted against.
+#[derive(Clone, Copy, Debug)]
+enum ThrottleScope {
+    Email,
+    Ip,
+}
+
+impl ThrottleScope {
+    fn as_str(self) -> &'static str {
+        match self {
+            ThrottleScope::Email => "email",
+            ThrottleScope::Ip => "ip",
+        }
+    }
+
+    fn from_db(scope: &str) -> Self {
+        match scope {
+            "ip" => ThrottleScope::Ip,
+            _ => ThrottleScope::Email,
+        }
+    }
+
+    fn free_failures(self) -> i32 {
+        match self {
+            ThrottleScope::Email => FREE_FAILURES_PER_EMAIL,
+            ThrottleScope::Ip => FREE_FAILURES_PER_IP,
+        }
+    }
+
+    fn max_failures(self) -> i32 {
+        dotenv().ok();
+        let (var, default) = match self {
+            ThrottleScope::Email => ("LOGIN_MAX_FAILURES", DEFAULT_MAX_FAILURES_PER_EMAIL),
+            ThrottleScope::Ip => ("LOGIN_MAX_FAILURES_PER_IP", DEFAULT_MAX_FAILURES_PER_IP),
+        };
+        env::var(var)
+            .ok()
+            .and_then(|value| value.parse::<i32>().ok())
+            .unwrap_or(default)
+    }
+}
+
+pub fn lockout_duration() -> Duration {
+    dotenv().ok();
+    let secs = env::var("LOGIN_LOCKOUT_SECS")
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_LOCKOUT_SECS);
+    Duration::seconds(secs)
+}
+
+/// How long after its last failure a subject with `failures` failed logins has
+/// to wait before it may try again.
+fn backoff(scope: ThrottleScope, failures: i32) -> Duration {
+    let lockout = lockout_duration();
+    if failures >= scope.max_failures() {
+        return lockout;
+    }
+    match failures - scope.free_failures() {
+        doublings if doublings < 0 => Duration::zero(),
+        doublings => Duration::seconds(2_i64.saturating_pow(doublings as u32)).min(lockout),
+    }
+}
+
+/// Emails are counted case-insensitively, so changing the case of an address
+/// does not give an attacker more guesses.
+fn email_subject(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+fn subjects(email: &str, client: &ClientDetails) -> Vec<(ThrottleScope, String)> {
+    let mut subjects = vec![(ThrottleScope::Email, email_subject(email))];
+    if let Some(ip) = &client.ip {
+        subjects.push((ThrottleScope::Ip, ip.clone()));
+    }
+    subjects
+}
+
+/// Writes one row to `security_events`.
+async fn record_security_event<'e, E: PgExecutor<'e>>(
+    executor: E,
+    kind: &str,
+    email: Option<&str>,
+    ip: Option<&str>,
+    details: serde_json::Value,
+) -> Result<(), Errors> {
+    let query = sqlx::query(
+        "INSERT INTO security_events (id, kind, email, ip, details, created_at) VALUES ($1, $2, $3, $4, $5, $6)",
+    )
+    .bind(Uuid::new_v4().as_simple().to_string())
+    .bind(kind)
+    .bind(email)
+    .bind(ip)
+    .bind(details)
+    .bind(Utc::now())
+    .execute(executor)
+    .await;
+    match query {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            error!("Unable to record security event {}: {:?}", kind, err);
+            Err(Errors::DatabaseError(err))
+        }
+    }
+}
+
+/// Returns whether a login for `email` from `client` has to wait because of
+/// earlier failures of the email or of the client's IP address.
+pub async fn login_blocked(
+    pool: &PgPool,
+    email: &str,
+    client: &ClientDetails,
+) -> Result<bool, Errors> {
+    let query = sqlx::query("SELECT scope, failures, last_failure_at FROM login_failures WHERE (scope = 'email' AND subject = $1) OR (scope = 'ip' AND subject = $2)")
+        .bind(email_subject(email))
+        .bind(&client.ip)
+        .fetch_all(pool)
+        .await;
+    let rows = match query {
+        Ok(rows) => rows,
+        Err(err) => {
+            error!("Unable to get failed logins of {}: {:?}", email, err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let now = Utc::now();
+    let blocked = rows.iter().any(|row| {
+        let scope = ThrottleScope::from_db(row.get::<&str, &str>("scope"));
+        let failures = row.get::<i32, &str>("failures");
+        row.get::<DateTime<Utc>, &str>("last_failure_at") + backoff(scope, failures) > now
+    });
+    Ok(blocked)
+}
+
+/// Counts a failed login against `email` and the client's IP address, and
+/// records a `login_lockout` security event for each of them that is locked
+/// out by it.
+pub async fn record_login_failure(
+    pool: &PgPool,
+    email: &str,
+    client: &ClientDetails,
+) -> Result<(), Errors> {
+    let now = Utc::now();
+    let lockout = lockout_duration();
+    for (scope, subject) in subjects(email, client) {
+        // a subject without failures for as long as a lockout lasts starts over
+        let query = sqlx::query("INSERT INTO login_failures (scope, subject, failures, last_failure_at) VALUES ($1, $2, 1, $3) ON CONFLICT (scope, subject) DO UPDATE SET failures = CASE WHEN login_failures.last_failure_at < $4 THEN 1 ELSE login_failures.failures + 1 END, last_failure_at = $3 RETURNING failures")
+            .bind(scope.as_str())
+            .bind(&subject)
+            .bind(now)
+            .bind(now - lockout)
+            .fetch_one(pool)
+            .await;
+        let failures = match query {
+            Ok(row) => row.get::<i32, &str>("failures"),
+            Err(err) => {
+                error!("Unable to count failed login of {}: {:?}", subject, err);
+                let err = Errors::DatabaseError(err);
+                return Err(err);
+            }
+        };
+        if failures == scope.max_failures() {
+            warn!(
+                "{} {} locked out after {} failed logins",
+                scope.as_str(),
+                subject,
+                failures
+            );
+            let details = serde_json::json!({
+                "scope": scope.as_str(),
+                "failures": failures,
+                "locked_until": now + lockout,
+            });
+            record_security_event(
+                pool,
+                "login_lockout",
+                Some(email),
+                client.ip.as_deref(),
+                details,
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Forgets the failed logins of `email` after a successful login. Failures of
+/// the IP address are kept, as they may belong to other emails.
+pub async fn clear_login_failures<'e, E: PgExecutor<'e>>(
+    executor: E,
+    email: &str,
+) -> Result<u64, Errors> {
+    let query = sqlx::query("DELETE FROM login_failures WHERE scope = 'email' AND subject = $1")
+        .bind(email_subject(email))
+        .execute(executor)
+        .await;
+    match query {
+        Ok(result) => Ok(result.rows_affected()),
+        Err(err) => {
+            error!("Unable to clear failed logins of {}: {:?}", email, err);
+            Err(Errors::DatabaseError(err))
+        }
+    }
+}
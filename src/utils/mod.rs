@@ -1,2 +1,15 @@
+pub mod admin_controller;
+pub mod api_key_controller;
+pub mod idempotency;
+pub mod ledger;
+pub mod login_throttle;
+pub mod money;
+pub mod oauth_controller;
+pub mod password_controller;
+pub mod reconciliation;
+pub mod token_controller;
+pub mod totp;
+pub mod two_factor_controller;
 pub mod user_controller;
 pub mod user_structs;
+pub mod verification_controller;
@@ -0,0 +1,254 @@
+use crate::errors::Errors;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::error::BoxDynError;
+use sqlx::postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef};
+use sqlx::{encode::IsNull, Decode, Encode, Postgres, Type};
+use std::fmt;
+use std::str::FromStr;
+
+/// An exact amount of money, stored as a whole number of minor units (cents).
+///
+/// Amounts are stored in `BIGINT` columns and travel over the wire as decimal
+/// strings with exactly [`Money::SCALE`] fractional digits, e.g. `"12.50"`, so
+/// that no client has to round-trip them through a float.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Money(i64);
+
+impl Money {
+    /// Number of fractional digits in the decimal representation.
+    pub const SCALE: u32 = 2;
+    pub const ZERO: Money = Money(0);
+    const MINOR_PER_MAJOR: i64 = 10_i64.pow(Money::SCALE);
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    pub fn checked_add(self, other: Money) -> Result<Money, Errors> {
+        self.0
+            .checked_add(other.0)
+            .map(Money)
+            .ok_or(Errors::MoneyOverflow)
+    }
+
+    pub fn checked_sub(self, other: Money) -> Result<Money, Errors> {
+        self.0
+            .checked_sub(other.0)
+            .map(Money)
+            .ok_or(Errors::MoneyOverflow)
+    }
+
+    pub fn checked_neg(self) -> Result<Money, Errors> {
+        self.0.checked_neg().map(Money).ok_or(Errors::MoneyOverflow)
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        let per_major = Money::MINOR_PER_MAJOR as u64;
+        write!(
+            f,
+            "{}{}.{:0width$}",
+            sign,
+            abs / per_major,
+            abs % per_major,
+            width = Money::SCALE as usize
+        )
+    }
+}
+
+impl FromStr for Money {
+    type Err = Errors;
+
+    /// Parses a plain decimal such as `"10"`, `"-3.5"` or `"0.01"`. More than
+    /// [`Money::SCALE`] fractional digits is rejected rather than rounded.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (negative, digits) = match value.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, value),
+        };
+        let (major, minor) = match digits.split_once('.') {
+            Some((major, minor)) => (major, minor),
+            None => (digits, ""),
+        };
+        if major.is_empty()
+            || !major.bytes().all(|b| b.is_ascii_digit())
+            || !minor.bytes().all(|b| b.is_ascii_digit())
+            || minor.len() > Money::SCALE as usize
+            || (digits.contains('.') && minor.is_empty())
+        {
+            return Err(Errors::InvalidAmount);
+        }
+
+        let major = major.parse::<i64>().map_err(|_| Errors::MoneyOverflow)?;
+        let mut minor_units = 0_i64;
+        for (i, digit) in minor.bytes().enumerate() {
+            minor_units += i64::from(digit - b'0') * 10_i64.pow(Money::SCALE - 1 - i as u32);
+        }
+        let amount = major
+            .checked_mul(Money::MINOR_PER_MAJOR)
+            .and_then(|amount| amount.checked_add(minor_units))
+            .ok_or(Errors::MoneyOverflow)?;
+        Ok(Money(if negative { -amount } else { amount }))
+    }
+}
+
+impl Type<Postgres> for Money {
+    fn type_info() -> PgTypeInfo {
+        <i64 as Type<Postgres>>::type_info()
+    }
+}
+
+impl<'q> Encode<'q, Postgres> for Money {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+        <i64 as Encode<'q, Postgres>>::encode_by_ref(&self.0, buf)
+    }
+}
+
+impl<'r> Decode<'r, Postgres> for Money {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        Ok(Money(<i64 as Decode<'r, Postgres>>::decode(value)?))
+    }
+}
+
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    /// Accepts the canonical string form, and plain JSON numbers for clients
+    /// that have not moved to strings yet.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(MoneyVisitor)
+    }
+}
+
+struct MoneyVisitor;
+
+impl<'de> Visitor<'de> for MoneyVisitor {
+    type Value = Money;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "a decimal amount with at most {} fractional digits",
+            Money::SCALE
+        )
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Money, E> {
+        value.parse().map_err(E::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Money, E> {
+        self.visit_str(&value.to_string())
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Money, E> {
+        self.visit_str(&value.to_string())
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<Money, E> {
+        // `Display` for f64 prints the shortest decimal that round-trips, so
+        // `0.1` is read back as exactly ten cents
+        self.visit_str(&value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_plain_decimals() {
+        assert_eq!("10".parse::<Money>().unwrap(), Money(1000));
+        assert_eq!("-3.5".parse::<Money>().unwrap(), Money(-350));
+        assert_eq!("0.01".parse::<Money>().unwrap(), Money(1));
+        assert_eq!("12.50".parse::<Money>().unwrap(), Money(1250));
+    }
+
+    #[test]
+    fn rejects_anything_else() {
+        for value in [
+            "", "-", "1.", ".5", "1e5", "0.001", "1,00", "+1", " 1", "1.-5",
+        ] {
+            assert!(
+                matches!(value.parse::<Money>(), Err(Errors::InvalidAmount)),
+                "{:?} was accepted",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn displays_with_two_fractional_digits() {
+        assert_eq!(Money(0).to_string(), "0.00");
+        assert_eq!(Money(5).to_string(), "0.05");
+        assert_eq!(Money(1250).to_string(), "12.50");
+        assert_eq!(Money(-350).to_string(), "-3.50");
+        assert_eq!(Money(i64::MIN).to_string(), "-92233720368547758.08");
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for minor in [0, 1, -1, 99, 100, -12345, i64::MAX, i64::MIN + 1] {
+            let money = Money(minor);
+            assert_eq!(money.to_string().parse::<Money>().unwrap(), money);
+        }
+    }
+
+    #[test]
+    fn deserializes_strings_and_numbers() {
+        let parse = |json: &str| serde_json::from_str::<Money>(json);
+        assert_eq!(parse("\"12.50\"").unwrap(), Money(1250));
+        assert_eq!(parse("12").unwrap(), Money(1200));
+        assert_eq!(parse("-3").unwrap(), Money(-300));
+        // floats go through their shortest decimal, so these are exact
+        assert_eq!(parse("0.1").unwrap(), Money(10));
+        assert_eq!(parse("19.99").unwrap(), Money(1999));
+        assert_eq!(parse("1e5").unwrap(), Money(10_000_000));
+        assert!(parse("0.001").is_err());
+        assert!(parse("1e300").is_err());
+        assert!(parse("\"1.\"").is_err());
+        assert_eq!(serde_json::to_string(&Money(1250)).unwrap(), "\"12.50\"");
+    }
+
+    #[test]
+    fn out_of_range_amounts_overflow() {
+        assert!(matches!(
+            "92233720368547758.08".parse::<Money>(),
+            Err(Errors::MoneyOverflow)
+        ));
+        assert!(matches!(
+            "99999999999999999999".parse::<Money>(),
+            Err(Errors::MoneyOverflow)
+        ));
+        assert_eq!(
+            "92233720368547758.07".parse::<Money>().unwrap(),
+            Money(i64::MAX)
+        );
+    }
+
+    #[test]
+    fn checked_arithmetic_overflows() {
+        let max = Money(i64::MAX);
+        let min = Money(i64::MIN);
+        assert!(matches!(
+            max.checked_add(Money(1)),
+            Err(Errors::MoneyOverflow)
+        ));
+        assert!(matches!(
+            min.checked_sub(Money(1)),
+            Err(Errors::MoneyOverflow)
+        ));
+        assert!(matches!(min.checked_neg(), Err(Errors::MoneyOverflow)));
+        assert_eq!(max.checked_sub(Money(1)).unwrap(), Money(i64::MAX - 1));
+        assert_eq!(max.checked_neg().unwrap(), Money(-i64::MAX));
+        assert_eq!(Money(150).checked_add(Money(-200)).unwrap(), Money(-50));
+    }
+}
@@ -0,0 +1,830 @@
+//! An OAuth 2.0 authorization server on top of the user store. Users register
+//! clients, allow them scopes through the authorization-code grant with PKCE
+//! (RFC 7636), and clients get tokens from `service::encode_client_token` that
+//! `authorization_middleware` accepts within their scopes. A client can also
+//! get a token for the user who registered it with the client-credentials
+//! grant.
+
+use crate::errors::Errors;
+use crate::service::encode_client_token;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{prelude::*, Duration};
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, PgPool, Row};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use super::token_controller::{hash_token, new_secret_token};
+use super::user_structs::{
+    AccountStatus, ApiScope, AuthorizationDecision, AuthorizationPrompt, AuthorizationRequest,
+    OAuthClient, OAuthConsent, RegisterClientRequest,
+};
+
+/// How long an authorization code can be redeemed. RFC 6749 recommends at
+/// most ten minutes.
+const AUTHORIZATION_CODE_TTL_SECS: i64 = 10 * 60;
+const MAX_CLIENT_NAME_LEN: usize = 100;
+const MAX_REDIRECT_URIS: usize = 10;
+const MAX_REDIRECT_URI_LEN: usize = 2000;
+/// Lengths RFC 7636 allows for a code verifier.
+const MIN_CODE_VERIFIER_LEN: usize = 43;
+const MAX_CODE_VERIFIER_LEN: usize = 128;
+/// Length of a base64url encoded SHA-256, which every S256 challenge is.
+const CODE_CHALLENGE_LEN: usize = 43;
+
+pub const AUTHORIZATION_CODE_GRANT: &str = "authorization_code";
+pub const CLIENT_CREDENTIALS_GRANT: &str = "client_credentials";
+
+/// A client that has not been revoked.
+pub struct RegisteredClient {
+    pub id: String,
+    pub owner_id: String,
+    pub name: String,
+    secret_hash: Option<String>,
+    pub redirect_uris: Vec<String>,
+    pub scopes: Vec<ApiScope>,
+}
+
+/// An access token issued to a client.
+pub struct ClientToken {
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+    pub scopes: Vec<ApiScope>,
+}
+
+fn scopes_from_db(scopes: Vec<String>) -> Vec<ApiScope> {
+    scopes
+        .iter()
+        .filter_map(|scope| ApiScope::from_db(scope))
+        .collect()
+}
+
+fn scope_names(scopes: &[ApiScope]) -> Vec<&'static str> {
+    scopes.iter().map(|scope| scope.as_str()).collect()
+}
+
+/// Parses the space separated `scope` of a request, which may ask for any of
+/// `allowed`, and for all of them when it is missing.
+fn requested_scopes(scope: Option<&str>, allowed: &[ApiScope]) -> Result<Vec<ApiScope>, Errors> {
+    let scope = scope.unwrap_or_default();
+    if scope.trim().is_empty() {
+        return Ok(allowed.to_vec());
+    }
+    let mut scopes = Vec::new();
+    for name in scope.split_whitespace() {
+        match ApiScope::from_db(name) {
+            Some(scope) if allowed.contains(&scope) => {
+                if !scopes.contains(&scope) {
+                    scopes.push(scope);
+                }
+            }
+            _ => {
+                let err = Errors::InvalidOAuthScope;
+                return Err(err);
+            }
+        }
+    }
+    Ok(scopes)
+}
+
+/// Redirect URIs have to use https, except on the loopback address where
+/// native apps listen, and cannot have a fragment.
+fn valid_redirect_uri(uri: &str) -> bool {
+    let host_and_path = if let Some(rest) = uri.strip_prefix("https://") {
+        rest
+    } else if let Some(rest) = uri.strip_prefix("http://") {
+        let host = rest.split(['/', '?', ':']).next().unwrap_or_default();
+        if host != "localhost" && host != "127.0.0.1" {
+            return false;
+        }
+        rest
+    } else {
+        return false;
+    };
+    uri.len() <= MAX_REDIRECT_URI_LEN
+        && !host_and_path.is_empty()
+        && !host_and_path.starts_with(['/', '?'])
+        && !uri.contains('#')
+        && !uri.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// Percent-encodes `value` for a query string.
+fn query_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// `redirect_uri` with `params` added to its query string.
+fn redirect_with(redirect_uri: &str, params: &[(&str, Option<&str>)]) -> String {
+    let mut uri = redirect_uri.to_string();
+    let mut separator = if uri.contains('?') { '&' } else { '?' };
+    for (name, value) in params {
+        if let Some(value) = value {
+            uri.push(separator);
+            uri.push_str(name);
+            uri.push('=');
+            uri.push_str(&query_encode(value));
+            separator = '&';
+        }
+    }
+    uri
+}
+
+fn valid_code_verifier(verifier: &str) -> bool {
+    (MIN_CODE_VERIFIER_LEN..=MAX_CODE_VERIFIER_LEN).contains(&verifier.len())
+        && verifier
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"-._~".contains(&byte))
+}
+
+/// The S256 challenge of PKCE code verifier `verifier`.
+pub fn code_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+async fn user_of(pool: &PgPool, email: &str) -> Result<String, Errors> {
+    let query = sqlx::query("SELECT id FROM users WHERE email = $1")
+        .bind(email)
+        .fetch_optional(pool)
+        .await;
+    match query {
+        Ok(Some(row)) => Ok(row.get::<String, &str>("id")),
+        Ok(None) => {
+            let err = Errors::UserDoesNotExist;
+            Err(err)
+        }
+        Err(err) => {
+            error!("Unable to find user {}: {:?}", email, err);
+            let err = Errors::DatabaseError(err);
+            Err(err)
+        }
+    }
+}
+
+/// Registers a client for `email` and returns its secret, which is not stored
+/// and cannot be shown again, unless the client is public.
+pub async fn register_client(
+    pool: &PgPool,
+    email: &str,
+    request: RegisterClientRequest,
+) -> Result<(Option<String>, OAuthClient), Errors> {
+    let name = request.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_CLIENT_NAME_LEN {
+        let err = Errors::InvalidOAuthRequest(format!(
+            "name must be between 1 and {} characters",
+            MAX_CLIENT_NAME_LEN
+        ));
+        return Err(err);
+    }
+    if request.redirect_uris.is_empty() || request.redirect_uris.len() > MAX_REDIRECT_URIS {
+        let err = Errors::InvalidOAuthRequest(format!(
+            "between 1 and {} redirect_uris are required",
+            MAX_REDIRECT_URIS
+        ));
+        return Err(err);
+    }
+    if let Some(uri) = request
+        .redirect_uris
+        .iter()
+        .find(|uri| !valid_redirect_uri(uri))
+    {
+        let err = Errors::InvalidOAuthRequest(format!(
+            "redirect_uri {} must be an https URI without a fragment",
+            uri
+        ));
+        return Err(err);
+    }
+    let mut scopes = Vec::new();
+    for scope in request.scopes {
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+    if scopes.is_empty() {
+        let err = Errors::InvalidOAuthRequest("at least one scope is required".to_string());
+        return Err(err);
+    }
+
+    let owner_id = user_of(pool, email).await?;
+    let secret = (!request.public).then(new_secret_token);
+    let client = OAuthClient {
+        client_id: Uuid::new_v4().as_simple().to_string(),
+        name: name.to_string(),
+        redirect_uris: request.redirect_uris,
+        scopes,
+        public: request.public,
+        created_at: Utc::now(),
+    };
+    let query = sqlx::query("INSERT INTO oauth_clients (id, owner_id, owner_email, name, secret_hash, redirect_uris, scopes, created_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)")
+        .bind(&client.client_id)
+        .bind(&owner_id)
+        .bind(email)
+        .bind(&client.name)
+        .bind(secret.as_deref().map(hash_token))
+        .bind(&client.redirect_uris)
+        .bind(scope_names(&client.scopes))
+        .bind(client.created_at)
+        .execute(pool)
+        .await;
+    if let Err(err) = query {
+        error!("Unable to insert into oauth_clients table{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    info!(
+        "User: {} registered OAuth client {}",
+        email, client.client_id
+    );
+    Ok((secret, client))
+}
+
+/// Lists the clients `email` registered and has not revoked, newest first.
+pub async fn list_clients(pool: &PgPool, email: &str) -> Result<Vec<OAuthClient>, Errors> {
+    let query = sqlx::query("SELECT id, name, secret_hash IS NULL AS public, redirect_uris, scopes, created_at FROM oauth_clients WHERE owner_email = $1 AND revoked_at IS NULL ORDER BY created_at DESC, id")
+        .bind(email)
+        .fetch_all(pool)
+        .await;
+    match query {
+        Ok(rows) => Ok(rows
+            .iter()
+            .map(|row| OAuthClient {
+                client_id: row.get::<String, &str>("id"),
+                name: row.get::<String, &str>("name"),
+                redirect_uris: row.get::<Vec<String>, &str>("redirect_uris"),
+                scopes: scopes_from_db(row.get::<Vec<String>, &str>("scopes")),
+                public: row.get::<bool, &str>("public"),
+                created_at: row.get::<DateTime<Utc>, &str>("created_at"),
+            })
+            .collect()),
+        Err(err) => {
+            error!("Unable to list OAuth clients of {}: {:?}", email, err);
+            let err = Errors::DatabaseError(err);
+            Err(err)
+        }
+    }
+}
+
+/// Revokes a client of `email`. Its tokens stop working with it.
+pub async fn revoke_client(pool: &PgPool, email: &str, client_id: &str) -> Result<(), Errors> {
+    let query = sqlx::query("UPDATE oauth_clients SET revoked_at = $3 WHERE id = $1 AND owner_email = $2 AND revoked_at IS NULL")
+        .bind(client_id)
+        .bind(email)
+        .bind(Utc::now())
+        .execute(pool)
+        .await;
+    match query {
+        Ok(result) if result.rows_affected() == 0 => {
+            warn!(
+                "user: {} attempted to revoke unknown OAuth client {}",
+                email, client_id
+            );
+            let err = Errors::OAuthClientNotFound;
+            Err(err)
+        }
+        Ok(_) => {
+            info!("User: {} revoked OAuth client {}", email, client_id);
+            Ok(())
+        }
+        Err(err) => {
+            error!("Unable to revoke OAuth client {}: {:?}", client_id, err);
+            let err = Errors::DatabaseError(err);
+            Err(err)
+        }
+    }
+}
+
+async fn find_client(pool: &PgPool, client_id: &str) -> Result<Option<RegisteredClient>, Errors> {
+    let query = sqlx::query("SELECT id, owner_id, name, secret_hash, redirect_uris, scopes FROM oauth_clients WHERE id = $1 AND revoked_at IS NULL")
+        .bind(client_id)
+        .fetch_optional(pool)
+        .await;
+    match query {
+        Ok(row) => Ok(row.map(|row| RegisteredClient {
+            id: row.get::<String, &str>("id"),
+            owner_id: row.get::<String, &str>("owner_id"),
+            name: row.get::<String, &str>("name"),
+            secret_hash: row.get::<Option<String>, &str>("secret_hash"),
+            redirect_uris: row.get::<Vec<String>, &str>("redirect_uris"),
+            scopes: scopes_from_db(row.get::<Vec<String>, &str>("scopes")),
+        })),
+        Err(err) => {
+            error!("Unable to get OAuth client {}: {:?}", client_id, err);
+            let err = Errors::DatabaseError(err);
+            Err(err)
+        }
+    }
+}
+
+/// Checks an authorization request and returns its client and scopes. Errors
+/// are returned to the user rather than to the redirect URI, as the URI may
+/// not belong to the client.
+async fn check_request(
+    pool: &PgPool,
+    request: &AuthorizationRequest,
+) -> Result<(RegisteredClient, Vec<ApiScope>), Errors> {
+    let client = match find_client(pool, &request.client_id).await? {
+        Some(client) => client,
+        None => {
+            let err = Errors::InvalidOAuthRequest("unknown client_id".to_string());
+            return Err(err);
+        }
+    };
+    if !client.redirect_uris.contains(&request.redirect_uri) {
+        let err = Errors::InvalidOAuthRequest(
+            "redirect_uri is not registered for the client".to_string(),
+        );
+        return Err(err);
+    }
+    if request.response_type != "code" {
+        let err = Errors::InvalidOAuthRequest("response_type must be code".to_string());
+        return Err(err);
+    }
+    if request.code_challenge_method != "S256" {
+        let err = Errors::InvalidOAuthRequest("code_challenge_method must be S256".to_string());
+        return Err(err);
+    }
+    if request.code_challenge.len() != CODE_CHALLENGE_LEN
+        || URL_SAFE_NO_PAD.decode(&request.code_challenge).is_err()
+    {
+        let err = Errors::InvalidOAuthRequest("invalid code_challenge".to_string());
+        return Err(err);
+    }
+    let scopes = requested_scopes(request.scope.as_deref(), &client.scopes)?;
+    Ok((client, scopes))
+}
+
+/// Returns what `email` is asked to allow by an authorization request, and
+/// whether they already allowed it.
+pub async fn authorization_prompt(
+    pool: &PgPool,
+    email: &str,
+    request: &AuthorizationRequest,
+) -> Result<AuthorizationPrompt, Errors> {
+    let (client, scopes) = check_request(pool, request).await?;
+    let query = sqlx::query("SELECT scopes @> $3 AS consented FROM oauth_consents WHERE email = $1 AND client_id = $2 AND revoked_at IS NULL")
+        .bind(email)
+        .bind(&client.id)
+        .bind(scope_names(&scopes))
+        .fetch_optional(pool)
+        .await;
+    let consented = match query {
+        Ok(row) => row.is_some_and(|row| row.get::<bool, &str>("consented")),
+        Err(err) => {
+            error!("Unable to get consent of {}: {:?}", email, err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    Ok(AuthorizationPrompt {
+        client_id: client.id,
+        client_name: client.name,
+        redirect_uri: request.redirect_uri.clone(),
+        scopes,
+        consented,
+    })
+}
+
+/// Records the decision of `email` on an authorization request and returns
+/// where to send the user: back to the client with an authorization code if
+/// they approved, or with an `access_denied` error if not.
+pub async fn authorize(
+    pool: &PgPool,
+    email: &str,
+    decision: AuthorizationDecision,
+) -> Result<String, Errors> {
+    let request = decision.request;
+    let (client, scopes) = check_request(pool, &request).await?;
+    if !decision.approve {
+        info!("User: {} denied OAuth client {}", email, client.id);
+        return Ok(redirect_with(
+            &request.redirect_uri,
+            &[
+                ("error", Some("access_denied")),
+                ("state", request.state.as_deref()),
+            ],
+        ));
+    }
+
+    let user_id = user_of(pool, email).await?;
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let now = Utc::now();
+    // scopes allowed earlier are kept, unless the consent was revoked since
+    let query1 = sqlx::query("INSERT INTO oauth_consents (user_id, client_id, email, scopes, created_at, updated_at) VALUES ($1, $2, $3, $4, $5, $5) ON CONFLICT (user_id, client_id) DO UPDATE SET scopes = ARRAY(SELECT DISTINCT unnest(CASE WHEN oauth_consents.revoked_at IS NULL THEN oauth_consents.scopes ELSE '{}' END || EXCLUDED.scopes)), updated_at = EXCLUDED.updated_at, revoked_at = NULL")
+        .bind(&user_id)
+        .bind(&client.id)
+        .bind(email)
+        .bind(scope_names(&scopes))
+        .bind(now)
+        .execute(&mut *trnx)
+        .await;
+    if let Err(err) = query1 {
+        error!("Unable to insert into oauth_consents table{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    let code = new_secret_token();
+    let query2 = sqlx::query("INSERT INTO oauth_authorization_codes (id, code_hash, client_id, user_id, email, redirect_uri, scopes, code_challenge, created_at, expires_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)")
+        .bind(Uuid::new_v4().as_simple().to_string())
+        .bind(hash_token(&code))
+        .bind(&client.id)
+        .bind(&user_id)
+        .bind(email)
+        .bind(&request.redirect_uri)
+        .bind(scope_names(&scopes))
+        .bind(&request.code_challenge)
+        .bind(now)
+        .bind(now + Duration::seconds(AUTHORIZATION_CODE_TTL_SECS))
+        .execute(&mut *trnx)
+        .await;
+    if let Err(err) = query2 {
+        error!(
+            "Unable to insert into oauth_authorization_codes table{:?}",
+            err
+        );
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit authorization{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    info!(
+        "User: {} allowed OAuth client {} {}",
+        email,
+        client.id,
+        scope_names(&scopes).join(" ")
+    );
+    Ok(redirect_with(
+        &request.redirect_uri,
+        &[("code", Some(&code)), ("state", request.state.as_deref())],
+    ))
+}
+
+/// Lists the clients `email` has allowed to act on their behalf.
+pub async fn list_consents(pool: &PgPool, email: &str) -> Result<Vec<OAuthConsent>, Errors> {
+    let query = sqlx::query("SELECT oauth_consents.client_id, oauth_clients.name, oauth_consents.scopes, oauth_consents.created_at, oauth_consents.updated_at FROM oauth_consents JOIN oauth_clients ON oauth_clients.id = oauth_consents.client_id WHERE oauth_consents.email = $1 AND oauth_consents.revoked_at IS NULL AND oauth_clients.revoked_at IS NULL ORDER BY oauth_consents.updated_at DESC, oauth_consents.client_id")
+        .bind(email)
+        .fetch_all(pool)
+        .await;
+    match query {
+        Ok(rows) => Ok(rows
+            .iter()
+            .map(|row| OAuthConsent {
+                client_id: row.get::<String, &str>("client_id"),
+                client_name: row.get::<String, &str>("name"),
+                scopes: scopes_from_db(row.get::<Vec<String>, &str>("scopes")),
+                created_at: row.get::<DateTime<Utc>, &str>("created_at"),
+                updated_at: row.get::<DateTime<Utc>, &str>("updated_at"),
+            })
+            .collect()),
+        Err(err) => {
+            error!("Unable to list consents of {}: {:?}", email, err);
+            let err = Errors::DatabaseError(err);
+            Err(err)
+        }
+    }
+}
+
+/// Takes back what `email` allowed `client_id`, and revokes the tokens the
+/// client got with it.
+pub async fn revoke_consent(pool: &PgPool, email: &str, client_id: &str) -> Result<u64, Errors> {
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let now = Utc::now();
+    let query1 = sqlx::query("UPDATE oauth_consents SET revoked_at = $3 WHERE email = $1 AND client_id = $2 AND revoked_at IS NULL")
+        .bind(email)
+        .bind(client_id)
+        .bind(now)
+        .execute(&mut *trnx)
+        .await;
+    match query1 {
+        Ok(result) if result.rows_affected() == 0 => {
+            warn!(
+                "user: {} attempted to revoke unknown consent of {}",
+                email, client_id
+            );
+            let err = Errors::OAuthConsentNotFound;
+            return Err(err);
+        }
+        Ok(_) => {}
+        Err(err) => {
+            error!("Unable to revoke consent of {}: {:?}", email, err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    }
+    let query2 = sqlx::query("UPDATE oauth_tokens SET revoked_at = $4 WHERE email = $1 AND client_id = $2 AND grant_type = $3 AND revoked_at IS NULL")
+        .bind(email)
+        .bind(client_id)
+        .bind(AUTHORIZATION_CODE_GRANT)
+        .bind(now)
+        .execute(&mut *trnx)
+        .await;
+    let revoked = match query2 {
+        Ok(result) => result.rows_affected(),
+        Err(err) => {
+            error!("Unable to revoke OAuth tokens of {}: {:?}", email, err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit consent revocation{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    info!(
+        "User: {} revoked consent of OAuth client {} and {} token(s)",
+        email, client_id, revoked
+    );
+    Ok(revoked)
+}
+
+/// Revokes every client `email` registered, every consent they gave and every
+/// token clients got for them, such as when the password is reset because the
+/// account may have been taken over.
+pub async fn revoke_all_oauth_grants(conn: &mut PgConnection, email: &str) -> Result<(), Errors> {
+    let now = Utc::now();
+    for (table, column) in [
+        ("oauth_clients", "owner_email"),
+        ("oauth_consents", "email"),
+        ("oauth_tokens", "email"),
+    ] {
+        let query = sqlx::query(&format!(
+            "UPDATE {} SET revoked_at = $2 WHERE {} = $1 AND revoked_at IS NULL",
+            table, column
+        ))
+        .bind(email)
+        .bind(now)
+        .execute(&mut *conn)
+        .await;
+        if let Err(err) = query {
+            error!("Unable to revoke {} of {}: {:?}", table, email, err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the client `client_id` if `secret` is its secret. Public clients
+/// have none, and authenticate with their id alone.
+pub async fn authenticate_client(
+    pool: &PgPool,
+    client_id: &str,
+    secret: Option<&str>,
+) -> Result<RegisteredClient, Errors> {
+    let client = find_client(pool, client_id).await?;
+    let authenticated = match (&client, secret) {
+        (Some(client), Some(secret)) => client.secret_hash.as_deref() == Some(&hash_token(secret)),
+        (Some(client), None) => client.secret_hash.is_none(),
+        (None, _) => false,
+    };
+    match client {
+        Some(client) if authenticated => Ok(client),
+        _ => {
+            warn!("OAuth client {} failed to authenticate", client_id);
+            let err = Errors::InvalidOAuthClient;
+            Err(err)
+        }
+    }
+}
+
+/// Issues a token of `client` for user `user_id` and records it in
+/// `oauth_tokens`.
+async fn issue_client_token(
+    conn: &mut PgConnection,
+    client: &RegisteredClient,
+    user_id: &str,
+    scopes: Vec<ApiScope>,
+    grant_type: &str,
+    authorization_code_id: Option<&str>,
+) -> Result<ClientToken, Errors> {
+    let query1 = sqlx::query("SELECT email, status FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_one(&mut *conn)
+        .await;
+    let row = match query1 {
+        Ok(row) => row,
+        Err(err) => {
+            error!("Unable to find user {}: {:?}", user_id, err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    if AccountStatus::from_db(row.get::<&str, &str>("status")) == AccountStatus::Closed {
+        let err = Errors::InvalidOAuthGrant;
+        return Err(err);
+    }
+    let email = row.get::<String, &str>("email");
+    let (token, expires_at) = match encode_client_token(email.clone(), &client.id, &scopes) {
+        Ok(token) => token,
+        Err(err) => {
+            error!("Unable to generate token{:?}", err);
+            let err = Errors::InternalServerError;
+            return Err(err);
+        }
+    };
+    let query2 = sqlx::query("INSERT INTO oauth_tokens (id, token_hash, client_id, user_id, email, scopes, grant_type, authorization_code_id, created_at, expires_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)")
+        .bind(Uuid::new_v4().as_simple().to_string())
+        .bind(hash_token(&token))
+        .bind(&client.id)
+        .bind(user_id)
+        .bind(&email)
+        .bind(scope_names(&scopes))
+        .bind(grant_type)
+        .bind(authorization_code_id)
+        .bind(Utc::now())
+        .bind(expires_at)
+        .execute(&mut *conn)
+        .await;
+    if let Err(err) = query2 {
+        error!("Unable to insert into oauth_tokens table{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    info!(
+        "Issued {} token of OAuth client {} for {}",
+        grant_type, client.id, email
+    );
+    Ok(ClientToken {
+        token,
+        expires_at,
+        scopes,
+    })
+}
+
+/// Redeems an authorization code of `client`. A code works once: it is used up
+/// by the first attempt, and presenting it again revokes the token issued for
+/// it, as the code may have been stolen.
+pub async fn exchange_code(
+    pool: &PgPool,
+    client: &RegisteredClient,
+    code: &str,
+    redirect_uri: &str,
+    code_verifier: &str,
+) -> Result<ClientToken, Errors> {
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let now = Utc::now();
+    let query1 = sqlx::query("SELECT id, client_id, user_id, redirect_uri, scopes, code_challenge, expires_at, used_at FROM oauth_authorization_codes WHERE code_hash = $1 FOR UPDATE")
+        .bind(hash_token(code))
+        .fetch_optional(&mut *trnx)
+        .await;
+    let row = match query1 {
+        Ok(Some(row)) => row,
+        Ok(None) => {
+            let err = Errors::InvalidOAuthGrant;
+            return Err(err);
+        }
+        Err(err) => {
+            error!("Unable to get authorization code{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let code_id = row.get::<String, &str>("id");
+    let query2 = if row.get::<Option<DateTime<Utc>>, &str>("used_at").is_some() {
+        warn!("Authorization code {} was presented again", code_id);
+        sqlx::query("UPDATE oauth_tokens SET revoked_at = $2 WHERE authorization_code_id = $1 AND revoked_at IS NULL")
+    } else {
+        sqlx::query("UPDATE oauth_authorization_codes SET used_at = $2 WHERE id = $1")
+    }
+    .bind(&code_id)
+    .bind(now)
+    .execute(&mut *trnx)
+    .await;
+    if let Err(err) = query2 {
+        error!("Unable to use up authorization code {}: {:?}", code_id, err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    let user_id = row.get::<String, &str>("user_id");
+    let scopes = scopes_from_db(row.get::<Vec<String>, &str>("scopes"));
+    let valid = row.get::<Option<DateTime<Utc>>, &str>("used_at").is_none()
+        && row.get::<DateTime<Utc>, &str>("expires_at") > now
+        && row.get::<&str, &str>("client_id") == client.id
+        && row.get::<&str, &str>("redirect_uri") == redirect_uri
+        && valid_code_verifier(code_verifier)
+        && row.get::<&str, &str>("code_challenge") == code_challenge(code_verifier);
+    let token = if valid {
+        // the user may have taken their consent back since
+        let query3 = sqlx::query("SELECT 1 FROM oauth_consents WHERE user_id = $1 AND client_id = $2 AND revoked_at IS NULL AND scopes @> $3")
+            .bind(&user_id)
+            .bind(&client.id)
+            .bind(scope_names(&scopes))
+            .fetch_optional(&mut *trnx)
+            .await;
+        match query3 {
+            Ok(Some(_)) => {
+                issue_client_token(
+                    &mut trnx,
+                    client,
+                    &user_id,
+                    scopes,
+                    AUTHORIZATION_CODE_GRANT,
+                    Some(&code_id),
+                )
+                .await
+            }
+            Ok(None) => Err(Errors::InvalidOAuthGrant),
+            Err(err) => {
+                error!("Unable to get consent of {}: {:?}", user_id, err);
+                let err = Errors::DatabaseError(err);
+                return Err(err);
+            }
+        }
+    } else {
+        Err(Errors::InvalidOAuthGrant)
+    };
+    if matches!(
+        token,
+        Err(Errors::DatabaseError(_) | Errors::InternalServerError)
+    ) {
+        return token;
+    }
+    // a rejected code is used up as well
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit authorization code{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    token
+}
+
+/// Issues a token for the user who registered `client`, within the scopes it
+/// asks for. Only clients with a secret can use this grant.
+pub async fn client_credentials_token(
+    pool: &PgPool,
+    client: &RegisteredClient,
+    scope: Option<&str>,
+) -> Result<ClientToken, Errors> {
+    if client.secret_hash.is_none() {
+        let err = Errors::UnauthorizedOAuthClient;
+        return Err(err);
+    }
+    let scopes = requested_scopes(scope, &client.scopes)?;
+    let mut conn = match pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            error!("Unable to get connection{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    issue_client_token(
+        &mut conn,
+        client,
+        &client.owner_id,
+        scopes,
+        CLIENT_CREDENTIALS_GRANT,
+        None,
+    )
+    .await
+}
+
+/// Whether `token` was issued to a client that has not been revoked, has not
+/// been revoked itself and belongs to an account that is not closed.
+pub async fn oauth_token_active(pool: &PgPool, token: &str) -> bool {
+    let query = sqlx::query("SELECT users.status FROM oauth_tokens JOIN oauth_clients ON oauth_clients.id = oauth_tokens.client_id JOIN users ON users.id = oauth_tokens.user_id WHERE oauth_tokens.token_hash = $1 AND oauth_tokens.revoked_at IS NULL AND oauth_clients.revoked_at IS NULL")
+        .bind(hash_token(token))
+        .fetch_optional(pool)
+        .await;
+    match query {
+        Ok(Some(row)) => {
+            AccountStatus::from_db(row.get::<&str, &str>("status")) != AccountStatus::Closed
+        }
+        Ok(None) => false,
+        Err(err) => {
+            error!("Unable to look up OAuth token{:?}", err);
+            false
+        }
+    }
+}
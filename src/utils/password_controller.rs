@@ -0,0 +1,192 @@
+use crate::config::mailer::{send_email, Email};
+use crate::errors::Errors;
+use bcrypt::{hash, DEFAULT_COST};
+use chrono::{prelude::*, Duration};
+use dotenv::dotenv;
+use sqlx::{PgPool, Row};
+use std::env;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use super::api_key_controller::revoke_all_api_keys;
+use super::login_throttle::clear_login_failures;
+use super::oauth_controller::revoke_all_oauth_grants;
+use super::token_controller::{end_sessions, hash_token, new_secret_token, SessionScope};
+use super::user_controller::validate_password;
+
+/// How long a reset token can be used when `PASSWORD_RESET_TTL_SECS` is not set.
+const DEFAULT_RESET_TTL_SECS: i64 = 60 * 60;
+
+pub fn reset_token_ttl() -> Duration {
+    dotenv().ok();
+    let secs = env::var("PASSWORD_RESET_TTL_SECS")
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_RESET_TTL_SECS);
+    Duration::seconds(secs)
+}
+
+/// Emails a password reset token to `email` if it belongs to a user. Earlier
+/// reset tokens of the user stop working.
+///
+/// Callers must answer the same whether or not the user exists, so unknown
+/// emails are not an error. Known emails take longer, so callers should not
+/// wait for this either.
+pub async fn forgot_password(pool: &PgPool, email: &str) -> Result<(), Errors> {
+    let query1 = sqlx::query("SELECT id FROM users WHERE email = $1")
+        .bind(email)
+        .fetch_optional(pool)
+        .await;
+    let user_id = match query1 {
+        Ok(Some(row)) => row.get::<String, &str>("id"),
+        Ok(None) => {
+            info!("password reset requested for unknown email {}", email);
+            return Ok(());
+        }
+        Err(err) => {
+            error!("Unable to find user {}: {:?}", email, err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let now = Utc::now();
+    let query2 = sqlx::query(
+        "UPDATE password_resets SET expires_at = $2 WHERE user_id = $1 AND used_at IS NULL AND expires_at > $2",
+    )
+    .bind(&user_id)
+    .bind(now)
+    .execute(&mut *trnx)
+    .await;
+    if let Err(err) = query2 {
+        error!("Unable to expire reset tokens of {}: {:?}", email, err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    let token = new_secret_token();
+    let ttl = reset_token_ttl();
+    let query3 = sqlx::query("INSERT INTO password_resets (id, user_id, email, token_hash, created_at, expires_at) VALUES ($1, $2, $3, $4, $5, $6)")
+        .bind(Uuid::new_v4().as_simple().to_string())
+        .bind(&user_id)
+        .bind(email)
+        .bind(hash_token(&token))
+        .bind(now)
+        .bind(now + ttl)
+        .execute(&mut *trnx)
+        .await;
+    if let Err(err) = query3 {
+        error!("Unable to insert into password_resets table{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+
+    let mail = Email {
+        to: email.to_string(),
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Use this token with POST /password/reset to choose a new password:\n\n{}\n\nIt expires in {} minutes. If you did not ask to reset your password, ignore this email.",
+            token,
+            ttl.num_minutes()
+        ),
+    };
+    // the token is only stored once the email is on its way
+    send_email(mail).await?;
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit password reset{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    info!("password reset token sent to {}", email);
+    Ok(())
+}
+
+/// Sets a new password with a reset token from [`forgot_password`]. The token
+/// is used up, and every session of the user is ended. Returns the user's email
+/// and how many sessions were ended.
+pub async fn reset_password(
+    pool: &PgPool,
+    token: &str,
+    new_password: &str,
+) -> Result<(String, u64), Errors> {
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let now = Utc::now();
+    let query1 = sqlx::query("SELECT id, email FROM password_resets WHERE token_hash = $1 AND used_at IS NULL AND expires_at > $2 FOR UPDATE")
+        .bind(hash_token(token))
+        .bind(now)
+        .fetch_optional(&mut *trnx)
+        .await;
+    let (id, email) = match query1 {
+        Ok(Some(row)) => (
+            row.get::<String, &str>("id"),
+            row.get::<String, &str>("email"),
+        ),
+        Ok(None) => {
+            warn!("unknown, used or expired password reset token");
+            let err = Errors::InvalidResetToken;
+            return Err(err);
+        }
+        Err(err) => {
+            error!("Unable to look up reset token{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    validate_password(new_password)?;
+    let password_hash = hash(new_password, DEFAULT_COST)?;
+
+    let query2 = sqlx::query("UPDATE password_resets SET used_at = $2 WHERE id = $1")
+        .bind(&id)
+        .bind(now)
+        .execute(&mut *trnx)
+        .await;
+    if let Err(err) = query2 {
+        error!("Unable to use up reset token{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    let query3 =
+        sqlx::query("UPDATE userlogin SET password = $2, updated_at = $3 WHERE email = $1")
+            .bind(&email)
+            .bind(&password_hash)
+            .bind(now)
+            .execute(&mut *trnx)
+            .await;
+    if let Err(err) = query3 {
+        error!("Unable to update userlogin table{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    let ended = end_sessions(&mut trnx, &email, SessionScope::All).await?;
+    // whoever knew the old password may have created keys with it
+    revoke_all_api_keys(&mut *trnx, &email).await?;
+    revoke_all_oauth_grants(&mut trnx, &email).await?;
+    // the user proved they own the email, so a lockout no longer applies
+    clear_login_failures(&mut *trnx, &email).await?;
+
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit password reset{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    info!(
+        "User: {} reset their password and ended {} session(s)",
+        email,
+        ended.len()
+    );
+    Ok((email, ended.len() as u64))
+}
@@ -0,0 +1,356 @@
+use crate::errors::Errors;
+use chrono::{prelude::*, Duration};
+use dotenv::dotenv;
+use serde::Serialize;
+use sqlx::{PgPool, Postgres, Row};
+use std::collections::HashSet;
+use std::env;
+use tracing::{error, info, warn};
+
+use super::ledger::{post_entry, EntryKind, Posting, EQUITY_ACCOUNT_ID};
+use super::money::Money;
+use super::user_structs::TransactionStatus;
+
+const CORRECTION: &str = "reconciliation correction";
+/// How long a transaction may stay pending when `PENDING_TRANSACTION_TIMEOUT_SECS`
+/// is not set. Settling one takes well under a second.
+const DEFAULT_PENDING_TIMEOUT_SECS: i64 = 5 * 60;
+
+pub fn pending_timeout() -> Duration {
+    dotenv().ok();
+    let secs = env::var("PENDING_TRANSACTION_TIMEOUT_SECS")
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_PENDING_TIMEOUT_SECS);
+    Duration::seconds(secs)
+}
+
+/// Marks the transactions that have been pending for longer than `timeout` as
+/// failed, and returns how many there were. They were recorded by a request
+/// that stopped before settling them, so no money moved for them.
+///
+/// A transaction that is being settled right now is locked by its settlement,
+/// which only completes it if it is still pending, so each transaction ends up
+/// either completed or failed.
+pub async fn fail_stale_transactions(pool: &PgPool, timeout: Duration) -> Result<u64, Errors> {
+    let now = Utc::now().trunc_subsecs(6);
+    let query = sqlx::query(
+        "UPDATE transactions SET status = $1, failed_at = $2, failure_reason = $3 WHERE status = $4 AND created_at < $5",
+    )
+    .bind(TransactionStatus::Failed.as_str())
+    .bind(now)
+    .bind(Errors::TransactionAbandoned.to_string())
+    .bind(TransactionStatus::Pending.as_str())
+    .bind(now - timeout)
+    .execute(pool)
+    .await;
+    match query {
+        Ok(result) => {
+            if result.rows_affected() > 0 {
+                warn!(
+                    "marked {} abandoned pending transaction(s) as failed",
+                    result.rows_affected()
+                );
+            }
+            Ok(result.rows_affected())
+        }
+        Err(err) => {
+            error!("Unable to fail stale pending transactions{:?}", err);
+            Err(Errors::DatabaseError(err))
+        }
+    }
+}
+
+/// An account whose balances disagree with its transaction history.
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountDrift {
+    pub user_id: String,
+    pub email: String,
+    /// Initial funding plus credits minus debits from the completed and reversed
+    /// rows of the `transactions` table.
+    pub expected: Money,
+    /// The cached `users.balance`.
+    pub cached: Money,
+    /// The sum of the postings on the user's ledger account.
+    pub ledger: Money,
+    /// The transaction at which the ledger balance of the account, taken in
+    /// transaction order, stopped matching the balance expected at that point
+    /// and has not matched since. `None` when the ledger matches and only the
+    /// cached balance drifted, or when a journal entry of no transaction made
+    /// it differ.
+    pub first_divergent_transaction: Option<String>,
+}
+
+/// One change to the balance of an account.
+struct Step {
+    at: DateTime<Utc>,
+    transaction_id: Option<String>,
+    /// What the change adds to the balance expected from the history.
+    expected: Money,
+    /// What the change adds to the ledger balance.
+    ledger: Money,
+}
+
+struct AccountState {
+    expected: Money,
+    cached: Money,
+    ledger: Money,
+    first_divergent_transaction: Option<String>,
+}
+
+async fn account_state(
+    trnx: &mut sqlx::Transaction<'_, Postgres>,
+    user_id: &str,
+    email: &str,
+    cached: Money,
+) -> Result<AccountState, Errors> {
+    // accounts opened by the ledger migration start from an opening balance that
+    // already includes every earlier transaction
+    let query1 = sqlx::query("SELECT created_at FROM ledger_accounts WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(&mut *trnx)
+        .await;
+    let opened_at = match query1 {
+        Ok(row) => row.map(|row| row.get::<DateTime<Utc>, &str>("created_at")),
+        Err(err) => {
+            error!("Unable to read ledger account for {}: {:?}", email, err);
+            return Err(Errors::DatabaseError(err));
+        }
+    };
+
+    let query2 = sqlx::query(
+        "SELECT e.transaction_id, e.kind, e.created_at, p.amount FROM journal_entries e JOIN postings p ON p.entry_id = e.id WHERE p.account_id = $1",
+    )
+    .bind(user_id)
+    .fetch_all(&mut *trnx)
+    .await;
+    let postings = match query2 {
+        Ok(rows) => rows,
+        Err(err) => {
+            error!("Unable to read postings for {}: {:?}", email, err);
+            return Err(Errors::DatabaseError(err));
+        }
+    };
+
+    let query3 = sqlx::query(
+        "SELECT t.id, t.from_email, t.amount, t.created_at,
+            (SELECT COALESCE(SUM(p.amount), 0)::BIGINT FROM journal_entries e JOIN postings p ON p.entry_id = e.id
+                WHERE e.transaction_id = t.id AND p.account_id = $2) AS posted
+        FROM transactions t
+        WHERE (t.from_email = $1 OR t.to_email = $1) AND ($3::TIMESTAMPTZ IS NULL OR t.created_at >= $3)
+            AND t.status IN ('completed', 'reversed')
+        ORDER BY t.created_at, t.id",
+    )
+    .bind(email)
+    .bind(user_id)
+    .bind(opened_at)
+    .fetch_all(&mut *trnx)
+    .await;
+    let transactions = match query3 {
+        Ok(rows) => rows,
+        Err(err) => {
+            error!("Unable to read transactions for {}: {:?}", email, err);
+            return Err(Errors::DatabaseError(err));
+        }
+    };
+
+    // every change to the expected and the ledger balance, in the order it
+    // happened: each transaction with what was posted for it, and every other
+    // posting at the time of its journal entry
+    let mut steps = Vec::with_capacity(transactions.len() + postings.len());
+    let mut counted = HashSet::new();
+    for row in transactions {
+        let id = row.get::<String, &str>("id");
+        let amount = row.get::<Money, &str>("amount");
+        let signed = if row.get::<String, &str>("from_email") == email {
+            amount.checked_neg()?
+        } else {
+            amount
+        };
+        counted.insert(id.clone());
+        steps.push(Step {
+            at: row.get::<DateTime<Utc>, &str>("created_at"),
+            transaction_id: Some(id),
+            expected: signed,
+            ledger: row.get::<Money, &str>("posted"),
+        });
+    }
+    for row in postings {
+        let transaction_id = row.get::<Option<String>, &str>("transaction_id");
+        if transaction_id.is_some_and(|id| counted.contains(&id)) {
+            continue;
+        }
+        let kind = EntryKind::from_db(row.get::<&str, &str>("kind"));
+        let amount = row.get::<Money, &str>("amount");
+        // anything else without a counted transaction, such as a correction,
+        // is not part of the expected balance
+        let funding = kind == EntryKind::Funding;
+        steps.push(Step {
+            at: row.get::<DateTime<Utc>, &str>("created_at"),
+            transaction_id: None,
+            expected: if funding { amount } else { Money::ZERO },
+            ledger: amount,
+        });
+    }
+    steps.sort_by(|a, b| (a.at, &a.transaction_id).cmp(&(b.at, &b.transaction_id)));
+
+    let mut expected = Money::ZERO;
+    let mut ledger = Money::ZERO;
+    // the step the ledger has differed since, if it differs
+    let mut divergence = None;
+    for step in steps {
+        expected = expected.checked_add(step.expected)?;
+        ledger = ledger.checked_add(step.ledger)?;
+        if ledger == expected {
+            // such as after a correction
+            divergence = None;
+        } else if divergence.is_none() {
+            divergence = Some(step.transaction_id);
+        }
+    }
+    let first_divergent_transaction = divergence.flatten();
+
+    Ok(AccountState {
+        expected,
+        cached,
+        ledger,
+        first_divergent_transaction,
+    })
+}
+
+/// Compares every user's cached balance and ledger account against the balance
+/// implied by their funding and the `transactions` table, and returns the
+/// accounts that disagree. Reads from a single consistent snapshot.
+pub async fn reconcile(pool: &PgPool) -> Result<Vec<AccountDrift>, Errors> {
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            return Err(Errors::DatabaseError(err));
+        }
+    };
+    let query1 = sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ")
+        .execute(&mut *trnx)
+        .await;
+    if let Err(err) = query1 {
+        error!("Unable to set isolation level{:?}", err);
+        return Err(Errors::DatabaseError(err));
+    }
+
+    let query2 = sqlx::query("SELECT id, email, balance FROM users ORDER BY email")
+        .fetch_all(&mut *trnx)
+        .await;
+    let users = match query2 {
+        Ok(rows) => rows,
+        Err(err) => {
+            error!("Unable to list users{:?}", err);
+            return Err(Errors::DatabaseError(err));
+        }
+    };
+
+    let mut drifts = Vec::new();
+    for row in users {
+        let user_id = row.get::<String, &str>("id");
+        let email = row.get::<String, &str>("email");
+        let cached = row.get::<Money, &str>("balance");
+        let state = account_state(&mut trnx, &user_id, &email, cached).await?;
+        if state.cached != state.expected || state.ledger != state.expected {
+            warn!(
+                "account {} drifted: expected {}, cached {}, ledger {}",
+                email, state.expected, state.cached, state.ledger
+            );
+            drifts.push(AccountDrift {
+                user_id,
+                email,
+                expected: state.expected,
+                cached: state.cached,
+                ledger: state.ledger,
+                first_divergent_transaction: state.first_divergent_transaction,
+            });
+        }
+    }
+    Ok(drifts)
+}
+
+/// Brings one account back in line with its transaction history: posts a
+/// correcting journal entry against the equity account for any ledger
+/// difference and resets the cached balance. The account is re-checked under a
+/// row lock first; returns the drift that was corrected, if any.
+pub async fn fix_account(pool: &PgPool, user_id: &str) -> Result<Option<AccountDrift>, Errors> {
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            return Err(Errors::DatabaseError(err));
+        }
+    };
+    let query1 = sqlx::query("SELECT email, balance FROM users WHERE id = $1 FOR UPDATE")
+        .bind(user_id)
+        .fetch_optional(&mut *trnx)
+        .await;
+    let (email, cached) = match query1 {
+        Ok(Some(row)) => (
+            row.get::<String, &str>("email"),
+            row.get::<Money, &str>("balance"),
+        ),
+        Ok(None) => return Err(Errors::UserDoesNotExist),
+        Err(err) => {
+            error!("Unable to lock user {}: {:?}", user_id, err);
+            return Err(Errors::DatabaseError(err));
+        }
+    };
+
+    let state = account_state(&mut trnx, user_id, &email, cached).await?;
+    if state.cached == state.expected && state.ledger == state.expected {
+        return Ok(None);
+    }
+
+    if state.ledger != state.expected {
+        let delta = state.expected.checked_sub(state.ledger)?;
+        let correction = [
+            Posting {
+                account_id: user_id,
+                amount: delta,
+            },
+            Posting {
+                account_id: EQUITY_ACCOUNT_ID,
+                amount: delta.checked_neg()?,
+            },
+        ];
+        post_entry(
+            &mut trnx,
+            EntryKind::Correction,
+            None,
+            CORRECTION,
+            &correction,
+        )
+        .await?;
+    }
+    // `post_entry` moved the cached balance by the same delta, so set it outright
+    let query2 = sqlx::query("UPDATE users SET balance = $1 WHERE id = $2")
+        .bind(state.expected)
+        .bind(user_id)
+        .execute(&mut *trnx)
+        .await;
+    if let Err(err) = query2 {
+        error!("Unable to reset cached balance for {}: {:?}", email, err);
+        return Err(Errors::DatabaseError(err));
+    }
+
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit reconciliation correction{:?}", err);
+        return Err(Errors::DatabaseError(err));
+    }
+    info!(
+        "corrected account {}: expected {}, cached {}, ledger {}",
+        email, state.expected, state.cached, state.ledger
+    );
+    Ok(Some(AccountDrift {
+        user_id: user_id.to_string(),
+        email,
+        expected: state.expected,
+        cached: state.cached,
+        ledger: state.ledger,
+        first_divergent_transaction: state.first_divergent_transaction,
+    }))
+}
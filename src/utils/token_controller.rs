@@ -0,0 +1,410 @@
+use crate::errors::Errors;
+use crate::service::encode_token;
+use chrono::{prelude::*, Duration};
+use dotenv::dotenv;
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, PgPool, Row};
+use std::env;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use super::user_structs::{AccountStatus, ClientDetails, Role, Session};
+
+/// How long a refresh token can be used when `REFRESH_TOKEN_TTL_SECS` is not
+/// set.
+const DEFAULT_REFRESH_TOKEN_TTL_SECS: i64 = 30 * 24 * 60 * 60;
+
+/// An access token together with the refresh token that renews it.
+pub struct IssuedTokens {
+    pub token: String,
+    pub token_expires_at: DateTime<Utc>,
+    pub refresh_token: String,
+}
+
+pub fn refresh_token_ttl() -> Duration {
+    dotenv().ok();
+    let secs = env::var("REFRESH_TOKEN_TTL_SECS")
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_REFRESH_TOKEN_TTL_SECS);
+    Duration::seconds(secs)
+}
+
+/// A random token for the user to present later, such as a refresh token.
+/// Two v4 uuids give 244 random bits.
+pub(crate) fn new_secret_token() -> String {
+    format!(
+        "{}{}",
+        Uuid::new_v4().as_simple(),
+        Uuid::new_v4().as_simple()
+    )
+}
+
+/// Secret tokens are only stored as their hex encoded SHA-256.
+pub(crate) fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Issues an access token and a refresh token in token family `family_id`.
+pub async fn issue_tokens(
+    conn: &mut PgConnection,
+    user_id: &str,
+    email: &str,
+    role: Role,
+    family_id: &str,
+) -> Result<IssuedTokens, Errors> {
+    let (token, token_expires_at) = match encode_token(email.to_string(), role) {
+        Ok(token) => token,
+        Err(err) => {
+            error!("Unable to generate token{:?}", err);
+            let err = Errors::InternalServerError;
+            return Err(err);
+        }
+    };
+    let now = Utc::now();
+    let query1 = sqlx::query("INSERT INTO authorise (user_id, email, token, created_at, expires_at, family_id) VALUES ($1, $2, $3, $4, $5, $6)")
+        .bind(user_id)
+        .bind(email)
+        .bind(&token)
+        .bind(now)
+        .bind(token_expires_at)
+        .bind(family_id)
+        .execute(&mut *conn)
+        .await;
+    if let Err(err) = query1 {
+        error!("Unable to insert into authorise table{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+
+    let refresh_token = new_secret_token();
+    let query2 = sqlx::query("INSERT INTO refresh_tokens (id, family_id, user_id, email, token_hash, created_at, expires_at) VALUES ($1, $2, $3, $4, $5, $6, $7)")
+        .bind(Uuid::new_v4().as_simple().to_string())
+        .bind(family_id)
+        .bind(user_id)
+        .bind(email)
+        .bind(hash_token(&refresh_token))
+        .bind(now)
+        .bind(now + refresh_token_ttl())
+        .execute(&mut *conn)
+        .await;
+    if let Err(err) = query2 {
+        error!("Unable to insert into refresh_tokens table{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    Ok(IssuedTokens {
+        token,
+        token_expires_at,
+        refresh_token,
+    })
+}
+
+/// Starts a session for a login and returns its id, which is the family id of
+/// every token issued in it.
+pub async fn start_session(
+    conn: &mut PgConnection,
+    user_id: &str,
+    email: &str,
+    client: &ClientDetails,
+) -> Result<String, Errors> {
+    let session_id = Uuid::new_v4().as_simple().to_string();
+    let now = Utc::now();
+    let query = sqlx::query("INSERT INTO sessions (id, user_id, email, user_agent, ip, created_at, last_seen) VALUES ($1, $2, $3, $4, $5, $6, $7)")
+        .bind(&session_id)
+        .bind(user_id)
+        .bind(email)
+        .bind(&client.user_agent)
+        .bind(&client.ip)
+        .bind(now)
+        .bind(now)
+        .execute(&mut *conn)
+        .await;
+    if let Err(err) = query {
+        error!("Unable to insert into sessions table{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    Ok(session_id)
+}
+
+/// Which of a user's sessions [`end_sessions`] ends.
+pub enum SessionScope<'a> {
+    All,
+    Only(&'a str),
+    AllExcept(&'a str),
+}
+
+/// Returns the id of the session `token` was issued in.
+pub async fn session_of(conn: &mut PgConnection, token: &str) -> Result<String, Errors> {
+    let query = sqlx::query("SELECT family_id FROM authorise WHERE token = $1")
+        .bind(token)
+        .fetch_one(&mut *conn)
+        .await;
+    match query {
+        Ok(row) => Ok(row.get::<String, &str>("family_id")),
+        Err(err) => {
+            error!("Unable to find the session of a token{:?}", err);
+            let err = Errors::DatabaseError(err);
+            Err(err)
+        }
+    }
+}
+
+/// Ends the sessions of `email` in `scope` and returns the ids of the ones that
+/// were still active. Ending a session revokes its access tokens and its
+/// refresh tokens, so it cannot be renewed either.
+pub async fn end_sessions(
+    conn: &mut PgConnection,
+    email: &str,
+    scope: SessionScope<'_>,
+) -> Result<Vec<String>, Errors> {
+    let (only, except) = match scope {
+        SessionScope::All => (None, None),
+        SessionScope::Only(id) => (Some(id), None),
+        SessionScope::AllExcept(id) => (None, Some(id)),
+    };
+    let now = Utc::now();
+    let query1 = sqlx::query("UPDATE sessions SET revoked_at = $2 WHERE email = $1 AND revoked_at IS NULL AND ($3::text IS NULL OR id = $3) AND ($4::text IS NULL OR id <> $4) RETURNING id")
+        .bind(email)
+        .bind(now)
+        .bind(only)
+        .bind(except)
+        .fetch_all(&mut *conn)
+        .await;
+    let ended = match query1 {
+        Ok(rows) => rows
+            .iter()
+            .map(|row| row.get::<String, &str>("id"))
+            .collect::<Vec<_>>(),
+        Err(err) => {
+            error!("Unable to end sessions of {}: {:?}", email, err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let query2 = sqlx::query(
+        "UPDATE authorise SET revoked_at = $2 WHERE family_id = ANY($1) AND revoked_at IS NULL",
+    )
+    .bind(&ended)
+    .bind(now)
+    .execute(&mut *conn)
+    .await;
+    if let Err(err) = query2 {
+        error!("Unable to revoke tokens of {}: {:?}", email, err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    let query3 = sqlx::query(
+        "UPDATE refresh_tokens SET revoked_at = $2 WHERE family_id = ANY($1) AND revoked_at IS NULL",
+    )
+    .bind(&ended)
+    .bind(now)
+    .execute(&mut *conn)
+    .await;
+    if let Err(err) = query3 {
+        error!("Unable to revoke refresh tokens of {}: {:?}", email, err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    Ok(ended)
+}
+
+/// Uses up `refresh_token` and issues a new access token and refresh token in
+/// the same family.
+///
+/// A refresh token can only be used once. Presenting one that was already used
+/// means it was copied, so the whole family is revoked and both the legitimate
+/// client and whoever copied it have to log in again.
+pub async fn refresh_tokens(pool: &PgPool, refresh_token: &str) -> Result<IssuedTokens, Errors> {
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let query1 = sqlx::query("SELECT refresh_tokens.id, family_id, user_id, refresh_tokens.email, expires_at, used_at, revoked_at, users.role::text AS role, users.status FROM refresh_tokens JOIN users ON users.id = refresh_tokens.user_id WHERE token_hash = $1 FOR UPDATE OF refresh_tokens")
+        .bind(hash_token(refresh_token))
+        .fetch_optional(&mut *trnx)
+        .await;
+    let row = match query1 {
+        Ok(Some(row)) => row,
+        Ok(None) => {
+            warn!("Unknown refresh token");
+            let err = Errors::InvalidRefreshToken;
+            return Err(err);
+        }
+        Err(err) => {
+            error!("Unable to look up refresh token{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let id = row.get::<String, &str>("id");
+    let family_id = row.get::<String, &str>("family_id");
+    let user_id = row.get::<String, &str>("user_id");
+    let email = row.get::<String, &str>("email");
+
+    if row
+        .get::<Option<DateTime<Utc>>, &str>("revoked_at")
+        .is_some()
+    {
+        warn!("revoked refresh token of {} was used", email);
+        let err = Errors::InvalidRefreshToken;
+        return Err(err);
+    }
+    if row.get::<Option<DateTime<Utc>>, &str>("used_at").is_some() {
+        warn!(
+            "refresh token of {} was used twice, revoking token family {}",
+            email, family_id
+        );
+        end_sessions(&mut trnx, &email, SessionScope::Only(&family_id)).await?;
+        if let Err(err) = trnx.commit().await {
+            error!("Unable to commit token family revocation{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+        let err = Errors::InvalidRefreshToken;
+        return Err(err);
+    }
+    if row.get::<DateTime<Utc>, &str>("expires_at") < Utc::now() {
+        warn!("expired refresh token of {} was used", email);
+        let err = Errors::InvalidRefreshToken;
+        return Err(err);
+    }
+    if AccountStatus::from_db(row.get::<&str, &str>("status")) == AccountStatus::Closed {
+        warn!("closed account {} attempted to refresh its token", email);
+        let err = Errors::AccountClosed;
+        return Err(err);
+    }
+    let role = Role::from_db(row.get::<&str, &str>("role"));
+
+    let query2 = sqlx::query("UPDATE refresh_tokens SET used_at = $2 WHERE id = $1")
+        .bind(&id)
+        .bind(Utc::now())
+        .execute(&mut *trnx)
+        .await;
+    if let Err(err) = query2 {
+        error!("Unable to use up refresh token{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    let query3 = sqlx::query("UPDATE sessions SET last_seen = $2 WHERE id = $1")
+        .bind(&family_id)
+        .bind(Utc::now())
+        .execute(&mut *trnx)
+        .await;
+    if let Err(err) = query3 {
+        error!("Unable to update session {}: {:?}", family_id, err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    let tokens = issue_tokens(&mut trnx, &user_id, &email, role, &family_id).await?;
+
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit token refresh{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    info!("User: {} refreshed their token at {}", email, Utc::now());
+    Ok(tokens)
+}
+
+/// Logs out of the session `token` belongs to, or with `all_sessions` out of
+/// every session of `email`, and returns how many sessions were ended.
+pub async fn logout_user(
+    pool: &PgPool,
+    email: &str,
+    token: &str,
+    all_sessions: bool,
+) -> Result<u64, Errors> {
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let ended = if all_sessions {
+        end_sessions(&mut trnx, email, SessionScope::All).await?
+    } else {
+        let session_id = session_of(&mut trnx, token).await?;
+        end_sessions(&mut trnx, email, SessionScope::Only(&session_id)).await?
+    };
+
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit logout{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    info!("User: {} logged out of {} session(s)", email, ended.len());
+    Ok(ended.len() as u64)
+}
+
+/// Lists the active sessions of `email`, most recent first. The session `token`
+/// belongs to is marked as the current one.
+pub async fn list_sessions(
+    pool: &PgPool,
+    email: &str,
+    token: &str,
+) -> Result<Vec<Session>, Errors> {
+    // a session stays active while it has an access token or a refresh token
+    // that can still be used
+    let query = sqlx::query("SELECT id, user_agent, ip, created_at, last_seen, id = (SELECT family_id FROM authorise WHERE token = $2) AS current FROM sessions WHERE email = $1 AND revoked_at IS NULL AND (EXISTS (SELECT 1 FROM authorise WHERE family_id = sessions.id AND revoked_at IS NULL AND expires_at > $3) OR EXISTS (SELECT 1 FROM refresh_tokens WHERE family_id = sessions.id AND revoked_at IS NULL AND used_at IS NULL AND expires_at > $3)) ORDER BY created_at DESC, id")
+        .bind(email)
+        .bind(token)
+        .bind(Utc::now())
+        .fetch_all(pool)
+        .await;
+    match query {
+        Ok(rows) => Ok(rows
+            .iter()
+            .map(|row| Session {
+                id: row.get::<String, &str>("id"),
+                user_agent: row.get::<Option<String>, &str>("user_agent"),
+                ip: row.get::<Option<String>, &str>("ip"),
+                created_at: row.get::<DateTime<Utc>, &str>("created_at"),
+                last_seen: row.get::<DateTime<Utc>, &str>("last_seen"),
+                current: row.get::<Option<bool>, &str>("current").unwrap_or(false),
+            })
+            .collect()),
+        Err(err) => {
+            error!("Unable to list sessions of {}: {:?}", email, err);
+            let err = Errors::DatabaseError(err);
+            Err(err)
+        }
+    }
+}
+
+/// Ends one session of `email`. Sessions of other users and sessions that
+/// already ended are not found.
+pub async fn delete_session(pool: &PgPool, email: &str, session_id: &str) -> Result<(), Errors> {
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let ended = end_sessions(&mut trnx, email, SessionScope::Only(session_id)).await?;
+    if ended.is_empty() {
+        warn!(
+            "user: {} attempted to end unknown session {}",
+            email, session_id
+        );
+        let err = Errors::SessionNotFound;
+        return Err(err);
+    }
+
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit session deletion{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    info!("User: {} ended session {}", email, session_id);
+    Ok(())
+}
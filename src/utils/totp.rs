@@ -0,0 +1,86 @@
+//! Time-based one-time passwords as described in RFC 6238, with the settings
+//! every authenticator app understands: HMAC-SHA1, 6 digits and 30 second
+//! steps.
+
+use base32::Alphabet;
+use chrono::prelude::*;
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use sha1::Sha1;
+
+pub const DIGITS: usize = 6;
+pub const STEP_SECS: i64 = 30;
+/// Length of a new secret. RFC 4226 recommends 160 bits.
+const SECRET_BYTES: usize = 20;
+/// Codes of this many steps before or after the current one are accepted too,
+/// to allow for clocks that are slightly off.
+const ALLOWED_DRIFT_STEPS: i64 = 1;
+
+const SECRET_ALPHABET: Alphabet = Alphabet::Rfc4648 { padding: false };
+
+/// Returns a new random secret, base32 encoded as authenticator apps expect it.
+pub fn new_secret() -> String {
+    let mut bytes = [0u8; SECRET_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    base32::encode(SECRET_ALPHABET, &bytes)
+}
+
+/// The time step `at` falls in.
+pub fn time_step(at: DateTime<Utc>) -> i64 {
+    at.timestamp().div_euclid(STEP_SECS)
+}
+
+/// The code of base32 `secret` for time step `step`, or `None` if the secret is
+/// not valid base32.
+pub fn code_at(secret: &str, step: i64) -> Option<String> {
+    let key = base32::decode(SECRET_ALPHABET, secret)?;
+    let mut mac = Hmac::<Sha1>::new_from_slice(&key).ok()?;
+    mac.update(&(step as u64).to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    // dynamic truncation, RFC 4226 section 5.3
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    let code = binary % 10_u32.pow(DIGITS as u32);
+    Some(format!("{:0width$}", code, width = DIGITS))
+}
+
+/// Returns the time step `code` belongs to if it is a valid code of `secret`
+/// around `at`.
+pub fn matching_step(secret: &str, code: &str, at: DateTime<Utc>) -> Option<i64> {
+    if code.len() != DIGITS || !code.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let current = time_step(at);
+    (current - ALLOWED_DRIFT_STEPS..=current + ALLOWED_DRIFT_STEPS)
+        .find(|step| code_at(secret, *step).as_deref() == Some(code))
+}
+
+/// The `otpauth://` URI authenticator apps read from a QR code.
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        percent_encode(issuer),
+        percent_encode(account),
+        secret,
+        percent_encode(issuer),
+        DIGITS,
+        STEP_SECS
+    )
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'@' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
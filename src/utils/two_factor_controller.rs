@@ -0,0 +1,526 @@
+use crate::errors::Errors;
+use chrono::{prelude::*, Duration};
+use dotenv::dotenv;
+use rand::{rngs::OsRng, RngCore};
+use sqlx::{PgConnection, PgPool, Row};
+use std::env;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use super::login_throttle::{login_blocked, record_login_failure};
+use super::money::Money;
+use super::token_controller::{hash_token, new_secret_token};
+use super::totp;
+use super::user_controller::finish_login;
+use super::user_structs::{ClientDetails, LoginChallenge, TotpEnrollment, User};
+
+/// Name authenticator apps show next to the codes when `TOTP_ISSUER` is not
+/// set.
+const DEFAULT_TOTP_ISSUER: &str = "Transaction Service";
+/// How long a login challenge can be completed when `LOGIN_CHALLENGE_TTL_SECS`
+/// is not set.
+const DEFAULT_LOGIN_CHALLENGE_TTL_SECS: i64 = 5 * 60;
+/// Wrong codes a login challenge takes before it stops working and the user has
+/// to enter their password again.
+const MAX_CHALLENGE_ATTEMPTS: i32 = 5;
+/// Number of recovery codes handed out at a time.
+const RECOVERY_CODE_COUNT: usize = 10;
+/// Random bytes in a recovery code, 80 bits.
+const RECOVERY_CODE_BYTES: usize = 10;
+
+fn totp_issuer() -> String {
+    dotenv().ok();
+    env::var("TOTP_ISSUER").unwrap_or_else(|_| DEFAULT_TOTP_ISSUER.to_string())
+}
+
+pub fn login_challenge_ttl() -> Duration {
+    dotenv().ok();
+    let secs = env::var("LOGIN_CHALLENGE_TTL_SECS")
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_LOGIN_CHALLENGE_TTL_SECS);
+    Duration::seconds(secs)
+}
+
+/// Transfers above this amount need a fresh code from users with two-factor
+/// authentication. Unless `TOTP_TRANSFER_THRESHOLD` is set, no transfer does.
+pub fn transfer_code_threshold() -> Option<Money> {
+    dotenv().ok();
+    env::var("TOTP_TRANSFER_THRESHOLD")
+        .ok()
+        .and_then(|value| value.parse::<Money>().ok())
+}
+
+/// Codes are compared without the spaces and dashes users type them with.
+fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// The TOTP secret of a user, locked until the surrounding database
+/// transaction ends.
+struct TotpSecret {
+    user_id: String,
+    secret: String,
+    confirmed: bool,
+    last_used_step: Option<i64>,
+}
+
+async fn lock_totp_secret(
+    conn: &mut PgConnection,
+    email: &str,
+) -> Result<Option<TotpSecret>, Errors> {
+    let query = sqlx::query("SELECT user_id, secret, confirmed_at IS NOT NULL AS confirmed, last_used_step FROM totp_secrets WHERE email = $1 FOR UPDATE")
+        .bind(email)
+        .fetch_optional(&mut *conn)
+        .await;
+    match query {
+        Ok(row) => Ok(row.map(|row| TotpSecret {
+            user_id: row.get::<String, &str>("user_id"),
+            secret: row.get::<String, &str>("secret"),
+            confirmed: row.get::<bool, &str>("confirmed"),
+            last_used_step: row.get::<Option<i64>, &str>("last_used_step"),
+        })),
+        Err(err) => {
+            error!("Unable to get TOTP secret of {}: {:?}", email, err);
+            let err = Errors::DatabaseError(err);
+            Err(err)
+        }
+    }
+}
+
+/// Uses up `code` if it is a code of `secret` that is newer than every code
+/// accepted before, so that a code seen by someone else cannot be replayed.
+async fn use_totp_code(
+    conn: &mut PgConnection,
+    secret: &TotpSecret,
+    code: &str,
+) -> Result<bool, Errors> {
+    let step = match totp::matching_step(&secret.secret, &normalize_code(code), Utc::now()) {
+        Some(step) if secret.last_used_step.is_none_or(|last| step > last) => step,
+        _ => return Ok(false),
+    };
+    let query = sqlx::query("UPDATE totp_secrets SET last_used_step = $2 WHERE user_id = $1")
+        .bind(&secret.user_id)
+        .bind(step)
+        .execute(&mut *conn)
+        .await;
+    if let Err(err) = query {
+        error!("Unable to use up TOTP code{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    Ok(true)
+}
+
+async fn use_recovery_code(
+    conn: &mut PgConnection,
+    user_id: &str,
+    code: &str,
+) -> Result<bool, Errors> {
+    let query = sqlx::query("UPDATE recovery_codes SET used_at = $3 WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL")
+        .bind(user_id)
+        .bind(hash_token(&normalize_code(code)))
+        .bind(Utc::now())
+        .execute(&mut *conn)
+        .await;
+    match query {
+        Ok(result) => Ok(result.rows_affected() > 0),
+        Err(err) => {
+            error!("Unable to use up recovery code{:?}", err);
+            let err = Errors::DatabaseError(err);
+            Err(err)
+        }
+    }
+}
+
+/// A new recovery code: random bytes from the operating system as hex, in groups
+/// of five so it is easier to copy down.
+fn new_recovery_code() -> String {
+    let mut bytes = [0u8; RECOVERY_CODE_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    let hex = hex::encode(bytes);
+    format!(
+        "{}-{}-{}-{}",
+        &hex[..5],
+        &hex[5..10],
+        &hex[10..15],
+        &hex[15..]
+    )
+}
+
+/// Replaces the recovery codes of `user_id` with new ones and returns them.
+async fn new_recovery_codes(conn: &mut PgConnection, user_id: &str) -> Result<Vec<String>, Errors> {
+    let query1 = sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await;
+    if let Err(err) = query1 {
+        error!("Unable to remove recovery codes{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    let now = Utc::now();
+    let mut codes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let code = new_recovery_code();
+        let query2 = sqlx::query("INSERT INTO recovery_codes (id, user_id, code_hash, created_at) VALUES ($1, $2, $3, $4)")
+            .bind(Uuid::new_v4().as_simple().to_string())
+            .bind(user_id)
+            .bind(hash_token(&normalize_code(&code)))
+            .bind(now)
+            .execute(&mut *conn)
+            .await;
+        if let Err(err) = query2 {
+            error!("Unable to insert into recovery_codes table{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+        codes.push(code);
+    }
+    Ok(codes)
+}
+
+/// Creates a new TOTP secret for `email`. It is only asked for once the user
+/// confirms it with [`confirm_totp`]; until then enrolling again replaces it.
+pub async fn enroll_totp(pool: &PgPool, email: &str) -> Result<TotpEnrollment, Errors> {
+    let secret = totp::new_secret();
+    let query = sqlx::query("INSERT INTO totp_secrets (user_id, email, secret, created_at) SELECT id, email, $2, $3 FROM users WHERE email = $1 ON CONFLICT (user_id) DO UPDATE SET secret = EXCLUDED.secret, created_at = EXCLUDED.created_at, last_used_step = NULL WHERE totp_secrets.confirmed_at IS NULL")
+        .bind(email)
+        .bind(&secret)
+        .bind(Utc::now())
+        .execute(pool)
+        .await;
+    match query {
+        Ok(result) if result.rows_affected() == 0 => {
+            warn!("user {} attempted to enroll in 2FA again", email);
+            let err = Errors::TwoFactorAlreadyEnabled;
+            Err(err)
+        }
+        Ok(_) => {
+            info!("User: {} started 2FA enrollment", email);
+            Ok(TotpEnrollment {
+                otpauth_uri: totp::otpauth_uri(&totp_issuer(), email, &secret),
+                secret,
+            })
+        }
+        Err(err) => {
+            error!("Unable to insert into totp_secrets table{:?}", err);
+            let err = Errors::DatabaseError(err);
+            Err(err)
+        }
+    }
+}
+
+/// Turns on two-factor authentication with the secret from [`enroll_totp`],
+/// once `code` shows the user's authenticator app has it. Returns the user's
+/// recovery codes, which are not shown again.
+pub async fn confirm_totp(pool: &PgPool, email: &str, code: &str) -> Result<Vec<String>, Errors> {
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let secret = match lock_totp_secret(&mut trnx, email).await? {
+        Some(secret) if secret.confirmed => {
+            let err = Errors::TwoFactorAlreadyEnabled;
+            return Err(err);
+        }
+        Some(secret) => secret,
+        None => {
+            let err = Errors::TwoFactorNotEnabled;
+            return Err(err);
+        }
+    };
+    if !use_totp_code(&mut trnx, &secret, code).await? {
+        warn!("user {} confirmed 2FA with a wrong code", email);
+        let err = Errors::InvalidTotpCode;
+        return Err(err);
+    }
+    let query = sqlx::query("UPDATE totp_secrets SET confirmed_at = $2 WHERE user_id = $1")
+        .bind(&secret.user_id)
+        .bind(Utc::now())
+        .execute(&mut *trnx)
+        .await;
+    if let Err(err) = query {
+        error!("Unable to confirm TOTP secret{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    let codes = new_recovery_codes(&mut trnx, &secret.user_id).await?;
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit 2FA confirmation{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    info!("User: {} enabled 2FA", email);
+    Ok(codes)
+}
+
+/// Turns off two-factor authentication. `code` may be a recovery code.
+pub async fn disable_totp(pool: &PgPool, email: &str, code: &str) -> Result<(), Errors> {
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let secret = match lock_totp_secret(&mut trnx, email).await? {
+        Some(secret) if secret.confirmed => secret,
+        _ => {
+            let err = Errors::TwoFactorNotEnabled;
+            return Err(err);
+        }
+    };
+    if !use_totp_code(&mut trnx, &secret, code).await?
+        && !use_recovery_code(&mut trnx, &secret.user_id, code).await?
+    {
+        warn!("user {} attempted to disable 2FA with a wrong code", email);
+        let err = Errors::InvalidTotpCode;
+        return Err(err);
+    }
+    let query1 = sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+        .bind(&secret.user_id)
+        .execute(&mut *trnx)
+        .await;
+    if let Err(err) = query1 {
+        error!("Unable to remove recovery codes{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    let query2 = sqlx::query("DELETE FROM totp_secrets WHERE user_id = $1")
+        .bind(&secret.user_id)
+        .execute(&mut *trnx)
+        .await;
+    if let Err(err) = query2 {
+        error!("Unable to remove TOTP secret{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit disabling 2FA{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    info!("User: {} disabled 2FA", email);
+    Ok(())
+}
+
+/// Replaces the recovery codes of `email` after checking a code from their
+/// authenticator app, and returns the new codes.
+pub async fn regenerate_recovery_codes(
+    pool: &PgPool,
+    email: &str,
+    code: &str,
+) -> Result<Vec<String>, Errors> {
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let secret = match lock_totp_secret(&mut trnx, email).await? {
+        Some(secret) if secret.confirmed => secret,
+        _ => {
+            let err = Errors::TwoFactorNotEnabled;
+            return Err(err);
+        }
+    };
+    if !use_totp_code(&mut trnx, &secret, code).await? {
+        warn!("user {} asked for recovery codes with a wrong code", email);
+        let err = Errors::InvalidTotpCode;
+        return Err(err);
+    }
+    let codes = new_recovery_codes(&mut trnx, &secret.user_id).await?;
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit recovery codes{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    info!("User: {} got new recovery codes", email);
+    Ok(codes)
+}
+
+/// Starts the second step of a login for a user whose password was right.
+pub async fn create_login_challenge(
+    pool: &PgPool,
+    user_id: &str,
+    email: &str,
+) -> Result<LoginChallenge, Errors> {
+    let token = new_secret_token();
+    let now = Utc::now();
+    let expires_at = now + login_challenge_ttl();
+    let query = sqlx::query("INSERT INTO login_challenges (id, user_id, email, token_hash, created_at, expires_at) VALUES ($1, $2, $3, $4, $5, $6)")
+        .bind(Uuid::new_v4().as_simple().to_string())
+        .bind(user_id)
+        .bind(email)
+        .bind(hash_token(&token))
+        .bind(now)
+        .bind(expires_at)
+        .execute(pool)
+        .await;
+    if let Err(err) = query {
+        error!("Unable to insert into login_challenges table{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    Ok(LoginChallenge {
+        email: email.to_string(),
+        challenge_token: token,
+        expires_at,
+    })
+}
+
+/// Completes a login started by [`create_login_challenge`] with a code from
+/// the user's authenticator app or a recovery code.
+///
+/// A challenge can be completed once, and stops working after a few wrong
+/// codes. Wrong codes count as failed logins of the email, and while its logins
+/// are blocked no code is accepted.
+pub async fn complete_login_challenge(
+    pool: &PgPool,
+    challenge_token: &str,
+    code: &str,
+    client: &ClientDetails,
+) -> Result<User, Errors> {
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let now = Utc::now();
+    let query1 = sqlx::query("SELECT id, user_id, email FROM login_challenges WHERE token_hash = $1 AND used_at IS NULL AND expires_at > $2 AND attempts < $3 FOR UPDATE")
+        .bind(hash_token(challenge_token))
+        .bind(now)
+        .bind(MAX_CHALLENGE_ATTEMPTS)
+        .fetch_optional(&mut *trnx)
+        .await;
+    let (id, user_id, email) = match query1 {
+        Ok(Some(row)) => (
+            row.get::<String, &str>("id"),
+            row.get::<String, &str>("user_id"),
+            row.get::<String, &str>("email"),
+        ),
+        Ok(None) => {
+            warn!("unknown, used or expired login challenge");
+            let err = Errors::InvalidLoginChallenge;
+            return Err(err);
+        }
+        Err(err) => {
+            error!("Unable to look up login challenge{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    // a user who turned 2FA off in the meantime has to log in again
+    let secret = match lock_totp_secret(&mut trnx, &email).await? {
+        Some(secret) if secret.confirmed => secret,
+        _ => {
+            let err = Errors::InvalidLoginChallenge;
+            return Err(err);
+        }
+    };
+
+    let blocked = login_blocked(pool, &email, client).await?;
+    // a blocked attempt still uses up the challenge
+    let passed = !blocked
+        && (use_totp_code(&mut trnx, &secret, code).await?
+            || use_recovery_code(&mut trnx, &user_id, code).await?);
+    let query2 = if passed {
+        sqlx::query("UPDATE login_challenges SET used_at = $2 WHERE id = $1")
+            .bind(&id)
+            .bind(now)
+    } else {
+        sqlx::query("UPDATE login_challenges SET attempts = attempts + 1 WHERE id = $1").bind(&id)
+    };
+    if let Err(err) = query2.execute(&mut *trnx).await {
+        error!("Unable to update login challenge{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit login challenge{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    if blocked {
+        warn!("blocked 2FA login attempt for {}", email);
+        let err = Errors::InvalidTotpCode;
+        return Err(err);
+    }
+    if !passed {
+        warn!("user {} entered a wrong 2FA code at login", email);
+        record_login_failure(pool, &email, client).await?;
+        let err = Errors::InvalidTotpCode;
+        return Err(err);
+    }
+    finish_login(pool, &user_id, client).await
+}
+
+/// Checks the code a user sent with a transfer of `amount`. Only transfers
+/// above [`transfer_code_threshold`] from users with two-factor authentication
+/// need one, and it has to be a code from the authenticator app that was not
+/// used before. Wrong codes count as failed logins of the email, and while its
+/// logins are blocked no code is accepted.
+pub async fn require_transfer_code(
+    pool: &PgPool,
+    email: &str,
+    amount: Money,
+    code: Option<&str>,
+) -> Result<(), Errors> {
+    match transfer_code_threshold() {
+        Some(threshold) if amount > threshold => {}
+        _ => return Ok(()),
+    }
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let secret = match lock_totp_secret(&mut trnx, email).await? {
+        Some(secret) if secret.confirmed => secret,
+        _ => return Ok(()),
+    };
+    let code = match code {
+        Some(code) => code,
+        None => {
+            warn!("user {} sent {} without a 2FA code", email, amount);
+            let err = Errors::TotpRequired;
+            return Err(err);
+        }
+    };
+    // the client of the transfer is not counted, only the email
+    let client = ClientDetails::default();
+    if login_blocked(pool, email, &client).await? {
+        warn!("blocked 2FA code for a transfer of {} by {}", amount, email);
+        let err = Errors::InvalidTotpCode;
+        return Err(err);
+    }
+    if !use_totp_code(&mut trnx, &secret, code).await? {
+        warn!("user {} sent {} with a wrong 2FA code", email, amount);
+        record_login_failure(pool, email, &client).await?;
+        let err = Errors::InvalidTotpCode;
+        return Err(err);
+    }
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit 2FA code{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    Ok(())
+}
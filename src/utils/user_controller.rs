@@ -1,20 +1,92 @@
 use crate::errors::Errors;
-use crate::service::encode_token;
 use chrono::prelude::*;
 
-use super::user_structs::{Transaction, User, UserRegister};
+use super::admin_controller::record_admin_action;
+use super::api_key_controller::revoke_all_api_keys;
+use super::idempotency::{link_idempotent_transaction, IdempotencyClaim};
+use super::ledger::{open_user_account, post_entry, EntryKind, Posting, EQUITY_ACCOUNT_ID};
+use super::login_throttle::{clear_login_failures, login_blocked, record_login_failure};
+use super::money::Money;
+use super::oauth_controller::revoke_all_oauth_grants;
+use super::token_controller::{
+    end_sessions, issue_tokens, session_of, start_session, SessionScope,
+};
+use super::two_factor_controller::create_login_challenge;
+use super::user_structs::{
+    AccountStatus, ClientDetails, ListTransactionsQuery, LoginOutcome, Role, SortOrder,
+    Transaction, TransactionDetails, TransactionDirection, TransactionKind, TransactionPage,
+    TransactionStatus, User, UserRegister,
+};
+use super::verification_controller::send_verification_email;
 use bcrypt::{hash, verify, DEFAULT_COST};
-use sqlx::{PgPool, Row};
+use sqlx::postgres::PgRow;
+use sqlx::{PgPool, Postgres, QueryBuilder, Row};
 use tracing::{error, info, warn};
 use uuid::Uuid;
 
+const MIN_PASSWORD_CHARS: usize = 8;
+/// A bcrypt hash at `DEFAULT_COST` of a random password nobody knows. Logins for
+/// unknown emails are checked against it, so they take as long as a wrong password.
+const DUMMY_PASSWORD_HASH: &str = "$2b$12$nvyVyMj27nfndADVtfm7meSoMTITWi1WZ3RXTKlyQxHuMCq1qliE.";
+/// bcrypt ignores everything after the first 72 bytes.
+const MAX_PASSWORD_BYTES: usize = 72;
+
+/// Checks a new password against the password policy: 8 to 72 bytes long,
+/// with at least one letter and one digit.
+pub(crate) fn validate_password(password: &str) -> Result<(), Errors> {
+    if password.chars().count() < MIN_PASSWORD_CHARS {
+        let err = Errors::WeakPassword(format!(
+            "password must be at least {} characters",
+            MIN_PASSWORD_CHARS
+        ));
+        return Err(err);
+    }
+    if password.len() > MAX_PASSWORD_BYTES {
+        let err = Errors::WeakPassword(format!(
+            "password must be at most {} bytes",
+            MAX_PASSWORD_BYTES
+        ));
+        return Err(err);
+    }
+    if !password.chars().any(char::is_alphabetic) || !password.chars().any(|c| c.is_ascii_digit()) {
+        let err = Errors::WeakPassword(
+            "password must contain at least one letter and one digit".to_string(),
+        );
+        return Err(err);
+    }
+    Ok(())
+}
+
+const MAX_EMAIL_CHARS: usize = 255;
+
+/// Rejects strings that cannot be an email address. Whether the address really
+/// belongs to the user is checked by email verification.
+pub(crate) fn validate_email(email: &str) -> Result<(), Errors> {
+    let valid = match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+        }
+        None => false,
+    };
+    if !valid || email.chars().count() > MAX_EMAIL_CHARS || email.contains(char::is_whitespace) {
+        let err = Errors::InvalidEmail;
+        return Err(err);
+    }
+    Ok(())
+}
+
 pub async fn register_user(
     pool: &PgPool,
     fullname: &str,
     email: &str,
     password: &str,
-    balance: &f64,
+    balance: &Money,
 ) -> Result<User, Errors> {
+    validate_email(email)?;
     let password_hash = hash(password, DEFAULT_COST).unwrap();
     let userlogin = &UserRegister {
         id: Uuid::new_v4().as_simple().to_string(),
@@ -28,17 +100,22 @@ pub async fn register_user(
         .bind(&userlogin.email)
         .fetch_optional(pool)
         .await;
-    if query.is_ok() {
-        let row = query.unwrap();
-        if row.is_some() {
-            warn!(
-                "Registering User with email {} already exists",
-                userlogin.email
-            );
-            let err = Errors::DuplicateUserEmail;
+    if let Ok(Some(_)) = query {
+        warn!(
+            "Registering User with email {} already exists",
+            userlogin.email
+        );
+        let err = Errors::DuplicateUserEmail;
+        return Err(err);
+    }
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
             return Err(err);
         }
-    }
+    };
     let query1 = sqlx::query(
         "INSERT INTO userlogin (full_name, password, email, id, created_at, updated_at) VALUES ($1, $2, $3, $4, $5, $6)",
     )
@@ -46,274 +123,1187 @@ pub async fn register_user(
     .bind(&userlogin.password_hash)
     .bind(&userlogin.email)
     .bind(&userlogin.id)
-    .bind(&userlogin.created_at)
-    .bind(&userlogin.updated_at)
-    .execute(pool)
+    .bind(userlogin.created_at)
+    .bind(userlogin.updated_at)
+    .execute(&mut *trnx)
     .await;
 
-    if query1.is_ok() {
-        let query2 = sqlx::query(
-            "INSERT INTO users (id, full_name, role,email,balance) VALUES ($1, $2, 'user', $3,$4)",
-        )
-        .bind(&userlogin.id)
-        .bind(&userlogin.fullname)
-        .bind(&userlogin.email)
-        .bind(&balance)
-        .execute(pool)
-        .await;
+    if let Err(err) = query1 {
+        error!("Unable to insert into userlogin table{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
 
-        if query2.is_ok() {
-            let user = User {
-                id: userlogin.id.clone(),
-                fullname: userlogin.fullname.clone(),
-                email: userlogin.email.clone(),
-                role: "user".to_string(),
-                token: "Not Valid".to_string(),
-                balance: *balance,
-            };
-            return Ok(user);
-        } else {
-            let _ = sqlx::query("DELETE FROM userlogin WHERE id = $1")
-                .bind(&userlogin.id)
-                .execute(pool)
-                .await;
+    // the balance starts at zero and is set by the funding entry below
+    let query2 = sqlx::query(
+        "INSERT INTO users (id, full_name, role,email,balance) VALUES ($1, $2, $3::role, $4, 0)",
+    )
+    .bind(&userlogin.id)
+    .bind(&userlogin.fullname)
+    .bind(Role::User.as_str())
+    .bind(&userlogin.email)
+    .execute(&mut *trnx)
+    .await;
+    if let Err(err) = query2 {
+        error!("Unable to insert into users table{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
 
-            error!("Unable to insert into users table{:?}", query2);
+    open_user_account(&mut trnx, &userlogin.id, &userlogin.email).await?;
+    if *balance != Money::ZERO {
+        let funding = [
+            Posting {
+                account_id: EQUITY_ACCOUNT_ID,
+                amount: balance.checked_neg()?,
+            },
+            Posting {
+                account_id: &userlogin.id,
+                amount: *balance,
+            },
+        ];
+        post_entry(
+            &mut trnx,
+            EntryKind::Funding,
+            None,
+            "initial funding",
+            &funding,
+        )
+        .await?;
+    }
 
-            let err = Errors::DatabaseError(query2.unwrap_err());
-            return Err(err);
-        }
-    } else {
-        error!("Unable to insert into userlogin table{:?}", query1);
-        let err = Errors::DatabaseError(query1.unwrap_err());
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit user registration{:?}", err);
+        let err = Errors::DatabaseError(err);
         return Err(err);
     }
+    // the user can ask for the email again, so registration does not fail here
+    if let Err(err) = send_verification_email(&userlogin.id, &userlogin.email).await {
+        error!(
+            "Unable to send verification email to {}: {}",
+            userlogin.email, err
+        );
+    }
+
+    let user = User {
+        id: userlogin.id.clone(),
+        fullname: userlogin.fullname.clone(),
+        email: userlogin.email.clone(),
+        role: Role::User,
+        token: "Not Valid".to_string(),
+        token_expires_at: None,
+        refresh_token: None,
+        email_verified: false,
+        balance: *balance,
+    };
+    Ok(user)
 }
-pub async fn login_user(pool: &PgPool, email: &str, password: &str) -> Result<User, Errors> {
+/// Checks a user's password. Users without two-factor authentication are
+/// logged in straight away; users with it get a challenge to complete with a
+/// code from their authenticator app.
+///
+/// Failed logins are counted per email and per client IP. While either has to
+/// wait after too many failures, every login fails with the same
+/// `WrongCredentials` as a wrong password, so guessing tells nothing.
+pub async fn login_user(
+    pool: &PgPool,
+    email: &str,
+    password: &str,
+    client: &ClientDetails,
+) -> Result<LoginOutcome, Errors> {
+    let blocked = login_blocked(pool, email, client).await?;
     let query1 = sqlx::query("SELECT * FROM userlogin WHERE email = $1")
         .bind(email)
         .fetch_one(pool)
         .await;
 
-    if query1.is_ok() {
-        let row = query1.unwrap();
-        let pass = row.get::<String, &str>("password");
-        let userid = row.get::<String, &str>("id");
-        let email = row.get::<String, &str>("email");
-        let fullname = row.get::<String, &str>("full_name");
-        let dehashed_pass = verify(password, &pass);
-        match dehashed_pass {
-            Ok(value) => {
-                if value {
-                    let token = encode_token(email.clone());
-                    let tokenstr;
-                    if token.is_err() {
-                        error!("Unable to generate token{:?}", token);
-                        let err = Errors::InternalServerError;
-                        return Err(err);
-                    } else {
-                        tokenstr = token.unwrap();
-                    }
-
-                    let query2 = sqlx::query("INSERT INTO authorise (id, email, token) VALUES ($1, $2, $3) ON CONFLICT (id) DO UPDATE SET token = EXCLUDED.token")
-                        .bind(&userid)
-                        .bind(&email)
-                        .bind(&tokenstr)
-                        .execute(pool)
-                        .await;
-                    if query2.is_err() {
-                        error!("Unable to insert into authorise table{:?}", query2);
-                        let err = Errors::InternalServerError;
-                        return Err(err);
-                    }
-                    let query3 = sqlx::query("SELECT balance FROM users WHERE id = $1")
-                        .bind(&userid)
-                        .fetch_one(pool)
-                        .await;
-                    if query3.is_err() {
-                        error!("Unable to get balance for user{:?}", email);
-                        let err = Errors::DatabaseError(query3.err().unwrap());
-                        return Err(err);
-                    }
-                    let row3 = query3.unwrap();
-                    let balance = row3.get::<f64, &str>("balance");
-                    let user = User {
-                        id: userid.to_string(),
-                        fullname: fullname.to_string(),
-                        email: email.to_string(),
-                        role: "user".to_string(),
-                        token: tokenstr,
-                        balance,
-                        //TODO: get role from db
-                    };
-                    info!("User: {} logged in at {}", user.email, Utc::now());
-                    return Ok(user);
-                } else {
-                    let err = Errors::WrongCredentials;
-                    return Err(err);
-                }
-            }
-            Err(err) => {
-                error!("Password verification error: {}", err);
-                let err = Errors::BcryptError(err);
-                return Err(err);
+    let row = match query1 {
+        Ok(row) => row,
+        Err(_) => {
+            warn!("User with email {} does not exist", email);
+            let _ = verify(password, DUMMY_PASSWORD_HASH);
+            if !blocked {
+                record_login_failure(pool, email, client).await?;
             }
+            let err = Errors::WrongCredentials;
+            return Err(err);
         }
-    } else {
-        warn!("User with email {} does not exist", email);
-        let err = Errors::WrongCredentials;
+    };
+    let pass = row.get::<String, &str>("password");
+    let userid = row.get::<String, &str>("id");
+    let email = row.get::<String, &str>("email");
+    // the password is checked even while blocked, so that the answer takes as
+    // long as any other
+    match verify(password, &pass) {
+        Ok(_) if blocked => {
+            warn!("blocked login attempt for {}", email);
+            let err = Errors::WrongCredentials;
+            return Err(err);
+        }
+        Ok(true) => {}
+        Ok(false) => {
+            record_login_failure(pool, &email, client).await?;
+            let err = Errors::WrongCredentials;
+            return Err(err);
+        }
+        Err(err) => {
+            error!("Password verification error: {}", err);
+            let err = Errors::BcryptError(err);
+            return Err(err);
+        }
+    }
+    let query2 = sqlx::query("SELECT status, EXISTS (SELECT 1 FROM totp_secrets WHERE totp_secrets.user_id = users.id AND confirmed_at IS NOT NULL) AS two_factor_enabled FROM users WHERE id = $1")
+        .bind(&userid)
+        .fetch_one(pool)
+        .await;
+    let row2 = match query2 {
+        Ok(row) => row,
+        Err(err) => {
+            error!("Unable to get status for user{:?}", email);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    // frozen accounts can still log in to see their data
+    if AccountStatus::from_db(row2.get::<&str, &str>("status")) == AccountStatus::Closed {
+        warn!("closed account {} attempted to log in", email);
+        let err = Errors::AccountClosed;
+        return Err(err);
+    }
+    if row2.get::<bool, &str>("two_factor_enabled") {
+        let challenge = create_login_challenge(pool, &userid, &email).await?;
+        info!("User: {} passed the password step of login", email);
+        return Ok(LoginOutcome::TwoFactorRequired(challenge));
+    }
+    let user = finish_login(pool, &userid, client).await?;
+    Ok(LoginOutcome::LoggedIn(user))
+}
+
+/// Logs in user `user_id`, whose credentials have been checked, by starting a
+/// session and issuing its first tokens.
+pub(crate) async fn finish_login(
+    pool: &PgPool,
+    user_id: &str,
+    client: &ClientDetails,
+) -> Result<User, Errors> {
+    let query1 = sqlx::query("SELECT full_name, email, balance, role::text AS role, status, email_verified_at IS NOT NULL AS email_verified FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_one(pool)
+        .await;
+    let row = match query1 {
+        Ok(row) => row,
+        Err(err) => {
+            error!("Unable to get balance for user{:?}", user_id);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let email = row.get::<String, &str>("email");
+    let role = Role::from_db(row.get::<&str, &str>("role"));
+    // the account may have been closed while a two-factor login was under way
+    if AccountStatus::from_db(row.get::<&str, &str>("status")) == AccountStatus::Closed {
+        warn!("closed account {} attempted to log in", email);
+        let err = Errors::AccountClosed;
+        return Err(err);
+    }
+    // failures are only forgotten once the second step passed as well, so a
+    // password alone does not reset the count of wrong 2FA codes
+    clear_login_failures(pool, &email).await?;
+
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    // every login starts a new session, which is the family of its tokens
+    let session_id = start_session(&mut trnx, user_id, &email, client).await?;
+    let tokens = issue_tokens(&mut trnx, user_id, &email, role, &session_id).await?;
+    // expired tokens are rejected anyway, so their rows are only kept until the
+    // user's next login
+    let query2 = sqlx::query("DELETE FROM authorise WHERE user_id = $1 AND expires_at < $2")
+        .bind(user_id)
+        .bind(Utc::now())
+        .execute(&mut *trnx)
+        .await;
+    if let Err(err) = query2 {
+        warn!("Unable to remove expired tokens of {}: {:?}", email, err);
+    }
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit login{:?}", err);
+        let err = Errors::DatabaseError(err);
         return Err(err);
     }
+    let user = User {
+        id: user_id.to_string(),
+        fullname: row.get::<String, &str>("full_name"),
+        email,
+        role,
+        token: tokens.token,
+        token_expires_at: Some(tokens.token_expires_at),
+        refresh_token: Some(tokens.refresh_token),
+        email_verified: row.get::<bool, &str>("email_verified"),
+        balance: row.get::<Money, &str>("balance"),
+    };
+    info!("User: {} logged in at {}", user.email, Utc::now());
+    Ok(user)
 }
 
-pub async fn get_user_balance(pool: &PgPool, email: &str) -> Result<f64, Errors> {
+pub async fn get_user_balance(pool: &PgPool, email: &str) -> Result<Money, Errors> {
     let query = sqlx::query("SELECT balance FROM users WHERE email = $1")
         .bind(email)
         .fetch_one(pool)
         .await;
 
-    if query.is_ok() {
-        let row = query.unwrap();
-        let balance = row.get::<f64, &str>("balance");
-        return Ok(balance);
-    } else {
-        error!("Unable to get balance");
-        let err = Errors::DatabaseError(query.err().unwrap());
+    match query {
+        Ok(row) => {
+            let balance = row.get::<Money, &str>("balance");
+            Ok(balance)
+        }
+        Err(err) => {
+            error!("Unable to get balance");
+            let err = Errors::DatabaseError(err);
+            Err(err)
+        }
+    }
+}
+
+/// Locks the `users` row for `email` until the surrounding database transaction
+/// ends and returns the user's id (which is also their ledger account id),
+/// current balance and account status.
+///
+/// `FOR NO KEY UPDATE` is enough to serialise balance changes, and unlike `FOR
+/// UPDATE` it does not block the foreign key checks of concurrent inserts into
+/// `transactions`, which would otherwise deadlock with pending transfers.
+async fn lock_user_balance(
+    trnx: &mut sqlx::Transaction<'_, Postgres>,
+    email: &str,
+) -> Result<(String, Money, AccountStatus), Errors> {
+    let query =
+        sqlx::query("SELECT id, balance, status FROM users WHERE email = $1 FOR NO KEY UPDATE")
+            .bind(email)
+            .fetch_optional(&mut *trnx)
+            .await;
+    match query {
+        Ok(Some(row)) => Ok((
+            row.get::<String, &str>("id"),
+            row.get::<Money, &str>("balance"),
+            AccountStatus::from_db(row.get::<&str, &str>("status")),
+        )),
+        Ok(None) => {
+            error!("User with email {} does not exist", email);
+            let err = Errors::UserDoesNotExist;
+            Err(err)
+        }
+        Err(err) => {
+            error!("Unable to lock account balance for {}: {:?}", email, err);
+            let err = Errors::DatabaseError(err);
+            Err(err)
+        }
+    }
+}
+
+/// Columns read into a [`Transaction`] by [`transaction_from_row`].
+const TRANSACTION_COLUMNS: &str = "id, from_email, to_email, amount, created_at, kind, reverses_id, status, completed_at, failed_at, reversed_at, failure_reason, memo, reference, metadata";
+
+fn transaction_from_row(row: &PgRow) -> Transaction {
+    Transaction {
+        id: row.get::<String, &str>("id"),
+        from_email: row.get::<String, &str>("from_email"),
+        to_email: row.get::<String, &str>("to_email"),
+        amount: row.get::<Money, &str>("amount"),
+        trnx_time: row.get::<DateTime<Utc>, &str>("created_at"),
+        kind: TransactionKind::from_db(row.get::<&str, &str>("kind")),
+        reverses_id: row.get::<Option<String>, &str>("reverses_id"),
+        status: TransactionStatus::from_db(row.get::<&str, &str>("status")),
+        completed_at: row.get::<Option<DateTime<Utc>>, &str>("completed_at"),
+        failed_at: row.get::<Option<DateTime<Utc>>, &str>("failed_at"),
+        reversed_at: row.get::<Option<DateTime<Utc>>, &str>("reversed_at"),
+        failure_reason: row.get::<Option<String>, &str>("failure_reason"),
+        details: TransactionDetails {
+            memo: row.get::<Option<String>, &str>("memo"),
+            reference: row.get::<Option<String>, &str>("reference"),
+            metadata: row.get::<Option<serde_json::Value>, &str>("metadata"),
+        },
+    }
+}
+
+const MAX_MEMO_CHARS: usize = 280;
+const MAX_REFERENCE_CHARS: usize = 128;
+const MAX_METADATA_BYTES: usize = 4096;
+
+fn validate_details(details: &TransactionDetails) -> Result<(), Errors> {
+    if let Some(memo) = &details.memo {
+        if memo.chars().count() > MAX_MEMO_CHARS {
+            let err = Errors::InvalidTransactionDetails(format!(
+                "memo must be at most {} characters",
+                MAX_MEMO_CHARS
+            ));
+            return Err(err);
+        }
+    }
+    if let Some(reference) = &details.reference {
+        if reference.is_empty() || reference.chars().count() > MAX_REFERENCE_CHARS {
+            let err = Errors::InvalidTransactionDetails(format!(
+                "reference must be between 1 and {} characters",
+                MAX_REFERENCE_CHARS
+            ));
+            return Err(err);
+        }
+    }
+    if let Some(metadata) = &details.metadata {
+        if !metadata.is_object() {
+            let err =
+                Errors::InvalidTransactionDetails("metadata must be a JSON object".to_string());
+            return Err(err);
+        }
+        if metadata.to_string().len() > MAX_METADATA_BYTES {
+            let err = Errors::InvalidTransactionDetails(format!(
+                "metadata must be at most {} bytes",
+                MAX_METADATA_BYTES
+            ));
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Records a transaction in the `pending` state as part of `trnx`. The caller
+/// commits it on its own, before moving any money, so the attempt stays on
+/// record even if moving the money fails later.
+async fn insert_pending_transaction(
+    trnx: &mut sqlx::Transaction<'_, Postgres>,
+    from_email: &str,
+    to_email: &str,
+    amount: Money,
+    kind: TransactionKind,
+    reverses_id: Option<&str>,
+    details: &TransactionDetails,
+) -> Result<Transaction, Errors> {
+    let id = Uuid::new_v4().as_simple().to_string();
+    // Postgres keeps microseconds, so return the same time the row will hold
+    let trnx_time = Utc::now().trunc_subsecs(6);
+    let query = sqlx::query(
+        "INSERT INTO transactions (from_email, to_email, amount, id, created_at, kind, reverses_id, status, memo, reference, metadata) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+    )
+    .bind(from_email)
+    .bind(to_email)
+    .bind(amount)
+    .bind(&id)
+    .bind(trnx_time)
+    .bind(kind.as_str())
+    .bind(reverses_id)
+    .bind(TransactionStatus::Pending.as_str())
+    .bind(&details.memo)
+    .bind(&details.reference)
+    .bind(&details.metadata)
+    .execute(&mut *trnx)
+    .await;
+    match query {
+        Ok(_) => Ok(Transaction {
+            id,
+            from_email: from_email.to_string(),
+            to_email: to_email.to_string(),
+            amount,
+            trnx_time,
+            kind,
+            reverses_id: reverses_id.map(str::to_string),
+            status: TransactionStatus::Pending,
+            completed_at: None,
+            failed_at: None,
+            reversed_at: None,
+            failure_reason: None,
+            details: details.clone(),
+        }),
+        // an attempt involving an unknown account cannot reference it, so there
+        // is nothing to record
+        Err(sqlx::Error::Database(err)) if err.code().as_deref() == Some("23503") => {
+            error!("User {} or {} does not exist", from_email, to_email);
+            let err = Errors::UserDoesNotExist;
+            Err(err)
+        }
+        Err(err) => {
+            error!(" transaction failed{:?}", err);
+            let err = Errors::TransactionError;
+            Err(err)
+        }
+    }
+}
+
+/// Moves a pending transaction to `failed` and records why. Failing to record the
+/// failure is only logged, the caller reports the original error.
+async fn mark_transaction_failed(pool: &PgPool, transaction: &mut Transaction, reason: &Errors) {
+    let failed_at = Utc::now().trunc_subsecs(6);
+    let query = sqlx::query(
+        "UPDATE transactions SET status = $2, failed_at = $3, failure_reason = $4 WHERE id = $1 AND status = $5",
+    )
+    .bind(&transaction.id)
+    .bind(TransactionStatus::Failed.as_str())
+    .bind(failed_at)
+    .bind(reason.to_string())
+    .bind(TransactionStatus::Pending.as_str())
+    .execute(pool)
+    .await;
+    if let Err(err) = query {
+        error!(
+            "Unable to mark transaction {} as failed: {:?}",
+            transaction.id, err
+        );
+        return;
+    }
+    transaction.status = TransactionStatus::Failed;
+    transaction.failed_at = Some(failed_at);
+    transaction.failure_reason = Some(reason.to_string());
+}
+
+/// Locks transfer `id` for the rest of `trnx` and returns it together with the
+/// amount that has not been refunded yet.
+async fn lock_refundable(
+    trnx: &mut sqlx::Transaction<'_, Postgres>,
+    id: &str,
+) -> Result<(Transaction, Money), Errors> {
+    let query1 = sqlx::query(&format!(
+        "SELECT {TRANSACTION_COLUMNS} FROM transactions WHERE id = $1 FOR NO KEY UPDATE"
+    ))
+    .bind(id)
+    .fetch_optional(&mut *trnx)
+    .await;
+    let original = match query1 {
+        Ok(Some(row)) => transaction_from_row(&row),
+        Ok(None) => {
+            let err = Errors::TransactionNotFound;
+            return Err(err);
+        }
+        Err(err) => {
+            error!("Unable to lock transaction {}: {:?}", id, err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+
+    let query2 = sqlx::query(
+        "SELECT COALESCE(SUM(amount), 0)::BIGINT AS refunded FROM transactions WHERE reverses_id = $1 AND status = $2",
+    )
+    .bind(id)
+    .bind(TransactionStatus::Completed.as_str())
+    .fetch_one(&mut *trnx)
+    .await;
+    let refunded = match query2 {
+        Ok(row) => row.get::<Money, &str>("refunded"),
+        Err(err) => {
+            error!("Unable to sum refunds of {}: {:?}", id, err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let remaining = original.amount.checked_sub(refunded)?;
+    Ok((original, remaining))
+}
+
+/// Moves the money for a pending transaction in a single database transaction:
+/// locks the sender and receiver rows in email order, checks the balances,
+/// posts the journal entry and marks the transaction completed. Refunds also
+/// re-check the amount left on the original transfer under its row lock and
+/// mark it reversed once nothing is left. Returns the completion time.
+async fn apply_transaction(
+    pool: &PgPool,
+    transaction: &Transaction,
+) -> Result<DateTime<Utc>, Errors> {
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let from_email = transaction.from_email.as_str();
+    let to_email = transaction.to_email.as_str();
+    let amount = transaction.amount;
+
+    // the original is locked before any users row, the same order every refund uses
+    let fully_refunds = match &transaction.reverses_id {
+        Some(original_id) => {
+            let (_, remaining) = lock_refundable(&mut trnx, original_id).await?;
+            if amount > remaining {
+                warn!(
+                    "refund of {} for transaction {} exceeds the {} left to refund",
+                    amount, original_id, remaining
+                );
+                let err = Errors::RefundExceedsOriginal;
+                return Err(err);
+            }
+            amount == remaining
+        }
+        None => false,
+    };
+
+    let ((from_id, from_balance, from_status), (to_id, to_balance, to_status)) =
+        if from_email < to_email {
+            let from = lock_user_balance(&mut trnx, from_email).await?;
+            let to = lock_user_balance(&mut trnx, to_email).await?;
+            (from, to)
+        } else {
+            let to = lock_user_balance(&mut trnx, to_email).await?;
+            let from = lock_user_balance(&mut trnx, from_email).await?;
+            (from, to)
+        };
+
+    // checked under the row locks, so a freeze applies to every transfer that
+    // has not locked the account yet
+    match (from_status, to_status) {
+        (AccountStatus::Frozen, _) => {
+            warn!("frozen account {} attempted to send money", from_email);
+            let err = Errors::AccountFrozen;
+            return Err(err);
+        }
+        (AccountStatus::Closed, _) => {
+            warn!("closed account {} attempted to send money", from_email);
+            let err = Errors::AccountClosed;
+            return Err(err);
+        }
+        (_, AccountStatus::Frozen) => {
+            warn!("transfer to frozen account {} rejected", to_email);
+            let err = Errors::RecipientFrozen;
+            return Err(err);
+        }
+        (_, AccountStatus::Closed) => {
+            warn!("transfer to closed account {} rejected", to_email);
+            let err = Errors::RecipientClosed;
+            return Err(err);
+        }
+        (AccountStatus::Active, AccountStatus::Active) => {}
+    }
+    if from_balance < amount {
+        warn!("user {} has insufficient balance", from_email);
+        let err = Errors::InsufficientBalance;
+        return Err(err);
+    }
+    if let Err(err) = to_balance.checked_add(amount) {
+        warn!(
+            "transfer of {} would overflow the balance of {}",
+            amount, to_email
+        );
         return Err(err);
     }
+
+    let postings = [
+        Posting {
+            account_id: &from_id,
+            amount: amount.checked_neg()?,
+        },
+        Posting {
+            account_id: &to_id,
+            amount,
+        },
+    ];
+    let kind = transaction.kind.as_str();
+    if let Err(err) = post_entry(
+        &mut trnx,
+        EntryKind::Transaction,
+        Some(&transaction.id),
+        kind,
+        &postings,
+    )
+    .await
+    {
+        // dropping `trnx` rolls back everything above
+        error!(
+            "Unable to post {} {} to the ledger: {}",
+            kind, transaction.id, err
+        );
+        let err = Errors::TransactionError;
+        return Err(err);
+    }
+
+    let completed_at = Utc::now().trunc_subsecs(6);
+    let query1 = sqlx::query(
+        "UPDATE transactions SET status = $2, completed_at = $3 WHERE id = $1 AND status = $4",
+    )
+    .bind(&transaction.id)
+    .bind(TransactionStatus::Completed.as_str())
+    .bind(completed_at)
+    .bind(TransactionStatus::Pending.as_str())
+    .execute(&mut *trnx)
+    .await;
+    match query1 {
+        Ok(result) if result.rows_affected() == 1 => {}
+        Ok(_) => {
+            error!("Transaction {} is no longer pending", transaction.id);
+            let err = Errors::TransactionError;
+            return Err(err);
+        }
+        Err(err) => {
+            error!(
+                "Unable to complete transaction {}: {:?}",
+                transaction.id, err
+            );
+            let err = Errors::TransactionError;
+            return Err(err);
+        }
+    }
+
+    if let (true, Some(original_id)) = (fully_refunds, &transaction.reverses_id) {
+        let query2 =
+            sqlx::query("UPDATE transactions SET status = $2, reversed_at = $3 WHERE id = $1")
+                .bind(original_id)
+                .bind(TransactionStatus::Reversed.as_str())
+                .bind(completed_at)
+                .execute(&mut *trnx)
+                .await;
+        if let Err(err) = query2 {
+            error!(
+                "Unable to mark transaction {} reversed: {:?}",
+                original_id, err
+            );
+            let err = Errors::TransactionError;
+            return Err(err);
+        }
+    }
+
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit transaction{:?}", err);
+        let err = Errors::TransactionError;
+        return Err(err);
+    }
+    Ok(completed_at)
 }
 
+/// Applies a pending transaction and returns it completed. If moving the money
+/// fails the transaction is marked failed with the reason, and the error is
+/// returned.
+async fn settle_transaction(
+    pool: &PgPool,
+    mut transaction: Transaction,
+) -> Result<Transaction, Errors> {
+    match apply_transaction(pool, &transaction).await {
+        Ok(completed_at) => {
+            transaction.status = TransactionStatus::Completed;
+            transaction.completed_at = Some(completed_at);
+            Ok(transaction)
+        }
+        Err(err) => {
+            mark_transaction_failed(pool, &mut transaction, &err).await;
+            Err(err)
+        }
+    }
+}
+
+/// Moves `amount` from `from_email` to `to_email` and records the transfer
+/// together with the client's `details`.
+///
+/// The transfer is recorded as pending first. Its journal entry and the move to
+/// completed then run in a single database transaction, so either both are
+/// applied or neither is and the transfer is marked failed instead. A transfer
+/// made under an idempotency `claim` is recorded under that key as it is made.
 pub async fn create_transaction(
     pool: &PgPool,
     from_email: &str,
     to_email: &str,
-    amount: f64,
+    amount: Money,
+    details: &TransactionDetails,
+    claim: Option<&IdempotencyClaim<'_>>,
 ) -> Result<Transaction, Errors> {
-    let query1 = sqlx::query("SELECT balance FROM users WHERE email = $1")
-        .bind(&from_email)
-        .fetch_one(pool)
-        .await;
-    if query1.is_ok() {
-        let row = query1.unwrap();
-        let from_balance = row.get::<f64, &str>("balance");
-        if from_balance < amount {
-            warn!("user {} has insufficient balance", from_email);
-            let err = Errors::InsufficientBalance;
+    validate_details(details)?;
+    // unknown senders are left to the foreign key check of the insert
+    let query = sqlx::query(
+        "SELECT email_verified_at IS NOT NULL AS email_verified FROM users WHERE email = $1",
+    )
+    .bind(from_email)
+    .fetch_optional(pool)
+    .await;
+    match query {
+        Ok(Some(row)) if !row.get::<bool, &str>("email_verified") => {
+            warn!("unverified user {} attempted to send money", from_email);
+            let err = Errors::EmailNotVerified;
             return Err(err);
         }
-        let query2 = sqlx::query("SELECT balance FROM users WHERE email = $1")
-            .bind(&to_email)
-            .fetch_one(pool)
-            .await;
-        if query2.is_ok() {
-            let query3 = sqlx::query(
-                "UPDATE users
-            SET balance = CASE
-                WHEN email = $1 THEN balance - $3
-                WHEN email = $2 THEN balance + $3
-            END
-            WHERE email IN ($1, $2);",
-            )
-            .bind(from_email)
-            .bind(to_email)
-            .bind(amount)
-            .execute(pool)
+        Ok(_) => {}
+        Err(err) => {
+            error!("Unable to check the email of {}: {:?}", from_email, err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    }
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let transaction = insert_pending_transaction(
+        &mut trnx,
+        from_email,
+        to_email,
+        amount,
+        TransactionKind::Transfer,
+        None,
+        details,
+    )
+    .await?;
+    // kept together, so a retry that takes over the key sees the transfer
+    if let Some(claim) = claim {
+        link_idempotent_transaction(&mut trnx, claim, &transaction.id).await?;
+    }
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit transaction{:?}", err);
+        let err = Errors::TransactionError;
+        return Err(err);
+    }
+    settle_transaction(pool, transaction).await
+}
+
+/// Returns money from the receiver of transfer `id` to its sender, as a new
+/// `refund` transaction pointing back at the original.
+///
+/// Only the receiver of the original transfer and admins can refund it, and only
+/// once it has completed. Without an `amount` everything not refunded yet is
+/// returned; several partial refunds may never add up to more than the original
+/// amount. A transfer that has been refunded in full is marked reversed.
+///
+/// Admins can only refund other users' transfers with `staff_view`, which is
+/// for tokens from `/login`, and such a refund is recorded as an admin action.
+pub async fn refund_transaction(
+    pool: &PgPool,
+    id: &str,
+    actor_email: &str,
+    amount: Option<Money>,
+    staff_view: bool,
+) -> Result<Transaction, Errors> {
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    // checked again under the same lock when the refund is applied
+    let (original, remaining) = lock_refundable(&mut trnx, id).await?;
+    drop(trnx);
+
+    let admin_refund = original.to_email != actor_email;
+    if admin_refund {
+        match get_user_role(pool, actor_email).await {
+            Ok(Role::Admin) if staff_view => {}
+            // the sender can see the transfer but not pay themselves back
+            Ok(_) if original.from_email == actor_email => {
+                warn!(
+                    "user {} attempted to refund their own transfer {}",
+                    actor_email, id
+                );
+                let err = Errors::Forbidden;
+                return Err(err);
+            }
+            Ok(_) | Err(Errors::UserDoesNotExist) => {
+                warn!(
+                    "user {} attempted to refund transaction {} of other users",
+                    actor_email, id
+                );
+                let err = Errors::TransactionNotFound;
+                return Err(err);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    let refundable = matches!(
+        original.status,
+        TransactionStatus::Completed | TransactionStatus::Reversed
+    );
+    if original.kind != TransactionKind::Transfer {
+        let err = Errors::NotRefundable;
+        return Err(err);
+    }
+    if !refundable {
+        let err = Errors::TransactionNotCompleted;
+        return Err(err);
+    }
+
+    let amount = amount.unwrap_or(remaining);
+    if amount.is_negative() || (amount == Money::ZERO && remaining != Money::ZERO) {
+        let err = Errors::InvalidAmount;
+        return Err(err);
+    }
+    if amount > remaining || remaining == Money::ZERO {
+        warn!(
+            "refund of {} for transaction {} exceeds the {} left to refund",
+            amount, id, remaining
+        );
+        let err = Errors::RefundExceedsOriginal;
+        return Err(err);
+    }
+
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let pending = insert_pending_transaction(
+        &mut trnx,
+        &original.to_email,
+        &original.from_email,
+        amount,
+        TransactionKind::Refund,
+        Some(id),
+        &TransactionDetails::default(),
+    )
+    .await?;
+    if admin_refund {
+        // the money comes out of the receiver's account
+        let query = sqlx::query("SELECT id FROM users WHERE email = $1")
+            .bind(&original.to_email)
+            .fetch_one(&mut *trnx)
             .await;
-            if query3.is_ok() {
-                let id = Uuid::new_v4().as_simple().to_string();
-                let trnx_time = Utc::now();
-                let query4 = sqlx::query(
-                    "INSERT INTO transactions (from_email, to_email, amount,id,created_at) VALUES ($1, $2, $3, $4,$5)",
-                )
-                .bind(&from_email)
-                .bind(&to_email)
-                .bind(&amount)
-                .bind(&id)
-                .bind(&trnx_time)
-                .execute(pool)
-                .await;
-                if query4.is_ok() {
-                    let transaction = Transaction {
-                        id,
-                        from_email: from_email.to_string(),
-                        to_email: to_email.to_string(),
-                        amount,
-                        trnx_time,
-                    };
-                    return Ok(transaction);
-                } else {
-                    //reverse the balance for failed transaction
-                    error!(" transaction failed{:?}", query4);
-                    let query5 = sqlx::query(
-                        "UPDATE users
-            SET balance = CASE
-                WHEN email = $1 THEN balance + $3
-                WHEN email = $2 THEN balance - $3
-            END
-            WHERE email IN ($1, $2);",
-                    )
-                    .bind(from_email)
-                    .bind(to_email)
-                    .bind(amount)
-                    .execute(pool)
-                    .await;
-                    if query5.is_ok() {
-                        let err = Errors::TransactionError;
-                        return Err(err);
-                    } else {
-                        error!(
-                            "FATAL: error unable to reverse the account balance:{:?}",
-                            query5
-                        );
-                        let err = Errors::DatabaseError(query5.err().unwrap());
-                        return Err(err);
-                    }
-                }
-            } else {
-                error!("Unable to update account balance{:?}", query3);
-                let err = Errors::TransactionError;
+        let receiver_id = match query {
+            Ok(row) => row.get::<String, &str>("id"),
+            Err(err) => {
+                error!("Unable to get user {}: {:?}", original.to_email, err);
+                let err = Errors::DatabaseError(err);
                 return Err(err);
             }
-        } else {
-            error!("User with email {} does not exist", to_email);
+        };
+        let details = serde_json::json!({
+            "transaction_id": id,
+            "refund_id": pending.id,
+            "amount": amount,
+        });
+        record_admin_action(
+            &mut *trnx,
+            actor_email,
+            "refund_transaction",
+            Some(&receiver_id),
+            details,
+        )
+        .await?;
+    }
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit transaction{:?}", err);
+        let err = Errors::TransactionError;
+        return Err(err);
+    }
+    let refund = settle_transaction(pool, pending).await?;
+    info!(
+        "user {} refunded {} of transaction {} as {}",
+        actor_email, amount, id, refund.id
+    );
+    Ok(refund)
+}
+
+/// Largest page `list_transactions` returns, and the size used when no limit is given.
+pub(crate) const MAX_PAGE_SIZE: i64 = 200;
+pub(crate) const DEFAULT_PAGE_SIZE: i64 = 50;
+
+/// An `ILIKE` pattern matching any text that contains `text`, with the `LIKE`
+/// wildcards in it matched literally.
+pub(crate) fn contains_pattern(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+fn encode_cursor(trnx_time: &DateTime<Utc>, id: &str) -> String {
+    hex::encode(format!(
+        "{}|{}",
+        trnx_time.to_rfc3339_opts(SecondsFormat::Micros, true),
+        id
+    ))
+}
+
+fn decode_cursor(cursor: &str) -> Result<(DateTime<Utc>, String), Errors> {
+    let decoded = hex::decode(cursor).map_err(|_| Errors::InvalidCursor)?;
+    let decoded = String::from_utf8(decoded).map_err(|_| Errors::InvalidCursor)?;
+    let (trnx_time, id) = decoded.split_once('|').ok_or(Errors::InvalidCursor)?;
+    let trnx_time = DateTime::parse_from_rfc3339(trnx_time)
+        .map_err(|_| Errors::InvalidCursor)?
+        .with_timezone(&Utc);
+    Ok((trnx_time, id.to_string()))
+}
+
+/// Returns one page of the transactions `email` sent or received, filtered and
+/// ordered as requested, using keyset pagination on `(created_at, id)`.
+pub async fn list_transactions(
+    pool: &PgPool,
+    email: &str,
+    filter: &ListTransactionsQuery,
+) -> Result<TransactionPage, Errors> {
+    let limit = filter
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let cursor = match &filter.cursor {
+        Some(cursor) => Some(decode_cursor(cursor)?),
+        None => None,
+    };
+
+    let mut query = QueryBuilder::<Postgres>::new(format!(
+        "SELECT {TRANSACTION_COLUMNS} FROM transactions WHERE "
+    ));
+    match filter.direction {
+        Some(TransactionDirection::Sent) => {
+            query.push("from_email = ").push_bind(email);
+        }
+        Some(TransactionDirection::Received) => {
+            query.push("to_email = ").push_bind(email);
+        }
+        None => {
+            query
+                .push("(from_email = ")
+                .push_bind(email)
+                .push(" OR to_email = ")
+                .push_bind(email)
+                .push(")");
+        }
+    }
+    if let Some(counterparty) = &filter.counterparty {
+        query
+            .push(" AND ((from_email = ")
+            .push_bind(email)
+            .push(" AND to_email = ")
+            .push_bind(counterparty.clone())
+            .push(") OR (to_email = ")
+            .push_bind(email)
+            .push(" AND from_email = ")
+            .push_bind(counterparty.clone())
+            .push("))");
+    }
+    if let Some(kind) = filter.kind {
+        query.push(" AND kind = ").push_bind(kind.as_str());
+    }
+    if let Some(status) = filter.status {
+        query.push(" AND status = ").push_bind(status.as_str());
+    }
+    if let Some(reference) = &filter.reference {
+        query.push(" AND reference = ").push_bind(reference.clone());
+    }
+    if let Some(memo) = &filter.memo {
+        query
+            .push(" AND memo ILIKE ")
+            .push_bind(contains_pattern(memo));
+    }
+    if let Some(metadata) = &filter.metadata {
+        let metadata = match serde_json::from_str::<serde_json::Value>(metadata) {
+            Ok(metadata) if metadata.is_object() => metadata,
+            _ => {
+                let err = Errors::InvalidTransactionDetails(
+                    "metadata filter must be a JSON object".to_string(),
+                );
+                return Err(err);
+            }
+        };
+        query.push(" AND metadata @> ").push_bind(metadata);
+    }
+    if let Some(min_amount) = filter.min_amount {
+        query.push(" AND amount >= ").push_bind(min_amount);
+    }
+    if let Some(max_amount) = filter.max_amount {
+        query.push(" AND amount <= ").push_bind(max_amount);
+    }
+    if let Some(since) = filter.since {
+        query.push(" AND created_at >= ").push_bind(since);
+    }
+    if let Some(until) = filter.until {
+        query.push(" AND created_at < ").push_bind(until);
+    }
+    let (comparison, direction) = match filter.order {
+        SortOrder::Asc => (">", "ASC"),
+        SortOrder::Desc => ("<", "DESC"),
+    };
+    if let Some((trnx_time, id)) = cursor {
+        query
+            .push(" AND (created_at, id) ")
+            .push(comparison)
+            .push(" (")
+            .push_bind(trnx_time)
+            .push(", ")
+            .push_bind(id)
+            .push(")");
+    }
+    query
+        .push(format!(
+            " ORDER BY created_at {direction}, id {direction} LIMIT "
+        ))
+        // one extra row tells whether there is a next page
+        .push_bind(limit + 1);
+
+    let rows = match query.build().fetch_all(pool).await {
+        Ok(rows) => rows,
+        Err(err) => {
+            error!("Unable to get transactions");
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let mut transactions = rows.iter().map(transaction_from_row).collect::<Vec<_>>();
+
+    let mut next_cursor = None;
+    if transactions.len() as i64 > limit {
+        transactions.truncate(limit as usize);
+        next_cursor = transactions
+            .last()
+            .map(|last| encode_cursor(&last.trnx_time, &last.id));
+    }
+    Ok(TransactionPage {
+        transactions,
+        next_cursor,
+    })
+}
+
+pub async fn get_user_role(pool: &PgPool, email: &str) -> Result<Role, Errors> {
+    let query = sqlx::query("SELECT role::text AS role FROM users WHERE email = $1")
+        .bind(email)
+        .fetch_optional(pool)
+        .await;
+    match query {
+        Ok(Some(row)) => Ok(Role::from_db(row.get::<&str, &str>("role"))),
+        Ok(None) => {
             let err = Errors::UserDoesNotExist;
+            Err(err)
+        }
+        Err(err) => {
+            error!("Unable to get role for user {}", email);
+            let err = Errors::DatabaseError(err);
+            Err(err)
+        }
+    }
+}
+
+/// Fetches a single transaction for `viewer_email`. Only the sender, the
+/// receiver and staff can see it; everyone else gets `TransactionNotFound`, the
+/// same as for an id that does not exist.
+///
+/// Staff only see other users' transactions with `staff_view`, which is for
+/// tokens from `/login`. API keys and OAuth clients of staff are limited to
+/// their own transactions, as they are kept off the admin routes.
+pub async fn get_transaction(
+    pool: &PgPool,
+    id: &str,
+    viewer_email: &str,
+    staff_view: bool,
+) -> Result<Transaction, Errors> {
+    let query = sqlx::query(&format!(
+        "SELECT {TRANSACTION_COLUMNS} FROM transactions WHERE id = $1"
+    ))
+    .bind(id)
+    .fetch_optional(pool)
+    .await;
+    let row = match query {
+        Ok(Some(row)) => row,
+        Ok(None) => {
+            let err = Errors::TransactionNotFound;
             return Err(err);
         }
-    } else {
-        error!("User with email {} does not exist", to_email);
-        let err = Errors::UserDoesNotExist;
-        return Err(err);
+        Err(err) => {
+            error!("Unable to get transaction {}", id);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let transaction = transaction_from_row(&row);
+    if transaction.from_email == viewer_email || transaction.to_email == viewer_email {
+        return Ok(transaction);
+    }
+    match get_user_role(pool, viewer_email).await {
+        Ok(role) if staff_view && role != Role::User => Ok(transaction),
+        Ok(_) | Err(Errors::UserDoesNotExist) => {
+            warn!(
+                "user {} attempted to view transaction {} of other users",
+                viewer_email, id
+            );
+            let err = Errors::TransactionNotFound;
+            Err(err)
+        }
+        Err(err) => Err(err),
     }
 }
 
-pub async fn list_transactions(pool: &PgPool, email: &str) -> Result<Vec<Transaction>, Errors> {
-    let query = sqlx::query("SELECT * FROM transactions WHERE from_email = $1 OR to_email = $1")
+/// Replaces the password of `email` after checking the current one, and ends
+/// every other session of the user. Returns how many sessions were ended.
+pub async fn change_password(
+    pool: &PgPool,
+    email: &str,
+    token: &str,
+    current_password: &str,
+    new_password: &str,
+) -> Result<u64, Errors> {
+    let query1 = sqlx::query("SELECT password FROM userlogin WHERE email = $1")
         .bind(email)
-        .fetch_all(pool)
+        .fetch_one(pool)
         .await;
-    if query.is_ok() {
-        let mut transactions = Vec::new();
-        for row in query.unwrap() {
-            let id = row.get::<String, &str>("id");
-            let from_email = row.get::<String, &str>("from_email");
-            let to_email = row.get::<String, &str>("to_email");
-            let amount = row.get::<f64, &str>("amount");
-            let trnx_time = row.get::<DateTime<Utc>, &str>("created_at");
-            let transaction = Transaction {
-                id,
-                from_email,
-                to_email,
-                amount,
-                trnx_time,
-            };
-            transactions.push(transaction);
-        }
-        return Ok(transactions);
-    } else {
-        error!("Unable to get transactions");
-        let err = Errors::DatabaseError(query.err().unwrap());
+    let pass = match query1 {
+        Ok(row) => row.get::<String, &str>("password"),
+        Err(err) => {
+            error!(" Unable to find user {} ", email);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    match verify(current_password, &pass) {
+        Ok(true) => {}
+        Ok(false) => {
+            warn!("user: {} gave a wrong current password", email);
+            let err = Errors::WrongCredentials;
+            return Err(err);
+        }
+        Err(err) => {
+            error!("Password verification error: {}", err);
+            let err = Errors::BcryptError(err);
+            return Err(err);
+        }
+    }
+    if new_password == current_password {
+        let err = Errors::PasswordReused;
+        return Err(err);
+    }
+    validate_password(new_password)?;
+    let password_hash = hash(new_password, DEFAULT_COST)?;
+
+    let mut trnx = match pool.begin().await {
+        Ok(trnx) => trnx,
+        Err(err) => {
+            error!("Unable to start transaction{:?}", err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    let query2 =
+        sqlx::query("UPDATE userlogin SET password = $2, updated_at = $3 WHERE email = $1")
+            .bind(email)
+            .bind(&password_hash)
+            .bind(Utc::now())
+            .execute(&mut *trnx)
+            .await;
+    if let Err(err) = query2 {
+        error!("Unable to update userlogin table{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    // whoever knew the old password may still be logged in somewhere else
+    let session_id = session_of(&mut trnx, token).await?;
+    let ended = end_sessions(&mut trnx, email, SessionScope::AllExcept(&session_id)).await?;
+    // and may have created keys with it, as after a reset
+    revoke_all_api_keys(&mut *trnx, email).await?;
+    revoke_all_oauth_grants(&mut trnx, email).await?;
+
+    if let Err(err) = trnx.commit().await {
+        error!("Unable to commit password change{:?}", err);
+        let err = Errors::DatabaseError(err);
         return Err(err);
     }
+    info!(
+        "User: {} changed their password and ended {} other session(s)",
+        email,
+        ended.len()
+    );
+    Ok(ended.len() as u64)
 }
 
 pub async fn update_user(
@@ -326,46 +1316,47 @@ pub async fn update_user(
         .bind(user_email)
         .fetch_one(pool)
         .await;
-    if query1.is_ok() {
-        let row = query1.unwrap();
-        let fullname = row.get::<String, &str>("full_name");
-        if fullname != old_name {
-            let err = Errors::WrongCredentials;
+    let row = match query1 {
+        Ok(row) => row,
+        Err(err) => {
+            error!(" Unable to find user {} ", user_email);
+            let err = Errors::DatabaseError(err);
             return Err(err);
         }
-        let query2 = sqlx::query("UPDATE users SET full_name = $1 WHERE email = $2")
-            .bind(new_name)
-            .bind(user_email)
-            .execute(pool)
-            .await;
-        if query2.is_ok() {
-            let query3 = sqlx::query("UPDATE userlogin SET full_name = $1 WHERE email = $2")
-                .bind(new_name)
+    };
+    let fullname = row.get::<String, &str>("full_name");
+    if fullname != old_name {
+        let err = Errors::WrongCredentials;
+        return Err(err);
+    }
+    let query2 = sqlx::query("UPDATE users SET full_name = $1 WHERE email = $2")
+        .bind(new_name)
+        .bind(user_email)
+        .execute(pool)
+        .await;
+    if let Err(err) = query2 {
+        error!("Unable to update users table{:?}", err);
+        let err = Errors::DatabaseError(err);
+        return Err(err);
+    }
+    let query3 = sqlx::query("UPDATE userlogin SET full_name = $1 WHERE email = $2")
+        .bind(new_name)
+        .bind(user_email)
+        .execute(pool)
+        .await;
+    match query3 {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            let query4 = sqlx::query("UPDATE users SET full_name = $1 WHERE email = $2")
+                .bind(old_name)
                 .bind(user_email)
                 .execute(pool)
                 .await;
-            if query3.is_ok() {
-                return Ok(());
-            } else {
-                let query4 = sqlx::query("UPDATE users SET full_name = $1 WHERE email = $2")
-                    .bind(old_name)
-                    .bind(user_email)
-                    .execute(pool)
-                    .await;
-                if query4.is_err() {
-                    error!("FATAL:Unable to revert users table{:?}", query4);
-                }
-                let err = Errors::DatabaseError(query3.err().unwrap());
-                return Err(err);
+            if query4.is_err() {
+                error!("FATAL:Unable to revert users table{:?}", query4);
             }
-        } else {
-            error!("Unable to update users table{:?}", query2);
-            let err = Errors::DatabaseError(query2.err().unwrap());
-            return Err(err);
+            let err = Errors::DatabaseError(err);
+            Err(err)
         }
-    } else {
-        error!(" Unable to find user {} ", user_email);
-        let err = Errors::DatabaseError(query1.err().unwrap());
-        return Err(err);
     }
 }
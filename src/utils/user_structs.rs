@@ -1,14 +1,125 @@
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
 
+use super::money::Money;
+
+/// Values of the `role` enum in the database. Every new user is a `User`; the
+/// other roles are staff roles granted by hand.
+#[derive(Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    User,
+    Admin,
+    Support,
+    Auditor,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Admin => "admin",
+            Role::Support => "support",
+            Role::Auditor => "auditor",
+        }
+    }
+
+    pub fn from_db(role: &str) -> Self {
+        match role {
+            "admin" => Role::Admin,
+            "support" => Role::Support,
+            "auditor" => Role::Auditor,
+            _ => Role::User,
+        }
+    }
+}
+
+/// Whether an account can be used. Admins freeze, unfreeze and close accounts.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountStatus {
+    Active,
+    /// Can log in and read, but cannot send or receive money.
+    Frozen,
+    /// Can no longer log in. Closing an account is final.
+    Closed,
+}
+
+impl AccountStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AccountStatus::Active => "active",
+            AccountStatus::Frozen => "frozen",
+            AccountStatus::Closed => "closed",
+        }
+    }
+
+    pub fn from_db(status: &str) -> Self {
+        match status {
+            "frozen" => AccountStatus::Frozen,
+            "closed" => AccountStatus::Closed,
+            _ => AccountStatus::Active,
+        }
+    }
+}
+
+/// A user as staff see it in the admin API.
+#[derive(Serialize)]
+pub struct UserAccount {
+    pub id: String,
+    pub fullname: String,
+    pub email: String,
+    pub role: Role,
+    pub status: AccountStatus,
+    pub balance: Money,
+}
+
+/// Query string of `GET /admin/users`. Results are ordered by email.
+#[derive(Deserialize, Serialize, Default)]
+pub struct UserSearchQuery {
+    /// Case-insensitive substring of the email or full name.
+    pub q: Option<String>,
+    pub role: Option<Role>,
+    pub status: Option<AccountStatus>,
+    pub limit: Option<i64>,
+    /// `next_cursor` from the previous page, used with the same filters.
+    pub cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct UserPage {
+    pub users: Vec<UserAccount>,
+    pub next_cursor: Option<String>,
+}
+
+/// Body of the freeze, unfreeze and close admin endpoints.
+#[derive(Deserialize)]
+pub struct AccountStatusRequest {
+    pub reason: String,
+}
+
+/// Body of `PUT /admin/users/:id/role`.
+#[derive(Deserialize)]
+pub struct ChangeRoleRequest {
+    pub role: Role,
+}
+
+// `id` is not part of any response yet
+#[allow(dead_code)]
 #[derive(Deserialize)]
 pub struct User {
     pub id: String,
     pub fullname: String,
     pub email: String,
-    pub role: String,
+    pub role: Role,
     pub token: String,
-    pub balance: f64,
+    /// Only set at login.
+    pub token_expires_at: Option<DateTime<Utc>>,
+    /// Only set at login.
+    pub refresh_token: Option<String>,
+    pub email_verified: bool,
+    pub balance: Money,
 }
 
 pub struct UserRegister {
@@ -25,7 +136,7 @@ pub struct RegisterRequest {
     pub fullname: String,
     pub password: String,
     pub email: String,
-    pub balance: Option<f64>,
+    pub balance: Option<Money>,
 }
 
 #[derive(Deserialize)]
@@ -34,22 +145,340 @@ pub struct LoginRequest {
     pub email: String,
 }
 
+/// Result of a login with the right password.
+pub enum LoginOutcome {
+    LoggedIn(User),
+    /// The user has two-factor authentication enabled and has to complete the
+    /// challenge with `POST /login/2fa`.
+    TwoFactorRequired(LoginChallenge),
+}
+
+pub struct LoginChallenge {
+    pub email: String,
+    pub challenge_token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Body of `POST /login/2fa`. `code` is a code from the user's authenticator
+/// app or one of their recovery codes.
+#[derive(Deserialize)]
+pub struct TwoFactorLoginRequest {
+    pub challenge_token: String,
+    pub code: String,
+}
+
+/// A TOTP secret that has been created but not confirmed yet.
+#[derive(Serialize)]
+pub struct TotpEnrollment {
+    /// The secret, base32 encoded, for users who type it in by hand.
+    pub secret: String,
+    /// The secret as an `otpauth://` URI for authenticator apps, usually shown
+    /// as a QR code.
+    pub otpauth_uri: String,
+}
+
+/// Body of the `/2fa` endpoints that ask for a code.
+#[derive(Deserialize)]
+pub struct TotpCodeRequest {
+    pub code: String,
+}
+
+/// The device a login came from, as far as the request tells.
+#[derive(Default)]
+pub struct ClientDetails {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// One login of a user, as listed by `GET /sessions`.
+#[derive(Serialize)]
+pub struct Session {
+    pub id: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    /// Whether the request listing the sessions was made in this session.
+    pub current: bool,
+}
+
+/// What an API key may be used for. A key can only call the routes of its
+/// scopes; everything else takes a token from `/login`.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ApiScope {
+    /// Listing and looking up transactions.
+    #[serde(rename = "transactions:read")]
+    TransactionsRead,
+    /// Sending money and refunding it.
+    #[serde(rename = "transactions:write")]
+    TransactionsWrite,
+    #[serde(rename = "balance:read")]
+    BalanceRead,
+}
+
+impl ApiScope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ApiScope::TransactionsRead => "transactions:read",
+            ApiScope::TransactionsWrite => "transactions:write",
+            ApiScope::BalanceRead => "balance:read",
+        }
+    }
+
+    /// Scopes that are no longer known grant nothing.
+    pub fn from_db(scope: &str) -> Option<Self> {
+        match scope {
+            "transactions:read" => Some(ApiScope::TransactionsRead),
+            "transactions:write" => Some(ApiScope::TransactionsWrite),
+            "balance:read" => Some(ApiScope::BalanceRead),
+            _ => None,
+        }
+    }
+}
+
+/// Body of `POST /api-keys`.
+#[derive(Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    /// Keys without an expiry work until they are revoked.
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// An API key as listed by `GET /api-keys`. The key itself is only shown once,
+/// when it is created.
+#[derive(Serialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<ApiScope>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// Body of `POST /oauth/clients`.
+#[derive(Deserialize)]
+pub struct RegisterClientRequest {
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    /// The most a token of the client can be allowed.
+    pub scopes: Vec<ApiScope>,
+    /// Public clients, such as mobile apps, cannot keep a secret. They get none
+    /// and can only use the authorization-code grant.
+    #[serde(default)]
+    pub public: bool,
+}
+
+/// An OAuth client as its owner sees it. The secret is only shown once, when
+/// the client is registered.
+#[derive(Serialize)]
+pub struct OAuthClient {
+    pub client_id: String,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub scopes: Vec<ApiScope>,
+    pub public: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Query string of `GET /oauth/authorize`, and body of `POST /oauth/authorize`
+/// together with the user's decision.
+#[derive(Deserialize)]
+pub struct AuthorizationRequest {
+    pub response_type: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    /// Space separated scopes. All scopes of the client when missing.
+    #[serde(default)]
+    pub scope: Option<String>,
+    #[serde(default)]
+    pub state: Option<String>,
+    pub code_challenge: String,
+    pub code_challenge_method: String,
+}
+
+/// Body of `POST /oauth/authorize`.
+#[derive(Deserialize)]
+pub struct AuthorizationDecision {
+    #[serde(flatten)]
+    pub request: AuthorizationRequest,
+    pub approve: bool,
+}
+
+/// What the user is asked to allow, as returned by `GET /oauth/authorize`.
+#[derive(Serialize)]
+pub struct AuthorizationPrompt {
+    pub client_id: String,
+    pub client_name: String,
+    pub redirect_uri: String,
+    pub scopes: Vec<ApiScope>,
+    /// Whether the user already allowed the client these scopes.
+    pub consented: bool,
+}
+
+/// A client a user has allowed to act on their behalf.
+#[derive(Serialize)]
+pub struct OAuthConsent {
+    pub client_id: String,
+    pub client_name: String,
+    pub scopes: Vec<ApiScope>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Form body of `POST /oauth/token`. Which fields are needed depends on
+/// `grant_type`.
+#[derive(Deserialize)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    #[serde(default)]
+    pub code: Option<String>,
+    #[serde(default)]
+    pub redirect_uri: Option<String>,
+    #[serde(default)]
+    pub code_verifier: Option<String>,
+    #[serde(default)]
+    pub client_id: Option<String>,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    #[serde(default)]
+    pub scope: Option<String>,
+}
+
+/// Body of `POST /token/refresh`.
+#[derive(Deserialize)]
+pub struct RefreshTokenRequest {
+    pub refresh_token: String,
+}
+
+/// Query string of `POST /logout`.
+#[derive(Deserialize)]
+pub struct LogoutQuery {
+    /// Revokes every token of the user instead of only the current one.
+    #[serde(default)]
+    pub all: bool,
+}
+
 #[derive(Deserialize)]
 pub struct UserAuth {
     pub email: String,
 }
 
+/// Body of `PUT /user/password`.
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+/// Body of `POST /verify-email`.
+#[derive(Deserialize)]
+pub struct VerifyEmailRequest {
+    pub token: String,
+}
+
+/// Body of `POST /password/forgot`.
+#[derive(Deserialize)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+/// Body of `POST /password/reset`.
+#[derive(Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub new_password: String,
+}
+
 #[derive(Deserialize)]
 pub struct ModifyUser {
     pub old_name: String,
     pub new_name: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct TransactionRequest {
     pub from_email: String,
     pub to_email: String,
-    pub amount: f64,
+    pub amount: Money,
+    #[serde(flatten)]
+    pub details: TransactionDetails,
+}
+
+/// Optional details a client attaches to a transfer to match it against its own
+/// records. Fields that are not set are left out of the JSON, so requests that
+/// do not use them serialize the same as before.
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq, Debug)]
+pub struct TransactionDetails {
+    /// Free text, at most 280 characters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memo: Option<String>,
+    /// The client's own id for the transfer, such as an invoice number, at most
+    /// 128 characters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    /// Any JSON object of at most 4 KiB.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionKind {
+    Transfer,
+    /// Money returned for an earlier transfer, named by `reverses_id`.
+    Refund,
+}
+
+impl TransactionKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TransactionKind::Transfer => "transfer",
+            TransactionKind::Refund => "refund",
+        }
+    }
+
+    pub fn from_db(kind: &str) -> Self {
+        match kind {
+            "refund" => TransactionKind::Refund,
+            _ => TransactionKind::Transfer,
+        }
+    }
+}
+
+/// Where a transaction is in its lifecycle: `Pending` until the money has
+/// moved, then `Completed` or `Failed`. A completed transfer becomes `Reversed`
+/// once it has been refunded in full.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionStatus {
+    Pending,
+    Completed,
+    Failed,
+    Reversed,
+}
+
+impl TransactionStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TransactionStatus::Pending => "pending",
+            TransactionStatus::Completed => "completed",
+            TransactionStatus::Failed => "failed",
+            TransactionStatus::Reversed => "reversed",
+        }
+    }
+
+    pub fn from_db(status: &str) -> Self {
+        match status {
+            "pending" => TransactionStatus::Pending,
+            "failed" => TransactionStatus::Failed,
+            "reversed" => TransactionStatus::Reversed,
+            _ => TransactionStatus::Completed,
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -57,6 +486,69 @@ pub struct Transaction {
     pub id: String,
     pub from_email: String,
     pub to_email: String,
-    pub amount: f64,
+    pub amount: Money,
     pub trnx_time: DateTime<Utc>,
+    pub kind: TransactionKind,
+    pub reverses_id: Option<String>,
+    pub status: TransactionStatus,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub failed_at: Option<DateTime<Utc>>,
+    pub reversed_at: Option<DateTime<Utc>>,
+    /// Why a failed transaction did not go through.
+    pub failure_reason: Option<String>,
+    #[serde(flatten)]
+    pub details: TransactionDetails,
+}
+
+/// Body of `POST /transaction/:id/refund`. Without an amount, everything not
+/// refunded yet is returned.
+#[derive(Deserialize)]
+pub struct RefundRequest {
+    pub amount: Option<Money>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionDirection {
+    Sent,
+    Received,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Query string of `GET /transaction`. Every filter is optional; results are
+/// ordered by `(created_at, id)`.
+#[derive(Deserialize, Default)]
+pub struct ListTransactionsQuery {
+    pub direction: Option<TransactionDirection>,
+    pub kind: Option<TransactionKind>,
+    pub status: Option<TransactionStatus>,
+    pub counterparty: Option<String>,
+    /// Exact match on the client reference.
+    pub reference: Option<String>,
+    /// Case-insensitive substring of the memo.
+    pub memo: Option<String>,
+    /// A JSON object the metadata must contain, e.g. `{"invoice":"INV-7"}`.
+    pub metadata: Option<String>,
+    pub min_amount: Option<Money>,
+    pub max_amount: Option<Money>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub order: SortOrder,
+    pub limit: Option<i64>,
+    /// `next_cursor` from the previous page, used with the same filters and order.
+    pub cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TransactionPage {
+    pub transactions: Vec<Transaction>,
+    pub next_cursor: Option<String>,
 }
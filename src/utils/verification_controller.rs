@@ -0,0 +1,89 @@
+use crate::config::mailer::{send_email, Email};
+use crate::errors::Errors;
+use crate::service::{
+    decode_verification_token, encode_verification_token, verification_token_ttl,
+};
+use chrono::prelude::*;
+use dotenv::dotenv;
+use sqlx::{PgPool, Row};
+use std::env;
+use tracing::{error, info, warn};
+
+/// Base URL of the verification links when `APP_URL` is not set.
+const DEFAULT_APP_URL: &str = "http://localhost:3042";
+
+fn app_url() -> String {
+    dotenv().ok();
+    env::var("APP_URL").unwrap_or_else(|_| DEFAULT_APP_URL.to_string())
+}
+
+/// Emails `email` a signed link that verifies it.
+pub async fn send_verification_email(user_id: &str, email: &str) -> Result<(), Errors> {
+    let token = encode_verification_token(user_id, email)?;
+    let mail = Email {
+        to: email.to_string(),
+        subject: "Verify your email".to_string(),
+        body: format!(
+            "Open this link to verify your email and start sending money:\n\n{}/verify-email?token={}\n\nThe link expires in {} hours.",
+            app_url().trim_end_matches('/'),
+            token,
+            verification_token_ttl().num_hours()
+        ),
+    };
+    send_email(mail).await?;
+    info!("verification email sent to {}", email);
+    Ok(())
+}
+
+/// Marks the email in a verification token as verified and returns it. Using a
+/// link again after that changes nothing.
+pub async fn verify_email(pool: &PgPool, token: &str) -> Result<String, Errors> {
+    let claims = decode_verification_token(token)?;
+    let query = sqlx::query("UPDATE users SET email_verified_at = COALESCE(email_verified_at, $3) WHERE id = $1 AND email = $2 RETURNING email")
+        .bind(&claims.sub)
+        .bind(&claims.email)
+        .bind(Utc::now())
+        .fetch_optional(pool)
+        .await;
+    match query {
+        Ok(Some(row)) => {
+            let email = row.get::<String, &str>("email");
+            info!("User: {} verified their email", email);
+            Ok(email)
+        }
+        Ok(None) => {
+            warn!("verification token for unknown user {}", claims.email);
+            let err = Errors::InvalidVerificationToken;
+            Err(err)
+        }
+        Err(err) => {
+            error!("Unable to verify email {}: {:?}", claims.email, err);
+            let err = Errors::DatabaseError(err);
+            Err(err)
+        }
+    }
+}
+
+/// Sends the verification email of `email` again, with a new link.
+pub async fn resend_verification_email(pool: &PgPool, email: &str) -> Result<(), Errors> {
+    let query = sqlx::query("SELECT id, email_verified_at FROM users WHERE email = $1")
+        .bind(email)
+        .fetch_one(pool)
+        .await;
+    let row = match query {
+        Ok(row) => row,
+        Err(err) => {
+            error!("Unable to find user {}: {:?}", email, err);
+            let err = Errors::DatabaseError(err);
+            return Err(err);
+        }
+    };
+    if row
+        .get::<Option<DateTime<Utc>>, &str>("email_verified_at")
+        .is_some()
+    {
+        let err = Errors::EmailAlreadyVerified;
+        return Err(err);
+    }
+    send_verification_email(&row.get::<String, &str>("id"), email).await
+}
@@ -0,0 +1,191 @@
+use sqlx::Row;
+use std::sync::Arc;
+use transaction_service::config::db::get_conn;
+use transaction_service::trnx_service;
+
+#[cfg(test)]
+use ::axum_test::TestServer;
+#[cfg(test)]
+use ::axum_test::TestServerConfig;
+use ::serde::Deserialize;
+
+fn test_server() -> TestServer {
+    std::env::set_var("RATE_LIMIT_AUTH_PER_MINUTE", "100000");
+    std::env::set_var("RATE_LIMIT_MONEY_PER_MINUTE", "100000");
+    let app = trnx_service();
+
+    // transfers are allowed to fail with insufficient balance here, so no
+    // status expectation is set by default
+    let config = TestServerConfig::builder().mock_transport().build();
+
+    TestServer::new_with_config(app, config).unwrap()
+}
+
+#[cfg(test)]
+mod test_concurrent_transactions {
+    use super::*;
+    use ::serde_json::json;
+    use axum_test::http::HeaderValue;
+
+    const ACCOUNTS: usize = 4;
+    const INITIAL_BALANCE: i64 = 100;
+    const TRANSFERS: usize = 300;
+
+    #[derive(Debug, Deserialize)]
+    struct LoginResponse {
+        token: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct BalanceResponse {
+        balance: String,
+    }
+
+    /// Converts a `"12.34"` wire amount into cents.
+    fn cents(amount: &str) -> i64 {
+        let (major, minor) = amount.split_once('.').unwrap();
+        let major = major.parse::<i64>().unwrap();
+        let minor = minor.parse::<i64>().unwrap();
+        if amount.starts_with('-') {
+            major * 100 - minor
+        } else {
+            major * 100 + minor
+        }
+    }
+
+    fn bearer(token: &str) -> HeaderValue {
+        HeaderValue::from_str(format!("Bearer {}", token).as_str()).unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn parallel_transfers_keep_balances_consistent() {
+        let server = Arc::new(test_server());
+        let run_id = uuid::Uuid::new_v4().as_simple().to_string();
+
+        let mut accounts = Vec::new();
+        for i in 0..ACCOUNTS {
+            let email = format!("concurrency-{}-{}@test.com", run_id, i);
+            server
+                .post("/register")
+                .json(&json!({
+                    "email": email,
+                    "password": "testpassword123",
+                    "fullname": format!("concurrency user {}", i),
+                    "balance": INITIAL_BALANCE.to_string()
+                }))
+                .await
+                .assert_status(axum_test::http::StatusCode::CREATED);
+            sqlx::query("UPDATE users SET email_verified_at = now() WHERE email = $1")
+                .bind(&email)
+                .execute(get_conn().await)
+                .await
+                .unwrap();
+            let login = server
+                .post("/login")
+                .json(&json!({
+                    "email": email,
+                    "password": "testpassword123"
+                }))
+                .await
+                .json::<LoginResponse>();
+            accounts.push((email, login.token));
+        }
+        let accounts = Arc::new(accounts);
+
+        // axum_test request futures are not `Send`, so the transfers are driven
+        // concurrently from a single `LocalSet`; every request still holds its own
+        // database connection, so the transfers overlap inside Postgres
+        let local = tokio::task::LocalSet::new();
+        let completed = local
+            .run_until(async {
+                let mut transfers = tokio::task::JoinSet::new();
+                for i in 0..TRANSFERS {
+                    let server = server.clone();
+                    let accounts = accounts.clone();
+                    transfers.spawn_local(async move {
+                        let from = i % ACCOUNTS;
+                        let to = (i * 7 + 1 + i / ACCOUNTS) % ACCOUNTS;
+                        let to = if to == from { (to + 1) % ACCOUNTS } else { to };
+                        let amount = (i % 40 + 1).to_string();
+                        let (from_email, token) = &accounts[from];
+                        let (to_email, _) = &accounts[to];
+                        server
+                            .post("/transaction")
+                            .add_header(axum_test::http::header::AUTHORIZATION, bearer(token))
+                            .json(&json!({
+                                "from_email": from_email,
+                                "to_email": to_email,
+                                "amount": amount
+                            }))
+                            .await
+                            .status_code()
+                    });
+                }
+
+                let mut completed = 0;
+                while let Some(status) = transfers.join_next().await {
+                    let status = status.unwrap();
+                    assert!(
+                        status == 201 || status == 400,
+                        "unexpected transfer status {}",
+                        status
+                    );
+                    if status == 201 {
+                        completed += 1;
+                    }
+                }
+                completed
+            })
+            .await;
+        assert!(completed > 0);
+
+        let mut total = 0;
+        for (email, token) in accounts.iter() {
+            let balance = cents(
+                &server
+                    .get("/balance")
+                    .add_header(axum_test::http::header::AUTHORIZATION, bearer(token))
+                    .json(&json!({ "email": email }))
+                    .await
+                    .json::<BalanceResponse>()
+                    .balance,
+            );
+            assert!(balance >= 0, "{} went negative: {}", email, balance);
+            total += balance;
+        }
+        assert_eq!(total, INITIAL_BALANCE * 100 * ACCOUNTS as i64);
+
+        // every cached balance matches its ledger account, and the ledger as a
+        // whole still balances
+        let pool = get_conn().await;
+        for (email, _) in accounts.iter() {
+            let row = sqlx::query(
+                "SELECT u.balance, COALESCE(SUM(p.amount), 0)::BIGINT AS ledger_balance FROM users u LEFT JOIN postings p ON p.account_id = u.id WHERE u.email = $1 GROUP BY u.balance",
+            )
+            .bind(email)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+            assert_eq!(
+                row.get::<i64, &str>("balance"),
+                row.get::<i64, &str>("ledger_balance"),
+                "{} does not match its ledger account",
+                email
+            );
+        }
+        let unbalanced = sqlx::query(
+            "SELECT COUNT(*) FROM (SELECT entry_id FROM postings GROUP BY entry_id HAVING SUM(amount) <> 0) AS entries",
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+        .get::<i64, usize>(0);
+        assert_eq!(unbalanced, 0);
+        let ledger_total = sqlx::query("SELECT COALESCE(SUM(amount), 0)::BIGINT FROM postings")
+            .fetch_one(pool)
+            .await
+            .unwrap()
+            .get::<i64, usize>(0);
+        assert_eq!(ledger_total, 0);
+    }
+}
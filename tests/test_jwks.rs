@@ -0,0 +1,271 @@
+use transaction_service::config::jwt_keys::{verification_key_files, JwtKeys};
+use transaction_service::errors::Errors;
+use transaction_service::trnx_service;
+
+#[cfg(test)]
+use ::axum_test::TestServer;
+#[cfg(test)]
+use ::axum_test::TestServerConfig;
+use ::serde::Deserialize;
+
+/// Test keys only; they sign nothing outside these tests.
+const CURRENT_KEY: &str = "tests/keys/rs256-current.pem";
+const PREVIOUS_KEY: &str = "tests/keys/rs256-previous.pem";
+const ED25519_KEY: &str = "tests/keys/ed25519.pem";
+
+fn verification_keys() -> Vec<(String, String)> {
+    vec![
+        (
+            "current".to_string(),
+            "tests/keys/rs256-current.pub.pem".to_string(),
+        ),
+        (
+            "previous".to_string(),
+            "tests/keys/rs256-previous.pub.pem".to_string(),
+        ),
+        ("ed".to_string(), "tests/keys/ed25519.pub.pem".to_string()),
+    ]
+}
+
+/// Signs with `current` and accepts the tokens of every key in
+/// [`verification_keys`], as the server of these tests does.
+fn server_keys() -> JwtKeys {
+    JwtKeys::from_pem_files("current", CURRENT_KEY, &verification_keys()).unwrap()
+}
+
+fn test_server() -> TestServer {
+    std::env::set_var("JWT_SIGNING_KEY_FILE", CURRENT_KEY);
+    std::env::set_var("JWT_SIGNING_KEY_ID", "current");
+    std::env::set_var(
+        "JWT_VERIFICATION_KEYS",
+        "current=tests/keys/rs256-current.pub.pem, previous=tests/keys/rs256-previous.pub.pem, ed=tests/keys/ed25519.pub.pem",
+    );
+    std::env::set_var("RATE_LIMIT_AUTH_PER_MINUTE", "100000");
+    std::env::set_var("RATE_LIMIT_READ_PER_MINUTE", "100000");
+    let app = trnx_service();
+    let config = TestServerConfig::builder().mock_transport().build();
+
+    TestServer::new_with_config(app, config).unwrap()
+}
+
+fn claims() -> serde_json::Value {
+    serde_json::json!({
+        "exp": chrono::Utc::now().timestamp() + 60,
+        "email": "someone@test.com",
+    })
+}
+
+#[cfg(test)]
+mod test_jwks {
+    use super::*;
+    use ::serde_json::json;
+    use axum_test::http::{HeaderValue, StatusCode};
+    use jsonwebtoken::{
+        decode, decode_header, encode,
+        jwk::{AlgorithmParameters, JwkSet},
+        Algorithm, DecodingKey, EncodingKey, Header, Validation,
+    };
+
+    #[derive(Debug, Deserialize)]
+    struct LoginResponse {
+        email: String,
+        token: String,
+    }
+
+    #[tokio::test]
+    async fn jwks_lists_every_verification_key() {
+        let server = test_server();
+        let response = server.get("/.well-known/jwks.json").await;
+        response.assert_status_ok();
+        assert_eq!(
+            response.headers().get("cache-control").unwrap(),
+            "public, max-age=300"
+        );
+        let body = response.json::<serde_json::Value>();
+        let keys = body["keys"].as_array().unwrap();
+        assert_eq!(keys.len(), 3);
+        for key in keys {
+            assert_eq!(key["use"], "sig");
+            // only public parts are published
+            assert!(key.get("d").is_none());
+        }
+        assert_eq!(keys[0]["kid"], "current");
+        assert_eq!(keys[0]["kty"], "RSA");
+        assert_eq!(keys[0]["alg"], "RS256");
+        assert_eq!(keys[0]["e"], "AQAB");
+        // a 2048 bit modulus, without the sign byte of its DER encoding
+        assert_eq!(keys[0]["n"].as_str().unwrap().len(), 342);
+        assert_eq!(keys[1]["kid"], "previous");
+        assert_eq!(keys[1]["kty"], "RSA");
+        assert_eq!(keys[2]["kid"], "ed");
+        assert_eq!(keys[2]["kty"], "OKP");
+        assert_eq!(keys[2]["crv"], "Ed25519");
+        assert_eq!(keys[2]["alg"], "EdDSA");
+        assert_eq!(keys[2]["x"].as_str().unwrap().len(), 43);
+    }
+
+    #[tokio::test]
+    async fn login_tokens_verify_with_the_published_key() {
+        let server = test_server();
+        let email = format!("jwks-{}@test.com", uuid::Uuid::new_v4().as_simple());
+        server
+            .post("/register")
+            .json(&json!({
+                        "email": email,
+                        "password": "testpassword123",
+                        "fullname": "jwks user"
+            }))
+            .await
+            .assert_status(StatusCode::CREATED);
+        let login = server
+            .post("/login")
+            .json(&json!({
+                        "email": email,
+                        "password": "testpassword123"
+            }))
+            .await
+            .json::<LoginResponse>();
+
+        let header = decode_header(&login.token).unwrap();
+        assert_eq!(header.alg, Algorithm::RS256);
+        assert_eq!(header.kid.as_deref(), Some("current"));
+
+        // a downstream service needs nothing but the key set
+        let jwks = server.get("/.well-known/jwks.json").await.json::<JwkSet>();
+        let jwk = jwks.find("current").unwrap();
+        assert!(matches!(jwk.algorithm, AlgorithmParameters::RSA(_)));
+        let claims = decode::<serde_json::Value>(
+            &login.token,
+            &DecodingKey::from_jwk(jwk).unwrap(),
+            &Validation::new(Algorithm::RS256),
+        )
+        .unwrap()
+        .claims;
+        assert_eq!(claims["email"], login.email);
+
+        server
+            .get("/sessions")
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                HeaderValue::from_str(&format!("Bearer {}", login.token)).unwrap(),
+            )
+            .await
+            .assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn tokens_of_any_verification_key_are_accepted() {
+        let keys = server_keys();
+        let previous =
+            JwtKeys::from_pem_files("previous", PREVIOUS_KEY, &verification_keys()).unwrap();
+        let token = previous.sign(&claims()).unwrap();
+        assert_eq!(
+            decode_header(&token).unwrap().kid.as_deref(),
+            Some("previous")
+        );
+        let verified = keys.verify::<serde_json::Value>(&token).unwrap();
+        assert_eq!(verified.claims["email"], "someone@test.com");
+
+        let ed = JwtKeys::from_pem_files("ed", ED25519_KEY, &verification_keys()).unwrap();
+        assert_eq!(ed.algorithm(), Algorithm::EdDSA);
+        let token = ed.sign(&claims()).unwrap();
+        assert_eq!(decode_header(&token).unwrap().alg, Algorithm::EdDSA);
+        assert!(keys.verify::<serde_json::Value>(&token).is_ok());
+
+        // public keys can also be given in PKCS#1 form
+        let pkcs1 = JwtKeys::from_pem_files(
+            "current",
+            CURRENT_KEY,
+            &[(
+                "current".to_string(),
+                "tests/keys/rs256-current.pkcs1.pem".to_string(),
+            )],
+        )
+        .unwrap();
+        assert_eq!(pkcs1.jwks().keys, server_keys().jwks().keys[..1]);
+    }
+
+    #[tokio::test]
+    async fn tokens_of_unknown_keys_are_rejected() {
+        let keys = server_keys();
+        // a key that has been dropped from the verification keys
+        let retired = JwtKeys::from_pem_files(
+            "retired",
+            PREVIOUS_KEY,
+            &[(
+                "retired".to_string(),
+                "tests/keys/rs256-previous.pub.pem".to_string(),
+            )],
+        )
+        .unwrap();
+        let token = retired.sign(&claims()).unwrap();
+        assert!(keys.verify::<serde_json::Value>(&token).is_err());
+
+        // a known kid signed with the wrong key
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("current".to_string());
+        let token = encode(
+            &header,
+            &claims(),
+            &EncodingKey::from_rsa_pem(&std::fs::read(PREVIOUS_KEY).unwrap()).unwrap(),
+        )
+        .unwrap();
+        assert!(keys.verify::<serde_json::Value>(&token).is_err());
+
+        // the public key used as an HMAC secret
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("current".to_string());
+        let public_key = std::fs::read("tests/keys/rs256-current.pub.pem").unwrap();
+        let token = encode(&header, &claims(), &EncodingKey::from_secret(&public_key)).unwrap();
+        assert!(keys.verify::<serde_json::Value>(&token).is_err());
+
+        // and a token without a kid
+        let token = encode(
+            &Header::new(Algorithm::RS256),
+            &claims(),
+            &EncodingKey::from_rsa_pem(&std::fs::read(CURRENT_KEY).unwrap()).unwrap(),
+        )
+        .unwrap();
+        assert!(keys.verify::<serde_json::Value>(&token).is_err());
+    }
+
+    #[tokio::test]
+    async fn misconfigured_keys_are_refused() {
+        // the signing key has to belong to its verification key
+        let mismatched = JwtKeys::from_pem_files("current", PREVIOUS_KEY, &verification_keys());
+        assert!(matches!(mismatched, Err(Errors::InvalidJwtKey(_))));
+        let unlisted = JwtKeys::from_pem_files("next", CURRENT_KEY, &verification_keys());
+        assert!(matches!(unlisted, Err(Errors::InvalidJwtKey(_))));
+        // a private key is not a verification key
+        let private = JwtKeys::from_pem_files(
+            "current",
+            CURRENT_KEY,
+            &[("current".to_string(), CURRENT_KEY.to_string())],
+        );
+        assert!(matches!(private, Err(Errors::InvalidJwtKey(_))));
+        // neither is a file that holds no key
+        let garbage = JwtKeys::from_pem_files(
+            "current",
+            CURRENT_KEY,
+            &[("current".to_string(), "Cargo.toml".to_string())],
+        );
+        assert!(matches!(garbage, Err(Errors::InvalidJwtKey(_))));
+    }
+
+    #[test]
+    fn verification_key_lists_are_parsed() {
+        assert_eq!(
+            verification_key_files(" current = a.pem,previous=b.pem, ").unwrap(),
+            vec![
+                ("current".to_string(), "a.pem".to_string()),
+                ("previous".to_string(), "b.pem".to_string()),
+            ]
+        );
+        for list in ["current", "current=a.pem,b.pem", "=a.pem", "current="] {
+            assert!(matches!(
+                verification_key_files(list),
+                Err(Errors::InvalidJwtKey(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use transaction_service::config::db::get_conn;
+use transaction_service::config::rate_limit::{
+    MemoryStore, PostgresStore, RateLimitPolicy, RateLimitStore,
+};
+use transaction_service::trnx_service;
+
+#[cfg(test)]
+use ::axum_test::TestServer;
+#[cfg(test)]
+use ::axum_test::TestServerConfig;
+use ::serde::Deserialize;
+
+/// Anonymous requests of the mock transport have no IP address, so they all
+/// share one bucket. Only one test in this file sends them.
+fn test_server() -> TestServer {
+    std::env::set_var("RATE_LIMIT_AUTH_PER_MINUTE", "5");
+    std::env::set_var("RATE_LIMIT_READ_PER_MINUTE", "2");
+    let app = trnx_service();
+    let config = TestServerConfig::builder().mock_transport().build();
+
+    TestServer::new_with_config(app, config).unwrap()
+}
+
+/// Takes three tokens from a new bucket that holds two and refills once an
+/// hour.
+async fn drain(store: &dyn RateLimitStore) {
+    let key = format!("test:{}", uuid::Uuid::new_v4().as_simple());
+    let policy = RateLimitPolicy {
+        capacity: 2,
+        refill_per_sec: 1.0 / 3600.0,
+    };
+    let first = store.take(&key, policy).await.unwrap();
+    assert!(first.allowed);
+    assert_eq!((first.limit, first.remaining), (2, 1));
+    let second = store.take(&key, policy).await.unwrap();
+    assert!(second.allowed);
+    assert_eq!(second.remaining, 0);
+    let third = store.take(&key, policy).await.unwrap();
+    assert!(!third.allowed);
+    assert_eq!(third.remaining, 0);
+    assert!(third.retry_after_secs > 3500 && third.retry_after_secs <= 3600);
+    assert!(third.reset_secs > 7100 && third.reset_secs <= 7200);
+}
+
+#[cfg(test)]
+mod test_rate_limit {
+    use super::*;
+    use ::serde_json::json;
+    use axum_test::http::{HeaderValue, StatusCode};
+    use sqlx::Row;
+
+    #[derive(Debug, Deserialize)]
+    struct LoginResponse {
+        token: String,
+    }
+
+    fn header(response: &axum_test::TestResponse, name: &str) -> String {
+        response
+            .headers()
+            .get(name)
+            .unwrap_or_else(|| panic!("no {} header", name))
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    async fn register_and_login(server: &TestServer, prefix: &str) -> HeaderValue {
+        let email = format!("{}-{}@test.com", prefix, uuid::Uuid::new_v4().as_simple());
+        server
+            .post("/register")
+            .json(&json!({
+                        "email": email,
+                        "password": "testpassword123",
+                        "fullname": "rate limited user"
+            }))
+            .await
+            .assert_status(StatusCode::CREATED);
+        let token = server
+            .post("/login")
+            .json(&json!({
+                        "email": email,
+                        "password": "testpassword123"
+            }))
+            .await
+            .json::<LoginResponse>()
+            .token;
+        HeaderValue::from_str(&format!("Bearer {}", token)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn route_groups_are_limited_per_client() {
+        let server = test_server();
+        let alice = register_and_login(&server, "alice-limited").await;
+        let bob = register_and_login(&server, "bob-limited").await;
+
+        // four of the five anonymous auth requests are used up
+        let last = server
+            .post("/password/forgot")
+            .json(&json!({ "email": "nobody@test.com" }))
+            .await;
+        last.assert_status(StatusCode::ACCEPTED);
+        assert_eq!(header(&last, "RateLimit-Limit"), "5");
+        assert_eq!(header(&last, "RateLimit-Remaining"), "0");
+        let limited = server
+            .post("/password/forgot")
+            .json(&json!({ "email": "nobody@test.com" }))
+            .await;
+        limited.assert_status(StatusCode::TOO_MANY_REQUESTS);
+        let retry_after = header(&limited, "Retry-After").parse::<u64>().unwrap();
+        assert!(retry_after > 0 && retry_after <= 12);
+        assert_eq!(
+            limited.json::<serde_json::Value>(),
+            json!({ "error": "Too many requests" })
+        );
+
+        // authenticated requests count against the user, not the address
+        for remaining in ["1", "0"] {
+            let response = server
+                .get("/sessions")
+                .add_header(axum_test::http::header::AUTHORIZATION, alice.clone())
+                .await;
+            response.assert_status_ok();
+            assert_eq!(header(&response, "RateLimit-Limit"), "2");
+            assert_eq!(header(&response, "RateLimit-Remaining"), remaining);
+        }
+        server
+            .get("/sessions")
+            .add_header(axum_test::http::header::AUTHORIZATION, alice.clone())
+            .await
+            .assert_status(StatusCode::TOO_MANY_REQUESTS);
+        server
+            .get("/sessions")
+            .add_header(axum_test::http::header::AUTHORIZATION, bob)
+            .await
+            .assert_status_ok();
+        // and each group has a bucket of its own
+        server
+            .post("/transaction")
+            .json(&json!({
+                        "from_email": "someone-else@test.com",
+                        "to_email": "nobody@test.com",
+                        "amount": "1.00"
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, alice)
+            .await
+            .assert_status_unauthorized();
+    }
+
+    #[tokio::test]
+    async fn memory_store_drains_buckets() {
+        drain(&MemoryStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn postgres_store_drains_buckets() {
+        drain(&PostgresStore).await;
+    }
+
+    #[tokio::test]
+    async fn memory_store_forgets_the_oldest_buckets() {
+        let store = MemoryStore::with_max_buckets(2);
+        let policy = RateLimitPolicy {
+            capacity: 2,
+            refill_per_sec: 1.0 / 3600.0,
+        };
+        for key in ["first", "second", "second", "third"] {
+            store.take(key, policy).await.unwrap();
+        }
+        // the first bucket made room for the third, and starts over
+        assert_eq!(store.take("first", policy).await.unwrap().remaining, 1);
+        // while the third one is still counting
+        assert_eq!(store.take("third", policy).await.unwrap().remaining, 0);
+    }
+
+    #[tokio::test]
+    async fn buckets_are_pruned_by_their_own_policy() {
+        let slow = RateLimitPolicy {
+            capacity: 2,
+            refill_per_sec: 1.0 / 3600.0,
+        };
+        let fast = RateLimitPolicy {
+            capacity: 1,
+            refill_per_sec: 1000.0,
+        };
+        let store = MemoryStore::with_max_buckets(2);
+        store.take("slow", slow).await.unwrap();
+        store.take("fast", fast).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        // by the fast policy the slow bucket would look full again
+        store.take("new", fast).await.unwrap();
+        assert_eq!(store.take("slow", slow).await.unwrap().remaining, 0);
+
+        let slow_key = format!("test:{}", uuid::Uuid::new_v4().as_simple());
+        let fast_key = format!("test:{}", uuid::Uuid::new_v4().as_simple());
+        PostgresStore.take(&slow_key, slow).await.unwrap();
+        PostgresStore.take(&fast_key, fast).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        assert!(PostgresStore.prune().await.unwrap() >= 1);
+        let left = sqlx::query("SELECT key FROM rate_limit_buckets WHERE key = $1 OR key = $2")
+            .bind(&slow_key)
+            .bind(&fast_key)
+            .fetch_all(get_conn().await)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get::<String, &str>("key"))
+            .collect::<Vec<_>>();
+        assert_eq!(left, vec![slow_key]);
+    }
+}
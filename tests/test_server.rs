@@ -8,7 +8,93 @@ use ::axum_test::TestServerConfig;
 use ::serde::Deserialize;
 use ::serde::Serialize;
 
+/// Where the service writes the emails it sends during tests.
+fn mail_dir() -> std::path::PathBuf {
+    std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("mail")
+}
+
+/// Marks a registered user's email as verified, as following the link in the
+/// verification email would. Unverified users cannot send money.
+async fn verify_email(email: &str) {
+    sqlx::query("UPDATE users SET email_verified_at = now() WHERE email = $1")
+        .bind(email)
+        .execute(transaction_service::config::db::get_conn().await)
+        .await
+        .unwrap();
+}
+
+/// A new email for a test user, so tests can run again against the same
+/// database.
+fn unique_email(prefix: &str) -> String {
+    format!("{}-{}@test.com", prefix, uuid::Uuid::new_v4().as_simple())
+}
+
+/// The Authorization header that sends `token` as a bearer token.
+fn bearer(token: &str) -> axum_test::http::HeaderValue {
+    axum_test::http::HeaderValue::from_str(&format!("Bearer {}", token)).unwrap()
+}
+
+/// Registers `email` with `balance` and the password every test uses, and
+/// verifies the email.
+async fn register_verified(server: &TestServer, email: &str, balance: &str) {
+    server
+        .post("/register")
+        .json(&serde_json::json!({
+                    "email": email,
+                    "password": "testpassword123",
+                    "fullname": "test user",
+                    "balance": balance
+        }))
+        .await;
+    verify_email(email).await;
+}
+
+/// Gives a registered user `role`. Roles go into tokens at login, so this has
+/// to come before it.
+async fn set_role(email: &str, role: &str) {
+    sqlx::query("UPDATE users SET role = $1::role WHERE email = $2")
+        .bind(role)
+        .bind(email)
+        .execute(transaction_service::config::db::get_conn().await)
+        .await
+        .unwrap();
+}
+
+/// Logs in a user registered by the helpers above and returns the
+/// Authorization header of their token.
+async fn login_bearer(server: &TestServer, email: &str) -> axum_test::http::HeaderValue {
+    let login = server
+        .post("/login")
+        .json(&serde_json::json!({
+                    "email": email,
+                    "password": "testpassword123"
+        }))
+        .await
+        .json::<serde_json::Value>();
+    bearer(login["token"].as_str().unwrap())
+}
+
+/// Registers and verifies `email` with `balance`, logs in and returns the
+/// Authorization header of the token.
+async fn register_and_login(
+    server: &TestServer,
+    email: &str,
+    balance: &str,
+) -> axum_test::http::HeaderValue {
+    register_verified(server, email, balance).await;
+    login_bearer(server, email).await
+}
+
 fn test_server() -> TestServer {
+    std::env::set_var("MAIL_DIR", mail_dir());
+    // only users with 2FA need a code for transfers above this
+    std::env::set_var("TOTP_TRANSFER_THRESHOLD", "500.00");
+    // lock emails out after 3 free failures and waits of 1 and 2 seconds
+    std::env::set_var("LOGIN_MAX_FAILURES", "5");
+    // rate limits have tests of their own
+    for group in ["AUTH", "MONEY", "READ"] {
+        std::env::set_var(format!("RATE_LIMIT_{group}_PER_MINUTE"), "100000");
+    }
     // Build an application with a route.
     let app = trnx_service();
 
@@ -55,14 +141,14 @@ mod test_get_user_balance {
     struct LoginRequest {
         email: String,
         fullname: String,
-        balance: f64,
+        balance: String,
         token: String,
     }
 
     #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
     struct BalanceRequest {
         email: String,
-        balance: f64,
+        balance: String,
     }
 
     #[tokio::test]
@@ -92,7 +178,37 @@ mod test_get_user_balance {
             .json::<BalanceRequest>();
         println!("{:?}", balance_response);
         let balance = balance_response.balance;
-        assert_eq!(balance, 100.0)
+        assert_eq!(balance, "100.00")
+    }
+
+    #[tokio::test]
+    async fn other_users_balance_is_refused() {
+        let server = test_server();
+        let alice = unique_email("balance-alice");
+        let bob = unique_email("balance-bob");
+        let alice_auth = register_and_login(&server, &alice, "40.00").await;
+        register_verified(&server, &bob, "60.00").await;
+
+        let response = server
+            .get("/balance")
+            .expect_failure()
+            .json(&json!({ "email": bob }))
+            .add_header(axum_test::http::header::AUTHORIZATION, alice_auth.clone())
+            .await;
+        assert_eq!(response.status_code(), 403);
+        assert!(response
+            .json::<serde_json::Value>()
+            .get("balance")
+            .is_none());
+
+        // without a body the balance is the token's own
+        let own = server
+            .get("/balance")
+            .add_header(axum_test::http::header::AUTHORIZATION, alice_auth)
+            .await
+            .json::<BalanceRequest>();
+        assert_eq!(own.email, alice);
+        assert_eq!(own.balance, "40.00");
     }
 }
 
@@ -106,7 +222,7 @@ mod test_get_user_transaction {
         pub id: String,
         pub from_email: String,
         pub to_email: String,
-        pub amount: f64,
+        pub amount: String,
         pub trnx_time: DateTime<Utc>,
     }
 
@@ -119,7 +235,7 @@ mod test_get_user_transaction {
     struct LoginRequest {
         email: String,
         fullname: String,
-        balance: f64,
+        balance: String,
         token: String,
     }
 
@@ -153,3 +269,3782 @@ mod test_get_user_transaction {
         assert_eq!(num_transactions, 2)
     }
 }
+
+#[cfg(test)]
+mod test_money_precision {
+    use super::*;
+    use ::serde_json::json;
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct LoginRequest {
+        email: String,
+        fullname: String,
+        balance: String,
+        token: String,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct BalanceRequest {
+        email: String,
+        balance: String,
+    }
+
+    #[tokio::test]
+    async fn repeated_small_transfers_do_not_drift() {
+        let server = test_server();
+        let run_id = uuid::Uuid::new_v4().as_simple().to_string();
+        let sender = format!("money-sender-{}@test.com", run_id);
+        let receiver = format!("money-receiver-{}@test.com", run_id);
+        for (email, balance) in [(&sender, "0.30"), (&receiver, "0")] {
+            server
+                .post("/register")
+                .json(&json!({
+                            "email": email,
+                            "password": "testpassword123",
+                            "fullname": "money user",
+                            "balance": balance
+                }))
+                .await;
+            verify_email(email).await;
+        }
+        let login = server
+            .post("/login")
+            .json(&json!({
+                        "email": sender,
+                        "password": "testpassword123"
+            }))
+            .await
+            .json::<LoginRequest>();
+        assert_eq!(login.balance, "0.30");
+        let headertoken = format!("Bearer {}", login.token);
+        let header_value = axum_test::http::HeaderValue::from_str(headertoken.as_str()).unwrap();
+
+        for _ in 0..3 {
+            server
+                .post("/transaction")
+                .json(&json!({
+                            "from_email": sender,
+                            "to_email": receiver,
+                            "amount": "0.10"
+                }))
+                .add_header(axum_test::http::header::AUTHORIZATION, header_value.clone())
+                .await;
+        }
+
+        server
+            .post("/transaction")
+            .expect_failure()
+            .json(&json!({
+                        "from_email": sender,
+                        "to_email": receiver,
+                        "amount": "0.001"
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, header_value.clone())
+            .await;
+
+        // each user can only read their own balance
+        let receiver_header = login_bearer(&server, &receiver).await;
+        for (email, expected, header) in [
+            (&sender, "0.00", header_value),
+            (&receiver, "0.30", receiver_header),
+        ] {
+            let balance_response = server
+                .get("/balance")
+                .json(&json!({
+                            "email": email,
+                }))
+                .add_header(axum_test::http::header::AUTHORIZATION, header)
+                .await
+                .json::<BalanceRequest>();
+            assert_eq!(balance_response.balance, expected);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_idempotency {
+    use super::*;
+    use ::serde_json::json;
+    use transaction_service::config::db::get_conn;
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct LoginRequest {
+        email: String,
+        fullname: String,
+        balance: String,
+        token: String,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct BalanceRequest {
+        email: String,
+        balance: String,
+    }
+
+    /// Makes a stored key look as if its first request had not finished, with
+    /// the claim taken at `locked_at`.
+    async fn unfinish(email: &str, key: &str, locked_at: &str) {
+        sqlx::query(&format!(
+            "UPDATE idempotency_keys SET response_status = NULL, response_body = NULL, completed_at = NULL, locked_at = {} WHERE email = $1 AND idempotency_key = $2",
+            locked_at
+        ))
+        .bind(email)
+        .bind(key)
+        .execute(get_conn().await)
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn retried_transaction_is_applied_once() {
+        let server = test_server();
+        let run_id = uuid::Uuid::new_v4().as_simple().to_string();
+        let sender = format!("idem-sender-{}@test.com", run_id);
+        let receiver = format!("idem-receiver-{}@test.com", run_id);
+        for (email, balance) in [(&sender, "10.00"), (&receiver, "0")] {
+            server
+                .post("/register")
+                .json(&json!({
+                            "email": email,
+                            "password": "testpassword123",
+                            "fullname": "idempotency user",
+                            "balance": balance
+                }))
+                .await;
+            verify_email(email).await;
+        }
+        let login = server
+            .post("/login")
+            .json(&json!({
+                        "email": sender,
+                        "password": "testpassword123"
+            }))
+            .await
+            .json::<LoginRequest>();
+        let headertoken = format!("Bearer {}", login.token);
+        let header_value = axum_test::http::HeaderValue::from_str(headertoken.as_str()).unwrap();
+        let key = axum_test::http::HeaderValue::from_str(run_id.as_str()).unwrap();
+        let transfer = json!({
+                    "from_email": sender,
+                    "to_email": receiver,
+                    "amount": "1.00"
+        });
+
+        let first = server
+            .post("/transaction")
+            .json(&transfer)
+            .add_header(axum_test::http::header::AUTHORIZATION, header_value.clone())
+            .add_header("Idempotency-Key", key.clone())
+            .await;
+        let retry = server
+            .post("/transaction")
+            .json(&transfer)
+            .add_header(axum_test::http::header::AUTHORIZATION, header_value.clone())
+            .add_header("Idempotency-Key", key.clone())
+            .await;
+        assert_eq!(first.status_code(), 201);
+        assert_eq!(retry.status_code(), 201);
+        assert_eq!(
+            first.json::<serde_json::Value>(),
+            retry.json::<serde_json::Value>()
+        );
+
+        let balance_response = server
+            .get("/balance")
+            .json(&json!({
+                        "email": sender,
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, header_value.clone())
+            .await
+            .json::<BalanceRequest>();
+        assert_eq!(balance_response.balance, "9.00");
+
+        let mismatch = server
+            .post("/transaction")
+            .expect_failure()
+            .json(&json!({
+                        "from_email": sender,
+                        "to_email": receiver,
+                        "amount": "2.00"
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, header_value.clone())
+            .add_header("Idempotency-Key", key.clone())
+            .await;
+        assert_eq!(mismatch.status_code(), 422);
+
+        // the key again, as if its first request had not finished yet
+        unfinish(&sender, &run_id, "now()").await;
+        let in_flight = server
+            .post("/transaction")
+            .expect_failure()
+            .json(&transfer)
+            .add_header(axum_test::http::header::AUTHORIZATION, header_value.clone())
+            .add_header("Idempotency-Key", key.clone())
+            .await;
+        assert_eq!(in_flight.status_code(), 409);
+        let in_flight_mismatch = server
+            .post("/transaction")
+            .expect_failure()
+            .json(&json!({
+                        "from_email": sender,
+                        "to_email": receiver,
+                        "amount": "2.00"
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, header_value.clone())
+            .add_header("Idempotency-Key", key.clone())
+            .await;
+        assert_eq!(in_flight_mismatch.status_code(), 422);
+
+        // a first request that stopped long ago does not keep the key stuck, and
+        // as its transfer went through the retry answers with it instead of
+        // moving the money again
+        unfinish(&sender, &run_id, "now() - interval '1 hour'").await;
+        let taken_over = server
+            .post("/transaction")
+            .json(&transfer)
+            .add_header(axum_test::http::header::AUTHORIZATION, header_value.clone())
+            .add_header("Idempotency-Key", key.clone())
+            .await;
+        assert_eq!(taken_over.status_code(), 201);
+        assert_eq!(
+            taken_over.json::<serde_json::Value>(),
+            first.json::<serde_json::Value>()
+        );
+        let balance_response = server
+            .get("/balance")
+            .add_header(axum_test::http::header::AUTHORIZATION, header_value.clone())
+            .await
+            .json::<BalanceRequest>();
+        assert_eq!(balance_response.balance, "9.00");
+        let replayed = server
+            .post("/transaction")
+            .json(&transfer)
+            .add_header(axum_test::http::header::AUTHORIZATION, header_value.clone())
+            .add_header("Idempotency-Key", key.clone())
+            .await;
+        assert_eq!(
+            taken_over.json::<serde_json::Value>(),
+            replayed.json::<serde_json::Value>()
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_reconciliation {
+    use super::*;
+    use ::serde_json::json;
+    use sqlx::Row;
+    use transaction_service::config::db::get_conn;
+    use transaction_service::reconciliation::{fail_stale_transactions, fix_account, reconcile};
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct LoginRequest {
+        email: String,
+        fullname: String,
+        balance: String,
+        token: String,
+    }
+
+    #[tokio::test]
+    async fn abandoned_pending_transactions_are_failed() {
+        let server = test_server();
+        let sender = unique_email("stale-sender");
+        let receiver = unique_email("stale-receiver");
+        register_verified(&server, &sender, "10.00").await;
+        register_verified(&server, &receiver, "0").await;
+        let pool = get_conn().await;
+        // as left behind by a request that stopped before settling them
+        let mut ids = vec![];
+        for age in ["1 hour", "1 second"] {
+            let id = uuid::Uuid::new_v4().as_simple().to_string();
+            sqlx::query(&format!(
+                "INSERT INTO transactions (id, from_email, to_email, amount, created_at, kind, status) VALUES ($1, $2, $3, 100, now() - interval '{}', 'transfer', 'pending')",
+                age
+            ))
+            .bind(&id)
+            .bind(&sender)
+            .bind(&receiver)
+            .execute(pool)
+            .await
+            .unwrap();
+            ids.push(id);
+        }
+
+        assert!(
+            fail_stale_transactions(pool, chrono::Duration::minutes(5))
+                .await
+                .unwrap()
+                >= 1
+        );
+        let mut statuses = vec![];
+        for id in &ids {
+            let row = sqlx::query("SELECT status, failure_reason FROM transactions WHERE id = $1")
+                .bind(id)
+                .fetch_one(pool)
+                .await
+                .unwrap();
+            statuses.push((
+                row.get::<String, &str>("status"),
+                row.get::<Option<String>, &str>("failure_reason"),
+            ));
+        }
+        assert_eq!(
+            statuses,
+            vec![
+                (
+                    "failed".to_string(),
+                    Some("transaction was abandoned before it settled".to_string())
+                ),
+                ("pending".to_string(), None),
+            ]
+        );
+        // no money moved for them
+        assert!(reconcile(pool)
+            .await
+            .unwrap()
+            .iter()
+            .all(|drift| drift.email != sender && drift.email != receiver));
+    }
+
+    #[tokio::test]
+    async fn drifting_accounts_are_reported_and_fixed() {
+        let server = test_server();
+        let run_id = uuid::Uuid::new_v4().as_simple().to_string();
+        let sender = format!("recon-sender-{}@test.com", run_id);
+        let receiver = format!("recon-receiver-{}@test.com", run_id);
+        for (email, balance) in [(&sender, "10.00"), (&receiver, "0")] {
+            server
+                .post("/register")
+                .json(&json!({
+                            "email": email,
+                            "password": "testpassword123",
+                            "fullname": "reconciliation user",
+                            "balance": balance
+                }))
+                .await;
+            verify_email(email).await;
+        }
+        let login = server
+            .post("/login")
+            .json(&json!({
+                        "email": sender,
+                        "password": "testpassword123"
+            }))
+            .await
+            .json::<LoginRequest>();
+        let headertoken = format!("Bearer {}", login.token);
+        let header_value = axum_test::http::HeaderValue::from_str(headertoken.as_str()).unwrap();
+        server
+            .post("/transaction")
+            .json(&json!({
+                        "from_email": sender,
+                        "to_email": receiver,
+                        "amount": "1.00"
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, header_value)
+            .await;
+
+        // a logged transfer that never reached the ledger or the balances, and
+        // a cached balance that was edited by hand
+        let pool = get_conn().await;
+        let orphan_id = format!("orphan-{}", run_id);
+        sqlx::query(
+            "INSERT INTO transactions (from_email, to_email, amount, id, created_at) VALUES ($1, $2, 250, $3, now())",
+        )
+        .bind(&sender)
+        .bind(&receiver)
+        .bind(&orphan_id)
+        .execute(pool)
+        .await
+        .unwrap();
+        sqlx::query("UPDATE users SET balance = balance + 100 WHERE email = $1")
+            .bind(&receiver)
+            .execute(pool)
+            .await
+            .unwrap();
+
+        let drifts = reconcile(pool).await.unwrap();
+        let sender_drift = drifts.iter().find(|d| d.email == sender).unwrap();
+        assert_eq!(sender_drift.expected.to_string(), "6.50");
+        assert_eq!(sender_drift.cached.to_string(), "9.00");
+        assert_eq!(sender_drift.ledger.to_string(), "9.00");
+        assert_eq!(
+            sender_drift.first_divergent_transaction.as_deref(),
+            Some(orphan_id.as_str())
+        );
+        let receiver_drift = drifts.iter().find(|d| d.email == receiver).unwrap();
+        assert_eq!(receiver_drift.expected.to_string(), "3.50");
+        assert_eq!(receiver_drift.cached.to_string(), "2.00");
+        assert_eq!(receiver_drift.ledger.to_string(), "1.00");
+
+        for drift in [sender_drift, receiver_drift] {
+            assert!(fix_account(pool, &drift.user_id).await.unwrap().is_some());
+        }
+        let drifts = reconcile(pool).await.unwrap();
+        assert!(!drifts
+            .iter()
+            .any(|d| d.email == sender || d.email == receiver));
+
+        // the correction brought the ledger back in line, so a later drift is
+        // reported from where it starts
+        let second_orphan_id = format!("orphan-2-{}", run_id);
+        sqlx::query(
+            "INSERT INTO transactions (from_email, to_email, amount, id, created_at) VALUES ($1, $2, 50, $3, now())",
+        )
+        .bind(&sender)
+        .bind(&receiver)
+        .bind(&second_orphan_id)
+        .execute(pool)
+        .await
+        .unwrap();
+        let drifts = reconcile(pool).await.unwrap();
+        let sender_drift = drifts.iter().find(|d| d.email == sender).unwrap();
+        assert_eq!(sender_drift.expected.to_string(), "6.00");
+        assert_eq!(sender_drift.ledger.to_string(), "6.50");
+        assert_eq!(
+            sender_drift.first_divergent_transaction.as_deref(),
+            Some(second_orphan_id.as_str())
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_transaction_pagination {
+    use super::*;
+    use ::serde_json::json;
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct Transaction {
+        pub id: String,
+        pub from_email: String,
+        pub to_email: String,
+        pub amount: String,
+        pub trnx_time: DateTime<Utc>,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct TransactionPage {
+        pub transactions: Vec<Transaction>,
+        pub next_cursor: Option<String>,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct LoginRequest {
+        email: String,
+        fullname: String,
+        balance: String,
+        token: String,
+    }
+
+    #[tokio::test]
+    async fn list_transactions_pages_and_filters() {
+        let server = test_server();
+        let run_id = uuid::Uuid::new_v4().as_simple().to_string();
+        let sender = format!("page-sender-{}@test.com", run_id);
+        let first = format!("page-first-{}@test.com", run_id);
+        let second = format!("page-second-{}@test.com", run_id);
+        for (email, balance) in [(&sender, "100.00"), (&first, "0"), (&second, "0")] {
+            server
+                .post("/register")
+                .json(&json!({
+                            "email": email,
+                            "password": "testpassword123",
+                            "fullname": "pagination user",
+                            "balance": balance
+                }))
+                .await;
+            verify_email(email).await;
+        }
+        let login = server
+            .post("/login")
+            .json(&json!({
+                        "email": sender,
+                        "password": "testpassword123"
+            }))
+            .await
+            .json::<LoginRequest>();
+        let headertoken = format!("Bearer {}", login.token);
+        let header_value = axum_test::http::HeaderValue::from_str(headertoken.as_str()).unwrap();
+        for (to_email, amount) in [
+            (&first, "1.00"),
+            (&first, "2.00"),
+            (&second, "2.50"),
+            (&first, "3.00"),
+            (&first, "4.00"),
+            (&first, "5.00"),
+        ] {
+            server
+                .post("/transaction")
+                .json(&json!({
+                            "from_email": sender,
+                            "to_email": to_email,
+                            "amount": amount
+                }))
+                .add_header(axum_test::http::header::AUTHORIZATION, header_value.clone())
+                .await;
+        }
+
+        // newest first, two at a time, only transfers to `first`
+        let mut amounts = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut request = server
+                .get("/transaction")
+                .add_query_param("counterparty", &first)
+                .add_query_param("limit", 2)
+                .add_header(axum_test::http::header::AUTHORIZATION, header_value.clone());
+            if let Some(cursor) = &cursor {
+                request = request.add_query_param("cursor", cursor);
+            }
+            let page = request.await.json::<TransactionPage>();
+            assert!(page.transactions.len() <= 2);
+            amounts.extend(page.transactions.into_iter().map(|t| t.amount));
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(amounts, ["5.00", "4.00", "3.00", "2.00", "1.00"]);
+
+        let page = server
+            .get("/transaction")
+            .add_query_param("direction", "sent")
+            .add_query_param("min_amount", "2.00")
+            .add_query_param("max_amount", "3.00")
+            .add_query_param("order", "asc")
+            .add_header(axum_test::http::header::AUTHORIZATION, header_value.clone())
+            .await
+            .json::<TransactionPage>();
+        let amounts: Vec<_> = page
+            .transactions
+            .iter()
+            .map(|t| t.amount.as_str())
+            .collect();
+        assert_eq!(amounts, ["2.00", "2.50", "3.00"]);
+        assert_eq!(page.next_cursor, None);
+
+        let received = server
+            .get("/transaction")
+            .add_query_param("direction", "received")
+            .add_header(axum_test::http::header::AUTHORIZATION, header_value.clone())
+            .await
+            .json::<TransactionPage>();
+        assert!(received.transactions.is_empty());
+
+        server
+            .get("/transaction")
+            .expect_failure()
+            .add_query_param("cursor", "not-a-cursor")
+            .add_header(axum_test::http::header::AUTHORIZATION, header_value)
+            .await
+            .assert_status_bad_request();
+    }
+}
+
+#[cfg(test)]
+mod test_get_transaction_by_id {
+    use super::*;
+    use ::serde_json::json;
+    use transaction_service::config::db::get_conn;
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct Transaction {
+        pub id: String,
+        pub from_email: String,
+        pub to_email: String,
+        pub amount: String,
+        pub trnx_time: DateTime<Utc>,
+    }
+
+    #[tokio::test]
+    async fn transaction_is_visible_to_participants_and_admins_only() {
+        let server = test_server();
+        let run_id = uuid::Uuid::new_v4().as_simple().to_string();
+        let sender = format!("lookup-sender-{}@test.com", run_id);
+        let receiver = format!("lookup-receiver-{}@test.com", run_id);
+        let outsider = format!("lookup-outsider-{}@test.com", run_id);
+        let sender_token = register_and_login(&server, &sender, "10.00").await;
+        let receiver_token = register_and_login(&server, &receiver, "10.00").await;
+        let outsider_token = register_and_login(&server, &outsider, "10.00").await;
+
+        let created = server
+            .post("/transaction")
+            .json(&json!({
+                        "from_email": sender,
+                        "to_email": receiver,
+                        "amount": "1.25"
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, sender_token.clone())
+            .await
+            .json::<Transaction>();
+        let path = format!("/transaction/{}", created.id);
+
+        for token in [&sender_token, &receiver_token] {
+            let fetched = server
+                .get(&path)
+                .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+                .await
+                .json::<Transaction>();
+            assert_eq!(fetched, created);
+        }
+
+        server
+            .get(&path)
+            .expect_failure()
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                outsider_token.clone(),
+            )
+            .await
+            .assert_status_not_found();
+        server
+            .get("/transaction/does-not-exist")
+            .expect_failure()
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                outsider_token.clone(),
+            )
+            .await
+            .assert_status_not_found();
+
+        sqlx::query("UPDATE users SET role = 'admin' WHERE email = $1")
+            .bind(&outsider)
+            .execute(get_conn().await)
+            .await
+            .unwrap();
+        let fetched = server
+            .get(&path)
+            .add_header(axum_test::http::header::AUTHORIZATION, outsider_token)
+            .await
+            .json::<Transaction>();
+        assert_eq!(fetched.amount, "1.25");
+    }
+}
+
+#[cfg(test)]
+mod test_refunds {
+    use super::*;
+    use ::serde_json::json;
+    use sqlx::Row;
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct Transaction {
+        pub id: String,
+        pub from_email: String,
+        pub to_email: String,
+        pub amount: String,
+        pub trnx_time: DateTime<Utc>,
+        pub kind: String,
+        pub reverses_id: Option<String>,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct TransactionPage {
+        transactions: Vec<Transaction>,
+        next_cursor: Option<String>,
+    }
+
+    async fn balance(
+        server: &TestServer,
+        email: &str,
+        token: &axum_test::http::HeaderValue,
+    ) -> String {
+        server
+            .get("/balance")
+            .json(&json!({ "email": email }))
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+            .await
+            .json::<serde_json::Value>()["balance"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn partial_and_full_refunds_are_capped_by_the_original() {
+        let server = test_server();
+        let run_id = uuid::Uuid::new_v4().as_simple().to_string();
+        let sender = format!("refund-sender-{}@test.com", run_id);
+        let receiver = format!("refund-receiver-{}@test.com", run_id);
+        let sender_token = register_and_login(&server, &sender, "10.00").await;
+        let receiver_token = register_and_login(&server, &receiver, "10.00").await;
+
+        let original = server
+            .post("/transaction")
+            .json(&json!({
+                        "from_email": sender,
+                        "to_email": receiver,
+                        "amount": "4.00"
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, sender_token.clone())
+            .await
+            .json::<Transaction>();
+        assert_eq!(original.kind, "transfer");
+        assert_eq!(original.reverses_id, None);
+        let path = format!("/transaction/{}/refund", original.id);
+
+        let partial = server
+            .post(&path)
+            .json(&json!({ "amount": "1.50" }))
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                receiver_token.clone(),
+            )
+            .await
+            .json::<Transaction>();
+        assert_eq!(partial.kind, "refund");
+        assert_eq!(partial.reverses_id.as_deref(), Some(original.id.as_str()));
+        assert_eq!(partial.from_email, receiver);
+        assert_eq!(partial.to_email, sender);
+        assert_eq!(partial.amount, "1.50");
+
+        server
+            .post(&path)
+            .json(&json!({ "amount": "2.51" }))
+            .expect_failure()
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                receiver_token.clone(),
+            )
+            .await
+            .assert_status_bad_request();
+
+        // without an amount the rest is refunded
+        let rest = server
+            .post(&path)
+            .json(&json!({}))
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                receiver_token.clone(),
+            )
+            .await
+            .json::<Transaction>();
+        assert_eq!(rest.amount, "2.50");
+        server
+            .post(&path)
+            .json(&json!({}))
+            .expect_failure()
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                receiver_token.clone(),
+            )
+            .await
+            .assert_status_bad_request();
+
+        assert_eq!(balance(&server, &sender, &sender_token).await, "10.00");
+        assert_eq!(balance(&server, &receiver, &receiver_token).await, "10.00");
+
+        let refunds = server
+            .get("/transaction?kind=refund&order=asc")
+            .add_header(axum_test::http::header::AUTHORIZATION, sender_token.clone())
+            .await
+            .json::<TransactionPage>();
+        assert_eq!(refunds.transactions, vec![partial.clone(), rest]);
+
+        // refunds themselves cannot be refunded
+        server
+            .post(&format!("/transaction/{}/refund", partial.id))
+            .json(&json!({}))
+            .expect_failure()
+            .add_header(axum_test::http::header::AUTHORIZATION, sender_token)
+            .await
+            .assert_status_unprocessable_entity();
+    }
+
+    #[tokio::test]
+    async fn only_the_receiver_can_refund() {
+        let server = test_server();
+        let run_id = uuid::Uuid::new_v4().as_simple().to_string();
+        let sender = format!("refund-sender-{}@test.com", run_id);
+        let receiver = format!("refund-receiver-{}@test.com", run_id);
+        let outsider = format!("refund-outsider-{}@test.com", run_id);
+        let sender_token = register_and_login(&server, &sender, "10.00").await;
+        register_and_login(&server, &receiver, "10.00").await;
+        let outsider_token = register_and_login(&server, &outsider, "10.00").await;
+
+        let original = server
+            .post("/transaction")
+            .json(&json!({
+                        "from_email": sender,
+                        "to_email": receiver,
+                        "amount": "1.00"
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, sender_token.clone())
+            .await
+            .json::<Transaction>();
+        let path = format!("/transaction/{}/refund", original.id);
+
+        server
+            .post(&path)
+            .json(&json!({}))
+            .expect_failure()
+            .add_header(axum_test::http::header::AUTHORIZATION, sender_token)
+            .await
+            .assert_status_forbidden();
+        server
+            .post(&path)
+            .json(&json!({}))
+            .expect_failure()
+            .add_header(axum_test::http::header::AUTHORIZATION, outsider_token)
+            .await
+            .assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn admin_refunds_are_recorded_as_admin_actions() {
+        let server = test_server();
+        let run_id = uuid::Uuid::new_v4().as_simple().to_string();
+        let sender = format!("refund-sender-{}@test.com", run_id);
+        let receiver = format!("refund-receiver-{}@test.com", run_id);
+        let admin = format!("refund-admin-{}@test.com", run_id);
+        let sender_token = register_and_login(&server, &sender, "10.00").await;
+        register_and_login(&server, &receiver, "10.00").await;
+        register_verified(&server, &admin, "0").await;
+        set_role(&admin, "admin").await;
+        let admin_token = login_bearer(&server, &admin).await;
+
+        let original = server
+            .post("/transaction")
+            .json(&json!({
+                        "from_email": sender,
+                        "to_email": receiver,
+                        "amount": "3.00"
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, sender_token)
+            .await
+            .json::<Transaction>();
+        let refund = server
+            .post(&format!("/transaction/{}/refund", original.id))
+            .json(&json!({ "amount": "1.00" }))
+            .add_header(axum_test::http::header::AUTHORIZATION, admin_token)
+            .await
+            .json::<Transaction>();
+        assert_eq!(refund.from_email, receiver);
+        assert_eq!(refund.to_email, sender);
+
+        let row = sqlx::query(
+            "SELECT a.action, a.details, u.email FROM admin_actions a JOIN users u ON u.id = a.target_user_id WHERE a.admin_email = $1",
+        )
+        .bind(&admin)
+        .fetch_one(transaction_service::config::db::get_conn().await)
+        .await
+        .unwrap();
+        assert_eq!(row.get::<String, &str>("action"), "refund_transaction");
+        assert_eq!(row.get::<String, &str>("email"), receiver);
+        assert_eq!(
+            row.get::<serde_json::Value, &str>("details"),
+            json!({
+                "transaction_id": original.id,
+                "refund_id": refund.id,
+                "amount": "1.00",
+            })
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_transaction_status {
+    use super::*;
+    use ::serde_json::json;
+    use transaction_service::config::db::get_conn;
+    use transaction_service::reconciliation::reconcile;
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct Transaction {
+        pub id: String,
+        pub amount: String,
+        pub status: String,
+        pub completed_at: Option<DateTime<Utc>>,
+        pub failed_at: Option<DateTime<Utc>>,
+        pub reversed_at: Option<DateTime<Utc>>,
+        pub failure_reason: Option<String>,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct TransactionPage {
+        transactions: Vec<Transaction>,
+        next_cursor: Option<String>,
+    }
+
+    #[tokio::test]
+    async fn transactions_record_each_status_transition() {
+        let server = test_server();
+        let run_id = uuid::Uuid::new_v4().as_simple().to_string();
+        let sender = format!("status-sender-{}@test.com", run_id);
+        let receiver = format!("status-receiver-{}@test.com", run_id);
+        let sender_token = register_and_login(&server, &sender, "5.00").await;
+        let receiver_token = register_and_login(&server, &receiver, "5.00").await;
+
+        let completed = server
+            .post("/transaction")
+            .json(&json!({
+                        "from_email": sender,
+                        "to_email": receiver,
+                        "amount": "2.00"
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, sender_token.clone())
+            .await
+            .json::<Transaction>();
+        assert_eq!(completed.status, "completed");
+        assert!(completed.completed_at.is_some());
+        assert_eq!(completed.failed_at, None);
+
+        server
+            .post("/transaction")
+            .json(&json!({
+                        "from_email": sender,
+                        "to_email": receiver,
+                        "amount": "50.00"
+            }))
+            .expect_failure()
+            .add_header(axum_test::http::header::AUTHORIZATION, sender_token.clone())
+            .await
+            .assert_status_bad_request();
+
+        let failed = server
+            .get("/transaction?status=failed")
+            .add_header(axum_test::http::header::AUTHORIZATION, sender_token.clone())
+            .await
+            .json::<TransactionPage>()
+            .transactions;
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].amount, "50.00");
+        assert_eq!(
+            failed[0].failure_reason.as_deref(),
+            Some("Insufficient balance")
+        );
+        assert!(failed[0].failed_at.is_some());
+        assert_eq!(failed[0].completed_at, None);
+
+        let not_refundable = server
+            .post(&format!("/transaction/{}/refund", failed[0].id))
+            .json(&json!({}))
+            .expect_failure()
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                receiver_token.clone(),
+            )
+            .await;
+        not_refundable.assert_status_unprocessable_entity();
+        assert_eq!(
+            not_refundable.json::<serde_json::Value>()["error"],
+            "Only completed transfers can be refunded"
+        );
+
+        server
+            .post(&format!("/transaction/{}/refund", completed.id))
+            .json(&json!({}))
+            .add_header(axum_test::http::header::AUTHORIZATION, receiver_token)
+            .await;
+        let reversed = server
+            .get(&format!("/transaction/{}", completed.id))
+            .add_header(axum_test::http::header::AUTHORIZATION, sender_token.clone())
+            .await
+            .json::<Transaction>();
+        assert_eq!(reversed.status, "reversed");
+        assert!(reversed.reversed_at.is_some());
+        assert_eq!(reversed.completed_at, completed.completed_at);
+
+        let page = server
+            .get("/transaction")
+            .add_header(axum_test::http::header::AUTHORIZATION, sender_token)
+            .await
+            .json::<TransactionPage>();
+        let statuses = page
+            .transactions
+            .iter()
+            .map(|t| t.status.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(statuses, vec!["completed", "failed", "reversed"]);
+
+        // failed attempts moved no money, so they do not count as drift
+        let drifts = reconcile(get_conn().await).await.unwrap();
+        assert!(!drifts
+            .iter()
+            .any(|d| d.email == sender || d.email == receiver));
+    }
+}
+
+#[cfg(test)]
+mod test_transaction_details {
+    use super::*;
+    use ::serde_json::json;
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct Transaction {
+        pub id: String,
+        pub amount: String,
+        pub memo: Option<String>,
+        pub reference: Option<String>,
+        pub metadata: Option<serde_json::Value>,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct TransactionPage {
+        transactions: Vec<Transaction>,
+        next_cursor: Option<String>,
+    }
+
+    async fn search(
+        server: &TestServer,
+        token: &axum_test::http::HeaderValue,
+        key: &str,
+        value: &str,
+    ) -> Vec<Transaction> {
+        server
+            .get("/transaction")
+            .add_query_param(key, value)
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+            .await
+            .json::<TransactionPage>()
+            .transactions
+    }
+
+    #[tokio::test]
+    async fn details_are_stored_and_searchable() {
+        let server = test_server();
+        let run_id = uuid::Uuid::new_v4().as_simple().to_string();
+        let sender = format!("details-sender-{}@test.com", run_id);
+        let receiver = format!("details-receiver-{}@test.com", run_id);
+        let sender_token = register_and_login(&server, &sender, "10.00").await;
+        let receiver_token = register_and_login(&server, &receiver, "10.00").await;
+
+        let invoice = server
+            .post("/transaction")
+            .json(&json!({
+                        "from_email": sender,
+                        "to_email": receiver,
+                        "amount": "1.00",
+                        "memo": "Invoice 100% paid_in_full",
+                        "reference": "INV-7",
+                        "metadata": { "invoice": "INV-7", "lines": [1, 2] }
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, sender_token.clone())
+            .await
+            .json::<Transaction>();
+        assert_eq!(invoice.reference.as_deref(), Some("INV-7"));
+        let plain = server
+            .post("/transaction")
+            .json(&json!({
+                        "from_email": sender,
+                        "to_email": receiver,
+                        "amount": "2.00"
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, sender_token.clone())
+            .await
+            .json::<Transaction>();
+        assert_eq!(plain.memo, None);
+        assert_eq!(plain.metadata, None);
+
+        assert_eq!(
+            search(&server, &receiver_token, "reference", "INV-7").await,
+            vec![invoice.clone()]
+        );
+        assert_eq!(
+            search(&server, &sender_token, "memo", "100% PAID").await,
+            vec![invoice.clone()]
+        );
+        // wildcards in the search text are matched literally
+        assert!(search(&server, &sender_token, "memo", "1_0")
+            .await
+            .is_empty());
+        assert_eq!(
+            search(&server, &sender_token, "metadata", r#"{"invoice":"INV-7"}"#).await,
+            vec![invoice]
+        );
+        assert!(
+            search(&server, &sender_token, "metadata", r#"{"invoice":"INV-8"}"#)
+                .await
+                .is_empty()
+        );
+        server
+            .get("/transaction")
+            .add_query_param("metadata", "[1]")
+            .expect_failure()
+            .add_header(axum_test::http::header::AUTHORIZATION, sender_token.clone())
+            .await
+            .assert_status_bad_request();
+    }
+
+    #[tokio::test]
+    async fn oversized_details_are_rejected() {
+        let server = test_server();
+        let run_id = uuid::Uuid::new_v4().as_simple().to_string();
+        let sender = format!("details-sender-{}@test.com", run_id);
+        let receiver = format!("details-receiver-{}@test.com", run_id);
+        let sender_token = register_and_login(&server, &sender, "10.00").await;
+        register_and_login(&server, &receiver, "10.00").await;
+
+        for details in [
+            json!({ "memo": "m".repeat(281) }),
+            json!({ "reference": "" }),
+            json!({ "reference": "r".repeat(129) }),
+            json!({ "metadata": "not an object" }),
+            json!({ "metadata": { "blob": "b".repeat(4096) } }),
+        ] {
+            let mut request = json!({
+                        "from_email": sender,
+                        "to_email": receiver,
+                        "amount": "1.00"
+            });
+            request
+                .as_object_mut()
+                .unwrap()
+                .extend(details.as_object().unwrap().clone());
+            server
+                .post("/transaction")
+                .json(&request)
+                .expect_failure()
+                .add_header(axum_test::http::header::AUTHORIZATION, sender_token.clone())
+                .await
+                .assert_status_bad_request();
+        }
+
+        let accepted = server
+            .post("/transaction")
+            .json(&json!({
+                        "from_email": sender,
+                        "to_email": receiver,
+                        "amount": "1.00",
+                        "memo": "é".repeat(280)
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, sender_token)
+            .await
+            .json::<Transaction>();
+        assert_eq!(accepted.memo.unwrap().chars().count(), 280);
+    }
+}
+
+#[cfg(test)]
+mod test_role_based_access {
+    use super::*;
+    use ::serde_json::json;
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct LoginRequest {
+        email: String,
+        fullname: String,
+        balance: String,
+        token: String,
+        role: String,
+    }
+
+    async fn register(server: &TestServer, email: &str, role: &str) {
+        register_verified(server, email, "1.00").await;
+        set_role(email, role).await;
+    }
+
+    async fn login(server: &TestServer, email: &str) -> LoginRequest {
+        server
+            .post("/login")
+            .json(&json!({
+                        "email": email,
+                        "password": "testpassword123"
+            }))
+            .await
+            .json::<LoginRequest>()
+    }
+
+    #[tokio::test]
+    async fn user_tokens_are_rejected_on_admin_routes() {
+        let server = test_server();
+        let run_id = uuid::Uuid::new_v4().as_simple().to_string();
+        let email = format!("rbac-user-{}@test.com", run_id);
+        register(&server, &email, "user").await;
+        let user = login(&server, &email).await;
+        assert_eq!(user.role, "user");
+
+        server
+            .get("/admin/reconciliation")
+            .expect_failure()
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(&user.token))
+            .await
+            .assert_status_forbidden();
+        server
+            .get("/admin/reconciliation")
+            .expect_failure()
+            .await
+            .assert_status_unauthorized();
+    }
+
+    #[tokio::test]
+    async fn staff_roles_are_loaded_at_login_and_checked_per_route() {
+        let server = test_server();
+        let run_id = uuid::Uuid::new_v4().as_simple().to_string();
+        for (role, allowed) in [("admin", true), ("auditor", true), ("support", false)] {
+            let email = format!("rbac-{}-{}@test.com", role, run_id);
+            register(&server, &email, role).await;
+            let staff = login(&server, &email).await;
+            assert_eq!(staff.role, role);
+
+            let request = server
+                .get("/admin/reconciliation")
+                .add_header(axum_test::http::header::AUTHORIZATION, bearer(&staff.token));
+            if allowed {
+                let response = request.await;
+                assert!(response.json::<serde_json::Value>()["drifts"].is_array());
+            } else {
+                request.expect_failure().await.assert_status_forbidden();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_admin_api {
+    use super::*;
+    use ::serde_json::json;
+    use sqlx::Row;
+    use transaction_service::config::db::get_conn;
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct UserAccount {
+        id: String,
+        fullname: String,
+        email: String,
+        role: String,
+        status: String,
+        balance: String,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct UserPage {
+        users: Vec<UserAccount>,
+        next_cursor: Option<String>,
+    }
+
+    async fn register_staff(
+        server: &TestServer,
+        email: &str,
+        role: &str,
+    ) -> axum_test::http::HeaderValue {
+        register_verified(server, email, "3.00").await;
+        set_role(email, role).await;
+        login_bearer(server, email).await
+    }
+
+    #[tokio::test]
+    async fn admins_manage_accounts_and_every_action_is_recorded() {
+        let server = test_server();
+        let run_id = uuid::Uuid::new_v4().as_simple().to_string();
+        let admin = format!("admin-api-admin-{}@test.com", run_id);
+        let alice = format!("admin-api-alice-{}@test.com", run_id);
+        let bob = format!("admin-api-bob-{}@test.com", run_id);
+        let admin_token = register_staff(&server, &admin, "admin").await;
+        let alice_token = register_staff(&server, &alice, "user").await;
+        register_staff(&server, &bob, "user").await;
+        server
+            .post("/transaction")
+            .json(&json!({
+                        "from_email": alice,
+                        "to_email": bob,
+                        "amount": "1.00"
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, alice_token.clone())
+            .await;
+
+        let first = server
+            .get("/admin/users")
+            .add_query_param("q", &run_id)
+            .add_query_param("limit", 2)
+            .add_header(axum_test::http::header::AUTHORIZATION, admin_token.clone())
+            .await
+            .json::<UserPage>();
+        let emails = first
+            .users
+            .iter()
+            .map(|u| u.email.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(emails, vec![admin.clone(), alice.clone()]);
+        let second = server
+            .get("/admin/users")
+            .add_query_param("q", &run_id)
+            .add_query_param("limit", 2)
+            .add_query_param("cursor", first.next_cursor.unwrap())
+            .add_header(axum_test::http::header::AUTHORIZATION, admin_token.clone())
+            .await
+            .json::<UserPage>();
+        assert_eq!(second.users.len(), 1);
+        assert_eq!(second.next_cursor, None);
+        let bob_account = second.users[0].clone();
+        assert_eq!(bob_account.email, bob);
+
+        let fetched = server
+            .get(&format!("/admin/users/{}", bob_account.id))
+            .add_header(axum_test::http::header::AUTHORIZATION, admin_token.clone())
+            .await
+            .json::<UserAccount>();
+        assert_eq!(fetched.balance, "4.00");
+        let transactions = server
+            .get(&format!("/admin/users/{}/transactions", bob_account.id))
+            .add_header(axum_test::http::header::AUTHORIZATION, admin_token.clone())
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(transactions["transactions"].as_array().unwrap().len(), 1);
+
+        server
+            .post(&format!("/admin/users/{}/freeze", bob_account.id))
+            .json(&json!({ "reason": "" }))
+            .expect_failure()
+            .add_header(axum_test::http::header::AUTHORIZATION, admin_token.clone())
+            .await
+            .assert_status_bad_request();
+        let frozen = server
+            .post(&format!("/admin/users/{}/freeze", bob_account.id))
+            .json(&json!({ "reason": "compliance review" }))
+            .add_header(axum_test::http::header::AUTHORIZATION, admin_token.clone())
+            .await
+            .json::<UserAccount>();
+        assert_eq!(frozen.status, "frozen");
+        let unfrozen = server
+            .post(&format!("/admin/users/{}/unfreeze", bob_account.id))
+            .json(&json!({ "reason": "review closed" }))
+            .add_header(axum_test::http::header::AUTHORIZATION, admin_token.clone())
+            .await
+            .json::<UserAccount>();
+        assert_eq!(unfrozen.status, "active");
+
+        let promoted = server
+            .put(&format!("/admin/users/{}/role", bob_account.id))
+            .json(&json!({ "role": "support" }))
+            .add_header(axum_test::http::header::AUTHORIZATION, admin_token.clone())
+            .await
+            .json::<UserAccount>();
+        assert_eq!(promoted.role, "support");
+        let admin_id = first.users[0].id.clone();
+        server
+            .put(&format!("/admin/users/{}/role", admin_id))
+            .json(&json!({ "role": "user" }))
+            .expect_failure()
+            .add_header(axum_test::http::header::AUTHORIZATION, admin_token.clone())
+            .await
+            .assert_status_forbidden();
+        server
+            .get("/admin/users/does-not-exist")
+            .expect_failure()
+            .add_header(axum_test::http::header::AUTHORIZATION, admin_token)
+            .await
+            .assert_status_not_found();
+
+        let rows = sqlx::query(
+            "SELECT action, target_user_id, details FROM admin_actions WHERE admin_email = $1 ORDER BY created_at",
+        )
+        .bind(&admin)
+        .fetch_all(get_conn().await)
+        .await
+        .unwrap();
+        let actions = rows
+            .iter()
+            .map(|row| row.get::<String, &str>("action"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            actions,
+            vec![
+                "search_users",
+                "search_users",
+                "view_user",
+                "list_user_transactions",
+                "freeze_account",
+                "unfreeze_account",
+                "change_role",
+            ]
+        );
+        let freeze = &rows[4];
+        assert_eq!(
+            freeze.get::<Option<String>, &str>("target_user_id"),
+            Some(bob_account.id)
+        );
+        assert_eq!(
+            freeze.get::<serde_json::Value, &str>("details")["reason"],
+            "compliance review"
+        );
+    }
+
+    #[tokio::test]
+    async fn staff_roles_are_limited_and_demotion_is_immediate() {
+        let server = test_server();
+        let run_id = uuid::Uuid::new_v4().as_simple().to_string();
+        let user = format!("admin-api-user-{}@test.com", run_id);
+        let support = format!("admin-api-support-{}@test.com", run_id);
+        let admin = format!("admin-api-admin-{}@test.com", run_id);
+        let user_token = register_staff(&server, &user, "user").await;
+        let support_token = register_staff(&server, &support, "support").await;
+        let admin_token = register_staff(&server, &admin, "admin").await;
+        let user_id = sqlx::query("SELECT id FROM users WHERE email = $1")
+            .bind(&user)
+            .fetch_one(get_conn().await)
+            .await
+            .unwrap()
+            .get::<String, &str>("id");
+
+        server
+            .get("/admin/users")
+            .expect_failure()
+            .add_header(axum_test::http::header::AUTHORIZATION, user_token)
+            .await
+            .assert_status_forbidden();
+        server
+            .get(&format!("/admin/users/{}", user_id))
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                support_token.clone(),
+            )
+            .await
+            .assert_status_ok();
+        server
+            .post(&format!("/admin/users/{}/freeze", user_id))
+            .json(&json!({ "reason": "support cannot freeze" }))
+            .expect_failure()
+            .add_header(axum_test::http::header::AUTHORIZATION, support_token)
+            .await
+            .assert_status_forbidden();
+
+        // the token still says admin, but the role was taken away
+        sqlx::query("UPDATE users SET role = 'user' WHERE email = $1")
+            .bind(&admin)
+            .execute(get_conn().await)
+            .await
+            .unwrap();
+        server
+            .get(&format!("/admin/users/{}", user_id))
+            .expect_failure()
+            .add_header(axum_test::http::header::AUTHORIZATION, admin_token)
+            .await
+            .assert_status_forbidden();
+    }
+}
+
+#[cfg(test)]
+mod test_account_status {
+    use super::*;
+    use ::serde_json::json;
+    use sqlx::Row;
+    use transaction_service::config::db::get_conn;
+
+    async fn register(server: &TestServer, email: &str, role: &str) {
+        register_verified(server, email, "5.00").await;
+        set_role(email, role).await;
+    }
+
+    fn login(server: &TestServer, email: &str) -> axum_test::TestRequest {
+        server.post("/login").json(&json!({
+                    "email": email,
+                    "password": "testpassword123"
+        }))
+    }
+
+    fn send(
+        server: &TestServer,
+        from: &str,
+        to: &str,
+        token: &axum_test::http::HeaderValue,
+    ) -> axum_test::TestRequest {
+        server
+            .post("/transaction")
+            .json(&json!({
+                        "from_email": from,
+                        "to_email": to,
+                        "amount": "1.00"
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+    }
+
+    fn set_status(
+        server: &TestServer,
+        user_id: &str,
+        action: &str,
+        token: &axum_test::http::HeaderValue,
+    ) -> axum_test::TestRequest {
+        server
+            .post(&format!("/admin/users/{}/{}", user_id, action))
+            .json(&json!({ "reason": "compliance" }))
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+    }
+
+    #[tokio::test]
+    async fn frozen_and_closed_accounts_cannot_move_money() {
+        let server = test_server();
+        let run_id = uuid::Uuid::new_v4().as_simple().to_string();
+        let alice = format!("status-alice-{}@test.com", run_id);
+        let bob = format!("status-bob-{}@test.com", run_id);
+        let admin = format!("status-admin-{}@test.com", run_id);
+        register(&server, &alice, "user").await;
+        register(&server, &bob, "user").await;
+        register(&server, &admin, "admin").await;
+        let alice_token = login_bearer(&server, &alice).await;
+        let bob_token = login_bearer(&server, &bob).await;
+        let admin_token = login_bearer(&server, &admin).await;
+        let bob_id = sqlx::query("SELECT id FROM users WHERE email = $1")
+            .bind(&bob)
+            .fetch_one(get_conn().await)
+            .await
+            .unwrap()
+            .get::<String, &str>("id");
+
+        set_status(&server, &bob_id, "freeze", &admin_token).await;
+        send(&server, &alice, &bob, &alice_token)
+            .expect_failure()
+            .await
+            .assert_status_unprocessable_entity();
+        send(&server, &bob, &alice, &bob_token)
+            .expect_failure()
+            .await
+            .assert_status_forbidden();
+        // frozen accounts can still log in and read
+        login(&server, &bob).await;
+        server
+            .get("/transaction?status=failed")
+            .add_header(axum_test::http::header::AUTHORIZATION, bob_token.clone())
+            .await
+            .assert_status_ok();
+        let failed = server
+            .get("/transaction?status=failed&direction=sent")
+            .add_header(axum_test::http::header::AUTHORIZATION, alice_token.clone())
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(
+            failed["transactions"][0]["failure_reason"],
+            "recipient account is frozen"
+        );
+
+        set_status(&server, &bob_id, "unfreeze", &admin_token).await;
+        send(&server, &alice, &bob, &alice_token).await;
+
+        set_status(&server, &bob_id, "close", &admin_token).await;
+        server
+            .get("/transaction")
+            .expect_failure()
+            .add_header(axum_test::http::header::AUTHORIZATION, bob_token)
+            .await
+            .assert_status_unauthorized();
+        login(&server, &bob)
+            .expect_failure()
+            .await
+            .assert_status_forbidden();
+        send(&server, &alice, &bob, &alice_token)
+            .expect_failure()
+            .await
+            .assert_status_unprocessable_entity();
+        set_status(&server, &bob_id, "unfreeze", &admin_token)
+            .expect_failure()
+            .await
+            .assert_status(axum_test::http::StatusCode::CONFLICT);
+    }
+}
+
+#[cfg(test)]
+mod test_logout {
+    use super::*;
+    use ::serde_json::json;
+    use transaction_service::config::db::get_conn;
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct LoginRequest {
+        email: String,
+        token: String,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct LogoutResponse {
+        email: String,
+        revoked_sessions: u64,
+    }
+
+    async fn login(server: &TestServer, email: &str) -> String {
+        let login = server
+            .post("/login")
+            .json(&json!({
+                        "email": email,
+                        "password": "testpassword123"
+            }))
+            .await
+            .json::<LoginRequest>();
+        login.token
+    }
+
+    async fn assert_authorised(server: &TestServer, token: &str, authorised: bool) {
+        let request = server
+            .post("/authorise")
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(token));
+        if authorised {
+            request.await.assert_status_ok();
+        } else {
+            request.expect_failure().await.assert_status_unauthorized();
+        }
+    }
+
+    #[tokio::test]
+    async fn logout_revokes_the_current_token_or_every_token() {
+        let server = test_server();
+        let email = format!("logout-{}@test.com", uuid::Uuid::new_v4().as_simple());
+        server
+            .post("/register")
+            .json(&json!({
+                        "email": email,
+                        "password": "testpassword123",
+                        "fullname": "logout user"
+            }))
+            .await;
+        let first = login(&server, &email).await;
+        let second = login(&server, &email).await;
+        assert_ne!(first, second);
+        assert_authorised(&server, &first, true).await;
+        assert_authorised(&server, &second, true).await;
+
+        let logout = server
+            .post("/logout")
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(&first))
+            .await
+            .json::<LogoutResponse>();
+        assert_eq!(logout.revoked_sessions, 1);
+        assert_authorised(&server, &first, false).await;
+        assert_authorised(&server, &second, true).await;
+
+        let third = login(&server, &email).await;
+        let logout = server
+            .post("/logout")
+            .add_query_param("all", true)
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(&second))
+            .await
+            .json::<LogoutResponse>();
+        assert_eq!(logout.revoked_sessions, 2);
+        assert_authorised(&server, &second, false).await;
+        assert_authorised(&server, &third, false).await;
+    }
+
+    #[tokio::test]
+    async fn tokens_missing_from_the_authorise_table_are_rejected() {
+        let server = test_server();
+        let email = format!(
+            "logout-missing-{}@test.com",
+            uuid::Uuid::new_v4().as_simple()
+        );
+        server
+            .post("/register")
+            .json(&json!({
+                        "email": email,
+                        "password": "testpassword123",
+                        "fullname": "logout user"
+            }))
+            .await;
+        let token = login(&server, &email).await;
+        assert_authorised(&server, &token, true).await;
+
+        sqlx::query("DELETE FROM authorise WHERE token = $1")
+            .bind(&token)
+            .execute(get_conn().await)
+            .await
+            .unwrap();
+        assert_authorised(&server, &token, false).await;
+    }
+}
+
+#[cfg(test)]
+mod test_refresh_tokens {
+    use super::*;
+    use ::serde_json::json;
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct Tokens {
+        token: String,
+        token_expires_at: DateTime<Utc>,
+        refresh_token: String,
+    }
+
+    async fn login_tokens(server: &TestServer, prefix: &str) -> Tokens {
+        let email = unique_email(prefix);
+        register_verified(server, &email, "0.00").await;
+        server
+            .post("/login")
+            .json(&json!({
+                        "email": email,
+                        "password": "testpassword123"
+            }))
+            .await
+            .json::<Tokens>()
+    }
+
+    fn refresh(server: &TestServer, refresh_token: &str) -> axum_test::TestRequest {
+        server
+            .post("/token/refresh")
+            .json(&json!({ "refresh_token": refresh_token }))
+    }
+
+    async fn assert_authorised(server: &TestServer, token: &str, authorised: bool) {
+        let request = server
+            .post("/authorise")
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(token));
+        if authorised {
+            request.await.assert_status_ok();
+        } else {
+            request.expect_failure().await.assert_status_unauthorized();
+        }
+    }
+
+    #[tokio::test]
+    async fn refresh_tokens_rotate_and_reuse_revokes_the_family() {
+        let server = test_server();
+        let first = login_tokens(&server, "refresh").await;
+        assert!(first.token_expires_at <= Utc::now() + chrono::Duration::minutes(15));
+
+        let second = refresh(&server, &first.refresh_token)
+            .await
+            .json::<Tokens>();
+        assert_ne!(second.refresh_token, first.refresh_token);
+        assert_authorised(&server, &second.token, true).await;
+        let third = refresh(&server, &second.refresh_token)
+            .await
+            .json::<Tokens>();
+        assert_authorised(&server, &third.token, true).await;
+
+        // the first refresh token was already used, so it must have been copied
+        refresh(&server, &first.refresh_token)
+            .expect_failure()
+            .await
+            .assert_status_unauthorized();
+        refresh(&server, &third.refresh_token)
+            .expect_failure()
+            .await
+            .assert_status_unauthorized();
+        assert_authorised(&server, &first.token, false).await;
+        assert_authorised(&server, &third.token, false).await;
+    }
+
+    #[tokio::test]
+    async fn unknown_and_logged_out_refresh_tokens_are_rejected() {
+        let server = test_server();
+        refresh(&server, "not-a-refresh-token")
+            .expect_failure()
+            .await
+            .assert_status_unauthorized();
+
+        let session = login_tokens(&server, "refresh-logout").await;
+        let other = login_tokens(&server, "refresh-other").await;
+        server
+            .post("/logout")
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                bearer(&session.token),
+            )
+            .await;
+        refresh(&server, &session.refresh_token)
+            .expect_failure()
+            .await
+            .assert_status_unauthorized();
+        // other users' sessions are untouched
+        refresh(&server, &other.refresh_token)
+            .await
+            .assert_status_ok();
+    }
+}
+
+#[cfg(test)]
+mod test_sessions {
+    use super::*;
+    use ::serde_json::json;
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct LoginRequest {
+        email: String,
+        token: String,
+        refresh_token: String,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct Session {
+        id: String,
+        user_agent: Option<String>,
+        ip: Option<String>,
+        created_at: DateTime<Utc>,
+        last_seen: DateTime<Utc>,
+        current: bool,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct SessionList {
+        sessions: Vec<Session>,
+    }
+
+    async fn register(server: &TestServer, prefix: &str) -> String {
+        let email = unique_email(prefix);
+        register_verified(server, &email, "0.00").await;
+        email
+    }
+
+    async fn login(server: &TestServer, email: &str, user_agent: &str) -> LoginRequest {
+        server
+            .post("/login")
+            .json(&json!({
+                        "email": email,
+                        "password": "testpassword123"
+            }))
+            .add_header(
+                axum_test::http::header::USER_AGENT,
+                axum_test::http::HeaderValue::from_str(user_agent).unwrap(),
+            )
+            .await
+            .json::<LoginRequest>()
+    }
+
+    async fn list(server: &TestServer, token: &str) -> Vec<Session> {
+        server
+            .get("/sessions")
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(token))
+            .await
+            .json::<SessionList>()
+            .sessions
+    }
+
+    #[tokio::test]
+    async fn users_can_list_and_end_their_sessions() {
+        let server = test_server();
+        let email = register(&server, "sessions").await;
+        let laptop = login(&server, &email, "laptop-browser").await;
+        let phone = login(&server, &email, "phone-app").await;
+
+        // logging in on the phone keeps the laptop logged in
+        let sessions = list(&server, &laptop.token).await;
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].user_agent.as_deref(), Some("phone-app"));
+        assert!(!sessions[0].current);
+        assert_eq!(sessions[1].user_agent.as_deref(), Some("laptop-browser"));
+        assert!(sessions[1].current);
+
+        let phone_session = sessions[0].id.clone();
+        server
+            .delete(&format!("/sessions/{}", phone_session))
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                bearer(&laptop.token),
+            )
+            .await
+            .assert_status_ok();
+        server
+            .post("/authorise")
+            .expect_failure()
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(&phone.token))
+            .await
+            .assert_status_unauthorized();
+        server
+            .post("/token/refresh")
+            .json(&json!({ "refresh_token": phone.refresh_token }))
+            .expect_failure()
+            .await
+            .assert_status_unauthorized();
+        let sessions = list(&server, &laptop.token).await;
+        assert_eq!(sessions.len(), 1);
+        assert!(sessions[0].current);
+
+        // ending it again finds nothing
+        server
+            .delete(&format!("/sessions/{}", phone_session))
+            .expect_failure()
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                bearer(&laptop.token),
+            )
+            .await
+            .assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn sessions_of_other_users_cannot_be_ended() {
+        let server = test_server();
+        let alice = register(&server, "sessions-alice").await;
+        let mallory = register(&server, "sessions-mallory").await;
+        let alice_login = login(&server, &alice, "alice-device").await;
+        let mallory_login = login(&server, &mallory, "mallory-device").await;
+        let alice_session = list(&server, &alice_login.token).await[0].id.clone();
+
+        server
+            .delete(&format!("/sessions/{}", alice_session))
+            .expect_failure()
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                bearer(&mallory_login.token),
+            )
+            .await
+            .assert_status_not_found();
+        server
+            .delete("/sessions/unknown-session")
+            .expect_failure()
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                bearer(&mallory_login.token),
+            )
+            .await
+            .assert_status_not_found();
+        assert_eq!(list(&server, &alice_login.token).await.len(), 1);
+    }
+}
+
+#[cfg(test)]
+mod test_password_change {
+    use super::*;
+    use ::serde_json::json;
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct LoginRequest {
+        email: String,
+        token: String,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct PasswordChanged {
+        email: String,
+        revoked_sessions: u64,
+    }
+
+    fn login(server: &TestServer, email: &str, password: &str) -> axum_test::TestRequest {
+        server.post("/login").json(&json!({
+                    "email": email,
+                    "password": password
+        }))
+    }
+
+    fn change(
+        server: &TestServer,
+        token: &str,
+        current_password: &str,
+        new_password: &str,
+    ) -> axum_test::TestRequest {
+        server
+            .put("/user/password")
+            .json(&json!({
+                        "current_password": current_password,
+                        "new_password": new_password
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(token))
+    }
+
+    #[tokio::test]
+    async fn password_change_checks_the_current_password_and_ends_other_sessions() {
+        let server = test_server();
+        let email = format!("password-{}@test.com", uuid::Uuid::new_v4().as_simple());
+        server
+            .post("/register")
+            .json(&json!({
+                        "email": email,
+                        "password": "testpassword123",
+                        "fullname": "password user"
+            }))
+            .await;
+        let laptop = login(&server, &email, "testpassword123")
+            .await
+            .json::<LoginRequest>();
+        let phone = login(&server, &email, "testpassword123")
+            .await
+            .json::<LoginRequest>();
+        let api_key = server
+            .post("/api-keys")
+            .json(&json!({ "name": "password", "scopes": ["transactions:read"] }))
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                bearer(&laptop.token),
+            )
+            .await
+            .json::<serde_json::Value>()["key"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        change(&server, &laptop.token, "wrongpassword1", "newpassword456")
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+        change(&server, &laptop.token, "testpassword123", "testpassword123")
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+        change(&server, &laptop.token, "testpassword123", "short1")
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+        // nothing changed so far
+        server
+            .post("/authorise")
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(&phone.token))
+            .await;
+
+        let changed = change(&server, &laptop.token, "testpassword123", "newpassword456")
+            .await
+            .json::<PasswordChanged>();
+        assert_eq!(changed.revoked_sessions, 1);
+        server
+            .post("/authorise")
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                bearer(&laptop.token),
+            )
+            .await;
+        server
+            .post("/authorise")
+            .expect_failure()
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(&phone.token))
+            .await
+            .assert_status_unauthorized();
+        // API keys created with the old password are revoked as well
+        server
+            .get("/transaction")
+            .expect_failure()
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(&api_key))
+            .await
+            .assert_status_unauthorized();
+        login(&server, &email, "testpassword123")
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+        login(&server, &email, "newpassword456").await;
+    }
+}
+
+#[cfg(test)]
+mod test_password_reset {
+    use super::*;
+    use ::serde_json::json;
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct LoginRequest {
+        email: String,
+        token: String,
+    }
+
+    /// The token in reset email number `n`, counting from 1, sent to `email`.
+    /// Reset emails are sent in the background, so this waits for it.
+    async fn reset_token(email: &str, n: usize) -> String {
+        let mailbox = mail_dir().join(format!("{}.txt", email));
+        for _ in 0..100 {
+            let mails = std::fs::read_to_string(&mailbox).unwrap_or_default();
+            if let Some(mail) = mails.split("to choose a new password:").nth(n) {
+                return mail.split_whitespace().next().unwrap().to_string();
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        panic!("reset email {} to {} did not arrive", n, email);
+    }
+
+    fn forgot(server: &TestServer, email: &str) -> axum_test::TestRequest {
+        server
+            .post("/password/forgot")
+            .json(&json!({ "email": email }))
+    }
+
+    fn reset(server: &TestServer, token: &str, new_password: &str) -> axum_test::TestRequest {
+        server.post("/password/reset").json(&json!({
+                    "token": token,
+                    "new_password": new_password
+        }))
+    }
+
+    fn login(server: &TestServer, email: &str, password: &str) -> axum_test::TestRequest {
+        server.post("/login").json(&json!({
+                    "email": email,
+                    "password": password
+        }))
+    }
+
+    async fn register(server: &TestServer) -> String {
+        let email = unique_email("reset");
+        register_verified(server, &email, "0.00").await;
+        email
+    }
+
+    #[tokio::test]
+    async fn forgot_password_answers_the_same_for_unknown_emails() {
+        let server = test_server();
+        let email = register(&server).await;
+        let known = forgot(&server, &email).await;
+        let unknown = forgot(
+            &server,
+            &format!("nobody-{}@test.com", uuid::Uuid::new_v4().as_simple()),
+        )
+        .await;
+        known.assert_status(axum_test::http::StatusCode::ACCEPTED);
+        unknown.assert_status(axum_test::http::StatusCode::ACCEPTED);
+        assert_eq!(known.text(), unknown.text());
+        assert_eq!(reset_token(&email, 1).await.len(), 64);
+    }
+
+    #[tokio::test]
+    async fn reset_tokens_work_once_and_end_every_session() {
+        let server = test_server();
+        let email = register(&server).await;
+        let session = login(&server, &email, "testpassword123")
+            .await
+            .json::<LoginRequest>();
+        let headertoken = format!("Bearer {}", session.token);
+        let api_key = server
+            .post("/api-keys")
+            .json(&json!({ "name": "reset", "scopes": ["transactions:read"] }))
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                axum_test::http::HeaderValue::from_str(headertoken.as_str()).unwrap(),
+            )
+            .await
+            .json::<serde_json::Value>()["key"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let client = server
+            .post("/oauth/clients")
+            .json(&json!({
+                        "name": "reset",
+                        "redirect_uris": ["https://app.example.com/callback"],
+                        "scopes": ["transactions:read"]
+            }))
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                axum_test::http::HeaderValue::from_str(headertoken.as_str()).unwrap(),
+            )
+            .await
+            .json::<serde_json::Value>();
+        let client_token = server
+            .post("/oauth/token")
+            .form(&[
+                ("grant_type", "client_credentials"),
+                ("client_id", client["client_id"].as_str().unwrap()),
+                ("client_secret", client["client_secret"].as_str().unwrap()),
+            ])
+            .await
+            .json::<serde_json::Value>()["access_token"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        forgot(&server, &email).await;
+        let first = reset_token(&email, 1).await;
+        forgot(&server, &email).await;
+        let token = reset_token(&email, 2).await;
+
+        // asking again replaces the earlier token
+        reset(&server, &first, "newpassword456")
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+        // a weak password does not use the token up
+        reset(&server, &token, "short1")
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+        reset(&server, &token, "newpassword456")
+            .await
+            .assert_status_ok();
+        reset(&server, &token, "otherpassword789")
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+
+        server
+            .post("/authorise")
+            .expect_failure()
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                axum_test::http::HeaderValue::from_str(headertoken.as_str()).unwrap(),
+            )
+            .await
+            .assert_status_unauthorized();
+        // API keys created with the old password are revoked as well
+        server
+            .get("/transaction")
+            .expect_failure()
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                axum_test::http::HeaderValue::from_str(&format!("Bearer {}", api_key)).unwrap(),
+            )
+            .await
+            .assert_status_unauthorized();
+        // and so are OAuth clients and their tokens
+        server
+            .get("/transaction")
+            .expect_failure()
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                axum_test::http::HeaderValue::from_str(&format!("Bearer {}", client_token))
+                    .unwrap(),
+            )
+            .await
+            .assert_status_unauthorized();
+        login(&server, &email, "testpassword123")
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+        login(&server, &email, "newpassword456").await;
+    }
+}
+
+#[cfg(test)]
+mod test_email_verification {
+    use super::*;
+    use ::serde_json::json;
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct LoginRequest {
+        email: String,
+        token: String,
+        email_verified: bool,
+    }
+
+    /// The token of the latest verification link sent to `email`.
+    fn latest_verification_token(email: &str) -> String {
+        let mailbox = std::fs::read_to_string(mail_dir().join(format!("{}.txt", email))).unwrap();
+        let (_, latest) = mailbox.rsplit_once("/verify-email?token=").unwrap();
+        latest.split_whitespace().next().unwrap().to_string()
+    }
+
+    /// The latest verification link sent to `email`, exactly as in the mail.
+    fn latest_verification_link(email: &str) -> String {
+        let mailbox = std::fs::read_to_string(mail_dir().join(format!("{}.txt", email))).unwrap();
+        mailbox
+            .split_whitespace()
+            .rfind(|word| word.contains("/verify-email?token="))
+            .unwrap()
+            .to_string()
+    }
+
+    async fn register(server: &TestServer, prefix: &str) -> String {
+        // not verified, unlike the users of register_verified
+        let email = unique_email(prefix);
+        server
+            .post("/register")
+            .json(&json!({
+                        "email": email,
+                        "password": "testpassword123",
+                        "fullname": "verification user",
+                        "balance": "5.00"
+            }))
+            .await;
+        email
+    }
+
+    async fn login(server: &TestServer, email: &str) -> LoginRequest {
+        server
+            .post("/login")
+            .json(&json!({
+                        "email": email,
+                        "password": "testpassword123"
+            }))
+            .await
+            .json::<LoginRequest>()
+    }
+
+    fn send(server: &TestServer, from: &str, to: &str, token: &str) -> axum_test::TestRequest {
+        server
+            .post("/transaction")
+            .json(&json!({
+                        "from_email": from,
+                        "to_email": to,
+                        "amount": "1.00"
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(token))
+    }
+
+    #[tokio::test]
+    async fn malformed_emails_cannot_register() {
+        let server = test_server();
+        for email in [
+            "not-an-email",
+            "two@@test.com",
+            "user@localhost",
+            "us er@test.com",
+        ] {
+            server
+                .post("/register")
+                .json(&json!({
+                            "email": email,
+                            "password": "testpassword123",
+                            "fullname": "bad email"
+                }))
+                .expect_failure()
+                .await
+                .assert_status_bad_request();
+        }
+    }
+
+    #[tokio::test]
+    async fn unverified_users_cannot_send_money_until_they_follow_the_link() {
+        let server = test_server();
+        let sender = register(&server, "unverified-sender").await;
+        let receiver = register(&server, "unverified-receiver").await;
+        let session = login(&server, &sender).await;
+        assert!(!session.email_verified);
+        send(&server, &sender, &receiver, &session.token)
+            .expect_failure()
+            .await
+            .assert_status_forbidden();
+
+        // access tokens are not verification tokens
+        server
+            .post("/verify-email")
+            .json(&json!({ "token": session.token }))
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+        server
+            .post("/verify-email/resend")
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                bearer(&session.token),
+            )
+            .await
+            .assert_status(axum_test::http::StatusCode::ACCEPTED);
+        server
+            .post("/verify-email")
+            .json(&json!({ "token": latest_verification_token(&sender) }))
+            .await
+            .assert_status_ok();
+
+        assert!(login(&server, &sender).await.email_verified);
+        send(&server, &sender, &receiver, &session.token)
+            .await
+            .assert_status(axum_test::http::StatusCode::CREATED);
+        server
+            .post("/verify-email/resend")
+            .expect_failure()
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                bearer(&session.token),
+            )
+            .await
+            .assert_status(axum_test::http::StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn the_link_in_the_email_verifies_it() {
+        let server = test_server();
+        let email = register(&server, "link-follower").await;
+        let link = latest_verification_link(&email);
+        let path = link.strip_prefix("http://localhost:3042").unwrap();
+
+        let response = server.get(path).await;
+        response.assert_status_ok();
+        assert_eq!(
+            response.json::<serde_json::Value>(),
+            json!({ "email": email, "email_verified": true })
+        );
+        assert!(login(&server, &email).await.email_verified);
+        server
+            .get("/verify-email?token=not-a-token")
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+    }
+}
+
+#[cfg(test)]
+mod test_two_factor {
+    use super::*;
+    use ::serde_json::json;
+    use std::time::Duration;
+    use transaction_service::totp::{code_at, time_step};
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct LoginRequest {
+        email: String,
+        token: String,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct Challenge {
+        email: String,
+        two_factor_required: bool,
+        challenge_token: String,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct Enrollment {
+        secret: String,
+        otpauth_uri: String,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct Confirmation {
+        two_factor_enabled: bool,
+        recovery_codes: Vec<String>,
+    }
+
+    /// Hands out codes of `secret` the way an authenticator app shows them.
+    /// A code is only accepted once, so every code comes from a newer time
+    /// step than the one before, starting with the previous step. That gives
+    /// a test three codes without waiting.
+    struct Authenticator {
+        secret: String,
+        next_step: i64,
+    }
+
+    impl Authenticator {
+        fn code(&mut self) -> String {
+            let step = self.next_step.max(time_step(Utc::now()) - 1);
+            self.next_step = step + 1;
+            code_at(&self.secret, step).unwrap()
+        }
+    }
+
+    async fn register(server: &TestServer, prefix: &str) -> String {
+        let email = unique_email(prefix);
+        register_verified(server, &email, "1000.00").await;
+        email
+    }
+
+    fn login(server: &TestServer, email: &str) -> axum_test::TestRequest {
+        server.post("/login").json(&json!({
+                    "email": email,
+                    "password": "testpassword123"
+        }))
+    }
+
+    fn complete_login(server: &TestServer, challenge: &str, code: &str) -> axum_test::TestRequest {
+        server.post("/login/2fa").json(&json!({
+                    "challenge_token": challenge,
+                    "code": code
+        }))
+    }
+
+    fn post_code(
+        server: &TestServer,
+        path: &str,
+        token: &str,
+        code: &str,
+    ) -> axum_test::TestRequest {
+        server
+            .post(path)
+            .json(&json!({ "code": code }))
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(token))
+    }
+
+    /// Registers a user with 2FA enabled and returns their email, a token from
+    /// before 2FA was enabled, their authenticator and their recovery codes.
+    async fn register_with_2fa(
+        server: &TestServer,
+        prefix: &str,
+    ) -> (String, String, Authenticator, Vec<String>) {
+        let email = register(server, prefix).await;
+        let token = login(server, &email).await.json::<LoginRequest>().token;
+        let enrollment = server
+            .post("/2fa/enroll")
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(&token))
+            .await
+            .json::<Enrollment>();
+        let mut authenticator = Authenticator {
+            secret: enrollment.secret,
+            next_step: 0,
+        };
+        let confirmation = post_code(server, "/2fa/confirm", &token, &authenticator.code())
+            .await
+            .json::<Confirmation>();
+        (email, token, authenticator, confirmation.recovery_codes)
+    }
+
+    async fn challenge(server: &TestServer, email: &str) -> String {
+        let response = login(server, email).await;
+        response.assert_status_ok();
+        let challenge = response.json::<Challenge>();
+        assert!(challenge.two_factor_required);
+        challenge.challenge_token
+    }
+
+    #[tokio::test]
+    async fn enrollment_is_confirmed_with_a_code() {
+        let server = test_server();
+        let email = register(&server, "enroll").await;
+        let token = login(&server, &email).await.json::<LoginRequest>().token;
+
+        let response = server
+            .post("/2fa/enroll")
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(&token))
+            .await;
+        response.assert_status(axum_test::http::StatusCode::CREATED);
+        let enrollment = response.json::<Enrollment>();
+        assert!(enrollment
+            .otpauth_uri
+            .starts_with("otpauth://totp/Transaction%20Service:enroll-"));
+        assert!(enrollment
+            .otpauth_uri
+            .contains(&format!("secret={}", enrollment.secret)));
+        // 160 bits, base32 encoded
+        assert_eq!(enrollment.secret.len(), 32);
+
+        // until it is confirmed, the secret is not asked for
+        login(&server, &email)
+            .await
+            .assert_status(axum_test::http::StatusCode::CREATED);
+        post_code(&server, "/2fa/confirm", &token, "000000")
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+
+        let mut authenticator = Authenticator {
+            secret: enrollment.secret,
+            next_step: 0,
+        };
+        let confirmation = post_code(&server, "/2fa/confirm", &token, &authenticator.code())
+            .await
+            .json::<Confirmation>();
+        assert!(confirmation.two_factor_enabled);
+        assert_eq!(confirmation.recovery_codes.len(), 10);
+        // 80 random bits each, as 20 hex digits in groups of five
+        for code in &confirmation.recovery_codes {
+            let digits = code.replace('-', "");
+            assert_eq!(code.len(), 23);
+            assert_eq!(digits.len(), 20);
+            assert!(digits.chars().all(|c| c.is_ascii_hexdigit()));
+        }
+
+        server
+            .post("/2fa/enroll")
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(&token))
+            .expect_failure()
+            .await
+            .assert_status(axum_test::http::StatusCode::CONFLICT);
+        challenge(&server, &email).await;
+    }
+
+    #[tokio::test]
+    async fn login_needs_a_code_after_the_password() {
+        let server = test_server();
+        let (email, _, mut authenticator, _) = register_with_2fa(&server, "login2fa").await;
+
+        let challenge_token = challenge(&server, &email).await;
+        complete_login(&server, &challenge_token, "000000")
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+        let code = authenticator.code();
+        let response = complete_login(&server, &challenge_token, &code).await;
+        response.assert_status(axum_test::http::StatusCode::CREATED);
+        let token = response.json::<LoginRequest>().token;
+        server
+            .get("/sessions")
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(&token))
+            .await;
+
+        // neither the challenge nor the code can be used again
+        complete_login(&server, &challenge_token, &authenticator.code())
+            .expect_failure()
+            .await
+            .assert_status_unauthorized();
+        let second_challenge = challenge(&server, &email).await;
+        complete_login(&server, &second_challenge, &code)
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+    }
+
+    #[tokio::test]
+    async fn challenges_stop_working_after_too_many_wrong_codes() {
+        let server = test_server();
+        let (email, _, mut authenticator, _) = register_with_2fa(&server, "attempts").await;
+
+        let challenge_token = challenge(&server, &email).await;
+        for _ in 0..5 {
+            complete_login(&server, &challenge_token, "000000")
+                .expect_failure()
+                .await
+                .assert_status_bad_request();
+        }
+        complete_login(&server, &challenge_token, &authenticator.code())
+            .expect_failure()
+            .await
+            .assert_status_unauthorized();
+    }
+
+    #[tokio::test]
+    async fn wrong_codes_count_as_failed_logins() {
+        let server = test_server();
+        let (email, _, mut authenticator, _) = register_with_2fa(&server, "guesser").await;
+
+        let challenge_token = challenge(&server, &email).await;
+        for _ in 0..3 {
+            complete_login(&server, &challenge_token, "000000")
+                .expect_failure()
+                .await
+                .assert_status_bad_request();
+        }
+        // the right code and the right password both have to wait now
+        let code = authenticator.code();
+        complete_login(&server, &challenge_token, &code)
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+        login(&server, &email)
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        complete_login(&server, &challenge_token, &code)
+            .await
+            .assert_status(axum_test::http::StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn recovery_codes_work_once() {
+        let server = test_server();
+        let (email, _, _, recovery_codes) = register_with_2fa(&server, "recovery").await;
+
+        let challenge_token = challenge(&server, &email).await;
+        complete_login(&server, &challenge_token, &recovery_codes[0].to_uppercase())
+            .await
+            .assert_status(axum_test::http::StatusCode::CREATED);
+
+        let challenge_token = challenge(&server, &email).await;
+        complete_login(&server, &challenge_token, &recovery_codes[0])
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+        complete_login(&server, &challenge_token, &recovery_codes[1])
+            .await
+            .assert_status(axum_test::http::StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn disabling_2fa_brings_back_single_step_login() {
+        let server = test_server();
+        let (email, token, _, recovery_codes) = register_with_2fa(&server, "disable").await;
+
+        post_code(&server, "/2fa/disable", &token, "000000")
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+        let response = post_code(&server, "/2fa/disable", &token, &recovery_codes[0]).await;
+        response.assert_status_ok();
+        assert_eq!(
+            response.json::<serde_json::Value>()["two_factor_enabled"],
+            json!(false)
+        );
+
+        login(&server, &email)
+            .await
+            .assert_status(axum_test::http::StatusCode::CREATED);
+        post_code(&server, "/2fa/disable", &token, &recovery_codes[1])
+            .expect_failure()
+            .await
+            .assert_status(axum_test::http::StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn large_transfers_need_a_fresh_code() {
+        let server = test_server();
+        let (email, token, mut authenticator, _) = register_with_2fa(&server, "bigsender").await;
+        let receiver = register(&server, "bigreceiver").await;
+        let send = |amount: &str| {
+            server
+                .post("/transaction")
+                .json(&json!({
+                            "from_email": email,
+                            "to_email": receiver,
+                            "amount": amount
+                }))
+                .add_header(axum_test::http::header::AUTHORIZATION, bearer(&token))
+        };
+
+        let totp_header = |code: &str| {
+            (
+                axum_test::http::HeaderName::from_static("x-totp-code"),
+                axum_test::http::HeaderValue::from_str(code).unwrap(),
+            )
+        };
+
+        send("600.00")
+            .expect_failure()
+            .await
+            .assert_status_forbidden();
+        let (name, value) = totp_header("000000");
+        send("600.00")
+            .add_header(name, value)
+            .expect_failure()
+            .await
+            .assert_status_forbidden();
+        let code = authenticator.code();
+        let (name, value) = totp_header(&code);
+        send("600.00")
+            .add_header(name, value)
+            .await
+            .assert_status(axum_test::http::StatusCode::CREATED);
+        // a code passes once, and is checked before the balance
+        let (name, value) = totp_header(&code);
+        send("500.01")
+            .add_header(name, value)
+            .expect_failure()
+            .await
+            .assert_status_forbidden();
+        send("300.00")
+            .await
+            .assert_status(axum_test::http::StatusCode::CREATED);
+
+        // the third wrong code makes the email wait, for transfers and logins
+        let (name, value) = totp_header("000000");
+        send("600.00")
+            .add_header(name, value)
+            .expect_failure()
+            .await
+            .assert_status_forbidden();
+        let (name, value) = totp_header(&authenticator.code());
+        send("600.00")
+            .add_header(name, value)
+            .expect_failure()
+            .await
+            .assert_status_forbidden();
+        login(&server, &email)
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+    }
+}
+
+#[cfg(test)]
+mod test_login_lockout {
+    use super::*;
+    use ::serde_json::json;
+    use sqlx::Row;
+    use std::time::Duration;
+    use transaction_service::config::db::get_conn;
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct LoginRequest {
+        email: String,
+        token: String,
+    }
+
+    async fn register(server: &TestServer, prefix: &str) -> String {
+        let email = unique_email(prefix);
+        register_verified(server, &email, "0.00").await;
+        email
+    }
+
+    fn login(server: &TestServer, email: &str, password: &str) -> axum_test::TestRequest {
+        server.post("/login").json(&json!({
+                    "email": email,
+                    "password": password
+        }))
+    }
+
+    async fn wrong_login(server: &TestServer, email: &str) -> serde_json::Value {
+        let response = login(server, email, "wrongpassword123")
+            .expect_failure()
+            .await;
+        response.assert_status_bad_request();
+        response.json::<serde_json::Value>()
+    }
+
+    async fn admin_token(server: &TestServer) -> axum_test::http::HeaderValue {
+        let email = register(server, "lockout-admin").await;
+        sqlx::query("UPDATE users SET role = 'admin'::role WHERE email = $1")
+            .bind(&email)
+            .execute(get_conn().await)
+            .await
+            .unwrap();
+        let token = login(server, &email, "testpassword123")
+            .await
+            .json::<LoginRequest>()
+            .token;
+        axum_test::http::HeaderValue::from_str(&format!("Bearer {}", token)).unwrap()
+    }
+
+    async fn user_id(email: &str) -> String {
+        sqlx::query("SELECT id FROM users WHERE email = $1")
+            .bind(email)
+            .fetch_one(get_conn().await)
+            .await
+            .unwrap()
+            .get::<String, &str>("id")
+    }
+
+    #[tokio::test]
+    async fn repeated_failures_lock_the_email_out() {
+        let server = test_server();
+        let email = register(&server, "lockout").await;
+
+        let wrong = wrong_login(&server, &email).await;
+        wrong_login(&server, &email).await;
+        wrong_login(&server, &email).await;
+        // the right password has to wait now, and fails like a wrong one
+        let blocked = login(&server, &email, "testpassword123")
+            .expect_failure()
+            .await;
+        blocked.assert_status_bad_request();
+        assert_eq!(blocked.json::<serde_json::Value>(), wrong);
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        wrong_login(&server, &email).await;
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        wrong_login(&server, &email).await;
+        tokio::time::sleep(Duration::from_millis(4100)).await;
+        login(&server, &email, "testpassword123")
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+
+        let events = sqlx::query(
+            "SELECT details FROM security_events WHERE kind = 'login_lockout' AND email = $1",
+        )
+        .bind(&email)
+        .fetch_all(get_conn().await)
+        .await
+        .unwrap();
+        assert_eq!(events.len(), 1);
+        let details = events[0].get::<serde_json::Value, &str>("details");
+        assert_eq!(details["scope"], json!("email"));
+        assert_eq!(details["failures"], json!(5));
+    }
+
+    #[tokio::test]
+    async fn admins_can_unlock_a_locked_out_user() {
+        let server = test_server();
+        let email = register(&server, "unlock").await;
+        sqlx::query("INSERT INTO login_failures (scope, subject, failures, last_failure_at) VALUES ('email', $1, 5, now())")
+            .bind(&email)
+            .execute(get_conn().await)
+            .await
+            .unwrap();
+        login(&server, &email, "testpassword123")
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+
+        let user_token = login(
+            &server,
+            &register(&server, "not-admin").await,
+            "testpassword123",
+        )
+        .await
+        .json::<LoginRequest>()
+        .token;
+        let id = user_id(&email).await;
+        server
+            .post(&format!("/admin/users/{}/unlock", id))
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                axum_test::http::HeaderValue::from_str(&format!("Bearer {}", user_token)).unwrap(),
+            )
+            .expect_failure()
+            .await
+            .assert_status_forbidden();
+
+        let admin = admin_token(&server).await;
+        server
+            .post(&format!("/admin/users/{}/unlock", id))
+            .add_header(axum_test::http::header::AUTHORIZATION, admin)
+            .await
+            .assert_status_ok();
+        login(&server, &email, "testpassword123")
+            .await
+            .assert_status(axum_test::http::StatusCode::CREATED);
+
+        let actions = sqlx::query(
+            "SELECT details FROM admin_actions WHERE action = 'unlock_login' AND target_user_id = $1",
+        )
+        .bind(&id)
+        .fetch_all(get_conn().await)
+        .await
+        .unwrap();
+        assert_eq!(actions.len(), 1);
+        assert_eq!(
+            actions[0].get::<serde_json::Value, &str>("details")["had_failures"],
+            json!(true)
+        );
+    }
+
+    #[tokio::test]
+    async fn a_successful_login_clears_earlier_failures() {
+        let server = test_server();
+        let email = register(&server, "clears").await;
+        for _ in 0..2 {
+            wrong_login(&server, &email).await;
+            wrong_login(&server, &email).await;
+            login(&server, &email, "testpassword123")
+                .await
+                .assert_status(axum_test::http::StatusCode::CREATED);
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_emails_are_throttled_too() {
+        let server = test_server();
+        let email = format!("nobody-{}@test.com", uuid::Uuid::new_v4().as_simple());
+        for _ in 0..3 {
+            wrong_login(&server, &email).await;
+        }
+        let failures = sqlx::query(
+            "SELECT failures FROM login_failures WHERE scope = 'email' AND subject = $1",
+        )
+        .bind(&email)
+        .fetch_one(get_conn().await)
+        .await
+        .unwrap()
+        .get::<i32, &str>("failures");
+        assert_eq!(failures, 3);
+    }
+
+    #[tokio::test]
+    async fn unknown_emails_take_as_long_as_wrong_passwords() {
+        let server = test_server();
+        let email = register(&server, "lockout-timing").await;
+
+        let started = std::time::Instant::now();
+        wrong_login(&server, &email).await;
+        let wrong_password = started.elapsed();
+        let started = std::time::Instant::now();
+        wrong_login(&server, &unique_email("lockout-nobody")).await;
+        let unknown_email = started.elapsed();
+
+        // both run bcrypt, which is most of the time either takes
+        assert!(
+            unknown_email * 2 > wrong_password,
+            "unknown email took {:?}, wrong password {:?}",
+            unknown_email,
+            wrong_password
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_api_keys {
+    use super::*;
+    use ::serde_json::json;
+    use axum_test::http::{HeaderValue, StatusCode};
+    use transaction_service::config::db::get_conn;
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    struct CreatedKey {
+        id: String,
+        key: String,
+        name: String,
+        prefix: String,
+        scopes: Vec<String>,
+        expires_at: Option<DateTime<Utc>>,
+    }
+
+    async fn create_key(server: &TestServer, token: &HeaderValue, scopes: &[&str]) -> CreatedKey {
+        let response = server
+            .post("/api-keys")
+            .json(&json!({ "name": "nightly export", "scopes": scopes }))
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+            .await;
+        response.assert_status(StatusCode::CREATED);
+        response.json::<CreatedKey>()
+    }
+
+    async fn list_keys(server: &TestServer, token: &HeaderValue) -> Vec<serde_json::Value> {
+        server
+            .get("/api-keys")
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+            .await
+            .json::<serde_json::Value>()["api_keys"]
+            .as_array()
+            .unwrap()
+            .clone()
+    }
+
+    #[tokio::test]
+    async fn api_keys_are_created_listed_and_revoked() {
+        let server = test_server();
+        let token = register_and_login(&server, &unique_email("api-keys"), "10.00").await;
+        let other_token =
+            register_and_login(&server, &unique_email("api-keys-other"), "10.00").await;
+
+        let created = create_key(&server, &token, &["transactions:read", "balance:read"]).await;
+        assert!(created.key.starts_with("trnx_"));
+        assert!(created.key.starts_with(&created.prefix));
+        assert_eq!(created.prefix.len(), 13);
+        assert_eq!(created.name, "nightly export");
+        assert_eq!(created.scopes, vec!["transactions:read", "balance:read"]);
+        assert_eq!(created.expires_at, None);
+
+        let keys = list_keys(&server, &token).await;
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0]["id"], created.id.as_str());
+        assert_eq!(keys[0]["prefix"], created.prefix.as_str());
+        // the key itself is shown once and only its hash is stored
+        assert!(keys[0].get("key").is_none());
+        let stored = sqlx::query("SELECT 1 FROM api_keys WHERE key_hash = $1")
+            .bind(&created.key)
+            .fetch_optional(get_conn().await)
+            .await
+            .unwrap();
+        assert!(stored.is_none());
+        assert!(list_keys(&server, &other_token).await.is_empty());
+
+        // keys of other users are not found
+        server
+            .delete(&format!("/api-keys/{}", created.id))
+            .add_header(axum_test::http::header::AUTHORIZATION, other_token)
+            .expect_failure()
+            .await
+            .assert_status_not_found();
+        server
+            .get("/transaction")
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(&created.key))
+            .await
+            .assert_status_ok();
+
+        let revoked = server
+            .delete(&format!("/api-keys/{}", created.id))
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+            .await;
+        assert_eq!(
+            revoked.json::<serde_json::Value>(),
+            json!({ "id": created.id, "revoked": true })
+        );
+        assert!(list_keys(&server, &token).await.is_empty());
+        server
+            .get("/transaction")
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(&created.key))
+            .expect_failure()
+            .await
+            .assert_status_unauthorized();
+        server
+            .delete(&format!("/api-keys/{}", created.id))
+            .add_header(axum_test::http::header::AUTHORIZATION, token)
+            .expect_failure()
+            .await
+            .assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn api_keys_only_reach_the_routes_of_their_scopes() {
+        let server = test_server();
+        let email = unique_email("api-scopes");
+        let token = register_and_login(&server, &email, "10.00").await;
+        let receiver = unique_email("api-scopes-receiver");
+        let _ = register_and_login(&server, &receiver, "10.00").await;
+        let reader = bearer(&create_key(&server, &token, &["balance:read"]).await.key);
+        let writer = bearer(
+            &create_key(&server, &token, &["transactions:write"])
+                .await
+                .key,
+        );
+
+        let balance = server
+            .get("/balance")
+            .json(&json!({ "email": email }))
+            .add_header(axum_test::http::header::AUTHORIZATION, reader.clone())
+            .await;
+        assert_eq!(balance.json::<serde_json::Value>()["balance"], "10.00");
+        server
+            .get("/transaction")
+            .add_header(axum_test::http::header::AUTHORIZATION, reader.clone())
+            .expect_failure()
+            .await
+            .assert_status_forbidden();
+        let transfer = json!({
+                    "from_email": email,
+                    "to_email": receiver,
+                    "amount": "2.50"
+        });
+        server
+            .post("/transaction")
+            .json(&transfer)
+            .add_header(axum_test::http::header::AUTHORIZATION, reader.clone())
+            .expect_failure()
+            .await
+            .assert_status_forbidden();
+        server
+            .post("/transaction")
+            .json(&transfer)
+            .add_header(axum_test::http::header::AUTHORIZATION, writer.clone())
+            .await
+            .assert_status(StatusCode::CREATED);
+
+        // account routes and admin routes take a token from /login
+        for key in [reader, writer] {
+            server
+                .get("/sessions")
+                .add_header(axum_test::http::header::AUTHORIZATION, key.clone())
+                .expect_failure()
+                .await
+                .assert_status_forbidden();
+            server
+                .post("/api-keys")
+                .json(&json!({ "name": "escalated", "scopes": ["transactions:read"] }))
+                .add_header(axum_test::http::header::AUTHORIZATION, key.clone())
+                .expect_failure()
+                .await
+                .assert_status_forbidden();
+            server
+                .get("/admin/users")
+                .add_header(axum_test::http::header::AUTHORIZATION, key)
+                .expect_failure()
+                .await
+                .assert_status_forbidden();
+        }
+        server
+            .get("/transaction")
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                bearer("trnx_0000000000000000000000000000000000000000000000000000000000000000"),
+            )
+            .expect_failure()
+            .await
+            .assert_status_unauthorized();
+    }
+
+    #[tokio::test]
+    async fn invalid_and_expired_keys_are_rejected() {
+        let server = test_server();
+        let token = register_and_login(&server, &unique_email("api-expiry"), "10.00").await;
+
+        for (request, error) in [
+            (
+                json!({ "name": " ", "scopes": ["balance:read"] }),
+                "name must be between 1 and 100 characters",
+            ),
+            (
+                json!({ "name": "no scopes", "scopes": [] }),
+                "at least one scope is required",
+            ),
+            (
+                json!({
+                    "name": "expired",
+                    "scopes": ["balance:read"],
+                    "expires_at": Utc::now() - chrono::Duration::minutes(1)
+                }),
+                "expires_at must be in the future",
+            ),
+        ] {
+            let response = server
+                .post("/api-keys")
+                .json(&request)
+                .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+                .expect_failure()
+                .await;
+            response.assert_status_bad_request();
+            assert_eq!(response.json::<serde_json::Value>()["error"], error);
+        }
+        server
+            .post("/api-keys")
+            .json(&json!({ "name": "unknown scope", "scopes": ["admin:write"] }))
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+
+        let expires_at = Utc::now() + chrono::Duration::hours(1);
+        let response = server
+            .post("/api-keys")
+            .json(&json!({
+                        "name": "expiring",
+                        "scopes": ["transactions:read"],
+                        "expires_at": expires_at
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+            .await;
+        let created = response.json::<CreatedKey>();
+        assert!(created.expires_at.is_some());
+        server
+            .get("/transaction")
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(&created.key))
+            .await
+            .assert_status_ok();
+        let last_used =
+            sqlx::query("SELECT 1 FROM api_keys WHERE id = $1 AND last_used_at IS NOT NULL")
+                .bind(&created.id)
+                .fetch_optional(get_conn().await)
+                .await
+                .unwrap();
+        assert!(last_used.is_some());
+
+        sqlx::query("UPDATE api_keys SET expires_at = now() - interval '1 second' WHERE id = $1")
+            .bind(&created.id)
+            .execute(get_conn().await)
+            .await
+            .unwrap();
+        server
+            .get("/transaction")
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(&created.key))
+            .expect_failure()
+            .await
+            .assert_status_unauthorized();
+        assert!(list_keys(&server, &token).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn balance_keys_only_read_their_owners_balance() {
+        let server = test_server();
+        let alice = unique_email("balance-key");
+        let token = register_and_login(&server, &alice, "10.00").await;
+        let bob = unique_email("balance-key-other");
+        register_and_login(&server, &bob, "99.00").await;
+        let key = create_key(&server, &token, &["balance:read"]).await;
+
+        server
+            .get("/balance")
+            .json(&json!({ "email": bob }))
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(&key.key))
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        server
+            .get("/balance")
+            .json(&json!({ "email": bob }))
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        let balance = server
+            .get("/balance")
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(&key.key))
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(balance["email"], alice);
+        assert_eq!(balance["balance"], "10.00");
+    }
+
+    #[tokio::test]
+    async fn staff_keys_do_not_see_other_users_transactions() {
+        let server = test_server();
+        let staff = unique_email("staff-key");
+        register_verified(&server, &staff, "0.00").await;
+        set_role(&staff, "support").await;
+        let staff_token = login_bearer(&server, &staff).await;
+        let alice = unique_email("staff-key-alice");
+        let alice_token = register_and_login(&server, &alice, "10.00").await;
+        let bob = unique_email("staff-key-bob");
+        register_and_login(&server, &bob, "0.00").await;
+        let transaction = server
+            .post("/transaction")
+            .json(&json!({
+                        "from_email": alice,
+                        "to_email": bob,
+                        "amount": "1.00"
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, alice_token)
+            .await
+            .json::<serde_json::Value>();
+        let path = format!("/transaction/{}", transaction["id"].as_str().unwrap());
+
+        server
+            .get(&path)
+            .add_header(axum_test::http::header::AUTHORIZATION, staff_token.clone())
+            .await
+            .assert_status_ok();
+        let key = create_key(&server, &staff_token, &["transactions:read"]).await;
+        server
+            .get(&path)
+            .add_header(axum_test::http::header::AUTHORIZATION, bearer(&key.key))
+            .expect_failure()
+            .await
+            .assert_status_not_found();
+    }
+}
+
+#[cfg(test)]
+mod test_oauth {
+    use super::*;
+    use ::serde_json::json;
+    use axum_test::http::{HeaderValue, StatusCode};
+    use base64::{
+        engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+        Engine,
+    };
+    use sha2::{Digest, Sha256};
+
+    const REDIRECT_URI: &str = "https://app.example.com/callback";
+
+    #[derive(Debug, Clone, Deserialize)]
+    struct RegisteredClient {
+        client_id: String,
+        client_secret: Option<String>,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    struct TokenResponse {
+        access_token: String,
+        token_type: String,
+        expires_in: i64,
+        scope: String,
+    }
+
+    fn new_verifier() -> String {
+        format!(
+            "{}-{}",
+            uuid::Uuid::new_v4().as_simple(),
+            uuid::Uuid::new_v4().as_simple()
+        )
+    }
+
+    fn challenge(verifier: &str) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+    }
+
+    async fn register_client(
+        server: &TestServer,
+        token: &HeaderValue,
+        scopes: &[&str],
+        public: bool,
+    ) -> RegisteredClient {
+        let response = server
+            .post("/oauth/clients")
+            .json(&json!({
+                        "name": "budget app",
+                        "redirect_uris": [REDIRECT_URI],
+                        "scopes": scopes,
+                        "public": public
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+            .await;
+        response.assert_status(StatusCode::CREATED);
+        response.json::<RegisteredClient>()
+    }
+
+    fn authorization_request(client_id: &str, scope: &str, verifier: &str) -> serde_json::Value {
+        json!({
+                    "response_type": "code",
+                    "client_id": client_id,
+                    "redirect_uri": REDIRECT_URI,
+                    "scope": scope,
+                    "state": "xyz",
+                    "code_challenge": challenge(verifier),
+                    "code_challenge_method": "S256"
+        })
+    }
+
+    /// Approves an authorization request and returns the code it redirects with.
+    async fn authorization_code(
+        server: &TestServer,
+        token: &HeaderValue,
+        client_id: &str,
+        scope: &str,
+        verifier: &str,
+    ) -> String {
+        let mut decision = authorization_request(client_id, scope, verifier);
+        decision["approve"] = json!(true);
+        let redirect_to = server
+            .post("/oauth/authorize")
+            .json(&decision)
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+            .await
+            .json::<serde_json::Value>()["redirect_to"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let query = redirect_to
+            .strip_prefix(&format!("{}?", REDIRECT_URI))
+            .unwrap();
+        assert!(query.ends_with("&state=xyz"));
+        query
+            .split('&')
+            .find_map(|param| param.strip_prefix("code="))
+            .unwrap()
+            .to_string()
+    }
+
+    fn exchange(
+        server: &TestServer,
+        client: &RegisteredClient,
+        code: &str,
+        verifier: &str,
+    ) -> axum_test::TestRequest {
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", REDIRECT_URI),
+            ("code_verifier", verifier),
+            ("client_id", &client.client_id),
+        ];
+        if let Some(secret) = &client.client_secret {
+            form.push(("client_secret", secret));
+        }
+        server.post("/oauth/token").form(&form)
+    }
+
+    #[tokio::test]
+    async fn authorization_code_grant_issues_scoped_tokens() {
+        let server = test_server();
+        let email = unique_email("oauth-code");
+        let token = register_and_login(&server, &email, "10.00").await;
+        let client = register_client(
+            &server,
+            &token,
+            &["transactions:read", "balance:read"],
+            false,
+        )
+        .await;
+        assert!(client.client_secret.is_some());
+
+        let verifier = new_verifier();
+        let prompt = server
+            .get("/oauth/authorize")
+            .add_query_params(authorization_request(
+                &client.client_id,
+                "transactions:read",
+                &verifier,
+            ))
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(prompt["client_name"], "budget app");
+        assert_eq!(prompt["scopes"], json!(["transactions:read"]));
+        assert_eq!(prompt["consented"], false);
+
+        // a code is used up by a wrong verifier
+        let code = authorization_code(
+            &server,
+            &token,
+            &client.client_id,
+            "transactions:read",
+            &verifier,
+        )
+        .await;
+        let response = exchange(&server, &client, &code, &new_verifier())
+            .expect_failure()
+            .await;
+        response.assert_status_bad_request();
+        assert_eq!(
+            response.json::<serde_json::Value>()["error"],
+            "invalid_grant"
+        );
+        exchange(&server, &client, &code, &verifier)
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+
+        let code = authorization_code(
+            &server,
+            &token,
+            &client.client_id,
+            "transactions:read",
+            &verifier,
+        )
+        .await;
+        let response = exchange(&server, &client, &code, &verifier).await;
+        response.assert_status_ok();
+        assert_eq!(response.headers().get("cache-control").unwrap(), "no-store");
+        let issued = response.json::<TokenResponse>();
+        assert_eq!(issued.token_type, "Bearer");
+        assert_eq!(issued.scope, "transactions:read");
+        assert!(issued.expires_in > 0);
+
+        server
+            .get("/transaction")
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                bearer(&issued.access_token),
+            )
+            .await
+            .assert_status_ok();
+        server
+            .get("/balance")
+            .json(&json!({ "email": email }))
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                bearer(&issued.access_token),
+            )
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        // routes for tokens from /login stay closed to clients
+        server
+            .get("/sessions")
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                bearer(&issued.access_token),
+            )
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        server
+            .post("/oauth/clients")
+            .json(&json!({
+                        "name": "another app",
+                        "redirect_uris": [REDIRECT_URI],
+                        "scopes": ["transactions:read"]
+            }))
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                bearer(&issued.access_token),
+            )
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        let prompt = server
+            .get("/oauth/authorize")
+            .add_query_params(authorization_request(
+                &client.client_id,
+                "transactions:read",
+                &verifier,
+            ))
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(prompt["consented"], true);
+
+        // presenting a code again revokes the token issued for it
+        exchange(&server, &client, &code, &verifier)
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+        server
+            .get("/transaction")
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                bearer(&issued.access_token),
+            )
+            .expect_failure()
+            .await
+            .assert_status_unauthorized();
+    }
+
+    #[tokio::test]
+    async fn client_tokens_of_staff_do_not_see_other_users_transactions() {
+        let server = test_server();
+        let staff = unique_email("oauth-staff");
+        register_verified(&server, &staff, "0.00").await;
+        set_role(&staff, "auditor").await;
+        let staff_token = login_bearer(&server, &staff).await;
+        let alice = unique_email("oauth-staff-alice");
+        let alice_token = register_and_login(&server, &alice, "10.00").await;
+        let bob = unique_email("oauth-staff-bob");
+        register_and_login(&server, &bob, "0.00").await;
+        let transaction = server
+            .post("/transaction")
+            .json(&json!({
+                        "from_email": alice,
+                        "to_email": bob,
+                        "amount": "1.00"
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, alice_token)
+            .await
+            .json::<serde_json::Value>();
+        let path = format!("/transaction/{}", transaction["id"].as_str().unwrap());
+        server
+            .get(&path)
+            .add_header(axum_test::http::header::AUTHORIZATION, staff_token.clone())
+            .await
+            .assert_status_ok();
+
+        let client = register_client(&server, &staff_token, &["transactions:read"], false).await;
+        let issued = server
+            .post("/oauth/token")
+            .form(&[
+                ("grant_type", "client_credentials"),
+                ("client_id", client.client_id.as_str()),
+                ("client_secret", client.client_secret.as_deref().unwrap()),
+            ])
+            .await
+            .json::<TokenResponse>();
+        let claims = issued.access_token.split('.').nth(1).unwrap();
+        let claims: serde_json::Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).unwrap()).unwrap();
+        assert_eq!(claims["role"], "user");
+        server
+            .get(&path)
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                bearer(&issued.access_token),
+            )
+            .expect_failure()
+            .await
+            .assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn client_credentials_grant_acts_as_the_owner() {
+        let server = test_server();
+        let email = unique_email("oauth-client");
+        let token = register_and_login(&server, &email, "10.00").await;
+        let client = register_client(&server, &token, &["balance:read"], false).await;
+        let secret = client.client_secret.clone().unwrap();
+
+        let response = server
+            .post("/oauth/token")
+            .form(&json!({
+                        "grant_type": "client_credentials",
+                        "client_id": client.client_id,
+                        "client_secret": "not the secret"
+            }))
+            .expect_failure()
+            .await;
+        response.assert_status_unauthorized();
+        assert_eq!(
+            response.json::<serde_json::Value>()["error"],
+            "invalid_client"
+        );
+        let response = server
+            .post("/oauth/token")
+            .form(&json!({
+                        "grant_type": "client_credentials",
+                        "scope": "transactions:write"
+            }))
+            .authorization(format!(
+                "Basic {}",
+                STANDARD.encode(format!("{}:{}", client.client_id, secret))
+            ))
+            .expect_failure()
+            .await;
+        response.assert_status_bad_request();
+        assert_eq!(
+            response.json::<serde_json::Value>()["error"],
+            "invalid_scope"
+        );
+        let response = server
+            .post("/oauth/token")
+            .form(&json!({
+                        "grant_type": "password",
+                        "client_id": client.client_id,
+                        "client_secret": secret
+            }))
+            .expect_failure()
+            .await;
+        assert_eq!(
+            response.json::<serde_json::Value>()["error"],
+            "unsupported_grant_type"
+        );
+
+        let issued = server
+            .post("/oauth/token")
+            .form(&json!({
+                        "grant_type": "client_credentials"
+            }))
+            .authorization(format!(
+                "Basic {}",
+                STANDARD.encode(format!("{}:{}", client.client_id, secret))
+            ))
+            .await
+            .json::<TokenResponse>();
+        assert_eq!(issued.scope, "balance:read");
+        let balance = server
+            .get("/balance")
+            .json(&json!({ "email": email }))
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                bearer(&issued.access_token),
+            )
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(balance["balance"], "10.00");
+
+        // public clients cannot keep a secret, so they cannot use this grant
+        let public = register_client(&server, &token, &["balance:read"], true).await;
+        assert!(public.client_secret.is_none());
+        let response = server
+            .post("/oauth/token")
+            .form(&json!({
+                        "grant_type": "client_credentials",
+                        "client_id": public.client_id
+            }))
+            .expect_failure()
+            .await;
+        assert_eq!(
+            response.json::<serde_json::Value>()["error"],
+            "unauthorized_client"
+        );
+
+        server
+            .delete(&format!("/oauth/clients/{}", client.client_id))
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+            .await
+            .assert_status_ok();
+        server
+            .get("/balance")
+            .json(&json!({ "email": email }))
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                bearer(&issued.access_token),
+            )
+            .expect_failure()
+            .await
+            .assert_status_unauthorized();
+        server
+            .delete(&format!("/oauth/clients/{}", client.client_id))
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+            .expect_failure()
+            .await
+            .assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn users_can_deny_and_take_back_consent() {
+        let server = test_server();
+        let owner_token = register_and_login(&server, &unique_email("oauth-owner"), "10.00").await;
+        let token = register_and_login(&server, &unique_email("oauth-user"), "10.00").await;
+        let client = register_client(&server, &owner_token, &["transactions:read"], true).await;
+
+        server
+            .post("/oauth/clients")
+            .json(&json!({
+                        "name": "plain http app",
+                        "redirect_uris": ["http://app.example.com/callback"],
+                        "scopes": ["transactions:read"]
+            }))
+            .add_header(axum_test::http::header::AUTHORIZATION, owner_token.clone())
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+
+        let verifier = new_verifier();
+        let mut request = authorization_request(&client.client_id, "", &verifier);
+        request["redirect_uri"] = json!("https://attacker.example.com/callback");
+        server
+            .get("/oauth/authorize")
+            .add_query_params(&request)
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+        let mut request = authorization_request(&client.client_id, "", &verifier);
+        request["code_challenge_method"] = json!("plain");
+        request["code_challenge"] = json!(verifier);
+        server
+            .get("/oauth/authorize")
+            .add_query_params(&request)
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+            .expect_failure()
+            .await
+            .assert_status_bad_request();
+
+        let mut decision = authorization_request(&client.client_id, "", &verifier);
+        decision["approve"] = json!(false);
+        let denied = server
+            .post("/oauth/authorize")
+            .json(&decision)
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(
+            denied["redirect_to"],
+            format!("{}?error=access_denied&state=xyz", REDIRECT_URI)
+        );
+
+        // a public client redeems its code without a secret
+        let code = authorization_code(&server, &token, &client.client_id, "", &verifier).await;
+        let issued = exchange(&server, &client, &code, &verifier)
+            .await
+            .json::<TokenResponse>();
+        assert_eq!(issued.scope, "transactions:read");
+        let consents = server
+            .get("/oauth/consents")
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(consents["consents"].as_array().unwrap().len(), 1);
+        assert_eq!(consents["consents"][0]["client_id"], client.client_id);
+
+        let revoked = server
+            .delete(&format!("/oauth/consents/{}", client.client_id))
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+            .await
+            .json::<serde_json::Value>();
+        assert_eq!(revoked["revoked_tokens"], 1);
+        server
+            .get("/transaction")
+            .add_header(
+                axum_test::http::header::AUTHORIZATION,
+                bearer(&issued.access_token),
+            )
+            .expect_failure()
+            .await
+            .assert_status_unauthorized();
+        let consents = server
+            .get("/oauth/consents")
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+            .await
+            .json::<serde_json::Value>();
+        assert!(consents["consents"].as_array().unwrap().is_empty());
+        server
+            .delete(&format!("/oauth/consents/{}", client.client_id))
+            .add_header(axum_test::http::header::AUTHORIZATION, token.clone())
+            .expect_failure()
+            .await
+            .assert_status_not_found();
+    }
+}